@@ -4,4 +4,10 @@
 
 pub mod tar;
 
-pub use tar::{create_archive, extract_archive};
+pub use tar::{
+    ArchiveOptions, ArchiveReader, ArchiveReport, ChangedFile, MAX_TAR_MTIME, SharedReport,
+    SkippedEntry, check_archive_name, check_tar_archive, create_archive, create_archive_from_files,
+    create_archive_from_files_into, create_archive_into, create_archive_with, extract_archive,
+    extract_archive_entries, extract_archive_mapped, extract_archive_stream,
+    extract_archive_stream_with, file_mtime, source_date_epoch, update_archive_into,
+};
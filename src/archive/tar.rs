@@ -1,15 +1,186 @@
 //! Tar archive operations
 //!
 //! Creates and extracts tar archives for directory encryption.
+//!
+//! Entry names are stored as the raw bytes the filesystem reports, so
+//! names that are not valid UTF-8 round-trip exactly on Unix: tar headers
+//! hold bytes, and longer names go into GNU long-name records, which hold
+//! bytes too. Where paths must be Unicode (Windows), such names cannot
+//! occur.
 
-use std::fs::{self, File};
-use std::io::{Read, Write};
-use std::path::Path;
+use std::collections::HashMap;
+use std::env;
+use std::ffi::OsString;
+use std::fs::{self, File, FileType};
+use std::io::{self, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::UNIX_EPOCH;
 
-use tar::{Archive, Builder};
-use walkdir::WalkDir;
+use tar::{Archive, Builder, Entry, EntryType, Header};
 
 use crate::error::{ResqryptError, Result};
+use crate::utils::telemetry::SpanStats;
+use crate::utils::{CountingWriter, create_output_dir, write_atomic};
+
+/// PAX record prefix used for extended attributes
+const PAX_XATTR_PREFIX: &str = "SCHILY.xattr.";
+
+/// Options controlling archive creation and extraction
+#[derive(Debug, Clone, Default)]
+pub struct ArchiveOptions {
+    /// Store extended attributes as PAX records and restore them on
+    /// extraction (Unix only, ignored elsewhere)
+    pub xattrs: bool,
+    /// Stamp every entry with this modification time (seconds since the
+    /// Unix epoch) instead of its real one, for reproducible archives
+    pub mtime: Option<u64>,
+    /// Name of the root directory stored in the archive, instead of the
+    /// source directory's own name (see [`check_archive_name`])
+    pub root_name: Option<String>,
+    /// Canonical paths left out of the archive, such as the output being
+    /// written inside the tree
+    pub exclude: Vec<PathBuf>,
+    /// On extraction, skip entries that cannot be unpacked (such as names
+    /// the filesystem rejects) instead of aborting
+    pub skip_failed_entries: bool,
+    /// When archiving, store each file as it was when opened: read exactly
+    /// its size at that moment, padding with zeros if it shrinks, instead
+    /// of failing when a file changes size while it is read
+    pub snapshot: bool,
+    /// When archiving a directory, store the directories where other
+    /// filesystems are mounted but not their contents, as `tar
+    /// --one-file-system` does (Unix only, ignored elsewhere)
+    pub one_file_system: bool,
+}
+
+/// A file that changed size while it was archived with
+/// [`ArchiveOptions::snapshot`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChangedFile {
+    /// Path of the file on disk
+    pub path: PathBuf,
+    /// Size when opened, which is what the archive stores
+    pub recorded: u64,
+    /// Size once it had been read
+    pub actual: u64,
+}
+
+/// What happened while an archive was created, for the caller to report
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ArchiveReport {
+    /// Files that changed size while they were read, always empty unless
+    /// [`ArchiveOptions::snapshot`] is set
+    pub changed: Vec<ChangedFile>,
+    /// Entries whose modification time was before the Unix epoch or past
+    /// [`MAX_TAR_MTIME`], stored clamped into that range
+    pub clamped_mtimes: Vec<PathBuf>,
+    /// Entries [`update_archive_into`] appended because they were not in
+    /// the old archive
+    pub added: Vec<PathBuf>,
+    /// Files [`update_archive_into`] replaced because they changed
+    pub updated: Vec<PathBuf>,
+    /// Mount points stored without their contents, always empty unless
+    /// [`ArchiveOptions::one_file_system`] is set
+    pub other_filesystems: Vec<PathBuf>,
+}
+
+/// Largest modification time that fits the 11 octal digits of a classic
+/// tar header (in the year 2242)
+///
+/// Larger and negative values need the binary GNU encoding, which some
+/// extractors misread.
+pub const MAX_TAR_MTIME: u64 = 0o77777777777;
+
+/// An archive entry left out of an extraction
+#[derive(Debug)]
+pub struct SkippedEntry {
+    /// Path of the entry as stored in the archive
+    pub path: PathBuf,
+    /// Why it could not be extracted
+    pub error: ResqryptError,
+}
+
+/// Check that `name` can be used as the archive root name
+///
+/// The name must be a single, non-empty path component.
+pub fn check_archive_name(name: &str) -> Result<()> {
+    if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\\']) {
+        return Err(ResqryptError::InvalidArgument(format!(
+            "invalid archive name {:?}: must be a single path component",
+            name
+        )));
+    }
+
+    Ok(())
+}
+
+/// Check that `reader` holds a well-formed tar archive that could be
+/// extracted, returning its number of entries
+///
+/// Every entry is read through, so bad header checksums and truncated data
+/// are caught, and no entry path may leave the destination.
+pub fn check_tar_archive<R: Read>(reader: R) -> Result<u64> {
+    let mut archive = Archive::new(reader);
+    let entries =
+        archive.entries().map_err(|e| ResqryptError::archive(format!("Read error: {}", e)))?;
+
+    let mut count = 0;
+    for entry in entries {
+        let mut entry = entry.map_err(|e| ResqryptError::archive(format!("Read error: {}", e)))?;
+        let path = entry
+            .path()
+            .map_err(|e| ResqryptError::archive(format!("Read error: {}", e)))?
+            .into_owned();
+        entry_destination(Path::new(""), &path)?;
+
+        let size = entry.size();
+        let read = io::copy(&mut entry, &mut io::sink())
+            .map_err(|e| ResqryptError::archive_at(&path, format_args!("Read error: {}", e)))?;
+        if read != size {
+            return Err(ResqryptError::archive_at(&path, "Read error: entry data is truncated"));
+        }
+        count += 1;
+    }
+
+    if count == 0 {
+        return Err(ResqryptError::archive("no tar entries found"));
+    }
+    Ok(count)
+}
+
+/// Modification time of `path` in seconds since the Unix epoch
+pub fn file_mtime<P: AsRef<Path>>(path: P) -> Result<u64> {
+    let path = path.as_ref();
+
+    if !path.exists() {
+        return Err(ResqryptError::NotFound(path.to_path_buf()));
+    }
+
+    fs::metadata(path)?.modified()?.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).map_err(|_| {
+        ResqryptError::InvalidArgument(format!(
+            "Modification time is before the Unix epoch: {}",
+            path.display()
+        ))
+    })
+}
+
+/// Archive timestamp from the `SOURCE_DATE_EPOCH` environment variable
+///
+/// Returns `None` when the variable is unset or empty.
+pub fn source_date_epoch() -> Result<Option<u64>> {
+    match env::var("SOURCE_DATE_EPOCH") {
+        Ok(value) if !value.is_empty() => value.trim().parse().map(Some).map_err(|_| {
+            ResqryptError::InvalidArgument(format!(
+                "SOURCE_DATE_EPOCH must be a non-negative integer, got {:?}",
+                value
+            ))
+        }),
+        _ => Ok(None),
+    }
+}
 
 /// Create a tar archive from a directory
 ///
@@ -19,6 +190,46 @@ use crate::error::{ResqryptError, Result};
 /// # Returns
 /// The tar archive as a byte vector
 pub fn create_archive<P: AsRef<Path>>(source_dir: P) -> Result<Vec<u8>> {
+    Ok(create_archive_with(source_dir, &ArchiveOptions::default())?.0)
+}
+
+/// Create a tar archive from a directory with the given options
+///
+/// Also returns an [`ArchiveReport`]. A file changing size while it is
+/// read is only reported with [`ArchiveOptions::snapshot`]; without it
+/// that is an error, as its entry would not match its recorded size.
+pub fn create_archive_with<P: AsRef<Path>>(
+    source_dir: P,
+    options: &ArchiveOptions,
+) -> Result<(Vec<u8>, ArchiveReport)> {
+    let mut archive_data = Vec::new();
+    let report = create_archive_into(source_dir, options, &mut archive_data)?;
+    Ok((archive_data, report))
+}
+
+/// Write a tar archive of a directory into `writer` as it is built
+///
+/// Nothing beyond the tar builder's own buffers is held in memory, so the
+/// archive can flow straight into compression and encryption (see
+/// [`ArchiveReader`]). Returns the report as [`create_archive_with`] does.
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(
+        skip_all,
+        fields(
+            source = %source_dir.as_ref().display(),
+            bytes = tracing::field::Empty,
+            elapsed_ms = tracing::field::Empty
+        )
+    )
+)]
+pub fn create_archive_into<P: AsRef<Path>, W: Write>(
+    source_dir: P,
+    options: &ArchiveOptions,
+    writer: W,
+) -> Result<ArchiveReport> {
+    let stats = SpanStats::start();
+    let mut writer = CountingWriter::new(writer);
     let source_dir = source_dir.as_ref();
 
     if !source_dir.is_dir() {
@@ -28,54 +239,717 @@ pub fn create_archive<P: AsRef<Path>>(source_dir: P) -> Result<Vec<u8>> {
         )));
     }
 
-    let mut archive_data = Vec::new();
+    let mut report = ArchiveReport::default();
 
     {
-        let mut builder = Builder::new(&mut archive_data);
+        let mut builder = Builder::new(&mut writer);
 
         // Get the parent directory name to use as the archive root
-        let dir_name = source_dir
-            .file_name()
-            .map(|n| n.to_string_lossy().to_string())
-            .unwrap_or_else(|| "archive".to_string());
+        let dir_name = root_name(source_dir, options)?;
 
-        for entry in WalkDir::new(source_dir).follow_links(false) {
-            let entry =
-                entry.map_err(|e| ResqryptError::ArchiveError(format!("Walk error: {}", e)))?;
+        let mut walk = SourceWalk::new(source_dir, options)?;
+        for entry in &mut walk {
+            let (entry, relative_path) = entry?;
 
-            let path = entry.path();
+            // Create archive path with directory name as root (the root
+            // itself is stored too, so its mode is restored)
+            let archive_path = Path::new(&dir_name).join(relative_path);
+
+            append_entry(&mut builder, entry.path(), &archive_path, options, &mut report)?;
+        }
+        report.other_filesystems = walk.mount_points;
 
-            // Calculate relative path within the archive
-            let relative_path = path
-                .strip_prefix(source_dir)
-                .map_err(|e| ResqryptError::ArchiveError(format!("Path error: {}", e)))?;
+        builder.finish().map_err(|e| ResqryptError::archive(format!("Finish error: {}", e)))?;
+    }
+    stats.record("bytes", writer.count());
 
-            // Skip the root directory itself
-            if relative_path.as_os_str().is_empty() {
+    Ok(report)
+}
+
+/// Create a tar archive from a list of files under `base`
+///
+/// Entries are stored relative to `base` beneath a root named after it, as
+/// [`create_archive_with`] would for the whole directory. Parent
+/// directories are not stored; extraction creates them. Changed files are
+/// handled and reported as by [`create_archive_with`].
+pub fn create_archive_from_files<P: AsRef<Path>>(
+    base: P,
+    files: &[PathBuf],
+    options: &ArchiveOptions,
+) -> Result<(Vec<u8>, ArchiveReport)> {
+    let mut archive_data = Vec::new();
+    let report = create_archive_from_files_into(base, files, options, &mut archive_data)?;
+    Ok((archive_data, report))
+}
+
+/// Write a tar archive of a list of files under `base` into `writer`, as
+/// [`create_archive_into`] does for a whole directory
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(
+        skip_all,
+        fields(
+            source = %base.as_ref().display(),
+            bytes = tracing::field::Empty,
+            elapsed_ms = tracing::field::Empty
+        )
+    )
+)]
+pub fn create_archive_from_files_into<P: AsRef<Path>, W: Write>(
+    base: P,
+    files: &[PathBuf],
+    options: &ArchiveOptions,
+    writer: W,
+) -> Result<ArchiveReport> {
+    let stats = SpanStats::start();
+    let mut writer = CountingWriter::new(writer);
+    let base = base.as_ref();
+    let dir_name = root_name(base, options)?;
+    let excluded = Excluded::new(base, options)?;
+
+    let mut report = ArchiveReport::default();
+
+    {
+        let mut builder = Builder::new(&mut writer);
+
+        for path in files {
+            let relative_path =
+                path.strip_prefix(base).map_err(|e| ResqryptError::archive_at(path, e))?;
+            if excluded.contains(relative_path) {
                 continue;
             }
-
-            // Create archive path with directory name as root
             let archive_path = Path::new(&dir_name).join(relative_path);
+            append_entry(&mut builder, path, &archive_path, options, &mut report)?;
+        }
+
+        builder.finish().map_err(|e| ResqryptError::archive(format!("Finish error: {}", e)))?;
+    }
+    stats.record("bytes", writer.count());
+
+    Ok(report)
+}
+
+/// Write into `writer` the archive `old` updated from `source_dir`
+///
+/// Entries of `old` are copied in order, except files that also exist in
+/// `source_dir` with a different size or modification time, which are
+/// replaced by the current file. Entries only in `source_dir` follow, and
+/// entries only in `old` are kept. Paths are matched below the root of
+/// `old`, so the name of `source_dir` does not matter. The report lists the
+/// added and updated entries besides what [`create_archive_into`] reports.
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(
+        skip_all,
+        fields(
+            source = %source_dir.as_ref().display(),
+            bytes = tracing::field::Empty,
+            elapsed_ms = tracing::field::Empty
+        )
+    )
+)]
+pub fn update_archive_into<R: Read, P: AsRef<Path>, W: Write>(
+    old: R,
+    source_dir: P,
+    options: &ArchiveOptions,
+    writer: W,
+) -> Result<ArchiveReport> {
+    let stats = SpanStats::start();
+    let mut writer = CountingWriter::new(writer);
+    let source_dir = source_dir.as_ref();
+
+    if !source_dir.is_dir() {
+        return Err(ResqryptError::InvalidArgument(format!(
+            "Source is not a directory: {}",
+            source_dir.display()
+        )));
+    }
+
+    // Source entries by path below the root, and in archiving order
+    let mut order = Vec::new();
+    let mut unseen = HashMap::new();
+    let mut walk = SourceWalk::new(source_dir, options)?;
+    for entry in &mut walk {
+        let (entry, relative) = entry?;
+        order.push(relative.clone());
+        unseen.insert(relative, entry.into_path());
+    }
+
+    let mut report =
+        ArchiveReport { other_filesystems: walk.mount_points, ..ArchiveReport::default() };
+
+    {
+        let mut builder = Builder::new(&mut writer);
+        let mut archive = Archive::new(old);
+        let mut root = None;
 
-            if path.is_dir() {
-                builder
-                    .append_dir(&archive_path, path)
-                    .map_err(|e| ResqryptError::ArchiveError(format!("Add dir error: {}", e)))?;
-            } else if path.is_file() {
-                let mut file = File::open(path)?;
-                builder
-                    .append_file(&archive_path, &mut file)
-                    .map_err(|e| ResqryptError::ArchiveError(format!("Add file error: {}", e)))?;
+        let entries =
+            archive.entries().map_err(|e| ResqryptError::archive(format!("Read error: {}", e)))?;
+        for entry in entries {
+            let mut entry =
+                entry.map_err(|e| ResqryptError::archive(format!("Read error: {}", e)))?;
+            let archive_path = entry
+                .path()
+                .map_err(|e| ResqryptError::archive(format!("Read error: {}", e)))?
+                .into_owned();
+
+            // The first entry is the root directory
+            let root: &PathBuf = root.get_or_insert_with(|| {
+                archive_path.components().next().map_or_else(PathBuf::new, |c| c.as_os_str().into())
+            });
+            let source =
+                archive_path.strip_prefix(root).ok().and_then(|relative| unseen.remove(relative));
+            match source {
+                Some(path) if is_outdated(entry.header(), &path, options)? => {
+                    append_entry(&mut builder, &path, &archive_path, options, &mut report)?;
+                    report.updated.push(archive_path);
+                }
+                _ => copy_entry(&mut builder, &mut entry, &archive_path)?,
+            }
+        }
+
+        let root = match root {
+            Some(root) => root,
+            None => root_name(source_dir, options)?.into(),
+        };
+        for relative in order {
+            let Some(path) = unseen.remove(&relative) else { continue };
+            // Other file types are skipped, as when creating an archive
+            if path.is_dir() || path.is_file() {
+                let archive_path = root.join(&relative);
+                append_entry(&mut builder, &path, &archive_path, options, &mut report)?;
+                report.added.push(archive_path);
             }
         }
 
+        builder.finish().map_err(|e| ResqryptError::archive(format!("Finish error: {}", e)))?;
+    }
+    stats.record("bytes", writer.count());
+
+    Ok(report)
+}
+
+/// Whether the archived entry with `header` no longer matches `path`
+///
+/// Directories are kept as archived. A file is outdated when its size or
+/// modification time differs, or when it took the place of a directory or
+/// the other way round; other file types at `path` keep the old entry.
+fn is_outdated(header: &Header, path: &Path, options: &ArchiveOptions) -> Result<bool> {
+    let Ok(metadata) = fs::metadata(path) else { return Ok(false) };
+    let archived_dir = header.entry_type().is_dir();
+    if metadata.is_dir() || archived_dir {
+        return Ok(metadata.is_dir() != archived_dir);
+    }
+    if !metadata.is_file() {
+        return Ok(false);
+    }
+
+    let mtime = options.mtime.or_else(|| clamped_mtime(&metadata)).unwrap_or_else(|| {
+        metadata
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |d| d.as_secs())
+    });
+    let at_path = |e| ResqryptError::archive_at(path, e);
+    Ok(header.size().map_err(at_path)? != metadata.len()
+        || header.mtime().map_err(at_path)? != mtime)
+}
+
+/// Copy an entry of another archive along with its extended attributes
+fn copy_entry<R: Read, W: Write>(
+    builder: &mut Builder<W>,
+    entry: &mut Entry<'_, R>,
+    archive_path: &Path,
+) -> Result<()> {
+    let at_path = |e| ResqryptError::archive_at(archive_path, e);
+    let mut records = Vec::new();
+    if let Some(extensions) = entry.pax_extensions().map_err(at_path)? {
+        for extension in extensions {
+            let extension = extension.map_err(at_path)?;
+            match extension.key() {
+                Ok(key) if key.starts_with(PAX_XATTR_PREFIX) => {
+                    records.push((key.to_string(), extension.value_bytes().to_vec()));
+                }
+                _ => {}
+            }
+        }
+    }
+    if !records.is_empty() {
         builder
-            .finish()
-            .map_err(|e| ResqryptError::ArchiveError(format!("Finish error: {}", e)))?;
+            .append_pax_extensions(records.iter().map(|(k, v)| (k.as_str(), v.as_slice())))
+            .map_err(at_path)?;
+    }
+
+    // The builder writes the path, long names included, afresh
+    let mut header = entry.header().clone();
+    builder.append_data(&mut header, archive_path, entry).map_err(at_path)?;
+    Ok(())
+}
+
+/// Report of an [`ArchiveReader`], filled in once the archive is complete
+pub type SharedReport = Arc<Mutex<ArchiveReport>>;
+
+/// Reader yielding a tar archive as it is built on a background thread
+///
+/// The archive is passed over in blocks through a bounded channel, so only
+/// a few blocks are ever held in memory whatever the size of the tree.
+/// Reaching the end of the archive waits for the thread and fails if
+/// archiving failed. Dropping the reader early stops the thread at its next
+/// write.
+pub struct ArchiveReader {
+    blocks: Receiver<Vec<u8>>,
+    block: Vec<u8>,
+    pos: usize,
+    worker: Option<JoinHandle<Result<ArchiveReport>>>,
+    report: SharedReport,
+}
+
+impl ArchiveReader {
+    /// Run `archive` on a background thread, reading what it writes
+    ///
+    /// `archive` is typically a call to [`create_archive_into`] or
+    /// [`create_archive_from_files_into`].
+    pub fn spawn<F>(archive: F) -> Self
+    where
+        F: FnOnce(&mut dyn Write) -> Result<ArchiveReport> + Send + 'static,
+    {
+        let (sender, blocks) = mpsc::sync_channel(ARCHIVE_QUEUE_BLOCKS);
+        let worker = thread::spawn(move || {
+            let mut out = io::BufWriter::with_capacity(ARCHIVE_BLOCK_SIZE, BlockSender(sender));
+            let report = archive(&mut out)?;
+            out.flush()?;
+            Ok(report)
+        });
+
+        Self {
+            blocks,
+            block: Vec::new(),
+            pos: 0,
+            worker: Some(worker),
+            report: SharedReport::default(),
+        }
+    }
+
+    /// Handle to the report, filled in once the archive is complete
+    pub fn report(&self) -> SharedReport {
+        Arc::clone(&self.report)
+    }
+
+    /// Wait for the archiving thread and collect its result
+    fn finish(&mut self) -> Result<()> {
+        let Some(worker) = self.worker.take() else { return Ok(()) };
+
+        let report =
+            worker.join().map_err(|_| ResqryptError::archive("archiving thread panicked"))??;
+        *self.report.lock().unwrap_or_else(|e| e.into_inner()) = report;
+        Ok(())
+    }
+}
+
+impl Read for ArchiveReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.block.len() {
+            match self.blocks.recv() {
+                Ok(block) => {
+                    self.block = block;
+                    self.pos = 0;
+                }
+                // The thread is done once it drops the sender
+                Err(_) => {
+                    self.finish().map_err(io::Error::other)?;
+                    return Ok(0);
+                }
+            }
+        }
+
+        let n = buf.len().min(self.block.len() - self.pos);
+        buf[..n].copy_from_slice(&self.block[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Size of the blocks passed from the archiving thread
+const ARCHIVE_BLOCK_SIZE: usize = 64 * 1024;
+
+/// Number of blocks queued before the archiving thread waits for the reader
+const ARCHIVE_QUEUE_BLOCKS: usize = 4;
+
+/// Writer sending each write as a block over a channel
+struct BlockSender(SyncSender<Vec<u8>>);
+
+impl Write for BlockSender {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.send(buf.to_vec()).map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Excluded paths, matched relative to the archived directory
+struct Excluded<'a> {
+    /// Canonical path of the archived directory, if anything is excluded
+    base: Option<PathBuf>,
+    paths: &'a [PathBuf],
+}
+
+impl<'a> Excluded<'a> {
+    fn new(dir: &Path, options: &'a ArchiveOptions) -> Result<Self> {
+        // Only pay for canonicalizing when there is something to exclude
+        let base = if options.exclude.is_empty() { None } else { Some(fs::canonicalize(dir)?) };
+        Ok(Self { base, paths: &options.exclude })
+    }
+
+    /// Whether the entry at `relative` (below the directory) is excluded
+    ///
+    /// The walk does not follow symlinks, so joining onto the canonical
+    /// directory gives the entry's canonical path.
+    fn contains(&self, relative: &Path) -> bool {
+        self.base.as_ref().is_some_and(|base| self.paths.contains(&base.join(relative)))
+    }
+}
+
+/// Walk of a directory in archiving order, leaving out excluded entries
+/// and, with [`ArchiveOptions::one_file_system`], the contents of other
+/// filesystems mounted inside it
+pub(crate) struct SourceWalk<'a> {
+    dir: &'a Path,
+    entries: DirWalk,
+    excluded: Excluded<'a>,
+    /// Device of the walked directory, when staying on its filesystem
+    device: Option<u64>,
+    /// Directories whose contents were skipped as other filesystems
+    mount_points: Vec<PathBuf>,
+}
+
+impl<'a> SourceWalk<'a> {
+    pub(crate) fn new(dir: &'a Path, options: &'a ArchiveOptions) -> Result<Self> {
+        let device = if options.one_file_system { device(&fs::metadata(dir)?) } else { None };
+        Ok(Self {
+            dir,
+            // Sorted, so the same tree always produces the same archive
+            entries: DirWalk::new(dir),
+            excluded: Excluded::new(dir, options)?,
+            device,
+            mount_points: Vec::new(),
+        })
+    }
+}
+
+impl Iterator for SourceWalk<'_> {
+    /// An entry and its path below the walked directory
+    type Item = Result<(WalkEntry, PathBuf)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let entry = match self.entries.next()? {
+                Ok(entry) => entry,
+                Err(e) => return Some(Err(e)),
+            };
+            let relative = match entry.path().strip_prefix(self.dir) {
+                Ok(relative) => relative.to_path_buf(),
+                Err(e) => return Some(Err(ResqryptError::archive_at(entry.path(), e))),
+            };
+            if self.excluded.contains(&relative) {
+                continue;
+            }
+
+            // The mount point itself is kept, like tar does
+            if self.device.is_some() && entry.depth() > 0 && entry.file_type().is_dir() {
+                match entry.metadata() {
+                    Ok(metadata) if device(&metadata) != self.device => {
+                        self.entries.skip_current_dir();
+                        self.mount_points.push(entry.path().to_path_buf());
+                    }
+                    Ok(_) => {}
+                    Err(e) => return Some(Err(ResqryptError::archive_at(entry.path(), e))),
+                }
+            }
+            return Some(Ok((entry, relative)));
+        }
+    }
+}
+
+/// Depth-first walk of a directory tree in file name order
+///
+/// The walked path comes first, at depth 0, and each directory before its
+/// contents. Symlinks below it are yielded, not followed. Errors carry the
+/// path that could not be read, and the walk goes on past them.
+pub(crate) struct DirWalk {
+    /// The walked path, until it is yielded
+    root: Option<PathBuf>,
+    /// Entries left in each directory being walked, last one first
+    stack: Vec<Vec<WalkEntry>>,
+    /// Directory just yielded, whose contents are listed next
+    pending: Option<(PathBuf, usize)>,
+    /// Deepest level to descend to
+    max_depth: usize,
+}
+
+/// An entry found by [`DirWalk`]
+#[derive(Debug)]
+pub(crate) struct WalkEntry {
+    path: PathBuf,
+    depth: usize,
+    file_type: FileType,
+}
+
+impl DirWalk {
+    pub(crate) fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: Some(root.into()), stack: Vec::new(), pending: None, max_depth: usize::MAX }
+    }
+
+    /// Descend at most `depth` levels below the walked path
+    pub(crate) fn max_depth(mut self, depth: usize) -> Self {
+        self.max_depth = depth;
+        self
+    }
+
+    /// Leave out the contents of the directory just yielded
+    pub(crate) fn skip_current_dir(&mut self) {
+        self.pending = None;
+    }
+
+    /// Entries of `dir` at `depth`, last in name order first
+    fn list(dir: &Path, depth: usize) -> Result<Vec<WalkEntry>> {
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(dir).map_err(|e| ResqryptError::archive_at(dir, e))? {
+            let entry = entry.map_err(|e| ResqryptError::archive_at(dir, e))?;
+            let file_type =
+                entry.file_type().map_err(|e| ResqryptError::archive_at(entry.path(), e))?;
+            entries.push(WalkEntry { path: entry.path(), depth, file_type });
+        }
+        entries.sort_by(|a, b| b.path.file_name().cmp(&a.path.file_name()));
+        Ok(entries)
+    }
+}
+
+impl Iterator for DirWalk {
+    type Item = Result<WalkEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let entry = match (self.root.take(), self.pending.take()) {
+            // The walked path itself is followed if it is a symlink
+            (Some(root), _) => match fs::metadata(&root) {
+                Ok(metadata) => WalkEntry { path: root, depth: 0, file_type: metadata.file_type() },
+                Err(e) => return Some(Err(ResqryptError::archive_at(root, e))),
+            },
+            (None, pending) => {
+                if let Some((dir, depth)) = pending {
+                    match Self::list(&dir, depth + 1) {
+                        Ok(entries) => self.stack.push(entries),
+                        Err(e) => return Some(Err(e)),
+                    }
+                }
+                loop {
+                    let entries = self.stack.last_mut()?;
+                    match entries.pop() {
+                        Some(entry) => break entry,
+                        None => _ = self.stack.pop(),
+                    }
+                }
+            }
+        };
+
+        if entry.file_type.is_dir() && entry.depth < self.max_depth {
+            self.pending = Some((entry.path.clone(), entry.depth));
+        }
+        Some(Ok(entry))
+    }
+}
+
+impl WalkEntry {
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub(crate) fn into_path(self) -> PathBuf {
+        self.path
+    }
+
+    /// Levels below the walked path, 0 for the path itself
+    pub(crate) fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Type of the entry itself, not of what a symlink points to
+    pub(crate) fn file_type(&self) -> FileType {
+        self.file_type
+    }
+
+    /// Metadata of the entry itself, not of what a symlink points to
+    pub(crate) fn metadata(&self) -> io::Result<fs::Metadata> {
+        if self.depth == 0 { fs::metadata(&self.path) } else { fs::symlink_metadata(&self.path) }
+    }
+}
+
+/// Device of the filesystem holding a file
+#[cfg(unix)]
+fn device(metadata: &fs::Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+
+    Some(metadata.dev())
+}
+
+/// Devices are not compared on this platform
+#[cfg(not(unix))]
+fn device(_metadata: &fs::Metadata) -> Option<u64> {
+    None
+}
+
+/// Name of the archive root for `dir`, unless overridden in `options`
+///
+/// Kept as raw bytes like every other entry name, rather than replacing
+/// invalid UTF-8 in the root alone.
+fn root_name(dir: &Path, options: &ArchiveOptions) -> Result<OsString> {
+    if let Some(name) = &options.root_name {
+        check_archive_name(name)?;
+        return Ok(name.into());
+    }
+
+    Ok(dir.file_name().map_or_else(|| "archive".into(), ToOwned::to_owned))
+}
+
+/// Append a directory or regular file to the archive
+///
+/// Other file types are skipped. A file that changes size while it is read
+/// is recorded in `report` with [`ArchiveOptions::snapshot`], and is an
+/// error otherwise; so is an out-of-range modification time, once clamped.
+fn append_entry<W: Write>(
+    builder: &mut Builder<W>,
+    path: &Path,
+    archive_path: &Path,
+    options: &ArchiveOptions,
+    report: &mut ArchiveReport,
+) -> Result<()> {
+    if options.xattrs && (path.is_dir() || path.is_file()) {
+        append_xattrs(builder, path)?;
+    }
+
+    if path.is_dir() {
+        let metadata = fs::metadata(path).map_err(|e| ResqryptError::archive_at(path, e))?;
+        let mut header = entry_header(path, &metadata, options, report);
+        builder
+            .append_data(&mut header, archive_path, io::empty())
+            .map_err(|e| ResqryptError::archive_at(path, e))?;
+    } else if path.is_file() {
+        // The header and the data must agree on the size, so both come
+        // from the open file
+        let at_path = |e| ResqryptError::archive_at(path, e);
+        let file = File::open(path).map_err(at_path)?;
+        let metadata = file.metadata().map_err(at_path)?;
+        let mut header = entry_header(path, &metadata, options, report);
+        let mut reader = SizedReader::new(file, metadata.len());
+        builder.append_data(&mut header, archive_path, &mut reader).map_err(at_path)?;
+
+        if let Some(actual) = reader.changed_size()? {
+            if !options.snapshot {
+                return Err(ResqryptError::archive_at(
+                    path,
+                    format_args!(
+                        "changed size while being archived ({} -> {} bytes); \
+                         use --snapshot to store it as it was when opened",
+                        metadata.len(),
+                        actual
+                    ),
+                ));
+            }
+            report.changed.push(ChangedFile {
+                path: path.to_path_buf(),
+                recorded: metadata.len(),
+                actual,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+/// Tar header for the file at `path` with `metadata`, applying the pinned
+/// modification time if any
+///
+/// Otherwise a modification time out of the header's octal range is
+/// clamped into it and recorded in `report`.
+fn entry_header(
+    path: &Path,
+    metadata: &fs::Metadata,
+    options: &ArchiveOptions,
+    report: &mut ArchiveReport,
+) -> Header {
+    let mut header = Header::new_gnu();
+    header.set_metadata(metadata);
+    match options.mtime {
+        Some(mtime) => header.set_mtime(mtime),
+        None => {
+            if let Some(mtime) = clamped_mtime(metadata) {
+                header.set_mtime(mtime);
+                report.clamped_mtimes.push(path.to_path_buf());
+            }
+        }
+    }
+    header
+}
+
+/// In-range replacement for the modification time in `metadata`, if it is
+/// before the Unix epoch or past [`MAX_TAR_MTIME`]
+fn clamped_mtime(metadata: &fs::Metadata) -> Option<u64> {
+    match metadata.modified().ok()?.duration_since(UNIX_EPOCH) {
+        Err(_) => Some(0),
+        Ok(since) if since.as_secs() > MAX_TAR_MTIME => Some(MAX_TAR_MTIME),
+        Ok(_) => None,
+    }
+}
+
+/// Reader over exactly `size` bytes of a file
+///
+/// Growth past `size` is left unread, and a file ending early is padded
+/// with zeros, so the data always matches a tar header written for `size`.
+struct SizedReader {
+    file: File,
+    size: u64,
+    read: u64,
+    /// Bytes actually found, once the file ended early
+    shrunk_to: Option<u64>,
+}
+
+impl SizedReader {
+    fn new(file: File, size: u64) -> Self {
+        Self { file, size, read: 0, shrunk_to: None }
     }
 
-    Ok(archive_data)
+    /// Size of the file after reading, if it no longer matches `size`
+    fn changed_size(&self) -> Result<Option<u64>> {
+        if self.shrunk_to.is_some() {
+            return Ok(self.shrunk_to);
+        }
+
+        let now = self.file.metadata()?.len();
+        Ok((now != self.size).then_some(now))
+    }
+}
+
+impl Read for SizedReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let want = buf.len().min((self.size - self.read) as usize);
+        let n = match self.shrunk_to {
+            Some(_) => 0,
+            None => self.file.read(&mut buf[..want])?,
+        };
+
+        let n = if n == 0 && want > 0 {
+            self.shrunk_to.get_or_insert(self.read);
+            buf[..want].fill(0);
+            want
+        } else {
+            n
+        };
+        self.read += n as u64;
+        Ok(n)
+    }
 }
 
 /// Extract a tar archive to a directory
@@ -84,17 +958,220 @@ pub fn create_archive<P: AsRef<Path>>(source_dir: P) -> Result<Vec<u8>> {
 /// * `archive_data` - The tar archive bytes
 /// * `dest_dir` - Destination directory (will be created if needed)
 pub fn extract_archive<P: AsRef<Path>>(archive_data: &[u8], dest_dir: P) -> Result<()> {
+    extract_archive_stream(archive_data, dest_dir)
+}
+
+/// Extract a tar archive read from a stream to a directory
+///
+/// Entries are unpacked as they are read, so the archive never has to be
+/// held in memory. Entries whose paths would escape `dest_dir` (absolute
+/// paths or `..` components) are rejected.
+///
+/// Directories, including the archive root, are created with their stored
+/// modes only after all other entries, deepest first, so restrictive modes
+/// such as `0o500` do not block writing their contents.
+///
+/// # Arguments
+/// * `reader` - Source of the tar archive bytes
+/// * `dest_dir` - Destination directory (will be created if needed)
+pub fn extract_archive_stream<R: Read, P: AsRef<Path>>(reader: R, dest_dir: P) -> Result<()> {
+    extract_archive_stream_with(reader, dest_dir, &ArchiveOptions::default())?;
+    Ok(())
+}
+
+/// Extract a tar archive read from a stream with the given options
+///
+/// Returns the entries skipped because they failed to unpack, which is
+/// always empty unless [`ArchiveOptions::skip_failed_entries`] is set.
+pub fn extract_archive_stream_with<R: Read, P: AsRef<Path>>(
+    reader: R,
+    dest_dir: P,
+    options: &ArchiveOptions,
+) -> Result<Vec<SkippedEntry>> {
+    extract_archive_mapped(reader, dest_dir, options, |path| Some(path.to_path_buf()))
+}
+
+/// Extract a tar archive, choosing per entry whether and where to extract
+///
+/// `map` receives each entry's path as stored in the archive and returns
+/// its destination relative to `dest_dir`, or `None` to skip the entry.
+/// Destinations that are absolute, contain `..` components or lead through
+/// a symlink outside `dest_dir` are rejected.
+///
+/// Directories are created last, deepest first, as in
+/// [`extract_archive_stream`].
+///
+/// With [`ArchiveOptions::skip_failed_entries`], an entry that cannot be
+/// unpacked, or whose destination is rejected, is skipped and returned
+/// with its error; the rest of the archive is still extracted. Errors
+/// reading the archive itself always abort.
+pub fn extract_archive_mapped<R, P, F>(
+    reader: R,
+    dest_dir: P,
+    options: &ArchiveOptions,
+    map: F,
+) -> Result<Vec<SkippedEntry>>
+where
+    R: Read,
+    P: AsRef<Path>,
+    F: FnMut(&Path) -> Option<PathBuf>,
+{
+    extract_archive_entries(&mut Archive::new(reader), dest_dir, options, map)
+}
+
+/// Extract the entries of an already opened tar archive, as
+/// [`extract_archive_mapped`] does
+///
+/// The archive is borrowed, so its reader can be taken back afterwards
+/// with [`Archive::into_inner`], for example to read past the end of the
+/// archive.
+pub fn extract_archive_entries<R, P, F>(
+    archive: &mut Archive<R>,
+    dest_dir: P,
+    options: &ArchiveOptions,
+    mut map: F,
+) -> Result<Vec<SkippedEntry>>
+where
+    R: Read,
+    P: AsRef<Path>,
+    F: FnMut(&Path) -> Option<PathBuf>,
+{
     let dest_dir = dest_dir.as_ref();
 
     // Create destination directory if it doesn't exist
-    fs::create_dir_all(dest_dir)?;
+    create_output_dir(dest_dir)?;
+    let dest_dir = dest_dir.canonicalize()?;
 
-    let mut archive = Archive::new(archive_data);
+    archive.set_unpack_xattrs(options.xattrs);
 
-    archive
-        .unpack(dest_dir)
-        .map_err(|e| ResqryptError::ArchiveError(format!("Extract error: {}", e)))?;
+    let entries =
+        archive.entries().map_err(|e| ResqryptError::archive(format!("Extract error: {}", e)))?;
 
+    let mut skipped = Vec::new();
+    let mut skip_or_fail = |path: PathBuf, result: Result<()>| match result {
+        Err(error) if options.skip_failed_entries => {
+            skipped.push(SkippedEntry { path, error });
+            Ok(())
+        }
+        result => result,
+    };
+
+    let mut directories = Vec::new();
+    for entry in entries {
+        let mut entry =
+            entry.map_err(|e| ResqryptError::archive(format!("Extract error: {}", e)))?;
+        let path = entry
+            .path()
+            .map_err(|e| ResqryptError::archive(format!("Extract error: {}", e)))?
+            .into_owned();
+
+        let Some(mapped) = map(&path) else { continue };
+        let target = match entry_destination(&dest_dir, &mapped) {
+            Ok(target) => target,
+            Err(e) => {
+                skip_or_fail(path, Err(e))?;
+                continue;
+            }
+        };
+
+        if entry.header().entry_type() == EntryType::Directory {
+            directories.push((path, target, entry));
+        } else {
+            let result = unpack_entry(&mut entry, &dest_dir, &target);
+            skip_or_fail(path, result)?;
+        }
+    }
+
+    directories.sort_by(|(_, a, _), (_, b, _)| b.cmp(a));
+    for (path, target, mut entry) in directories {
+        let result = unpack_entry(&mut entry, &dest_dir, &target);
+        skip_or_fail(path, result)?;
+    }
+
+    Ok(skipped)
+}
+
+/// Resolve a mapped entry path under `dest_dir`, rejecting escapes
+fn entry_destination(dest_dir: &Path, mapped: &Path) -> Result<PathBuf> {
+    let mut target = dest_dir.to_path_buf();
+    for component in mapped.components() {
+        match component {
+            Component::Normal(name) => target.push(name),
+            Component::CurDir => {}
+            _ => {
+                return Err(ResqryptError::archive_at(
+                    mapped,
+                    "Extract error: entry path escapes the destination",
+                ));
+            }
+        }
+    }
+
+    if target == dest_dir {
+        return Err(ResqryptError::archive(format!(
+            "Extract error: entry path {:?} is empty",
+            mapped
+        )));
+    }
+    Ok(target)
+}
+
+/// Unpack a single entry to `target`, creating its parent directories
+///
+/// A modification time out of the range archives are written with (see
+/// [`MAX_TAR_MTIME`]) is not restored, rather than risking a failure to
+/// set it; the entry keeps the time of extraction.
+fn unpack_entry<R: Read>(entry: &mut Entry<'_, R>, dest_dir: &Path, target: &Path) -> Result<()> {
+    if entry.header().mtime().map_or(true, |mtime| mtime > MAX_TAR_MTIME) {
+        entry.set_preserve_mtime(false);
+    }
+
+    let parent = target.parent().unwrap_or(dest_dir);
+    fs::create_dir_all(parent)?;
+
+    // A symlink extracted earlier must not redirect later entries
+    if !parent.canonicalize()?.starts_with(dest_dir) {
+        return Err(ResqryptError::archive_at(target, "Extract error: outside the destination"));
+    }
+
+    entry
+        .unpack(target)
+        .map_err(|e| ResqryptError::archive_at(target, format_args!("Extract error: {}", e)))?;
+    Ok(())
+}
+
+/// Append the extended attributes of `path` as PAX records for the next entry
+#[cfg(unix)]
+fn append_xattrs<W: Write>(builder: &mut Builder<W>, path: &Path) -> Result<()> {
+    let names = xattr::list(path)
+        .map_err(|e| ResqryptError::archive_at(path, format_args!("Read xattrs error: {}", e)))?;
+
+    let mut records = Vec::new();
+    for name in names {
+        // PAX keys must be UTF-8; skip attributes we cannot represent
+        let Some(name) = name.to_str() else { continue };
+        let value = xattr::get(path, name).map_err(|e| {
+            ResqryptError::archive_at(path, format_args!("Read xattrs error: {}", e))
+        })?;
+        if let Some(value) = value {
+            records.push((format!("{}{}", PAX_XATTR_PREFIX, name), value));
+        }
+    }
+
+    if !records.is_empty() {
+        builder
+            .append_pax_extensions(records.iter().map(|(k, v)| (k.as_str(), v.as_slice())))
+            .map_err(|e| {
+                ResqryptError::archive_at(path, format_args!("Add xattrs error: {}", e))
+            })?;
+    }
+
+    Ok(())
+}
+
+/// Extended attributes are not supported on this platform
+#[cfg(not(unix))]
+fn append_xattrs<W: Write>(_builder: &mut Builder<W>, _path: &Path) -> Result<()> {
     Ok(())
 }
 
@@ -106,8 +1183,10 @@ pub fn read_file<P: AsRef<Path>>(path: P) -> Result<Vec<u8>> {
         return Err(ResqryptError::NotFound(path.to_path_buf()));
     }
 
+    // Size the buffer up front; the file may still grow or shrink meanwhile
     let mut file = File::open(path)?;
-    let mut contents = Vec::new();
+    let len = file.metadata()?.len();
+    let mut contents = Vec::with_capacity(usize::try_from(len).unwrap_or(0));
     file.read_to_end(&mut contents)?;
 
     Ok(contents)
@@ -117,15 +1196,10 @@ pub fn read_file<P: AsRef<Path>>(path: P) -> Result<Vec<u8>> {
 pub fn write_file<P: AsRef<Path>>(path: P, data: &[u8]) -> Result<()> {
     let path = path.as_ref();
 
-    // Create parent directories if needed
-    if let Some(parent) = path.parent() {
-        fs::create_dir_all(parent)?;
-    }
-
-    let mut file = File::create(path)?;
-    file.write_all(data)?;
-
-    Ok(())
+    write_atomic(path, |file| {
+        file.write_all(data)?;
+        Ok(())
+    })
 }
 
 #[cfg(test)]
@@ -163,6 +1237,491 @@ mod tests {
         assert_eq!(content2, "Nested file");
     }
 
+    #[test]
+    fn test_extract_archive_stream() {
+        let source_dir = TempDir::new().unwrap();
+        fs::write(source_dir.path().join("streamed.txt"), "from a reader").unwrap();
+
+        let archive_data = create_archive(source_dir.path()).unwrap();
+
+        let dest_dir = TempDir::new().unwrap();
+        extract_archive_stream(std::io::Cursor::new(archive_data), dest_dir.path()).unwrap();
+
+        let extracted = dest_dir.path().join(source_dir.path().file_name().unwrap());
+        let content = fs::read_to_string(extracted.join("streamed.txt")).unwrap();
+        assert_eq!(content, "from a reader");
+    }
+
+    #[test]
+    fn test_extract_rejects_path_traversal() {
+        let mut archive_data = Vec::new();
+        {
+            let mut builder = Builder::new(&mut archive_data);
+            let mut header = tar::Header::new_gnu();
+            let payload = b"escaped";
+            // Bypass `set_path` validation to craft a malicious entry name
+            header.as_gnu_mut().unwrap().name[..9].copy_from_slice(b"../evil.t");
+            header.set_size(payload.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append(&header, &payload[..]).unwrap();
+            builder.finish().unwrap();
+        }
+
+        let root = TempDir::new().unwrap();
+        let dest = root.path().join("dest");
+        let _ = extract_archive_stream(archive_data.as_slice(), &dest);
+
+        assert!(!root.path().join("evil.t").exists());
+    }
+
+    #[test]
+    fn test_check_tar_archive() {
+        let source_dir = TempDir::new().unwrap();
+        fs::write(source_dir.path().join("a.txt"), "some content").unwrap();
+        let archive = create_archive(source_dir.path()).unwrap();
+        assert_eq!(check_tar_archive(archive.as_slice()).unwrap(), 2);
+
+        // Truncated in the middle of the file data
+        let data_end = 2 * 512 + 4;
+        assert!(check_tar_archive(&archive[..data_end]).is_err());
+
+        // Not a tar at all, or empty
+        assert!(check_tar_archive(&b"plain text, not a tar archive"[..]).is_err());
+        assert!(check_tar_archive(&[0u8; 1024][..]).is_err());
+        assert!(check_tar_archive(&[][..]).is_err());
+
+        // Entries escaping the destination
+        let mut escaping = Vec::new();
+        let mut builder = Builder::new(&mut escaping);
+        let mut header = tar::Header::new_gnu();
+        header.as_gnu_mut().unwrap().name[..9].copy_from_slice(b"../evil.t");
+        header.set_size(0);
+        header.set_cksum();
+        builder.append(&header, &[][..]).unwrap();
+        builder.finish().unwrap();
+        drop(builder);
+        assert!(check_tar_archive(escaping.as_slice()).is_err());
+    }
+
+    #[test]
+    fn test_extract_archive_mapped() {
+        let source_dir = TempDir::new().unwrap();
+        fs::write(source_dir.path().join("keep.txt"), "kept").unwrap();
+        fs::write(source_dir.path().join("skip.txt"), "skipped").unwrap();
+        let archive_data = create_archive(source_dir.path()).unwrap();
+
+        // Strip the archive root and extract only one file
+        let dest_dir = TempDir::new().unwrap();
+        extract_archive_mapped(
+            archive_data.as_slice(),
+            dest_dir.path(),
+            &ArchiveOptions::default(),
+            |path| {
+                let name = path.file_name()?;
+                (name == "keep.txt").then(|| PathBuf::from(name))
+            },
+        )
+        .unwrap();
+
+        assert_eq!(fs::read_to_string(dest_dir.path().join("keep.txt")).unwrap(), "kept");
+        assert_eq!(fs::read_dir(dest_dir.path()).unwrap().count(), 1);
+
+        let result = extract_archive_mapped(
+            archive_data.as_slice(),
+            dest_dir.path(),
+            &ArchiveOptions::default(),
+            |_| Some(PathBuf::from("../escaped")),
+        );
+        assert!(matches!(result, Err(ResqryptError::ArchiveError { .. })));
+        assert!(!dest_dir.path().parent().unwrap().join("escaped").exists());
+    }
+
+    #[test]
+    fn test_skip_failed_entries() {
+        let source_dir = TempDir::new().unwrap();
+        fs::write(source_dir.path().join("a.txt"), "blocked").unwrap();
+        fs::write(source_dir.path().join("b.txt"), "restored").unwrap();
+        let archive_data = create_archive(source_dir.path()).unwrap();
+
+        // A file where a.txt needs a directory makes its entry fail
+        let dest_dir = TempDir::new().unwrap();
+        fs::write(dest_dir.path().join("blocker"), "").unwrap();
+        let map = |path: &Path| {
+            let name = path.file_name()?;
+            Some(if name == "a.txt" { Path::new("blocker").join(name) } else { name.into() })
+        };
+
+        let strict = ArchiveOptions::default();
+        let result = extract_archive_mapped(archive_data.as_slice(), dest_dir.path(), &strict, map);
+        assert!(result.is_err());
+
+        let lenient = ArchiveOptions { skip_failed_entries: true, ..ArchiveOptions::default() };
+        let skipped =
+            extract_archive_mapped(archive_data.as_slice(), dest_dir.path(), &lenient, map)
+                .unwrap();
+        assert_eq!(skipped.len(), 1);
+        assert!(skipped[0].path.ends_with("a.txt"));
+        assert_eq!(fs::read_to_string(dest_dir.path().join("b.txt")).unwrap(), "restored");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_xattrs_roundtrip() {
+        let source_dir = TempDir::new().unwrap();
+        let file = source_dir.path().join("tagged.txt");
+        fs::write(&file, "with xattrs").unwrap();
+
+        // Skip on filesystems without user xattr support
+        if xattr::set(&file, "user.resqrypt", b"kept").is_err() {
+            return;
+        }
+
+        let options = ArchiveOptions { xattrs: true, ..ArchiveOptions::default() };
+        let archive_data = create_archive_with(source_dir.path(), &options).unwrap().0;
+
+        let dest_dir = TempDir::new().unwrap();
+        extract_archive_stream_with(archive_data.as_slice(), dest_dir.path(), &options).unwrap();
+
+        let extracted =
+            dest_dir.path().join(source_dir.path().file_name().unwrap()).join("tagged.txt");
+        let value = xattr::get(&extracted, "user.resqrypt").unwrap();
+        assert_eq!(value.as_deref(), Some(&b"kept"[..]));
+
+        // Without the option the attributes are not stored
+        let plain = create_archive(source_dir.path()).unwrap();
+        let plain_dest = TempDir::new().unwrap();
+        extract_archive(&plain, plain_dest.path()).unwrap();
+        let plain_file =
+            plain_dest.path().join(source_dir.path().file_name().unwrap()).join("tagged.txt");
+        assert_eq!(xattr::get(&plain_file, "user.resqrypt").unwrap(), None);
+    }
+
+    #[test]
+    fn test_pinned_mtime_is_reproducible() {
+        let source_dir = TempDir::new().unwrap();
+        fs::create_dir(source_dir.path().join("b")).unwrap();
+        fs::write(source_dir.path().join("b/inner.txt"), "inner").unwrap();
+        fs::write(source_dir.path().join("a.txt"), "first").unwrap();
+
+        let options = ArchiveOptions { mtime: Some(1_700_000_000), ..ArchiveOptions::default() };
+        let first = create_archive_with(source_dir.path(), &options).unwrap().0;
+
+        // Touching a file must not change the archive
+        let file = File::options().write(true).open(source_dir.path().join("a.txt")).unwrap();
+        file.set_modified(UNIX_EPOCH + std::time::Duration::from_secs(42)).unwrap();
+        let second = create_archive_with(source_dir.path(), &options).unwrap().0;
+        assert_eq!(first, second);
+
+        let mut archive = Archive::new(first.as_slice());
+        for entry in archive.entries().unwrap() {
+            assert_eq!(entry.unwrap().header().mtime().unwrap(), 1_700_000_000);
+        }
+    }
+
+    #[test]
+    fn test_custom_root_name() {
+        let source_dir = TempDir::new().unwrap();
+        fs::write(source_dir.path().join("file.txt"), "named").unwrap();
+
+        let options = ArchiveOptions {
+            root_name: Some("myproject".to_string()),
+            ..ArchiveOptions::default()
+        };
+        let archive_data = create_archive_with(source_dir.path(), &options).unwrap().0;
+        let dest_dir = TempDir::new().unwrap();
+        extract_archive(&archive_data, dest_dir.path()).unwrap();
+        let content = fs::read_to_string(dest_dir.path().join("myproject/file.txt")).unwrap();
+        assert_eq!(content, "named");
+
+        for name in ["", ".", "..", "a/b", "a\\b"] {
+            let options =
+                ArchiveOptions { root_name: Some(name.to_string()), ..ArchiveOptions::default() };
+            assert!(create_archive_with(source_dir.path(), &options).is_err(), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn test_exclude_paths() {
+        let source_dir = TempDir::new().unwrap();
+        fs::write(source_dir.path().join("data.txt"), "keep").unwrap();
+        fs::write(source_dir.path().join("out.resqrypt"), "stale").unwrap();
+
+        let stale = fs::canonicalize(source_dir.path().join("out.resqrypt")).unwrap();
+        let options = ArchiveOptions { exclude: vec![stale], ..ArchiveOptions::default() };
+        let archive_data = create_archive_with(source_dir.path(), &options).unwrap().0;
+
+        let dest_dir = TempDir::new().unwrap();
+        extract_archive(&archive_data, dest_dir.path()).unwrap();
+        let root = dest_dir.path().join(source_dir.path().file_name().unwrap());
+        assert!(root.join("data.txt").exists());
+        assert!(!root.join("out.resqrypt").exists());
+    }
+
+    #[test]
+    fn test_update_archive() {
+        let old_dir = TempDir::new().unwrap();
+        fs::create_dir(old_dir.path().join("sub")).unwrap();
+        fs::write(old_dir.path().join("same.txt"), "same").unwrap();
+        fs::write(old_dir.path().join("edited.txt"), "old").unwrap();
+        fs::write(old_dir.path().join("removed.txt"), "kept").unwrap();
+        let old = create_archive(old_dir.path()).unwrap();
+
+        // Update from a differently named copy of the tree
+        let source_dir = TempDir::new().unwrap();
+        fs::create_dir(source_dir.path().join("sub")).unwrap();
+        fs::write(source_dir.path().join("sub/new.txt"), "new").unwrap();
+        fs::write(source_dir.path().join("edited.txt"), "edited").unwrap();
+        fs::write(source_dir.path().join("same.txt"), "same").unwrap();
+        let mtime = fs::metadata(old_dir.path().join("same.txt")).unwrap().modified().unwrap();
+        let file = File::options().write(true).open(source_dir.path().join("same.txt")).unwrap();
+        file.set_modified(mtime).unwrap();
+
+        let mut updated = Vec::new();
+        let report = update_archive_into(
+            old.as_slice(),
+            source_dir.path(),
+            &ArchiveOptions::default(),
+            &mut updated,
+        )
+        .unwrap();
+        let root = PathBuf::from(old_dir.path().file_name().unwrap());
+        assert_eq!(report.added, vec![root.join("sub/new.txt")]);
+        assert_eq!(report.updated, vec![root.join("edited.txt")]);
+
+        let dest_dir = TempDir::new().unwrap();
+        extract_archive(&updated, dest_dir.path()).unwrap();
+        let read = |name: &str| fs::read_to_string(dest_dir.path().join(&root).join(name)).unwrap();
+        assert_eq!(read("same.txt"), "same");
+        assert_eq!(read("edited.txt"), "edited");
+        assert_eq!(read("removed.txt"), "kept");
+        assert_eq!(read("sub/new.txt"), "new");
+    }
+
+    #[test]
+    fn test_archive_reader_streams() {
+        let source_dir = TempDir::new().unwrap();
+        // Larger than the blocks and the queue together
+        fs::write(source_dir.path().join("big.bin"), vec![7u8; 1 << 20]).unwrap();
+        fs::write(source_dir.path().join("small.txt"), "small").unwrap();
+
+        let path = source_dir.path().to_path_buf();
+        let options = ArchiveOptions { mtime: Some(0), ..ArchiveOptions::default() };
+        let expected = create_archive_with(&path, &options).unwrap().0;
+
+        let mut reader = ArchiveReader::spawn(move |out| create_archive_into(&path, &options, out));
+        let mut streamed = Vec::new();
+        reader.read_to_end(&mut streamed).unwrap();
+        assert_eq!(streamed, expected);
+        assert_eq!(*reader.report().lock().unwrap(), ArchiveReport::default());
+
+        // Archiving errors surface at the end of the stream
+        let mut reader = ArchiveReader::spawn(|out| {
+            create_archive_into("/nonexistent/dir", &ArchiveOptions::default(), out)
+        });
+        let err = reader.read_to_end(&mut Vec::new()).unwrap_err();
+        assert!(matches!(ResqryptError::from_io(err), ResqryptError::InvalidArgument(_)));
+    }
+
+    #[test]
+    fn test_sized_reader_changes() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("log");
+        fs::write(&path, "0123456789").unwrap();
+
+        // Opened when it had 12 bytes but now ends at 10: padded with zeros
+        let mut data = Vec::new();
+        let mut reader = SizedReader::new(File::open(&path).unwrap(), 12);
+        reader.read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"0123456789\0\0");
+        assert_eq!(reader.changed_size().unwrap(), Some(10));
+
+        // Opened when it had 4 bytes but grew since: cut off
+        let mut data = Vec::new();
+        let mut reader = SizedReader::new(File::open(&path).unwrap(), 4);
+        reader.read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"0123");
+        assert_eq!(reader.changed_size().unwrap(), Some(10));
+
+        let mut reader = SizedReader::new(File::open(&path).unwrap(), 10);
+        reader.read_to_end(&mut Vec::new()).unwrap();
+        assert_eq!(reader.changed_size().unwrap(), None);
+    }
+
+    #[test]
+    fn test_pre_epoch_mtime_roundtrip() {
+        let source_dir = TempDir::new().unwrap();
+        let old = source_dir.path().join("old.txt");
+        fs::write(&old, "from 1969").unwrap();
+        let before_epoch = UNIX_EPOCH - std::time::Duration::from_secs(86_400);
+        File::options().write(true).open(&old).unwrap().set_modified(before_epoch).unwrap();
+
+        let (archive_data, report) =
+            create_archive_with(source_dir.path(), &ArchiveOptions::default()).unwrap();
+        assert_eq!(report.clamped_mtimes, vec![old]);
+
+        let dest_dir = TempDir::new().unwrap();
+        extract_archive(&archive_data, dest_dir.path()).unwrap();
+        let name = source_dir.path().file_name().unwrap();
+        let restored = dest_dir.path().join(name).join("old.txt");
+        assert_eq!(fs::read_to_string(restored).unwrap(), "from 1969");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_non_utf8_names_roundtrip() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        // Latin-1 names as left behind by older systems
+        let parent = TempDir::new().unwrap();
+        let root = parent.path().join(OsStr::from_bytes(b"d\xe9p\xf4t"));
+        let long = [b'x'; 120].iter().chain(b"\xff.txt").copied().collect::<Vec<_>>();
+        fs::create_dir(&root).unwrap();
+        fs::write(root.join(OsStr::from_bytes(b"caf\xe9.txt")), "short").unwrap();
+        fs::write(root.join(OsStr::from_bytes(&long)), "long").unwrap();
+
+        let (archive_data, _) = create_archive_with(&root, &ArchiveOptions::default()).unwrap();
+        let dest_dir = TempDir::new().unwrap();
+        extract_archive(&archive_data, dest_dir.path()).unwrap();
+
+        let restored = dest_dir.path().join(OsStr::from_bytes(b"d\xe9p\xf4t"));
+        let cafe = restored.join(OsStr::from_bytes(b"caf\xe9.txt"));
+        assert_eq!(fs::read_to_string(cafe).unwrap(), "short");
+        assert_eq!(fs::read_to_string(restored.join(OsStr::from_bytes(&long))).unwrap(), "long");
+    }
+
+    #[test]
+    fn test_out_of_range_mtime_not_restored() {
+        // As written for a pre-epoch file by tools that do not clamp
+        let mut builder = Builder::new(Vec::new());
+        let mut header = Header::new_gnu();
+        header.set_size(4);
+        header.set_mode(0o644);
+        header.set_mtime(-86_400i64 as u64);
+        builder.append_data(&mut header, "root/old.txt", &b"data"[..]).unwrap();
+        let archive_data = builder.into_inner().unwrap();
+
+        let dest_dir = TempDir::new().unwrap();
+        extract_archive(&archive_data, dest_dir.path()).unwrap();
+        let restored = dest_dir.path().join("root/old.txt");
+        assert_eq!(fs::read(&restored).unwrap(), b"data");
+        assert!(file_mtime(&restored).unwrap() > 0);
+    }
+
+    #[test]
+    fn test_file_mtime() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("reference");
+        let file = File::create(&path).unwrap();
+        file.set_modified(UNIX_EPOCH + std::time::Duration::from_secs(86_400)).unwrap();
+
+        assert_eq!(file_mtime(&path).unwrap(), 86_400);
+        assert!(matches!(file_mtime("/nonexistent/reference"), Err(ResqryptError::NotFound(_))));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_directory_modes_roundtrip() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        let set_mode = |path: &Path, mode: u32| {
+            fs::set_permissions(path, fs::Permissions::from_mode(mode)).unwrap();
+        };
+
+        let source_dir = TempDir::new().unwrap();
+        let private = source_dir.path().join("private");
+        let read_only = source_dir.path().join("read_only");
+        fs::create_dir(&private).unwrap();
+        fs::create_dir(&read_only).unwrap();
+        fs::write(read_only.join("inside.txt"), "written before the mode").unwrap();
+        set_mode(&private, 0o700);
+        set_mode(&read_only, 0o500);
+        set_mode(source_dir.path(), 0o750);
+
+        let archive_data = create_archive(source_dir.path()).unwrap();
+        set_mode(&read_only, 0o700);
+
+        let dest_dir = TempDir::new().unwrap();
+        extract_archive(&archive_data, dest_dir.path()).unwrap();
+        let extracted = dest_dir.path().join(source_dir.path().file_name().unwrap());
+
+        assert_eq!(mode(&extracted), 0o750);
+        assert_eq!(mode(&extracted.join("private")), 0o700);
+        assert_eq!(mode(&extracted.join("read_only")), 0o500);
+        let content = fs::read_to_string(extracted.join("read_only/inside.txt")).unwrap();
+        assert_eq!(content, "written before the mode");
+
+        // Let the temporary directory be removed
+        set_mode(&extracted.join("read_only"), 0o700);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_one_file_system_skips_mount_points() {
+        // Needs a filesystem mounted below the walked directory, as /dev
+        // usually is below /
+        let (root, mount) = (Path::new("/"), Path::new("/dev"));
+        let device_of = |path: &Path| device(&fs::metadata(path).unwrap());
+        if device_of(root) == device_of(mount) {
+            return;
+        }
+
+        let options = ArchiveOptions { one_file_system: true, ..ArchiveOptions::default() };
+        let mut walk = SourceWalk::new(root, &options).unwrap();
+        let mut paths = walk.by_ref().filter_map(Result::ok).map(|(entry, _)| entry.into_path());
+        assert!(paths.any(|path| path == mount));
+        assert!(paths.next().is_none_or(|path| !path.starts_with(mount)));
+        assert!(walk.mount_points.iter().any(|path| path == mount));
+    }
+
+    #[test]
+    fn test_dir_walk() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        fs::create_dir_all(root.join("b/deep")).unwrap();
+        fs::create_dir(root.join("skipped")).unwrap();
+        fs::write(root.join("a.txt"), "a").unwrap();
+        fs::write(root.join("b/c.txt"), "c").unwrap();
+        fs::write(root.join("b/deep/d.txt"), "d").unwrap();
+        fs::write(root.join("skipped/e.txt"), "e").unwrap();
+
+        // Each directory before its contents, in name order
+        let mut walk = DirWalk::new(root);
+        let mut seen = Vec::new();
+        while let Some(entry) = walk.next() {
+            let entry = entry.unwrap();
+            let relative = entry.path().strip_prefix(root).unwrap().to_path_buf();
+            if relative == Path::new("skipped") {
+                walk.skip_current_dir();
+            }
+            seen.push((relative, entry.depth()));
+        }
+        let expected = [
+            ("", 0),
+            ("a.txt", 1),
+            ("b", 1),
+            ("b/c.txt", 2),
+            ("b/deep", 2),
+            ("b/deep/d.txt", 3),
+            ("skipped", 1),
+        ];
+        let expected: Vec<_> = expected.map(|(path, depth)| (PathBuf::from(path), depth)).into();
+        assert_eq!(seen, expected);
+
+        let shallow: Vec<_> =
+            DirWalk::new(root).max_depth(1).map(|entry| entry.unwrap().depth()).collect();
+        assert_eq!(shallow, [0, 1, 1, 1]);
+
+        // Errors name the path that could not be read
+        let missing = root.join("missing");
+        let err = DirWalk::new(&missing).next().unwrap().unwrap_err();
+        assert!(
+            matches!(&err, ResqryptError::ArchiveError { path: Some(path), .. } if *path == missing)
+        );
+    }
+
     #[test]
     fn test_read_write_file() {
         let temp_dir = TempDir::new().unwrap();
@@ -173,6 +1732,8 @@ mod tests {
 
         let read_data = read_file(&file_path).unwrap();
         assert_eq!(data.as_slice(), read_data.as_slice());
+        // Sized from the metadata, so reading never grew the buffer
+        assert_eq!(read_data.capacity(), data.len());
     }
 
     #[test]
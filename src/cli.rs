@@ -2,11 +2,21 @@
 //!
 //! Defines the command-line interface for resqrypt.
 
-use std::path::PathBuf;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
 
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use zeroize::Zeroizing;
 
+use crate::config::{Config, Policy, Profile};
+use crate::crypto::stream::MAX_CHUNK_SIZE;
+use crate::crypto::{CipherKind, DEFAULT_MAX_MEMORY_COST, KdfParams, Padding, RandomSource};
+use crate::error::{ResqryptError, Result};
 use crate::kdf_defaults;
+use crate::utils::{
+    Glob, HashAlgo, decrypted_output_in, encrypted_output_in, get_password, has_glob_meta,
+    password_from_env, prompt_for_password,
+};
 
 /// Resqrypt - Secure file and directory encryption
 #[derive(Parser, Debug)]
@@ -14,22 +24,147 @@ use crate::kdf_defaults;
 #[command(author, version, about, long_about = None)]
 #[command(propagate_version = true)]
 pub struct Cli {
+    /// Config file path (default: ~/.config/resqrypt/config.toml)
+    #[arg(long, global = true)]
+    pub config: Option<PathBuf>,
+
+    /// When to use colored output
+    #[arg(long, value_enum, global = true)]
+    pub color: Option<ColorChoice>,
+
     #[command(subcommand)]
     pub command: Commands,
 }
 
+/// Colored output setting
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum ColorChoice {
+    /// Color when writing to a terminal
+    #[default]
+    Auto,
+    /// Always color
+    Always,
+    /// Never color
+    Never,
+}
+
 #[derive(Subcommand, Debug)]
 pub enum Commands {
     /// Encrypt a file or directory
-    Encrypt(EncryptArgs),
+    Encrypt(Box<EncryptArgs>),
     /// Decrypt a file or directory
     Decrypt(DecryptArgs),
+    /// Rewrite an encrypted file in the current format, optionally at a
+    /// different compression level (a full decrypt and re-encrypt)
+    Migrate(MigrateArgs),
+    /// Salvage what is left of a damaged chunked file, replacing the chunks
+    /// that fail authentication with zeros
+    Recover(RecoverArgs),
+    /// Authenticate an encrypted file, or only a byte range of its payload,
+    /// without writing anything
+    Verify(VerifyArgs),
+    /// Run built-in known-answer tests
+    Selftest,
+    /// List the supported ciphers, KDFs and compression of this build
+    Capabilities(CapabilitiesArgs),
 }
 
-/// Arguments for the encrypt command
+/// Largest `--max-argon2-memory`, the most MiB a KiB `u32` can express
+const MAX_MEMORY_MIB: i64 = (u32::MAX / 1024) as i64;
+
+/// Help of the `-v` flag of every command that derives a key
+const VERBOSE_HELP: &str = "Show verbose output; repeat for diagnostics (-vv: stage timings, \
+                            key fingerprint and chunk count, -vvv: also the full header)";
+
+/// Password and pepper options, shared by every command that derives a key
+#[derive(Args, Debug, Clone, Default)]
+pub struct PasswordArgs {
+    /// Password (read from RESQRYPT_PASSWORD, else prompted for, if not
+    /// provided)
+    #[arg(short, long)]
+    pub password: Option<String>,
+
+    /// Read the password from this file descriptor (Unix only; takes
+    /// precedence over RESQRYPT_PASSWORD)
+    #[cfg(unix)]
+    #[arg(long, value_name = "FD", conflicts_with = "password")]
+    pub password_fd: Option<i32>,
+
+    /// Use these base64-encoded raw bytes as the password (for binary keys;
+    /// takes precedence over other password sources)
+    #[arg(long, value_name = "B64")]
+    pub key_base64: Option<String>,
+
+    /// Secret pepper file, mixed into the password on encryption and never
+    /// stored in the output; files encrypted with it need it to decrypt
+    #[arg(long, value_name = "FILE", env = "RESQRYPT_PEPPER_FILE")]
+    pub pepper_file: Option<PathBuf>,
+
+    /// Largest Argon2id memory in MiB that an encrypted file's header may
+    /// ask for before its key is derived (default: 2048); raise it only for
+    /// files you trust
+    #[arg(long, value_name = "MIB", value_parser = clap::value_parser!(u32).range(1..=MAX_MEMORY_MIB))]
+    pub max_argon2_memory: Option<u32>,
+}
+
+impl PasswordArgs {
+    /// Password bytes from the options or the environment, else prompted
+    /// for with `prompt`
+    pub fn password(&self, prompt: &str) -> Result<Zeroizing<Vec<u8>>> {
+        self.password_or(|| prompt_for_password(prompt))
+    }
+
+    /// Like [`PasswordArgs::password`], asking twice at the prompt, as for
+    /// a new file
+    pub fn new_password(&self, prompt: &str) -> Result<Zeroizing<Vec<u8>>> {
+        self.password_or(|| {
+            let password = prompt_for_password(prompt)?;
+            if prompt_for_password("Confirm password: ")? != password {
+                return Err(ResqryptError::PasswordError("Passwords do not match".to_string()));
+            }
+            Ok(password)
+        })
+    }
+
+    /// Largest Argon2 memory cost in KiB to accept from a file header
+    pub fn max_memory_cost(&self) -> u32 {
+        self.max_argon2_memory.map_or(DEFAULT_MAX_MEMORY_COST, |mib| mib * 1024)
+    }
+
+    /// Whether the password is read from the terminal prompt
+    pub fn prompts(&self) -> bool {
+        self.key_base64.is_none()
+            && self.password.is_none()
+            && self.password_fd().is_none()
+            && password_from_env().is_none()
+    }
+
+    /// `--password-fd`, which only exists on Unix
+    fn password_fd(&self) -> Option<i32> {
+        #[cfg(unix)]
+        return self.password_fd;
+        #[cfg(not(unix))]
+        None
+    }
+
+    /// Password from the options in [`get_password`]'s order, else `prompt`
+    fn password_or(
+        &self,
+        prompt: impl FnOnce() -> Result<Zeroizing<Vec<u8>>>,
+    ) -> Result<Zeroizing<Vec<u8>>> {
+        get_password(
+            self.key_base64.as_deref(),
+            self.password.as_deref(),
+            self.password_fd(),
+            prompt,
+        )
+    }
+}
+
+/// Arguments for the migrate command
 #[derive(Parser, Debug)]
-pub struct EncryptArgs {
-    /// Input file or directory path
+pub struct MigrateArgs {
+    /// Input encrypted file path (.resqrypt)
     #[arg(short, long)]
     pub input: PathBuf,
 
@@ -37,43 +172,736 @@ pub struct EncryptArgs {
     #[arg(short, long)]
     pub output: PathBuf,
 
-    /// Encryption password (will prompt if not provided)
-    #[arg(short, long, env = "RESQRYPT_PASSWORD")]
-    pub password: Option<String>,
+    #[command(flatten)]
+    pub password: PasswordArgs,
 
-    /// Argon2id memory cost in MB
-    #[arg(long, default_value_t = kdf_defaults::MEMORY_COST / 1024)]
-    pub argon2_memory: u32,
+    /// zstd level to recompress at, 1-22 (default: 3)
+    #[arg(long, value_parser = clap::value_parser!(i32).range(1..=22))]
+    pub compression_level: Option<i32>,
 
-    /// Argon2id iteration count
-    #[arg(long, default_value_t = kdf_defaults::TIME_COST)]
-    pub argon2_iterations: u32,
+    #[arg(short, long, action = clap::ArgAction::Count, help = VERBOSE_HELP)]
+    pub verbose: u8,
 
-    /// Argon2id parallelism degree
-    #[arg(long, default_value_t = kdf_defaults::PARALLELISM)]
-    pub argon2_parallelism: u32,
+    /// Source of the new salt and nonce; set by the caller, not a flag
+    #[arg(skip)]
+    pub rng: RandomSource,
+}
+
+/// Arguments for the recover command
+#[derive(Parser, Debug)]
+pub struct RecoverArgs {
+    /// Input damaged encrypted file path (.resqrypt)
+    #[arg(short, long)]
+    pub input: PathBuf,
 
-    /// Show verbose output
+    /// Output file path for the recovered data (the tar stream for a
+    /// directory archive)
     #[arg(short, long)]
-    pub verbose: bool,
+    pub output: PathBuf,
+
+    #[command(flatten)]
+    pub password: PasswordArgs,
+
+    /// Write the decrypted payload as stored, without decompressing it
+    /// (compressed data cannot be decompressed past the first lost chunk)
+    #[arg(long)]
+    pub raw: bool,
+
+    #[arg(short, long, action = clap::ArgAction::Count, help = VERBOSE_HELP)]
+    pub verbose: u8,
 }
 
-/// Arguments for the decrypt command
+/// Report output format
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum OutputFormat {
+    /// Human-readable text
+    #[default]
+    Text,
+    /// JSON object
+    Json,
+}
+
+/// Arguments for the verify command
 #[derive(Parser, Debug)]
-pub struct DecryptArgs {
+pub struct VerifyArgs {
     /// Input encrypted file path (.resqrypt)
     #[arg(short, long)]
     pub input: PathBuf,
 
-    /// Output file or directory path
+    #[command(flatten)]
+    pub password: PasswordArgs,
+
+    /// Authenticate only the chunks holding these payload bytes, e.g.
+    /// `1G-2G` (inclusive), seeking over the rest; only for files stored
+    /// uncompressed or already zstd, whose payload is the original data
+    #[arg(long, value_name = "START-END", value_parser = parse_byte_range)]
+    pub range: Option<Range<u64>>,
+
+    #[arg(short, long, action = clap::ArgAction::Count, help = VERBOSE_HELP)]
+    pub verbose: u8,
+}
+
+/// Kind of data in an input file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum InputFormat {
+    /// Any file, restored as the same file
+    #[default]
+    File,
+    /// A tar archive, restored by extracting it
+    Tar,
+}
+
+/// Container the encrypt command writes
+#[cfg(feature = "age")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum ContainerFormat {
+    /// resqrypt's own format
+    #[default]
+    Resqrypt,
+    /// The age format, for `age` and `rage`
+    Age,
+}
+
+/// Arguments for the capabilities command
+#[derive(Parser, Debug)]
+pub struct CapabilitiesArgs {
+    /// Output format
+    #[arg(long, value_enum, default_value_t)]
+    pub format: OutputFormat,
+}
+
+/// Arguments for the encrypt command
+#[derive(Parser, Debug, Clone)]
+pub struct EncryptArgs {
+    /// Input file or directory path, or a glob such as `docs/**/*.pdf`
+    /// whose matching files are bundled into one archive
     #[arg(short, long)]
-    pub output: PathBuf,
+    pub input: PathBuf,
 
-    /// Decryption password (will prompt if not provided)
-    #[arg(short, long, env = "RESQRYPT_PASSWORD")]
-    pub password: Option<String>,
+    /// Treat the input as a glob even if a file of that name exists
+    #[arg(long)]
+    pub glob: bool,
+
+    /// Bundle only the paths listed in this file (`-` for stdin), one per
+    /// line and relative to the input directory
+    #[arg(long, value_name = "PATH", conflicts_with = "glob")]
+    pub files_from: Option<PathBuf>,
+
+    /// Like --files-from, but the paths are NUL-terminated as written by
+    /// `find -print0`
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["glob", "files_from"])]
+    pub files_from0: Option<PathBuf>,
+
+    /// Warn about and skip listed paths that do not exist instead of failing
+    #[arg(long)]
+    pub skip_errors: bool,
+
+    /// Encrypt each file matched by the glob or listed with --files-from
+    /// into its own `<name>.resqrypt` below --output-dir, mirroring the
+    /// input tree, instead of bundling them into one archive
+    #[arg(
+        long,
+        requires = "output_dir",
+        conflicts_with_all = ["update", "no_archive", "archive_name", "offset", "length"]
+    )]
+    pub each: bool,
+
+    /// Output encrypted file path (.resqrypt); repeat to write identical
+    /// copies to several paths in one pass
+    #[arg(short, long, required_unless_present = "output_dir")]
+    pub output: Vec<PathBuf>,
+
+    /// Write the output into this directory as `<input name>.resqrypt`
+    #[arg(long, value_name = "DIR", conflicts_with = "output")]
+    pub output_dir: Option<PathBuf>,
+
+    #[command(flatten)]
+    pub password: PasswordArgs,
+
+    /// Security profile, applied before individual parameter flags
+    #[arg(long, value_enum)]
+    pub profile: Option<Profile>,
+
+    /// Policy file with organization-wide settings, applied together with
+    /// /etc/resqrypt/policy.toml when that exists
+    #[arg(long, value_name = "FILE")]
+    pub kdf_params_from: Option<PathBuf>,
+
+    /// Encryption cipher (default: aes256gcm)
+    #[arg(long, value_enum)]
+    pub cipher: Option<CipherKind>,
+
+    /// Pick the faster cipher for this CPU: aes256gcm with hardware AES,
+    /// xchacha20poly1305 otherwise (the choice can differ across machines)
+    #[arg(long, conflicts_with = "cipher")]
+    pub auto_cipher: bool,
+
+    /// Argon2id memory cost in MiB (default: 64)
+    #[arg(long)]
+    pub argon2_memory: Option<u32>,
+
+    /// Argon2id iteration count (default: 3)
+    #[arg(long)]
+    pub argon2_iterations: Option<u32>,
+
+    /// Argon2id parallelism degree (default: 4, at most 4× the CPU count)
+    #[arg(long)]
+    pub argon2_parallelism: Option<u32>,
+
+    /// Raise the Argon2id iteration count until memory (MiB) × iterations
+    /// reaches MIN (default: 192, as with 64 MiB × 3)
+    #[arg(long, value_name = "MIN", num_args = 0..=1, default_missing_value = "192")]
+    pub time_cost_auto: Option<u32>,
+
+    /// zstd compression level, 1-22 (default: 3)
+    #[arg(long, value_parser = clap::value_parser!(i32).range(1..=22))]
+    pub compression_level: Option<i32>,
+
+    /// Enable zstd long-distance matching over a 2^WINDOW_LOG byte window
+    /// (10-30, default: 27), for large inputs with repetitions far apart;
+    /// encrypting and decrypting each need the window in memory
+    #[arg(
+        long,
+        value_name = "WINDOW_LOG",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "27",
+        value_parser = clap::value_parser!(u32).range(10..=30),
+        conflicts_with = "no_compress"
+    )]
+    pub long: Option<u32>,
+
+    /// Encrypt the file or archive without compressing it, for data that
+    /// is already compressed (incompressible input is detected and stored
+    /// as-is even without this flag)
+    #[arg(long, conflicts_with = "compression_level")]
+    pub no_compress: bool,
+
+    /// Plaintext bytes per encrypted chunk, 16K-16M; each chunk adds 20
+    /// bytes of overhead (default: 64K)
+    #[arg(long, value_name = "SIZE", value_parser = parse_chunk_size)]
+    pub chunk_size: Option<u32>,
+
+    /// Cut chunks where the content says instead of every --chunk-size
+    /// bytes, which becomes the largest chunk, so backup tools can
+    /// deduplicate unchanged chunks across versions; repeated chunks show
+    /// as repeated (pair with --no-compress)
+    #[arg(long)]
+    pub cdc: bool,
+
+    /// Follow the chunks with a Merkle tree over their auth tags, 32 bytes
+    /// per chunk, so `verify --range` can check any chunk on its own, even
+    /// with --cdc
+    #[arg(long)]
+    pub merkle: bool,
+
+    /// Refuse to start if the estimated memory use (Argon2id memory plus
+    /// chunk buffers and the zstd window) exceeds SIZE, e.g. `256M`
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    pub memory_budget: Option<u64>,
+
+    /// Hide the payload size by padding it before encryption: `pow2` pads
+    /// to the next power of two, a size such as `1M` to its next multiple
+    /// (the padding is authenticated and removed on decryption)
+    #[arg(long, value_name = "SCHEME", value_parser = parse_padding)]
+    pub pad_to: Option<Padding>,
+
+    /// Store extended attributes when archiving a directory (Unix only)
+    #[arg(long)]
+    pub xattrs: bool,
+
+    /// Store each file as it was when opened, warning instead of failing if
+    /// it grows or shrinks while being read (not a consistent snapshot of
+    /// the whole tree; use filesystem snapshots for that)
+    #[arg(long)]
+    pub snapshot: bool,
 
-    /// Show verbose output
+    /// Stay on the filesystem of the input directory: store the mount
+    /// points inside it, but not the contents of what is mounted there
+    /// (Unix only, as `tar --one-file-system`)
+    #[arg(long, conflicts_with_all = ["glob", "files_from", "files_from0"])]
+    pub one_file_system: bool,
+
+    /// Stamp every archived entry with this modification time, in seconds
+    /// since the Unix epoch (default: $SOURCE_DATE_EPOCH if set)
+    #[arg(long, value_name = "EPOCH")]
+    pub mtime: Option<u64>,
+
+    /// Name of the root directory stored in the archive (default: the
+    /// input directory's name)
+    #[arg(long, value_name = "NAME")]
+    pub archive_name: Option<String>,
+
+    /// Encrypt the only regular file of a directory input directly instead
+    /// of archiving the directory, storing its path in the header so
+    /// decryption recreates the directory and file; other directories are
+    /// archived as usual
+    #[arg(long, conflicts_with_all = ["glob", "files_from", "files_from0", "archive_name"])]
+    pub no_archive: bool,
+
+    /// What the input file holds: `tar` stores an existing tar archive
+    /// as is, after checking it is well-formed, as a directory archive
+    /// that decryption extracts
+    #[arg(
+        long,
+        value_enum,
+        default_value_t,
+        conflicts_with_all = ["glob", "files_from", "files_from0", "offset", "length", "update"]
+    )]
+    pub input_format: InputFormat,
+
+    /// Output container: `age` writes a file `age -d` or `rage -d` can
+    /// decrypt, zstd-compressed unless --no-compress, to the passphrase or
+    /// the --recipient keys. resqrypt-only header data is not carried: no
+    /// stored name (--no-archive) or source offset, and no comment or other
+    /// metadata. The cipher and Argon2id settings do not apply
+    #[cfg(feature = "age")]
+    #[arg(long, value_enum, default_value_t)]
+    pub format: ContainerFormat,
+
+    /// Encrypt to this age X25519 public key (`age1...`) instead of a
+    /// passphrase; repeat for several recipients (only with --format age)
+    #[cfg(feature = "age")]
+    #[arg(short = 'r', long, value_name = "AGE1...", conflicts_with_all = ["password", "key_base64"])]
+    pub recipient: Vec<String>,
+
+    /// Update the existing encrypted directory archive given with
+    /// `--output` from the input directory: add new files, replace those
+    /// whose size or modification time changed and keep the rest. The
+    /// file's cipher, Argon2id parameters and pepper are kept, and for a
+    /// --cdc file also its salt, so unchanged chunks stay byte-identical;
+    /// they must still satisfy an enforced policy
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "glob", "files_from", "files_from0", "output_dir", "no_archive", "archive_name",
+            "offset", "length", "filter_cmd", "pad_to", "cipher", "auto_cipher", "manifest",
+            "profile", "argon2_memory", "argon2_iterations", "argon2_parallelism",
+            "time_cost_auto",
+        ]
+    )]
+    pub update: bool,
+
+    /// Ask for confirmation (or fail without a terminal) before archiving
+    /// more than N entries [default: 100000]
+    #[arg(long, value_name = "N")]
+    pub max_files: Option<u64>,
+
+    /// Ask for confirmation (or fail without a terminal) before archiving
+    /// more than SIZE of file data, e.g. `10G` [default: 50G]
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    pub max_size: Option<u64>,
+
+    /// Archive inputs over --max-files or --max-size without asking
+    #[arg(short = 'y', long)]
+    pub yes: bool,
+
+    /// Stamp every archived entry with the modification time of this file
+    #[arg(long, value_name = "FILE", conflicts_with = "mtime")]
+    pub mtime_from: Option<PathBuf>,
+
+    /// Encrypt only the byte range of the input file starting at this
+    /// offset; the offset is recorded in the header
+    #[arg(long, value_name = "BYTES")]
+    pub offset: Option<u64>,
+
+    /// Encrypt only this many bytes of the input file (default: to the end)
+    #[arg(long, value_name = "BYTES")]
+    pub length: Option<u64>,
+
+    /// Pipe the plaintext through this shell command before encryption and
+    /// encrypt its output (the command sees the plaintext; only use trusted
+    /// commands)
+    #[arg(long, value_name = "CMD")]
+    pub filter_cmd: Option<String>,
+
+    /// Write a JSON manifest listing each source file, the output, its size
+    /// and status (also written when encryption fails); with --each, each
+    /// file is recorded as it finishes
+    #[arg(long, value_name = "PATH")]
+    pub manifest: Option<PathBuf>,
+
+    /// Fail instead of warning when the output extension looks wrong
+    #[arg(long)]
+    pub strict_extension: bool,
+
+    /// Hash the encrypted output as it is written and print the digest
+    /// (also printed with --verbose, using the default algorithm)
+    #[arg(long, value_enum, value_name = "ALGO")]
+    pub hash_algo: Option<HashAlgo>,
+
+    /// Print a warning to stderr when a password given by flag, environment
+    /// or file descriptor looks weak (never fails)
+    #[arg(long)]
+    pub warn_weak_password: bool,
+
+    #[arg(short, long, action = clap::ArgAction::Count, help = VERBOSE_HELP)]
+    pub verbose: u8,
+
+    /// Source of the new salt and nonce; set by the caller, not a flag
+    #[arg(skip)]
+    pub rng: RandomSource,
+
+    /// Relative path stored in the header; set by `--no-archive`, not a flag
+    #[arg(skip)]
+    pub stored_name: Option<String>,
+}
+
+/// Arguments for the decrypt command
+#[derive(Parser, Debug)]
+pub struct DecryptArgs {
+    /// Input encrypted file path (.resqrypt)
     #[arg(short, long)]
-    pub verbose: bool,
+    pub input: PathBuf,
+
+    /// Output file or directory path; `-` writes a file's plaintext to
+    /// stdout (directory archives need --allow-archive-stdout)
+    #[arg(
+        short,
+        long,
+        required_unless_present_any = ["output_dir", "test_password", "discard_output"]
+    )]
+    pub output: Option<PathBuf>,
+
+    /// With `-o -`, write a directory payload to stdout as a raw tar stream
+    /// (e.g. to pipe into `tar x`) instead of refusing
+    #[arg(long)]
+    pub allow_archive_stdout: bool,
+
+    /// Write the output into this directory, created if needed, stripping
+    /// `.resqrypt` from the input name (archives extract to DIR/NAME/)
+    #[arg(long, visible_alias = "to-dir", value_name = "DIR", conflicts_with = "output")]
+    pub output_dir: Option<PathBuf>,
+
+    #[command(flatten)]
+    pub password: PasswordArgs,
+
+    /// Number of password prompts before giving up
+    #[arg(long, default_value_t = 3, value_parser = clap::value_parser!(u32).range(1..))]
+    pub max_attempts: u32,
+
+    /// Restore extended attributes stored in a directory archive (Unix only)
+    #[arg(long)]
+    pub xattrs: bool,
+
+    /// Skip archive entries that cannot be extracted (e.g. names the
+    /// filesystem rejects) instead of aborting, and report how many failed
+    #[arg(long)]
+    pub ignore_extract_errors: bool,
+
+    /// Remove the first N components of each archive entry's path when
+    /// extracting, as tar does (1 drops the archive root); entries with no
+    /// components left are skipped
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    pub strip_components: usize,
+
+    /// Pipe the decrypted data through this shell command, the inverse of
+    /// the one given on encryption, and write its output
+    #[arg(long, value_name = "CMD")]
+    pub filter_cmd: Option<String>,
+
+    /// Fail instead of warning when the output extension looks wrong
+    #[arg(long)]
+    pub strict_extension: bool,
+
+    /// Skip N bytes of the input before the encrypted data, for data
+    /// embedded in a larger file
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    pub skip_bytes: u64,
+
+    /// Search the input (after --skip-bytes) for the start of the
+    /// encrypted data
+    #[arg(long)]
+    pub scan: bool,
+
+    /// Only check the password and write nothing; exits with 2 if it is
+    /// wrong and 1 on any other failure (fast for chunked files, which need
+    /// only their first chunk)
+    #[arg(long, conflicts_with_all = ["output", "output_dir", "filter_cmd"])]
+    pub test_password: bool,
+
+    /// Run the whole decryption, decompression and archive enumeration but
+    /// write nothing, reporting the decrypted size and throughput (for
+    /// benchmarking without disk I/O)
+    #[arg(
+        long,
+        hide = true,
+        conflicts_with_all = ["output", "output_dir", "test_password", "allow_archive_stdout"]
+    )]
+    pub discard_output: bool,
+
+    /// Refuse to derive the key if the estimated memory use (the file's
+    /// Argon2id memory plus chunk buffers and the zstd window) exceeds SIZE
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    pub memory_budget: Option<u64>,
+
+    #[arg(short, long, action = clap::ArgAction::Count, help = VERBOSE_HELP)]
+    pub verbose: u8,
+}
+
+/// Smallest chunk size accepted by `--chunk-size`
+const MIN_CHUNK_SIZE: u32 = 16 * 1024;
+
+/// Parse a size such as `65536`, `64K`, `1M` or `2G` (binary units)
+pub(crate) fn parse_size(value: &str) -> std::result::Result<u64, String> {
+    let upper = value.trim().to_ascii_uppercase();
+    // At most one `B` or `iB` suffix, the latter only after a unit
+    let (digits, binary) = match upper.strip_suffix("IB") {
+        Some(digits) => (digits, true),
+        None => (upper.strip_suffix('B').unwrap_or(&upper), false),
+    };
+    let (digits, multiplier) = match digits.strip_suffix('K') {
+        Some(digits) => (digits, 1024),
+        None => match digits.strip_suffix('M') {
+            Some(digits) => (digits, 1024 * 1024),
+            None => match digits.strip_suffix('G') {
+                Some(digits) => (digits, 1024 * 1024 * 1024),
+                None => (digits, 1),
+            },
+        },
+    };
+
+    digits
+        .parse::<u64>()
+        .ok()
+        .filter(|_| !binary || multiplier > 1)
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| format!("invalid size `{}` (expected e.g. 65536, 64K or 1M)", value))
+}
+
+/// Parse an inclusive byte range such as `0-4095` or `1G-2G`
+fn parse_byte_range(value: &str) -> std::result::Result<Range<u64>, String> {
+    let (start, end) = value
+        .split_once('-')
+        .ok_or_else(|| format!("invalid range `{}` (expected START-END, e.g. 0-4095)", value))?;
+    let (start, end) = (parse_size(start)?, parse_size(end)?);
+    if start > end {
+        return Err(format!("range `{}` ends before it starts", value));
+    }
+    end.checked_add(1)
+        .map(|end| start..end)
+        .ok_or_else(|| format!("range `{}` is too large", value))
+}
+
+/// Parse a chunk size in the range accepted by `--chunk-size`, from
+/// [`MIN_CHUNK_SIZE`] up to the format's [`MAX_CHUNK_SIZE`]
+fn parse_chunk_size(value: &str) -> std::result::Result<u32, String> {
+    let size = parse_size(value)?;
+    if !(u64::from(MIN_CHUNK_SIZE)..=u64::from(MAX_CHUNK_SIZE)).contains(&size) {
+        return Err(format!("chunk size must be between 16K and 16M, got {} bytes", size));
+    }
+    Ok(size as u32)
+}
+
+/// Parse a padding scheme: `pow2`, or a size whose multiples are the buckets
+fn parse_padding(value: &str) -> std::result::Result<Padding, String> {
+    if value.trim().eq_ignore_ascii_case("pow2") {
+        return Ok(Padding::PowerOfTwo);
+    }
+
+    match parse_size(value) {
+        Ok(0) => Err("padding size must be at least 1 byte".to_string()),
+        Ok(size) => Ok(Padding::Multiple(size)),
+        Err(_) => Err(format!("invalid padding `{}` (expected pow2 or a size such as 1M)", value)),
+    }
+}
+
+impl EncryptArgs {
+    /// Input glob, with `--glob` or when the input does not exist and
+    /// contains glob metacharacters
+    pub fn input_glob(&self) -> Result<Option<Glob>> {
+        let pattern = self.input.to_string_lossy();
+        if self.glob || (!self.input.exists() && has_glob_meta(&pattern)) {
+            Glob::new(&pattern).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// The `--files-from` or `--files-from0` list, and whether it is
+    /// NUL-separated
+    pub fn file_list(&self) -> Option<(&Path, bool)> {
+        match (&self.files_from, &self.files_from0) {
+            (Some(path), _) => Some((path, false)),
+            (None, Some(path)) => Some((path, true)),
+            (None, None) => None,
+        }
+    }
+
+    /// Whether only a byte range of the input is encrypted
+    pub fn is_range(&self) -> bool {
+        self.offset.is_some() || self.length.is_some()
+    }
+
+    /// Path the output is named after: the input, or a glob's base directory
+    pub fn source_path(&self) -> Result<PathBuf> {
+        Ok(match self.input_glob()? {
+            Some(glob) => glob.base().to_path_buf(),
+            None => self.input.clone(),
+        })
+    }
+
+    /// Whether the output is an age file rather than a resqrypt one
+    pub fn is_age(&self) -> bool {
+        #[cfg(feature = "age")]
+        return self.format == ContainerFormat::Age;
+        #[cfg(not(feature = "age"))]
+        false
+    }
+
+    /// Output paths from `--output` or `--output-dir`
+    pub fn output_paths(&self) -> Result<Vec<PathBuf>> {
+        match (self.output.as_slice(), &self.output_dir) {
+            ([], Some(dir)) => {
+                let mut output = encrypted_output_in(&self.source_path()?, dir)?;
+                if self.is_age() {
+                    output.set_extension("age");
+                }
+                Ok(vec![output])
+            }
+            ([], None) => Err(ResqryptError::InvalidArgument("no output path given".to_string())),
+            (outputs, _) => Ok(outputs.to_vec()),
+        }
+    }
+
+    /// Fill in options not given on the command line from a config
+    pub fn apply_config(&mut self, config: &Config) {
+        self.cipher = self.cipher.or(config.cipher);
+        self.argon2_memory = self.argon2_memory.or(config.argon2_memory);
+        self.argon2_iterations = self.argon2_iterations.or(config.argon2_iterations);
+        self.argon2_parallelism = self.argon2_parallelism.or(config.argon2_parallelism);
+        self.compression_level = self.compression_level.or(config.compression_level);
+        self.max_files = self.max_files.or(config.max_files);
+        self.max_size = self.max_size.or(config.max_size);
+    }
+
+    /// Fill in options from a policy, then check them if it is enforced
+    pub fn apply_policy(&mut self, policy: &Policy) -> Result<()> {
+        self.apply_config(&policy.config);
+        if !policy.enforced {
+            return Ok(());
+        }
+
+        if self.is_age() {
+            return Err(ResqryptError::InvalidArgument(format!(
+                "--format age cannot be used: the policy in {} is enforced, and age files do \
+                 not use its cipher and Argon2id settings",
+                policy.path.display()
+            )));
+        }
+        if let (true, Some(cipher)) = (self.auto_cipher, policy.config.cipher) {
+            return Err(ResqryptError::InvalidArgument(format!(
+                "--auto-cipher cannot be used: the policy in {} requires {}",
+                policy.path.display(),
+                cipher
+            )));
+        }
+        policy.check(
+            self.argon2_memory.unwrap_or(KdfParams::default().memory_mib()),
+            self.argon2_iterations.unwrap_or(kdf_defaults::TIME_COST),
+            self.cipher.unwrap_or_default(),
+        )
+    }
+}
+
+impl DecryptArgs {
+    /// Output path from `--output` or `--output-dir`
+    pub fn output_path(&self) -> Result<PathBuf> {
+        match (&self.output, &self.output_dir) {
+            (Some(output), _) => Ok(output.clone()),
+            (None, Some(dir)) if dir.exists() && !dir.is_dir() => {
+                Err(ResqryptError::InvalidArgument(format!(
+                    "output directory {} exists and is not a directory",
+                    dir.display()
+                )))
+            }
+            (None, Some(dir)) => decrypted_output_in(&self.input, dir),
+            (None, None) => Err(ResqryptError::InvalidArgument("no output path given".to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::kdf::DEFAULT_MIN_WORK_FACTOR;
+
+    #[test]
+    fn test_parse_chunk_size() {
+        assert_eq!(parse_chunk_size("65536"), Ok(65536));
+        assert_eq!(parse_chunk_size("64K"), Ok(64 * 1024));
+        assert_eq!(parse_chunk_size("1MiB"), Ok(1024 * 1024));
+        assert_eq!(parse_chunk_size("16m"), Ok(16 * 1024 * 1024));
+        assert!(parse_chunk_size("8K").is_err());
+        assert!(parse_chunk_size("32M").is_err());
+        assert!(parse_chunk_size("lots").is_err());
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("256M"), Ok(256 << 20));
+        assert_eq!(parse_size("2GiB"), Ok(2 << 30));
+        assert_eq!(parse_size("64kb"), Ok(64 << 10));
+        assert_eq!(parse_size("4096B"), Ok(4096));
+        assert!(parse_size("2T").is_err());
+
+        // Only one unit suffix is stripped
+        for value in ["1BBB", "64KIBIB", "2GBB", "64KBB", "5iB"] {
+            assert!(parse_size(value).is_err(), "{} parsed", value);
+        }
+    }
+
+    #[test]
+    fn test_parse_byte_range() {
+        assert_eq!(parse_byte_range("0-4095"), Ok(0..4096));
+        assert_eq!(parse_byte_range("1K-1K"), Ok(1024..1025));
+        assert!(parse_byte_range("10-5").is_err());
+        assert!(parse_byte_range("100").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_password_fd_conflicts_with_password() {
+        // Every command shares the same password options
+        for command in ["encrypt", "decrypt", "migrate", "recover"] {
+            let parse = |args: &[&str]| {
+                Cli::try_parse_from(
+                    [&["resqrypt", command, "-i", "in", "-o", "out"], args].concat(),
+                )
+            };
+
+            assert!(parse(&["--password-fd", "3"]).is_ok(), "{}", command);
+            assert!(parse(&["--password-fd", "3", "-p", "pw"]).is_err(), "{}", command);
+        }
+        let verify =
+            Cli::try_parse_from(["resqrypt", "verify", "-i", "in", "--key-base64", "AA=="]);
+        let Commands::Verify(args) = verify.unwrap().command else { unreachable!() };
+        assert_eq!(args.password.key_base64.as_deref(), Some("AA=="));
+        assert!(!args.password.prompts());
+    }
+
+    #[test]
+    fn test_parse_padding() {
+        assert_eq!(parse_padding("pow2"), Ok(Padding::PowerOfTwo));
+        assert_eq!(parse_padding("4096"), Ok(Padding::Multiple(4096)));
+        assert_eq!(parse_padding("1M"), Ok(Padding::Multiple(1024 * 1024)));
+        assert!(parse_padding("0").is_err());
+        assert!(parse_padding("huge").is_err());
+    }
+
+    #[test]
+    fn test_time_cost_auto_default() {
+        let time_cost_auto = |args: &[&str]| {
+            let cli = Cli::try_parse_from(
+                [&["resqrypt", "encrypt", "-i", "in", "-o", "out"], args].concat(),
+            )
+            .unwrap();
+            let Commands::Encrypt(args) = cli.command else { unreachable!() };
+            args.time_cost_auto
+        };
+
+        assert_eq!(time_cost_auto(&[]), None);
+        assert_eq!(time_cost_auto(&["--time-cost-auto"]), Some(DEFAULT_MIN_WORK_FACTOR));
+        assert_eq!(time_cost_auto(&["--time-cost-auto", "512"]), Some(512));
+    }
 }
@@ -0,0 +1,130 @@
+//! Capabilities command implementation
+//!
+//! Lists the ciphers, key derivation functions and compression supported by
+//! this build with their default parameters, and whether the CPU offers
+//! hardware AES, for picking options and for bug reports.
+
+use clap::ValueEnum;
+use serde_json::{Value, json};
+
+use crate::FORMAT_VERSION;
+use crate::cli::{CapabilitiesArgs, OutputFormat};
+use crate::compression::zstd::{DEFAULT_LEVEL, MAX_LEVEL};
+use crate::crypto::{CipherKind, hardware_aes};
+use crate::error::Result;
+use crate::kdf_defaults;
+use crate::utils::telemetry::SpanStats;
+
+/// Execute the capabilities command
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(skip_all, fields(elapsed_ms = tracing::field::Empty))
+)]
+pub fn execute(args: CapabilitiesArgs) -> Result<()> {
+    let _stats = SpanStats::start();
+    print!("{}", render(args.format));
+    Ok(())
+}
+
+/// Command-line name of a cipher
+fn cipher_id(cipher: CipherKind) -> String {
+    cipher.to_possible_value().map(|v| v.get_name().to_string()).unwrap_or_default()
+}
+
+/// Capabilities report in the requested format
+fn render(format: OutputFormat) -> String {
+    match format {
+        OutputFormat::Text => render_text(),
+        OutputFormat::Json => render_json(),
+    }
+}
+
+fn render_text() -> String {
+    let mut out = String::from("Ciphers:\n");
+    for &cipher in CipherKind::value_variants() {
+        out.push_str(&format!(
+            "  {:<18} {}, {}-byte nonce, {}-byte tag{}\n",
+            cipher_id(cipher),
+            cipher,
+            cipher.nonce_len(),
+            cipher.tag_len(),
+            if cipher == CipherKind::default() { " (default)" } else { "" }
+        ));
+    }
+
+    out.push_str("KDFs:\n");
+    out.push_str(&format!(
+        "  {:<18} Argon2id, {} MiB, {} iterations, parallelism {} (default)\n",
+        "argon2id",
+        kdf_defaults::MEMORY_COST / 1024,
+        kdf_defaults::TIME_COST,
+        kdf_defaults::PARALLELISM
+    ));
+
+    out.push_str("Compression:\n");
+    out.push_str(&format!("  {:<18} levels 1-{} (default {})\n", "zstd", MAX_LEVEL, DEFAULT_LEVEL));
+
+    let aes = match hardware_aes() {
+        Some(true) => "yes",
+        Some(false) => "no",
+        None => "unknown",
+    };
+    out.push_str(&format!("Hardware AES: {}\n", aes));
+    out.push_str(&format!("Auto cipher: {}\n", cipher_id(CipherKind::fastest())));
+    out.push_str(&format!("Format version: {}\n", FORMAT_VERSION));
+    out
+}
+
+fn render_json() -> String {
+    let ciphers: Vec<Value> = CipherKind::value_variants()
+        .iter()
+        .map(|&cipher| {
+            json!({
+                "id": cipher_id(cipher),
+                "name": cipher.to_string(),
+                "nonce_len": cipher.nonce_len(),
+                "tag_len": cipher.tag_len(),
+                "default": cipher == CipherKind::default(),
+            })
+        })
+        .collect();
+
+    let report = json!({
+        "ciphers": ciphers,
+        "kdfs": [{
+            "id": "argon2id",
+            "memory_kib": kdf_defaults::MEMORY_COST,
+            "iterations": kdf_defaults::TIME_COST,
+            "parallelism": kdf_defaults::PARALLELISM,
+            "default": true,
+        }],
+        "compression": [{
+            "id": "zstd",
+            "min_level": 1,
+            "max_level": MAX_LEVEL,
+            "default_level": DEFAULT_LEVEL,
+        }],
+        "hardware_aes": hardware_aes(),
+        "auto_cipher": cipher_id(CipherKind::fastest()),
+        "format_version": FORMAT_VERSION,
+    });
+    format!("{:#}\n", report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_lists_all_ciphers() {
+        let text = render(OutputFormat::Text);
+        let json = render(OutputFormat::Json);
+        for &cipher in CipherKind::value_variants() {
+            assert!(text.contains(&cipher_id(cipher)));
+            assert!(json.contains(&format!("\"id\": \"{}\"", cipher_id(cipher))));
+        }
+        assert!(text.contains("argon2id"));
+        assert!(json.contains("\"default\": true"));
+        assert!(json.ends_with("}\n"));
+    }
+}
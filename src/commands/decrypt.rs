@@ -1,133 +1,724 @@
 //! Decrypt command implementation
 //!
-//! Handles the decryption workflow:
+//! Handles the decryption workflow through a [`Pipeline`]:
 //! 1. Read encrypted file
 //! 2. Verify header and extract metadata
 //! 3. Derive key from password
-//! 4. Decrypt with AES-256-GCM
+//! 4. Decrypt with the cipher recorded in the header
 //! 5. Decompress (if was compressed)
 //! 6. Extract archive (if was directory)
 //! 7. Write output
 
-use std::fs::File;
-use std::io::Read;
-use std::path::Path;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 
-use rpassword::prompt_password;
+use tar::Archive;
+use zeroize::Zeroizing;
 
-use crate::archive::tar::{extract_archive, write_file};
+use crate::MAGIC_BYTES;
+use crate::archive::tar::{
+    ArchiveOptions, SkippedEntry, extract_archive_entries, extract_archive_mapped,
+};
 use crate::cli::DecryptArgs;
-use crate::compression::decompress;
-use crate::crypto::aes::decrypt_data;
-use crate::crypto::format::{FileHeader, read_header};
-use crate::crypto::kdf::derive_key;
+use crate::compression::zstd;
+use crate::crypto::FileHeader;
+use crate::crypto::stream::DEFAULT_CHUNK_SIZE;
 use crate::error::{ResqryptError, Result};
-use crate::utils::ProgressReporter;
+use crate::pipeline::{DecryptedPayload, MemoryEstimate, Pipeline};
+use crate::utils::telemetry::SpanStats;
+use crate::utils::{
+    CountingReader, ProgressReporter, StagingDir, check_distinct_paths, check_output_path,
+    filter_into, format_bytes, format_throughput, is_stdio, is_stream_output, load_pepper,
+    output_extension_issue, prompt_for_password, write_atomic,
+};
 
 /// Execute the decrypt command
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(
+        skip_all,
+        fields(
+            input = %args.input.display(),
+            bytes_in = tracing::field::Empty,
+            elapsed_ms = tracing::field::Empty
+        )
+    )
+)]
 pub fn execute(args: DecryptArgs) -> Result<()> {
-    let progress = ProgressReporter::new(args.verbose);
+    let stats = SpanStats::start();
+    stats.record_file_len("bytes_in", &args.input);
+    if args.test_password {
+        return match test_password(&args)? {
+            PasswordCheck::Correct => Ok(()),
+            PasswordCheck::Incorrect => {
+                Err(ResqryptError::PasswordError("Password is incorrect".to_string()))
+            }
+        };
+    }
+
+    let to_stdout = args.output.as_deref().is_some_and(is_stdio);
+    let mut progress = ProgressReporter::new(args.verbose);
+    if to_stdout {
+        progress = progress.with_stdout_reserved();
+    }
 
     // Validate input exists
     if !args.input.exists() {
         return Err(ResqryptError::NotFound(args.input.clone()));
     }
 
-    // Check if output already exists
-    if args.output.exists() {
-        return Err(ResqryptError::AlreadyExists(args.output.clone()));
+    // Nothing is written with --discard-output, so no path is needed
+    let output = if args.discard_output { PathBuf::new() } else { args.output_path()? };
+
+    if !to_stdout && !args.discard_output {
+        // Refuse to overwrite the input with the output
+        check_distinct_paths(&args.input, &output)?;
+
+        // Check if output already exists (refusing symlinks)
+        check_output_path(&output)?;
+
+        // Guard against a forgotten or leftover extension
+        if let Some(issue) =
+            output_extension_issue(&output, false).filter(|_| !is_stream_output(&output))
+        {
+            if args.strict_extension {
+                return Err(ResqryptError::InvalidArgument(issue));
+            }
+            progress.println(format!("⚠️  Warning: {}", issue));
+        }
     }
 
     // Get password
-    let password = get_password(&args.password)?;
+    let mut password = decryption_password(&args)?;
+    let started = Instant::now();
 
     progress.set_message("Reading encrypted file...");
 
-    // Read and parse encrypted file
-    let (header, ciphertext) = read_encrypted_file(&args.input)?;
+    // Read and parse the header
+    let pipeline = Pipeline::new().max_memory_cost(args.password.max_memory_cost());
+    let (mut input, offset) = open_input(&args)?;
+    let input_size = fs::metadata(&args.input)?.len() - offset;
+    let header = pipeline.read_header(&mut input)?;
+    progress.debug(3, format!("Header:\n{}", header));
+    let pipeline = pipeline_for(&args, &header, &progress)?;
+    let memory = check_memory_budget(&args, &header)?;
 
-    progress.set_message("Deriving decryption key...");
+    // A tar stream dumped on a terminal is never what was meant
+    if to_stdout && header.is_directory() && !args.allow_archive_stdout {
+        return Err(ResqryptError::InvalidArgument(
+            "the payload is a directory archive; pass --allow-archive-stdout to write it to \
+             stdout as a tar stream (e.g. `| tar x`), or give an output directory"
+                .to_string(),
+        ));
+    }
+    if !to_stdout && header.is_directory() && is_stream_output(&output) {
+        return Err(ResqryptError::InvalidArgument(format!(
+            "the payload is a directory archive and cannot be extracted into {}; \
+             use `-o - --allow-archive-stdout` to stream the tar instead",
+            output.display()
+        )));
+    }
 
-    // Derive key using params from file header
-    let key = derive_key(password.as_bytes(), &header.salt, &header.kdf_params)?;
+    // A stored name is recreated below the output, as an archive would be
+    let file_output = match &header.stored_name {
+        Some(name) if !to_stdout && !args.discard_output && !is_stream_output(&output) => {
+            output.join(name)
+        }
+        _ => output.clone(),
+    };
 
-    progress.set_message("Decrypting...");
+    // The chunks of a content-defined file are only checked as a sequence
+    // at the final chunk, so one spliced in from another version of the
+    // file would go unnoticed until then: extract out of sight, and move
+    // the result into place once the whole stream is authenticated
+    let staging = (header.is_directory()
+        && header.is_content_defined()
+        && !to_stdout
+        && !args.discard_output)
+        .then(|| StagingDir::new(&output));
+    let extract_dir = staging.as_ref().map_or(output.as_path(), StagingDir::path);
 
-    // Decrypt
-    let decrypted = decrypt_data(&key, &header.nonce, &ciphertext)?;
+    // Derive key and decrypt, re-prompting after a wrong interactive password
+    let unlocking = Instant::now();
+    let mut chunks = None;
+    let decrypted;
+    let payload: Box<dyn Read> = if header.is_chunked() {
+        // Chunks are decrypted as the output is written
+        let chunks = chunks.insert(pipeline.open_stream(&header, input)?);
+        with_password_retries(&args, &progress, &mut password, |password| {
+            chunks.unlock(&pipeline.key_password(&header, password)?)
+        })?;
+        progress.debug(
+            2,
+            format!(
+                "Key derived in {:.3}s (fingerprint {})",
+                unlocking.elapsed().as_secs_f64(),
+                chunks.key_fingerprint().unwrap_or_default()
+            ),
+        );
+        Box::new(chunks)
+    } else {
+        let (ciphertext, trailing) = pipeline.read_ciphertext(&mut input, &header)?;
+        warn_trailing(&progress, trailing);
+        decrypted = with_password_retries(&args, &progress, &mut password, |password| {
+            pipeline.open(&header, &ciphertext, &pipeline.key_password(&header, password)?)
+        })?;
+        progress.debug(
+            2,
+            format!("Key derived and payload opened in {:.3}s", unlocking.elapsed().as_secs_f64()),
+        );
+        Box::new(decrypted.as_slice())
+    };
+    let decrypting = Instant::now();
 
     progress.set_message("Processing decrypted data...");
 
-    // Decompress if needed
-    let output_data = if header.is_already_zstd() {
+    // The inverse filter must be supplied by the user; it is not stored
+    match (header.is_filtered(), &args.filter_cmd) {
+        (true, None) => progress.println(
+            "⚠️  Warning: the data was passed through a filter command on encryption; \
+             writing it unfiltered (pass the inverse command with --filter-cmd)",
+        ),
+        (false, Some(_)) => {
+            return Err(ResqryptError::InvalidArgument(
+                "--filter-cmd given but the file was not encrypted with a filter".to_string(),
+            ));
+        }
+        _ => {}
+    }
+
+    let archive_options = ArchiveOptions {
+        xattrs: args.xattrs,
+        skip_failed_entries: args.ignore_extract_errors,
+        ..ArchiveOptions::default()
+    };
+    let mut skipped = Vec::new();
+    let stripped = Stripped::default();
+    if args.strip_components > 0 && (!header.is_directory() || to_stdout || args.discard_output) {
+        progress.println(
+            "⚠️  Warning: --strip-components only applies when extracting a directory archive",
+        );
+    }
+
+    // Decompress if needed and write output
+    if header.is_already_zstd() {
         progress.set_message("Original was zstd, preserving format...");
-        decrypted
+    } else if header.is_uncompressed() {
+        progress.set_message("Data was stored uncompressed...");
     } else {
         progress.set_message("Decompressing...");
-        decompress(&decrypted)?
+    }
+    if let Some(len) = header.original_len {
+        progress.set_length(len);
+    }
+    let mut reader =
+        CountingReader::new(progress.wrap_read(pipeline.decompress_reader(&header, payload)?));
+
+    let mut discarded_entries = None;
+    let output_size = match (&args.filter_cmd, header.is_directory()) {
+        _ if args.discard_output => {
+            discarded_entries = match &args.filter_cmd {
+                Some(command) => filter_into(command, &mut reader, |stdout| {
+                    discard(stdout, header.is_directory())
+                })?,
+                None => discard(&mut reader, header.is_directory())?,
+            };
+            reader.count()
+        }
+        // Stream to stdout as is: a file's plaintext or the raw tar
+        _ if to_stdout => match &args.filter_cmd {
+            Some(command) => filter_into(command, &mut reader, write_stdout)?,
+            None => write_stdout(&mut reader)?,
+        },
+        (Some(command), true) => {
+            // Extract the filter's output as it arrives
+            let entries = report_entries(&progress, args.strip_components, &stripped);
+            skipped = filter_into(command, &mut reader, |stdout| {
+                extract_archive_mapped(stdout, extract_dir, &archive_options, entries)
+            })?;
+            reader.count()
+        }
+        (Some(command), false) => {
+            // Write the filter's output as it arrives; a failing filter
+            // removes the partial output
+            progress.set_message("Writing output...");
+            let mut written = 0;
+            write_atomic(&file_output, |file| {
+                let mut out = BufWriter::new(file);
+                written =
+                    filter_into(command, &mut reader, |stdout| copy_decrypted(stdout, &mut out))?;
+                out.flush()?;
+                Ok(())
+            })?;
+            written
+        }
+        (None, _) => {
+            // The payload borrows `reader` until the end of this statement
+            match DecryptedPayload::new(&header, Box::new(&mut reader)) {
+                DecryptedPayload::Archive(mut archive) => {
+                    // Stream the tar straight into the extractor
+                    let entries = report_entries(&progress, args.strip_components, &stripped);
+                    skipped = extract_archive_entries(
+                        &mut archive,
+                        extract_dir,
+                        &archive_options,
+                        entries,
+                    )?;
+                    io::copy(&mut archive.into_inner(), &mut io::sink()).map_err(read_error)?;
+                }
+                DecryptedPayload::File(data) => {
+                    // Written as it is decrypted, so only a chunk is held
+                    // in memory; a failure removes the partial output
+                    write_atomic(&file_output, |file| {
+                        let mut out = BufWriter::new(file);
+                        copy_decrypted(data, &mut out)?;
+                        out.flush()?;
+                        Ok(())
+                    })?;
+                }
+            };
+            reader.count()
+        }
     };
 
-    progress.set_message("Writing output...");
+    // Check that the chunk stream is complete
+    drop(reader);
+    let mut lengths = None;
+    if let Some(chunks) = chunks {
+        lengths = chunks.lengths();
+        let opened = chunks.chunks_opened();
+        warn_trailing(&progress, chunks.finish()?);
+        progress.debug(2, format!("Opened {} chunks", opened));
+    }
+    if let Some(staging) = staging {
+        staging.commit(&output)?;
+    }
+    progress.debug(
+        2,
+        format!("Decrypted and wrote the output in {:.3}s", decrypting.elapsed().as_secs_f64()),
+    );
 
-    // Write output
-    if header.is_directory() {
-        // Extract tar archive
-        extract_archive(&output_data, &args.output)?;
+    let elapsed = started.elapsed();
+    progress.finish("Done!");
+    let destination = if to_stdout {
+        "stdout".into()
+    } else if args.discard_output {
+        "nothing (discarded)".into()
     } else {
-        // Write file
-        write_file(&args.output, &output_data)?;
+        file_output.display().to_string()
+    };
+    progress.println(format!("✅ Decrypted: {} -> {}", args.input.display(), destination));
+    warn_skipped(&progress, &skipped);
+    warn_stripped(&progress, args.strip_components, &stripped);
+    if args.discard_output {
+        // The point of discarding is the measurement, so show it unasked
+        progress.println(format!("   {}", format_throughput(output_size, elapsed)));
+        if let Some(entries) = discarded_entries {
+            progress.println(format!("   Read {} archive entries", entries));
+        }
+    }
+    if let Some(weakness) = header.kdf_params.weakness() {
+        // `migrate` keeps the KDF parameters, so only a fresh encrypt helps
+        progress.println(format!(
+            "⚠️  Warning: this file used {}; consider decrypting and encrypting it again",
+            weakness
+        ));
     }
 
-    progress.finish("Done!");
-    progress.println(format!(
-        "✅ Decrypted: {} -> {}",
-        args.input.display(),
-        args.output.display()
-    ));
-
-    if args.verbose {
-        let input_size = ciphertext.len() + FileHeader::SIZE;
-        let output_size = output_data.len();
+    if args.verbose > 0 {
         progress.println(format!("   Input: {} bytes, Output: {} bytes", input_size, output_size));
+        progress.println(format!("   {}", format_throughput(output_size, elapsed)));
 
-        if header.is_directory() {
+        if header.is_directory() && args.discard_output {
+            progress.println("   Type: Directory (archive read and discarded)");
+        } else if header.is_directory() && to_stdout {
+            progress.println("   Type: Directory (written as a tar stream)");
+        } else if header.is_directory() {
             progress.println("   Type: Directory (extracted from archive)");
+        } else if let Some(name) = &header.stored_name {
+            progress.println(format!("   Type: File (stored as {})", name));
         } else {
             progress.println("   Type: File");
         }
+        if header.is_already_zstd() {
+            progress.println("   Compression: zstd (original was zstd, kept as is)");
+        } else if header.is_uncompressed() {
+            progress.println("   Compression: none");
+        } else {
+            progress.println("   Compression: zstd (decompressed)");
+        }
+        if let Some(lengths) = lengths.filter(|lengths| lengths.original_len > 0) {
+            let percent = lengths.payload_len as f64 / lengths.original_len as f64 * 100.0;
+            progress.println(format!("   Payload: compressed to {:.0}% of original", percent));
+        }
+        if let Some(offset) = header.source_offset {
+            progress.println(format!("   Range: source bytes from offset {}", offset));
+        }
+        if header.is_padded() {
+            progress.println("   Padding: removed");
+        }
+        if header.is_peppered() {
+            progress.println("   Pepper: applied");
+        }
+        if header.is_filtered() {
+            progress.println("   Filter: applied on encryption");
+        }
+        if offset > 0 {
+            progress.println(format!("   Offset: encrypted data starts at byte {}", offset));
+        }
+        progress.println(format!("   Cipher: {}", header.cipher));
+        progress.println(format!("   Argon2id: {}", header.kdf_params));
+        progress.println(format!(
+            "   Memory estimate: {} (Argon2id {}, buffers {})",
+            format_bytes(memory.total()),
+            format_bytes(memory.argon2),
+            format_bytes(memory.buffers)
+        ));
     }
 
     Ok(())
 }
 
-/// Get password from args or prompt
-fn get_password(password_arg: &Option<String>) -> Result<String> {
-    match password_arg {
-        Some(p) => Ok(p.clone()),
-        None => {
-            let password = prompt_password("Enter decryption password: ").map_err(|e| {
-                ResqryptError::PasswordError(format!("Failed to read password: {}", e))
-            })?;
+/// Outcome of `--test-password`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PasswordCheck {
+    /// The password opens the file
+    Correct,
+    /// The file failed to authenticate under the password
+    Incorrect,
+}
+
+/// Check the password without writing any output
+///
+/// Chunked files are checked by authenticating only their first chunk, so
+/// this takes about as long as deriving the key. Single-message files have
+/// to be decrypted in full.
+///
+/// Only a failed authentication is reported as
+/// [`PasswordCheck::Incorrect`]; failing to read the password or a missing
+/// pepper is an error, as for decryption.
+pub fn test_password(args: &DecryptArgs) -> Result<PasswordCheck> {
+    let progress = ProgressReporter::new(args.verbose);
+    if !args.input.exists() {
+        return Err(ResqryptError::NotFound(args.input.clone()));
+    }
 
-            if password.is_empty() {
-                return Err(ResqryptError::PasswordError("Password cannot be empty".to_string()));
+    let mut password = decryption_password(args)?;
+
+    progress.set_message("Reading header...");
+    let pipeline = Pipeline::new().max_memory_cost(args.password.max_memory_cost());
+    let (mut input, _) = open_input(args)?;
+    let header = pipeline.read_header(&mut input)?;
+    let pipeline = pipeline_for(args, &header, &progress)?;
+    check_memory_budget(args, &header)?;
+
+    // A wrong password is retried while prompts remain, and reported once
+    // the last attempt fails
+    let mut tries = 0;
+    let mut authenticate = |result: Result<()>| {
+        tries += 1;
+        match result {
+            Err(ResqryptError::PasswordError(_)) if !retries_password(args, tries) => {
+                Ok(PasswordCheck::Incorrect)
             }
+            result => result.map(|()| PasswordCheck::Correct),
+        }
+    };
+
+    let check = if header.is_chunked() {
+        let mut chunks = pipeline.open_stream(&header, input)?;
+        with_password_retries(args, &progress, &mut password, |password| {
+            let password = pipeline.key_password(&header, password)?;
+            authenticate(chunks.unlock(&password))
+        })?
+    } else {
+        let (ciphertext, _) = pipeline.read_ciphertext(&mut input, &header)?;
+        with_password_retries(args, &progress, &mut password, |password| {
+            let password = pipeline.key_password(&header, password)?;
+            authenticate(pipeline.open(&header, &ciphertext, &password).map(drop))
+        })?
+    };
 
-            Ok(password)
+    progress.finish("Done!");
+    match check {
+        PasswordCheck::Correct => {
+            println!("✅ Password is correct for {}", args.input.display())
+        }
+        PasswordCheck::Incorrect => {
+            eprintln!("❌ Password is incorrect for {}", args.input.display())
+        }
+    }
+
+    Ok(check)
+}
+
+/// Open the input positioned at the encrypted data
+///
+/// Skips `--skip-bytes`, then with `--scan` searches on for the magic
+/// bytes. Returns the reader and the offset of the encrypted data; its
+/// header is validated when read, as usual.
+fn open_input(args: &DecryptArgs) -> Result<(BufReader<File>, u64)> {
+    let file = File::open(&args.input)?;
+    let len = file.metadata()?.len();
+    if args.skip_bytes > len {
+        return Err(ResqryptError::InvalidArgument(format!(
+            "--skip-bytes {} is past the end of the {}-byte input",
+            args.skip_bytes, len
+        )));
+    }
+
+    let mut input = BufReader::new(file);
+    let mut offset = args.skip_bytes;
+    input.seek(SeekFrom::Start(offset))?;
+    if args.scan {
+        offset += find_magic(&mut input)?.ok_or_else(|| {
+            ResqryptError::InvalidFormat(format!(
+                "no encrypted data found in {}",
+                args.input.display()
+            ))
+        })?;
+        input.seek(SeekFrom::Start(offset))?;
+    }
+
+    Ok((input, offset))
+}
+
+/// Position of the first [`MAGIC_BYTES`] in `reader`, if any
+fn find_magic<R: Read>(mut reader: R) -> Result<Option<u64>> {
+    let mut buf = vec![0u8; 64 * 1024];
+    // Bytes kept from the previous read, so a match may span two reads
+    let mut kept = 0;
+    // Offset in the stream of `buf[0]`
+    let mut start = 0u64;
+    loop {
+        let n = reader.read(&mut buf[kept..])?;
+        if n == 0 {
+            return Ok(None);
+        }
+        let filled = kept + n;
+        if let Some(pos) =
+            buf[..filled].windows(MAGIC_BYTES.len()).position(|window| window == MAGIC_BYTES)
+        {
+            return Ok(Some(start + pos as u64));
+        }
+
+        kept = filled.min(MAGIC_BYTES.len() - 1);
+        buf.copy_within(filled - kept..filled, 0);
+        start += (filled - kept) as u64;
+    }
+}
+
+/// Run `attempt` with the password, re-prompting after a wrong interactive
+/// password until `--max-attempts` is reached
+fn with_password_retries<T>(
+    args: &DecryptArgs,
+    progress: &ProgressReporter,
+    password: &mut Zeroizing<Vec<u8>>,
+    mut attempt: impl FnMut(&[u8]) -> Result<T>,
+) -> Result<T> {
+    let mut tries = 1;
+    loop {
+        progress.set_message("Deriving key and decrypting...");
+
+        match attempt(password) {
+            Ok(value) => return Ok(value),
+            Err(ResqryptError::PasswordError(msg)) if retries_password(args, tries) => {
+                progress
+                    .println(format!("❌ {} (attempt {} of {})", msg, tries, args.max_attempts));
+                *password = prompt_for_password(DECRYPTION_PROMPT)?;
+                tries += 1;
+            }
+            Err(e) => return Err(e),
         }
     }
 }
 
-/// Read encrypted file and parse header
-fn read_encrypted_file(path: &Path) -> Result<(FileHeader, Vec<u8>)> {
-    let mut file = File::open(path)?;
+/// Pipeline for decrypting a file with `header`, holding the pepper it
+/// requires
+fn pipeline_for(
+    args: &DecryptArgs,
+    header: &FileHeader,
+    progress: &ProgressReporter,
+) -> Result<Pipeline> {
+    if args.password.pepper_file.is_some() && !header.is_peppered() {
+        progress.println("⚠️  Warning: the file was encrypted without a pepper; ignoring it");
+    }
+    let pipeline = Pipeline::new().max_memory_cost(args.password.max_memory_cost());
+    Ok(match load_pepper(args.password.pepper_file.as_deref(), header)? {
+        Some(pepper) => pipeline.pepper(pepper),
+        None => pipeline,
+    })
+}
 
-    // Read header
-    let header = read_header(&mut file)?;
+/// Read the decrypted data to the end without keeping it, walking the
+/// entries of a directory archive as extraction would
+///
+/// Returns the number of archive entries read.
+fn discard<R: Read>(mut reader: R, is_directory: bool) -> Result<Option<u64>> {
+    if !is_directory {
+        io::copy(&mut reader, &mut io::sink()).map_err(read_error)?;
+        return Ok(None);
+    }
+
+    let mut archive = Archive::new(reader);
+    let mut entries = 0;
+    for entry in archive.entries().map_err(read_error)? {
+        io::copy(&mut entry.map_err(read_error)?, &mut io::sink()).map_err(read_error)?;
+        entries += 1;
+    }
+    io::copy(&mut archive.into_inner(), &mut io::sink()).map_err(read_error)?;
+    Ok(Some(entries))
+}
+
+/// Estimate the memory needed to decrypt a file with `header`, refusing
+/// files that exceed `--memory-budget`
+///
+/// The zstd window is not recorded in the file; the estimate assumes the
+/// largest window of levels up to 19, which files written with `--long`
+/// or higher levels can exceed. Single-message files (v2 and older) are
+/// also held whole in memory on top of the estimate.
+fn check_memory_budget(args: &DecryptArgs, header: &FileHeader) -> Result<MemoryEstimate> {
+    let window_log = header.needs_decompression().then(|| zstd::window_log_for_level(19));
+    let chunk_size = header.chunk_size.unwrap_or(DEFAULT_CHUNK_SIZE);
+    let memory = MemoryEstimate::new(&header.kdf_params, chunk_size, window_log);
+    if let Some(budget) = args.memory_budget.filter(|&budget| memory.total() > budget) {
+        return Err(ResqryptError::InvalidArgument(format!(
+            "estimated memory use of {} (Argon2id {}, buffers {}) exceeds the budget of {}; \
+             the file's Argon2id memory is fixed, so raise --memory-budget to decrypt it",
+            format_bytes(memory.total()),
+            format_bytes(memory.argon2),
+            format_bytes(memory.buffers),
+            format_bytes(budget)
+        )));
+    }
+    Ok(memory)
+}
 
-    // Read remaining ciphertext
-    let mut ciphertext = Vec::new();
-    file.read_to_end(&mut ciphertext)?;
+/// Warn about ignored bytes after the encrypted data
+fn warn_trailing(progress: &ProgressReporter, trailing: u64) {
+    if trailing > 0 {
+        progress.println(format!(
+            "⚠️  Warning: ignoring {} trailing bytes after the ciphertext",
+            trailing
+        ));
+    }
+}
+
+/// Archive entries left out by `--strip-components`, by their stored path
+type Stripped = Arc<Mutex<Vec<PathBuf>>>;
+
+/// Entry callback for extraction showing each entry and a running count
+///
+/// Only updates the progress line with `-v`, so large trees show progress
+/// without printing a line per entry otherwise. The first `strip`
+/// components of each path are removed; entries with none left are
+/// recorded in `stripped`, except directories, which are recognized when
+/// a later entry lies beneath them.
+fn report_entries(
+    progress: &ProgressReporter,
+    strip: usize,
+    stripped: &Stripped,
+) -> impl FnMut(&Path) -> Option<PathBuf> + Send {
+    let progress = progress.clone();
+    let stripped = Arc::clone(stripped);
+    let mut count = 0u64;
+    move |path| {
+        let mapped = if strip > 0 {
+            let mut stripped = stripped.lock().unwrap_or_else(|e| e.into_inner());
+            stripped.retain(|dropped| !path.starts_with(dropped));
+            let mapped: PathBuf = path.components().skip(strip).collect();
+            if mapped.as_os_str().is_empty() {
+                stripped.push(path.to_path_buf());
+                return None;
+            }
+            mapped
+        } else {
+            path.to_path_buf()
+        };
+
+        if progress.verbosity() > 0 {
+            count += 1;
+            progress.set_message(format!("Extracting {} ({} entries)", mapped.display(), count));
+        }
+        Some(mapped)
+    }
+}
+
+/// Report entries left out because they had no more than N components
+fn warn_stripped(progress: &ProgressReporter, strip: usize, stripped: &Stripped) {
+    for path in stripped.lock().unwrap_or_else(|e| e.into_inner()).iter() {
+        progress.println(format!(
+            "⚠️  Warning: skipped {}: not deeper than --strip-components {}",
+            path.display(),
+            strip
+        ));
+    }
+}
+
+/// Report archive entries left out by `--ignore-extract-errors`
+fn warn_skipped(progress: &ProgressReporter, skipped: &[SkippedEntry]) {
+    for entry in skipped {
+        progress.println(format!("⚠️  Warning: skipped {}: {}", entry.path.display(), entry.error));
+    }
+    if !skipped.is_empty() {
+        progress.println(format!(
+            "⚠️  Warning: {} archive entries could not be extracted",
+            skipped.len()
+        ));
+    }
+}
+
+/// Copy the decrypted data to stdout, returning the number of bytes
+fn write_stdout<R: Read>(reader: R) -> Result<u64> {
+    let mut stdout = io::stdout().lock();
+    let written = copy_decrypted(reader, &mut stdout)?;
+    stdout.flush()?;
+    Ok(written)
+}
+
+/// Copy the decrypted data to `out`, returning the number of bytes
+///
+/// Read failures are decryption or decompression errors; write failures
+/// (such as a closed pipe or a full disk) are reported as I/O errors.
+fn copy_decrypted<R: Read, W: Write>(mut reader: R, out: &mut W) -> Result<u64> {
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut written = 0;
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(read_error(e)),
+        };
+        out.write_all(&buf[..n])?;
+        written += n as u64;
+    }
+    Ok(written)
+}
+
+/// Convert an error raised while reading the decrypted data
+fn read_error(err: io::Error) -> ResqryptError {
+    match ResqryptError::from_io(err) {
+        ResqryptError::Io(e) => {
+            ResqryptError::CompressionError(format!("Decompression failed: {}", e))
+        }
+        err => err,
+    }
+}
+
+/// Prompt for the decryption password
+const DECRYPTION_PROMPT: &str = "Enter decryption password: ";
+
+/// Get password bytes from the args, or prompt for one
+fn decryption_password(args: &DecryptArgs) -> Result<Zeroizing<Vec<u8>>> {
+    args.password.password(DECRYPTION_PROMPT)
+}
 
-    Ok((header, ciphertext))
+/// Whether a wrong password on attempt `tries` is followed by another
+/// prompt
+fn retries_password(args: &DecryptArgs, tries: u32) -> bool {
+    args.password.prompts() && tries < args.max_attempts
 }
@@ -1,165 +1,1362 @@
 //! Encrypt command implementation
 //!
-//! Handles the encryption workflow:
+//! Handles the encryption workflow through a [`Pipeline`]:
 //! 1. Read input (file or directory)
 //! 2. Archive if directory
 //! 3. Compress (if not already zstd)
-//! 4. Encrypt with AES-256-GCM
+//! 4. Encrypt with the selected cipher
 //! 5. Write output with header
 
-use std::fs;
-use std::io::Write;
-use std::path::Path;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, IsTerminal, Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use std::{slice, thread};
 
-use rpassword::prompt_password;
+use zeroize::Zeroizing;
 
-use crate::archive::tar::{create_archive, read_file};
-use crate::cli::EncryptArgs;
-use crate::compression::{compress, is_zstd_compressed};
-use crate::crypto::aes::{encrypt_data, generate_nonce};
-use crate::crypto::format::{FileHeader, write_header};
-use crate::crypto::kdf::{KdfParams, derive_key, generate_salt};
+use crate::archive::tar::{
+    ArchiveOptions, ArchiveReader, ArchiveReport, ChangedFile, SharedReport, SourceWalk,
+    check_archive_name, check_tar_archive, create_archive_from_files_into, create_archive_into,
+    file_mtime, source_date_epoch, update_archive_into,
+};
+use crate::cli::{EncryptArgs, InputFormat};
+use crate::compression::zstd;
+use crate::config::Policy;
+#[cfg(feature = "age")]
+use crate::crypto::age::{AgeRecipients, encrypt_age};
+use crate::crypto::format::check_stored_name;
+use crate::crypto::kdf::{DEFAULT_MAX_MEMORY_COST, KdfParams};
+use crate::crypto::stream::DEFAULT_CHUNK_SIZE;
+use crate::crypto::{CipherKind, HeaderFlags};
 use crate::error::{ResqryptError, Result};
-use crate::flags;
-use crate::utils::ProgressReporter;
+use crate::kdf_defaults;
+use crate::pipeline::{MemoryEstimate, NoCompression, Pipeline, ZstdCompressor};
+use crate::utils::telemetry::SpanStats;
+use crate::utils::{
+    CountingReader, FilterReader, Glob, HashAlgo, HashingWriter, ManifestEntry, ManifestWriter,
+    MultiProgressReporter, ProgressReporter, check_distinct_paths, check_output_outside_input,
+    check_output_path, create_output_dir, encrypted_output_in, format_bytes, format_throughput,
+    is_stdio, is_stream_output, load_pepper, output_extension_issue, read_file_list,
+    read_pepper_file, resolve_listed, resolve_path, weak_password_warning, write_atomic,
+    write_atomic_all,
+};
+
+/// Entry count above which archiving asks for confirmation
+const DEFAULT_MAX_FILES: u64 = 100_000;
+
+/// Total file size above which archiving asks for confirmation
+const DEFAULT_MAX_SIZE: u64 = 50 << 30;
+
+/// Input size up to which the payload is sealed as one message (format v2)
+/// rather than in chunks
+const SINGLE_SHOT_MAX: u64 = 1 << 20;
 
 /// Execute the encrypt command
-pub fn execute(args: EncryptArgs) -> Result<()> {
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(
+        skip_all,
+        fields(
+            input = %args.input.display(),
+            bytes_in = tracing::field::Empty,
+            elapsed_ms = tracing::field::Empty
+        )
+    )
+)]
+pub fn execute(mut args: EncryptArgs) -> Result<()> {
+    let stats = SpanStats::start();
+    stats.record_file_len("bytes_in", &args.input);
     let progress = ProgressReporter::new(args.verbose);
 
+    // Expand a glob input up front so an empty match fails early
+    let glob = args.input_glob()?;
+    let matches = match args.file_list() {
+        Some((list, nul)) => Some(read_listed_files(&args.input, list, nul, &args, &progress)?),
+        None => glob.as_ref().map(|glob| expand_glob(glob, &args.input)).transpose()?,
+    };
+    let mut source = args.source_path()?;
+
     // Validate input exists
-    if !args.input.exists() {
-        return Err(ResqryptError::NotFound(args.input.clone()));
+    if !source.exists() {
+        return Err(ResqryptError::NotFound(source));
+    }
+
+    let outputs = args.output_paths()?;
+    #[cfg(feature = "age")]
+    check_age_args(&args)?;
+    if args.update {
+        return update_archive(&args, &source, &outputs, &progress);
+    }
+    if args.no_archive && source.is_dir() {
+        // Outputs keep the directory's name; only the data read changes
+        match only_file(&source)?.and_then(|file| Some((stored_name(&source, &file)?, file))) {
+            Some((name, file)) => {
+                progress.debug(1, format!("Encrypting {} without an archive", file.display()));
+                args.input = file.clone();
+                args.stored_name = Some(name);
+                source = file;
+            }
+            None => progress.println(format!(
+                "⚠️  Warning: {} does not hold exactly one regular file with a storable name; \
+                 archiving it",
+                source.display()
+            )),
+        }
+    }
+    if let Some(output_dir) = &args.output_dir {
+        check_output_outside_input(&source, output_dir)?;
+    }
+    if args.input_format == InputFormat::Tar {
+        check_tar_input(&source, &progress)?;
     }
 
-    // Check if output already exists
-    if args.output.exists() {
-        return Err(ResqryptError::AlreadyExists(args.output.clone()));
+    if args.is_range() && (matches.is_some() || source.is_dir()) {
+        return Err(ResqryptError::InvalidArgument(
+            "--offset and --length only apply to a single input file".to_string(),
+        ));
     }
 
-    // Get password
-    let password = get_password(&args.password)?;
+    if let Some(name) = &args.archive_name {
+        if matches.is_none() && !source.is_dir() {
+            return Err(ResqryptError::InvalidArgument(
+                "--archive-name only applies to directory or glob inputs".to_string(),
+            ));
+        }
+        check_archive_name(name)?;
+    }
 
-    progress.set_message("Reading input...");
+    if args.each {
+        return encrypt_each(&args, &source, matches.as_deref(), &progress);
+    }
+
+    for (i, output) in outputs.iter().enumerate() {
+        if outputs[..i].contains(output) {
+            return Err(ResqryptError::InvalidArgument(format!(
+                "output {} given more than once",
+                output.display()
+            )));
+        }
+        check_output(&args, &source, output, &progress)?;
+    }
+
+    if matches.is_some() || source.is_dir() {
+        confirm_archive_size(&args, &source, matches.as_deref())?;
+    }
+
+    let password = encryption_password(&args, &progress)?;
+    let result = encrypt_source(
+        &args,
+        &source,
+        matches.as_deref(),
+        &outputs,
+        password.as_deref().map(Vec::as_slice),
+        &progress,
+    );
+
+    // Record the outcome even when encryption failed
+    match &args.manifest {
+        Some(path) => {
+            let sources = matches.unwrap_or_else(|| vec![args.input.clone()]);
+            let written = write_manifest(path, &sources, &outputs, &result);
+            result.and(written)
+        }
+        None => result,
+    }
+}
 
-    // Read input data
-    let (data, mut file_flags) = read_input(&args.input)?;
-    let input_size = data.len();
+/// Check that `output` can be written for `source`
+fn check_output(
+    args: &EncryptArgs,
+    source: &Path,
+    output: &Path,
+    progress: &ProgressReporter,
+) -> Result<()> {
+    // Refuse to overwrite the input with the output
+    check_distinct_paths(source, output)?;
 
-    progress.set_message("Processing data...");
+    // Check if output already exists (refusing symlinks)
+    check_output_path(output)?;
 
-    // Check if already zstd compressed
-    let data_to_encrypt = if is_zstd_compressed(&data) {
-        progress.set_message("Detected zstd format, skipping compression...");
-        file_flags |= flags::ALREADY_ZSTD;
-        data
+    // Guard against a forgotten or leftover extension
+    let issue = if args.is_age() {
+        age_extension_issue(output)
     } else {
-        progress.set_message("Compressing...");
-        compress(&data)?
+        output_extension_issue(output, true)
+    };
+    if let Some(issue) = issue.filter(|_| !is_stream_output(output)) {
+        if args.strict_extension {
+            return Err(ResqryptError::InvalidArgument(issue));
+        }
+        progress.println(format!("⚠️  Warning: {}", issue));
+    }
+    if args.manifest.as_deref() == Some(output) {
+        return Err(ResqryptError::InvalidArgument(
+            "manifest and output are the same file".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Ask for the encryption password, unless age recipients replace it
+fn encryption_password(
+    args: &EncryptArgs,
+    progress: &ProgressReporter,
+) -> Result<Option<Zeroizing<Vec<u8>>>> {
+    #[cfg(feature = "age")]
+    if args.is_age() && !args.recipient.is_empty() {
+        return Ok(None);
+    }
+
+    let password = args.password.new_password("Enter encryption password: ")?;
+    warn_weak_password(args, &password, progress);
+    Ok(Some(password))
+}
+
+/// Encrypt each of the glob or listed `matches` under `source` into its
+/// own output below --output-dir
+///
+/// Every output is checked and the password asked for once, up front. A
+/// file that fails is reported and the rest are still encrypted; each is
+/// added to the manifest as it finishes.
+fn encrypt_each(
+    args: &EncryptArgs,
+    source: &Path,
+    matches: Option<&[PathBuf]>,
+    progress: &ProgressReporter,
+) -> Result<()> {
+    let (Some(files), Some(output_dir)) = (matches, &args.output_dir) else {
+        return Err(ResqryptError::InvalidArgument(
+            "--each needs a glob, --files-from or --files-from0 input".to_string(),
+        ));
+    };
+
+    let mut outputs = Vec::with_capacity(files.len());
+    for file in files {
+        let relative = file.strip_prefix(source).unwrap_or(file);
+        let dir = output_dir.join(relative.parent().unwrap_or(Path::new("")));
+        let mut output = encrypted_output_in(file, &dir)?;
+        if args.is_age() {
+            output.set_extension("age");
+        }
+        check_output(args, file, &output, progress)?;
+        outputs.push(output);
+    }
+
+    let password = encryption_password(args, progress)?;
+    let password = password.as_deref().map(Vec::as_slice);
+    let failed = match &args.manifest {
+        Some(path) => {
+            let mut failed = 0;
+            write_atomic(path, |file| {
+                let mut manifest = ManifestWriter::new(BufWriter::new(file))?;
+                failed =
+                    encrypt_files(args, files, &outputs, password, |entry| manifest.entry(&entry))?;
+                manifest.finish()?;
+                Ok(())
+            })?;
+            failed
+        }
+        None => encrypt_files(args, files, &outputs, password, |_| Ok(()))?,
     };
 
-    progress.set_message("Deriving encryption key...");
+    if failed > 0 {
+        return Err(ResqryptError::BatchFailed { failed, total: files.len() });
+    }
+    Ok(())
+}
+
+/// Encrypt each of `files` into the output at the same index, passing
+/// each outcome to `record` as it finishes, and return how many failed
+///
+/// Stops early only if `record` fails or the user cancels.
+fn encrypt_files(
+    args: &EncryptArgs,
+    files: &[PathBuf],
+    outputs: &[PathBuf],
+    password: Option<&[u8]>,
+    mut record: impl FnMut(ManifestEntry<'_>) -> Result<()>,
+) -> Result<usize> {
+    let multi = MultiProgressReporter::new(args.verbose);
+    let mut failed = 0;
+    for (file, output) in files.iter().zip(outputs) {
+        let mut file_args = args.clone();
+        file_args.input = file.clone();
+        let progress = multi.task();
+        let result = create_output_dir(output.parent().unwrap_or(Path::new(""))).and_then(|()| {
+            encrypt_source(&file_args, file, None, slice::from_ref(output), password, &progress)
+        });
+
+        let size = fs::metadata(file).ok().filter(|m| m.is_file()).map(|m| m.len());
+        let error = result.as_ref().err().map(|e| e.to_string());
+        if let Some(error) = &error {
+            multi.println(format!("❌ {}: {}", file.display(), error));
+            failed += 1;
+        }
+        record(ManifestEntry { source: file, output, size, error: error.as_deref() })?;
+        if let Err(err @ (ResqryptError::Cancelled | ResqryptError::Declined(_))) = result {
+            return Err(err);
+        }
+    }
+
+    Ok(failed)
+}
+
+/// Encrypt `source` (or the glob `matches` under it) into each of
+/// `outputs`, with `password` unless age recipients are given
+fn encrypt_source(
+    args: &EncryptArgs,
+    source: &Path,
+    matches: Option<&[PathBuf]>,
+    outputs: &[PathBuf],
+    password: Option<&[u8]>,
+    progress: &ProgressReporter,
+) -> Result<()> {
+    #[cfg(feature = "age")]
+    if args.is_age() {
+        return encrypt_age_source(args, source, matches, outputs, password, progress);
+    }
+
+    let password = password.expect("resqrypt files are always encrypted with a password");
+    let started = Instant::now();
+
+    progress.set_message("Reading input...");
+
+    let OpenedSource { input, flags: mut file_flags, report, snapshot_size } =
+        open_source(args, source, matches, outputs, progress)?;
+    progress.debug(2, format!("Prepared input in {:.3}s", started.elapsed().as_secs_f64()));
 
     // Setup KDF parameters
-    let kdf_params =
-        KdfParams::new(args.argon2_memory, args.argon2_iterations, args.argon2_parallelism);
+    let mut kdf_params = KdfParams::new(
+        args.argon2_memory.unwrap_or(KdfParams::default().memory_mib()),
+        args.argon2_iterations.unwrap_or(kdf_defaults::TIME_COST),
+        args.argon2_parallelism.unwrap_or(kdf_defaults::PARALLELISM),
+    )?;
+    let raised_time_cost =
+        args.time_cost_auto.is_some_and(|min| kdf_params.ensure_min_work_factor(min));
+    kdf_params.validate()?;
+    if kdf_params.memory_cost > DEFAULT_MAX_MEMORY_COST {
+        progress.println(format!(
+            "⚠️  Warning: Argon2id memory of {} MiB is above the {} MiB that decryption accepts \
+             by default; decrypting will need --max-argon2-memory {}",
+            kdf_params.memory_mib(),
+            DEFAULT_MAX_MEMORY_COST / 1024,
+            kdf_params.memory_mib()
+        ));
+    }
+    let cpus = thread::available_parallelism().map_or(1, |n| n.get() as u32);
+    kdf_params.check_parallelism(cpus)?;
 
-    // Generate salt and derive key
-    let salt = generate_salt();
-    let key = derive_key(password.as_bytes(), &salt, &kdf_params)?;
+    let compression_level = args.compression_level.unwrap_or(zstd::DEFAULT_LEVEL);
 
-    progress.set_message("Encrypting...");
+    // Bound the memory use before any key is derived
+    let window_log = (!args.no_compress)
+        .then(|| args.long.unwrap_or_else(|| zstd::window_log_for_level(compression_level)));
+    let chunk_size = args.chunk_size.unwrap_or(DEFAULT_CHUNK_SIZE);
+    let memory = MemoryEstimate::new(&kdf_params, chunk_size, window_log);
+    if let Some(budget) = args.memory_budget.filter(|&budget| memory.total() > budget) {
+        return Err(ResqryptError::InvalidArgument(format!(
+            "estimated memory use of {} (Argon2id {}, buffers {}) exceeds the budget of {}; \
+             lower --argon2-memory, --chunk-size, --compression-level or --long",
+            format_bytes(memory.total()),
+            format_bytes(memory.argon2),
+            format_bytes(memory.buffers),
+            format_bytes(budget)
+        )));
+    }
 
-    // Generate nonce and encrypt
-    let nonce = generate_nonce();
-    let ciphertext = encrypt_data(&key, &nonce, &data_to_encrypt)?;
+    let mut pipeline = if args.no_compress {
+        Pipeline::new().compressor(NoCompression)
+    } else {
+        let mut compressor = ZstdCompressor::new(compression_level);
+        if let Some(window_log) = args.long {
+            if window_log > zstd::DEFAULT_LONG_WINDOW_LOG {
+                progress.println(format!(
+                    "⚠️  Warning: --long={} needs {} of memory to encrypt and again to decrypt",
+                    window_log,
+                    format_bytes(1 << window_log)
+                ));
+            }
+            compressor = compressor.long_distance(window_log);
+        }
+        Pipeline::new().compressor(compressor)
+    };
+    pipeline = pipeline.cipher(selected_cipher(args)).kdf_params(kdf_params).rng(args.rng.clone());
+    pipeline = pipeline.chunk_size(chunk_size);
+    if args.chunk_size.is_none() {
+        // Small inputs skip the chunk overhead unless chunks were asked for
+        pipeline = pipeline.single_shot_max(SINGLE_SHOT_MAX);
+    }
+    if args.cdc {
+        if !args.no_compress {
+            progress.println(
+                "⚠️  Warning: --cdc chunks rarely deduplicate without --no-compress, as an edit \
+                 changes the rest of the compressed stream",
+            );
+        }
+        pipeline = pipeline.content_defined();
+    }
+    if args.merkle {
+        pipeline = pipeline.merkle();
+    }
+    if let Some(padding) = args.pad_to {
+        pipeline = pipeline.padding(padding);
+    }
+    if progress.verbosity() >= 2 {
+        let progress = progress.clone();
+        pipeline = pipeline.trace(move |event| progress.debug(event.level(), event.to_string()));
+    }
+    if args.is_range() {
+        pipeline = pipeline.source_offset(args.offset.unwrap_or(0));
+    }
+    if let Some(path) = &args.password.pepper_file {
+        pipeline = pipeline.pepper(read_pepper_file(path)?);
+    }
+    if let Some(name) = &args.stored_name {
+        pipeline = pipeline.stored_name(name.clone());
+    }
 
-    progress.set_message("Writing output...");
+    // Run the plaintext through the filter command, if any
+    let input: Box<dyn Read> = match &args.filter_cmd {
+        Some(command) => {
+            file_flags |= HeaderFlags::FILTERED;
+            Box::new(FilterReader::spawn(command, input)?)
+        }
+        None => Box::new(input),
+    };
+    let mut input = CountingReader::new(input);
+
+    progress.set_message("Compressing and encrypting...");
+
+    // Compress (unless already zstd) and seal in chunks straight into the
+    // output files; the header is authenticated as AAD
+    let hash_algo = args.hash_algo.or((args.verbose > 0).then(HashAlgo::default));
+    let mut header = None;
+    let mut digest = None;
+    write_atomic_all(outputs, |tee| {
+        // Hash the bytes on their way to the temporary files
+        match hash_algo {
+            Some(algo) => {
+                let mut hashing = HashingWriter::new(tee, algo);
+                let out = BufWriter::new(&mut hashing);
+                header = Some(pipeline.encrypt_stream(&mut input, file_flags, password, out)?);
+                digest = Some(hashing.hex_digest());
+            }
+            None => {
+                let out = BufWriter::new(tee);
+                header = Some(pipeline.encrypt_stream(&mut input, file_flags, password, out)?);
+            }
+        }
+        Ok(())
+    })?;
+    let header = header.expect("header is set when the output was written");
+    let input_size = input.count();
+    let report = finished_report(args, &report, snapshot_size)?;
 
-    // Write output file
-    write_encrypted_file(&args.output, file_flags, &kdf_params, &salt, &nonce, &ciphertext)?;
+    let elapsed = started.elapsed();
+    let summary =
+        Summary { outputs, matches, report: &report, hash_algo, digest: digest.as_deref() };
+    report_encrypted(args, &summary, "", progress);
+    if raised_time_cost {
+        progress.println(format!(
+            "   Argon2id iterations raised to reach memory × iterations of {} MiB: {}",
+            args.time_cost_auto.unwrap_or_default(),
+            header.kdf_params
+        ));
+    }
 
+    if args.verbose > 0 {
+        report_sizes(outputs, input_size, elapsed, progress)?;
+        if let Some(profile) = args.profile {
+            progress.println(format!("   Profile: {}", profile));
+        }
+        if let Some(offset) = header.source_offset {
+            progress.println(format!("   Range: source bytes from offset {}", offset));
+        }
+        if let Some(command) = &args.filter_cmd {
+            progress.println(format!("   Filter: {}", command));
+        }
+        if args.auto_cipher {
+            progress.println(format!("   Cipher: {} (auto-selected for this CPU)", header.cipher));
+        } else {
+            progress.println(format!("   Cipher: {}", header.cipher));
+        }
+        progress.println(format!("   Argon2id: {}", header.kdf_params));
+        if let Some(chunk_size) = header.chunk_size {
+            progress.println(format!("   Chunk size: {}", format_bytes(chunk_size.into())));
+        }
+        if header.is_content_defined() {
+            progress.println("   Chunking: content-defined");
+        }
+        if header.is_merkle() {
+            progress.println("   Merkle tree: over the chunk tags");
+        }
+        progress.println(format!(
+            "   Memory estimate: {} (Argon2id {}, buffers {})",
+            format_bytes(memory.total()),
+            format_bytes(memory.argon2),
+            format_bytes(memory.buffers)
+        ));
+        if let Some(padding) = args.pad_to {
+            progress.println(format!("   Padding: to a {}", padding));
+        }
+        if header.is_peppered() {
+            progress.println("   Pepper: required to decrypt");
+        }
+        if header.is_already_zstd() {
+            progress.println("   Compression: skipped (input already zstd)");
+        } else if args.no_compress {
+            progress.println("   Compression: disabled (--no-compress)");
+        } else if header.is_uncompressed() {
+            progress.println("   Compression: skipped (would not reduce size)");
+        } else {
+            progress.println(format!("   Compression level: {}", compression_level));
+            if let Some(window_log) = args.long {
+                progress.println(format!(
+                    "   Long-distance matching: {} window",
+                    format_bytes(1 << window_log)
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Encrypt `source` (or the glob `matches` under it) into each of
+/// `outputs` as an age file
+///
+/// The payload is archived and zstd-compressed as for a resqrypt file, so
+/// `age -d` is followed by `zstd -d`, and by `tar x` for a directory.
+#[cfg(feature = "age")]
+fn encrypt_age_source(
+    args: &EncryptArgs,
+    source: &Path,
+    matches: Option<&[PathBuf]>,
+    outputs: &[PathBuf],
+    password: Option<&[u8]>,
+    progress: &ProgressReporter,
+) -> Result<()> {
+    let recipients = match password {
+        Some(password) => AgeRecipients::passphrase(password)?,
+        None => AgeRecipients::x25519(&args.recipient)?,
+    };
+    let started = Instant::now();
+
+    progress.set_message("Reading input...");
+    let OpenedSource { input, flags, report, snapshot_size } =
+        open_source(args, source, matches, outputs, progress)?;
+    progress.debug(2, format!("Prepared input in {:.3}s", started.elapsed().as_secs_f64()));
+
+    let input: Box<dyn Read> = match &args.filter_cmd {
+        Some(command) => Box::new(FilterReader::spawn(command, input)?),
+        None => input,
+    };
+    let mut input = BufReader::new(CountingReader::new(input));
+
+    // age has no flag for an already compressed payload, so zstd input is
+    // stored as it is and decompresses the same way
+    let already_zstd = crate::compression::is_zstd_compressed(io::BufRead::fill_buf(&mut input)?);
+    let compression_level = args.compression_level.unwrap_or(zstd::DEFAULT_LEVEL);
+    let mut payload: Box<dyn Read + '_> = match args.long {
+        _ if args.no_compress || already_zstd => Box::new(&mut input),
+        Some(window_log) => {
+            if window_log > zstd::DEFAULT_LONG_WINDOW_LOG {
+                progress.println(format!(
+                    "⚠️  Warning: --long={} needs {} of memory to encrypt, and `zstd -d \
+                     --long={}` to decompress",
+                    window_log,
+                    format_bytes(1 << window_log),
+                    window_log
+                ));
+            }
+            Box::new(zstd::compress_reader_long(&mut input, compression_level, window_log)?)
+        }
+        None => Box::new(zstd::compress_reader(&mut input, compression_level)?),
+    };
+
+    progress.set_message("Compressing and encrypting...");
+
+    let hash_algo = args.hash_algo.or((args.verbose > 0).then(HashAlgo::default));
+    let mut digest = None;
+    write_atomic_all(outputs, |tee| {
+        match hash_algo {
+            Some(algo) => {
+                let mut hashing = HashingWriter::new(tee, algo);
+                encrypt_age(&mut payload, &recipients, BufWriter::new(&mut hashing))?;
+                digest = Some(hashing.hex_digest());
+            }
+            None => {
+                encrypt_age(&mut payload, &recipients, BufWriter::new(tee))?;
+            }
+        }
+        Ok(())
+    })?;
+    drop(payload);
+    let input_size = input.get_ref().count();
+    let report = finished_report(args, &report, snapshot_size)?;
+
+    let elapsed = started.elapsed();
+    let summary =
+        Summary { outputs, matches, report: &report, hash_algo, digest: digest.as_deref() };
+    report_encrypted(args, &summary, " (age)", progress);
+
+    if args.verbose > 0 {
+        report_sizes(outputs, input_size, elapsed, progress)?;
+        match &recipients {
+            AgeRecipients::Passphrase(_) => progress.println("   Format: age, to a passphrase"),
+            AgeRecipients::X25519(keys) => {
+                progress.println(format!("   Format: age, to {} X25519 recipient(s)", keys.len()))
+            }
+        }
+        if let Some(command) = &args.filter_cmd {
+            progress.println(format!("   Filter: {}", command));
+        }
+        let mut decrypt = format!("age -d {}", outputs[0].display());
+        if already_zstd {
+            progress.println("   Compression: skipped (input already zstd)");
+        } else if args.no_compress {
+            progress.println("   Compression: disabled (--no-compress)");
+        } else {
+            progress.println(format!("   Compression level: {}", compression_level));
+            decrypt += &match args.long {
+                Some(window_log) => format!(" | zstd -d --long={}", window_log),
+                None => " | zstd -d".to_string(),
+            };
+        }
+        if flags.contains(HeaderFlags::IS_DIRECTORY) {
+            decrypt += " | tar x";
+        }
+        progress.println(format!("   Decrypt with: {}", decrypt));
+    }
+
+    Ok(())
+}
+
+/// What an encryption wrote, for [`report_encrypted`]
+struct Summary<'a> {
+    outputs: &'a [PathBuf],
+    /// Files bundled from a glob or list
+    matches: Option<&'a [PathBuf]>,
+    report: &'a ArchiveReport,
+    hash_algo: Option<HashAlgo>,
+    /// Digest of the output under `hash_algo`
+    digest: Option<&'a str>,
+}
+
+/// Report a finished encryption and any warnings about its input, with
+/// `container` after the output names (e.g. " (age)")
+fn report_encrypted(
+    args: &EncryptArgs,
+    summary: &Summary<'_>,
+    container: &str,
+    progress: &ProgressReporter,
+) {
     progress.finish("Done!");
+    let names: Vec<String> = summary.outputs.iter().map(|o| o.display().to_string()).collect();
     progress.println(format!(
-        "✅ Encrypted: {} -> {}",
+        "✅ Encrypted: {} -> {}{}",
         args.input.display(),
-        args.output.display()
+        names.join(", "),
+        container
     ));
+    if let Some(files) = summary.matches {
+        let what =
+            if args.file_list().is_some() { "listed files" } else { "files matching the glob" };
+        progress.println(format!("   Bundled {} {}", files.len(), what));
+    }
+    warn_changed(progress, &summary.report.changed);
+    warn_clamped_mtimes(progress, summary.report);
+    report_other_filesystems(progress, summary.report);
+    if let (Some(algo), Some(digest)) = (summary.hash_algo, summary.digest) {
+        progress.println(format!("   {}: {}", algo, digest));
+    }
+}
 
-    if args.verbose {
-        let output_size = ciphertext.len() + FileHeader::SIZE;
+/// Report the input and output sizes and the throughput, in verbose runs
+fn report_sizes(
+    outputs: &[PathBuf],
+    input_size: u64,
+    elapsed: Duration,
+    progress: &ProgressReporter,
+) -> Result<()> {
+    // A FIFO or device keeps no size to report
+    if is_stream_output(&outputs[0]) {
+        progress.println(format!("   Input: {} bytes", input_size));
+    } else {
+        let output_size = fs::metadata(&outputs[0])?.len();
         let ratio = (output_size as f64 / input_size as f64) * 100.0;
         progress.println(format!(
             "   Input: {} bytes, Output: {} bytes ({:.1}%)",
             input_size, output_size, ratio
         ));
     }
-
+    progress.println(format!("   {}", format_throughput(input_size, elapsed)));
     Ok(())
 }
 
-/// Get password from args or prompt
-fn get_password(password_arg: &Option<String>) -> Result<String> {
-    match password_arg {
-        Some(p) => Ok(p.clone()),
-        None => {
-            let password = prompt_password("Enter encryption password: ").map_err(|e| {
-                ResqryptError::PasswordError(format!("Failed to read password: {}", e))
+/// Refuse options that do not apply to the chosen output format
+///
+/// The cipher and Argon2id options are left alone, as they may come from
+/// a config written for resqrypt files.
+#[cfg(feature = "age")]
+fn check_age_args(args: &EncryptArgs) -> Result<()> {
+    if !args.is_age() {
+        if !args.recipient.is_empty() {
+            return Err(ResqryptError::InvalidArgument(
+                "--recipient only applies with --format age".to_string(),
+            ));
+        }
+        return Ok(());
+    }
+
+    let resqrypt_only = [
+        ("--update", args.update),
+        ("--chunk-size", args.chunk_size.is_some()),
+        ("--cdc", args.cdc),
+        ("--merkle", args.merkle),
+        ("--pad-to", args.pad_to.is_some()),
+        ("--pepper-file", args.password.pepper_file.is_some()),
+        ("--auto-cipher", args.auto_cipher),
+        ("--time-cost-auto", args.time_cost_auto.is_some()),
+    ];
+    match resqrypt_only.iter().find(|(_, given)| *given) {
+        Some((option, _)) => Err(ResqryptError::InvalidArgument(format!(
+            "{} cannot be used with --format age",
+            option
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Input opened for encryption by [`open_source`]
+struct OpenedSource {
+    /// The data to encrypt, reporting progress as it is read
+    input: Box<dyn Read + Send>,
+    /// Header flags describing the data
+    flags: HeaderFlags,
+    /// Report filled in as a directory is archived
+    report: SharedReport,
+    /// Size of the file with --snapshot, to detect later growth
+    snapshot_size: Option<u64>,
+}
+
+/// Open `source` (or the glob `matches` under it) for encryption
+fn open_source(
+    args: &EncryptArgs,
+    source: &Path,
+    matches: Option<&[PathBuf]>,
+    outputs: &[PathBuf],
+    progress: &ProgressReporter,
+) -> Result<OpenedSource> {
+    let archive_options = ArchiveOptions {
+        xattrs: args.xattrs,
+        mtime: archive_mtime(args)?,
+        root_name: args.archive_name.clone(),
+        exclude: excluded_outputs(source, outputs, progress)?,
+        snapshot: args.snapshot,
+        one_file_system: args.one_file_system,
+        ..ArchiveOptions::default()
+    };
+    let mut snapshot_size = None;
+    let (input, flags, report) = match matches {
+        Some(files) => {
+            let (base, files) = (source.to_path_buf(), files.to_vec());
+            let archive = ArchiveReader::spawn(move |out| {
+                create_archive_from_files_into(&base, &files, &archive_options, out)
+            });
+            let report = archive.report();
+            (Box::new(archive) as Box<dyn Read + Send>, HeaderFlags::IS_DIRECTORY, report)
+        }
+        // Checked to be a tar archive, stored as if it had been built here
+        None if args.input_format == InputFormat::Tar => (
+            Box::new(File::open(&args.input)?) as Box<dyn Read + Send>,
+            HeaderFlags::IS_DIRECTORY,
+            SharedReport::default(),
+        ),
+        None if args.is_range() => (
+            open_range(&args.input, args.offset.unwrap_or(0), args.length)?,
+            HeaderFlags::empty(),
+            SharedReport::default(),
+        ),
+        None if args.snapshot && fs::metadata(&args.input)?.is_file() => {
+            // Stop at the size the file had when opened
+            let file = File::open(&args.input)?;
+            let size = file.metadata()?.len();
+            snapshot_size = Some(size);
+            let input = Box::new(file.take(size)) as Box<dyn Read + Send>;
+            (input, HeaderFlags::empty(), SharedReport::default())
+        }
+        None => open_input(&args.input, &archive_options)?,
+    };
+
+    // The bar follows the bytes the compressor consumes, so slow high
+    // levels show progress too; only a file's total is known up front
+    if !flags.contains(HeaderFlags::IS_DIRECTORY) {
+        let size = snapshot_size.unwrap_or(fs::metadata(&args.input)?.len());
+        progress.set_length(args.length.unwrap_or(size.saturating_sub(args.offset.unwrap_or(0))));
+    }
+    let input = Box::new(progress.wrap_read(input));
+    Ok(OpenedSource { input, flags, report, snapshot_size })
+}
+
+/// Update the encrypted directory archive in `outputs` from `source`
+///
+/// The old archive is decrypted as it is merged with the directory, and
+/// the result encrypted under a fresh nonce with the old file's cipher,
+/// Argon2id parameters and pepper, which must satisfy any enforced
+/// policy. The salt is fresh too, except for content-defined files, which
+/// keep theirs so that unchanged chunks encrypt to the same bytes under
+/// the same key. The file is only replaced once the update is complete.
+fn update_archive(
+    args: &EncryptArgs,
+    source: &Path,
+    outputs: &[PathBuf],
+    progress: &ProgressReporter,
+) -> Result<()> {
+    let [output] = outputs else {
+        return Err(ResqryptError::InvalidArgument(
+            "--update takes a single --output to update".to_string(),
+        ));
+    };
+    if !source.is_dir() {
+        return Err(ResqryptError::InvalidArgument(
+            "--update only applies to directory inputs".to_string(),
+        ));
+    }
+    if is_stdio(output) || !output.is_file() {
+        return Err(ResqryptError::NotFound(output.clone()));
+    }
+    check_distinct_paths(source, output)?;
+
+    // Guard against merging files into anything but a plain tar archive
+    let max_memory_cost = args.password.max_memory_cost();
+    let header = Pipeline::new()
+        .max_memory_cost(max_memory_cost)
+        .read_header(&mut BufReader::new(File::open(output)?))?;
+    if !header.is_directory() || header.flags.contains(HeaderFlags::FILTERED) {
+        return Err(ResqryptError::InvalidArgument(format!(
+            "{} does not hold an encrypted directory; --update only applies to directory archives",
+            output.display()
+        )));
+    }
+    // The kept parameters are not the ones `apply_policy` checked
+    for policy in Policy::load_all(args.kdf_params_from.as_deref())? {
+        policy
+            .check(header.kdf_params.memory_mib(), header.kdf_params.time_cost, header.cipher)
+            .map_err(|e| {
+                ResqryptError::InvalidArgument(format!(
+                    "{} cannot be updated in place: {}; encrypt the directory anew without \
+                     --update",
+                    output.display(),
+                    e
+                ))
             })?;
+    }
+    let pepper = load_pepper(args.password.pepper_file.as_deref(), &header)?;
+    confirm_archive_size(args, source, None)?;
+
+    let password = args.password.new_password("Enter encryption password: ")?;
+
+    let archive_options = ArchiveOptions {
+        xattrs: args.xattrs,
+        mtime: archive_mtime(args)?,
+        exclude: excluded_outputs(source, outputs, progress)?,
+        snapshot: args.snapshot,
+        one_file_system: args.one_file_system,
+        ..ArchiveOptions::default()
+    };
+    let (old_path, source_dir) = (output.clone(), source.to_path_buf());
+    let (old_password, old_pepper) = (password.clone(), pepper.clone());
+    let archive = ArchiveReader::spawn(move |out| {
+        let mut decrypting = Pipeline::new().max_memory_cost(max_memory_cost);
+        if let Some(pepper) = old_pepper {
+            decrypting = decrypting.pepper(pepper);
+        }
+        let old = BufReader::new(File::open(&old_path)?);
+        let (_, old) = decrypting.decrypt_stream(old, &old_password)?;
+        update_archive_into(old, &source_dir, &archive_options, out)
+    });
+    let report = archive.report();
+
+    // A content-defined archive keeps its key and stays uncompressed, so
+    // unchanged chunks come out byte for byte the same
+    let content_defined = header.is_content_defined() || args.cdc;
+    let no_compress = args.no_compress || (content_defined && header.is_uncompressed());
+    let compression_level = args.compression_level.unwrap_or(zstd::DEFAULT_LEVEL);
+    let mut pipeline = if no_compress {
+        Pipeline::new().compressor(NoCompression)
+    } else {
+        Pipeline::new().compressor(ZstdCompressor::new(compression_level))
+    };
+    if header.is_content_defined() {
+        pipeline = pipeline.content_defined().reuse_salt(header.salt);
+    } else if args.cdc {
+        pipeline = pipeline.content_defined();
+    }
+    if header.is_merkle() || args.merkle {
+        pipeline = pipeline.merkle();
+    }
+    pipeline = pipeline
+        .cipher(header.cipher)
+        .kdf_params(header.kdf_params.clone())
+        .rng(args.rng.clone())
+        .chunk_size(args.chunk_size.unwrap_or(DEFAULT_CHUNK_SIZE));
+    if let Some(pepper) = pepper {
+        pipeline = pipeline.pepper(pepper);
+    }
+    if progress.verbosity() >= 2 {
+        let progress = progress.clone();
+        pipeline = pipeline.trace(move |event| progress.debug(event.level(), event.to_string()));
+    }
+
+    progress.set_message("Updating and encrypting...");
+    let input = progress.wrap_read(archive);
+    write_atomic(output, |file| {
+        pipeline.encrypt_stream(
+            input,
+            HeaderFlags::IS_DIRECTORY,
+            &password,
+            BufWriter::new(file),
+        )?;
+        Ok(())
+    })?;
+    let report = mem::take(&mut *report.lock().unwrap_or_else(|e| e.into_inner()));
+
+    progress.finish("Done!");
+    progress.println(format!("✅ Updated: {} -> {}", source.display(), output.display()));
+    progress.println(format!(
+        "   Added {} entries, updated {} files",
+        report.added.len(),
+        report.updated.len()
+    ));
+    for path in &report.added {
+        progress.println(format!("   + {}", path.display()));
+    }
+    for path in &report.updated {
+        progress.println(format!("   ~ {}", path.display()));
+    }
+    warn_changed(progress, &report.changed);
+    warn_clamped_mtimes(progress, &report);
+    report_other_filesystems(progress, &report);
+
+    if args.verbose > 0 {
+        progress.println(format!("   Cipher: {}", header.cipher));
+        progress.println(format!("   Argon2id: {}", header.kdf_params));
+        if no_compress {
+            progress.println("   Compression: disabled");
+        } else {
+            progress.println(format!("   Compression level: {}", compression_level));
+        }
+        if content_defined {
+            progress.println("   Chunking: content-defined");
+        }
+    }
+
+    Ok(())
+}
 
-            if password.is_empty() {
-                return Err(ResqryptError::PasswordError("Password cannot be empty".to_string()));
+/// Canonical output paths to leave out when archiving `source`
+///
+/// An output inside the input directory must never end up in its own
+/// archive; warn if one is already there.
+fn excluded_outputs(
+    source: &Path,
+    outputs: &[PathBuf],
+    progress: &ProgressReporter,
+) -> Result<Vec<PathBuf>> {
+    if !source.is_dir() {
+        return Ok(Vec::new());
+    }
+
+    let source = fs::canonicalize(source)?;
+    let mut excluded = Vec::new();
+    for output in outputs {
+        let resolved = resolve_path(output)?;
+        if resolved.starts_with(&source) {
+            if resolved.exists() {
+                progress.println(format!(
+                    "⚠️  Warning: not archiving {}, the output inside the input directory",
+                    output.display()
+                ));
             }
+            excluded.push(resolved);
+        }
+    }
 
-            // Confirm password
-            let confirm = prompt_password("Confirm password: ").map_err(|e| {
-                ResqryptError::PasswordError(format!("Failed to read password: {}", e))
-            })?;
+    Ok(excluded)
+}
+
+/// Write the manifest for `sources` encrypted into `outputs`, with one
+/// entry per copy
+///
+/// Bundled sources all share the outcome of their one archive; --each
+/// records each file separately as it finishes (see [`encrypt_each`]).
+fn write_manifest(
+    path: &Path,
+    sources: &[PathBuf],
+    outputs: &[PathBuf],
+    result: &Result<()>,
+) -> Result<()> {
+    let error = result.as_ref().err().map(|e| e.to_string());
 
-            if password != confirm {
-                return Err(ResqryptError::PasswordError("Passwords do not match".to_string()));
+    write_atomic(path, |file| {
+        let mut manifest = ManifestWriter::new(BufWriter::new(file))?;
+        for source in sources {
+            let size = fs::metadata(source).ok().filter(|m| m.is_file()).map(|m| m.len());
+            for output in outputs {
+                manifest.entry(&ManifestEntry { source, output, size, error: error.as_deref() })?;
             }
+        }
+        manifest.finish()?;
+        Ok(())
+    })
+}
 
-            Ok(password)
+/// Warn about a weak password given non-interactively, if asked to
+fn warn_weak_password(args: &EncryptArgs, password: &[u8], progress: &ProgressReporter) {
+    // A raw key is not a password, and a prompted one was typed knowingly
+    let non_interactive = args.password.key_base64.is_none() && !args.password.prompts();
+    if args.warn_weak_password && non_interactive {
+        if let Some(warning) = weak_password_warning(&String::from_utf8_lossy(password)) {
+            progress.eprintln(format!("⚠️  Warning: {}", warning));
         }
     }
 }
 
-/// Read input file or directory
-fn read_input(path: &Path) -> Result<(Vec<u8>, u8)> {
-    if path.is_dir() {
-        // Create tar archive from directory
-        let archive_data = create_archive(path)?;
-        Ok((archive_data, flags::IS_DIRECTORY))
+/// Cipher from `--auto-cipher`, `--cipher` or the config, in that order
+fn selected_cipher(args: &EncryptArgs) -> CipherKind {
+    if args.auto_cipher { CipherKind::fastest() } else { args.cipher.unwrap_or_default() }
+}
+
+/// Pinned archive timestamp from `--mtime`, `--mtime-from` or
+/// `SOURCE_DATE_EPOCH`, in that order
+fn archive_mtime(args: &EncryptArgs) -> Result<Option<u64>> {
+    match (args.mtime, &args.mtime_from) {
+        (Some(mtime), _) => Ok(Some(mtime)),
+        (None, Some(path)) => file_mtime(path).map(Some),
+        (None, None) => source_date_epoch(),
+    }
+}
+
+/// Files matching an input glob, failing when there are none
+fn expand_glob(glob: &Glob, pattern: &Path) -> Result<Vec<PathBuf>> {
+    let files = glob.expand()?;
+    if files.is_empty() {
+        return Err(ResqryptError::InvalidArgument(format!(
+            "no files match {}",
+            pattern.display()
+        )));
+    }
+    Ok(files)
+}
+
+/// Read the `--files-from` list and resolve its paths under `base`
+///
+/// Missing paths fail the command, or are skipped with a warning under
+/// `--skip-errors`.
+fn read_listed_files(
+    base: &Path,
+    list: &Path,
+    nul: bool,
+    args: &EncryptArgs,
+    progress: &ProgressReporter,
+) -> Result<Vec<PathBuf>> {
+    if !base.is_dir() {
+        return Err(ResqryptError::InvalidArgument(
+            "--files-from needs a directory input to resolve the listed paths".to_string(),
+        ));
+    }
+    let listed = if is_stdio(list) {
+        read_file_list(io::stdin().lock(), nul)?
     } else {
-        // Read file
-        let file_data = read_file(path)?;
-        Ok((file_data, 0))
+        read_file_list(File::open(list)?, nul)?
+    };
+
+    let mut files = Vec::new();
+    for path in listed {
+        let resolved = resolve_listed(base, &path)?;
+        if fs::symlink_metadata(&resolved).is_err() {
+            if !args.skip_errors {
+                return Err(ResqryptError::NotFound(resolved));
+            }
+            progress.println(format!("⚠️  Warning: skipping missing {}", resolved.display()));
+            continue;
+        }
+        files.push(resolved);
     }
+    if files.is_empty() {
+        return Err(ResqryptError::InvalidArgument(format!(
+            "no files listed in {}",
+            list.display()
+        )));
+    }
+    Ok(files)
 }
 
-/// Write the encrypted output file
-fn write_encrypted_file(
-    path: &Path,
-    flags: u8,
-    kdf_params: &KdfParams,
-    salt: &[u8; 32],
-    nonce: &[u8; 12],
-    ciphertext: &[u8],
+/// Ask before archiving more entries or file data than the limits allow
+///
+/// The walk stops as soon as a limit is passed, so even huge trees are
+/// checked quickly. Without a terminal to ask on, `--yes` is required; a
+/// refusal at the prompt fails with [`ResqryptError::Declined`].
+fn confirm_archive_size(
+    args: &EncryptArgs,
+    source: &Path,
+    matches: Option<&[PathBuf]>,
 ) -> Result<()> {
-    // Create parent directories if needed
-    if let Some(parent) = path.parent() {
-        fs::create_dir_all(parent)?;
+    if args.yes {
+        return Ok(());
+    }
+    let max_files = args.max_files.unwrap_or(DEFAULT_MAX_FILES);
+    let max_size = args.max_size.unwrap_or(DEFAULT_MAX_SIZE);
+
+    let (mut entries, mut size) = (0u64, 0u64);
+    let mut over = |metadata: fs::Metadata| {
+        entries += 1;
+        if metadata.is_file() {
+            size += metadata.len();
+        }
+        entries > max_files || size > max_size
+    };
+    // Unreadable entries are left for the archiver to report
+    let walk_options =
+        ArchiveOptions { one_file_system: args.one_file_system, ..ArchiveOptions::default() };
+    let exceeded = match matches {
+        Some(files) => files
+            .iter()
+            .filter_map(|file| fs::symlink_metadata(source.join(file)).ok())
+            .any(&mut over),
+        None => SourceWalk::new(source, &walk_options)?
+            .filter_map(|entry| entry.ok()?.0.metadata().ok())
+            .any(&mut over),
+    };
+    if !exceeded {
+        return Ok(());
+    }
+
+    let what = if entries > max_files {
+        format!("more than {} entries", max_files)
+    } else {
+        format!("more than {} of files", format_bytes(max_size))
+    };
+    if !io::stdin().is_terminal() || !io::stderr().is_terminal() {
+        return Err(ResqryptError::InvalidArgument(format!(
+            "{} holds {}; pass --yes to archive it anyway or raise --max-files or --max-size",
+            source.display(),
+            what
+        )));
     }
 
-    let mut file = fs::File::create(path)?;
+    eprint!("{} holds {}. Archive it anyway? [y/N] ", source.display(), what);
+    io::stderr().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    match answer.trim().to_ascii_lowercase().as_str() {
+        "y" | "yes" => Ok(()),
+        _ => Err(ResqryptError::Declined("archiving".to_string())),
+    }
+}
+
+/// The directory's only entry, if that is a regular file
+fn only_file(dir: &Path) -> Result<Option<PathBuf>> {
+    let mut entries = fs::read_dir(dir)?;
+    let (Some(entry), None) = (entries.next().transpose()?, entries.next()) else {
+        return Ok(None);
+    };
+    Ok(entry.file_type()?.is_file().then(|| entry.path()))
+}
+
+/// Path of `file` below `dir` as the header stores it, named after the
+/// directory as an archive root would be (`None` if it cannot be stored)
+fn stored_name(dir: &Path, file: &Path) -> Option<String> {
+    let root = dir.file_name().map_or(Some("archive"), |name| name.to_str())?;
+    let name = format!("{}/{}", root, file.file_name()?.to_str()?);
+    check_stored_name(&name).is_ok().then_some(name)
+}
+
+/// Open input file or directory as a reader
+///
+/// Directories are archived on a background thread as they are read, and
+/// files are read as they are encrypted, so neither is held in memory.
+fn open_input(
+    path: &Path,
+    archive_options: &ArchiveOptions,
+) -> Result<(Box<dyn Read + Send>, HeaderFlags, SharedReport)> {
+    // FIFOs, sockets and device nodes would block or fail confusingly
+    let file_type = fs::metadata(path)?.file_type();
+    if !file_type.is_dir() && !file_type.is_file() {
+        return Err(ResqryptError::InvalidArgument(format!(
+            "cannot encrypt special file: {}",
+            path.display()
+        )));
+    }
 
-    // Write header
-    let header = FileHeader::new(flags, kdf_params.clone(), *salt, *nonce);
-    write_header(&mut file, &header)?;
+    if file_type.is_dir() {
+        let (path, options) = (path.to_path_buf(), archive_options.clone());
+        let archive = ArchiveReader::spawn(move |out| create_archive_into(&path, &options, out));
+        let report = archive.report();
+        Ok((Box::new(archive), HeaderFlags::IS_DIRECTORY, report))
+    } else {
+        Ok((Box::new(File::open(path)?), HeaderFlags::empty(), SharedReport::default()))
+    }
+}
 
-    // Write ciphertext
-    file.write_all(ciphertext)?;
+/// Check that an --input-format tar input is a well-formed tar archive
+fn check_tar_input(source: &Path, progress: &ProgressReporter) -> Result<()> {
+    if !fs::metadata(source)?.is_file() {
+        return Err(ResqryptError::InvalidArgument(format!(
+            "--input-format tar needs a tar file as input, not {}",
+            source.display()
+        )));
+    }
 
+    let entries = check_tar_archive(BufReader::new(File::open(source)?)).map_err(|e| {
+        ResqryptError::InvalidArgument(format!(
+            "{} is not a well-formed tar archive: {}",
+            source.display(),
+            e
+        ))
+    })?;
+    progress.debug(1, format!("Input is a tar archive of {} entries", entries));
     Ok(())
 }
+
+/// Take the archive report once the input is read, adding a --snapshot
+/// file that changed size
+fn finished_report(
+    args: &EncryptArgs,
+    report: &SharedReport,
+    snapshot_size: Option<u64>,
+) -> Result<ArchiveReport> {
+    let mut report = mem::take(&mut *report.lock().unwrap_or_else(|e| e.into_inner()));
+    if let Some(recorded) = snapshot_size {
+        let actual = fs::metadata(&args.input)?.len();
+        if actual != recorded {
+            report.changed.push(ChangedFile { path: args.input.clone(), recorded, actual });
+        }
+    }
+    Ok(report)
+}
+
+/// Describe a problem with the extension of an age output, if there is one
+fn age_extension_issue(output: &Path) -> Option<String> {
+    (output.extension().is_none_or(|ext| ext != "age")).then(|| {
+        format!("output {} does not end in .age, which age files usually do", output.display())
+    })
+}
+
+/// Warn about files that changed size while they were read with --snapshot
+fn warn_changed(progress: &ProgressReporter, changed: &[ChangedFile]) {
+    for file in changed {
+        progress.println(format!(
+            "⚠️  Warning: {} changed size while being read ({} -> {} bytes); stored the first {} bytes",
+            file.path.display(),
+            file.recorded,
+            file.actual,
+            file.recorded
+        ));
+    }
+    if !changed.is_empty() {
+        progress.println(
+            "⚠️  Warning: files changed during encryption; use a filesystem snapshot for a \
+             consistent copy",
+        );
+    }
+}
+
+/// Warn about entries stored with a clamped modification time
+fn warn_clamped_mtimes(progress: &ProgressReporter, report: &ArchiveReport) {
+    for path in &report.clamped_mtimes {
+        progress.println(format!(
+            "⚠️  Warning: {} has a modification time outside 1970-2242; stored it clamped",
+            path.display()
+        ));
+    }
+}
+
+/// List the mount points whose contents --one-file-system left out
+fn report_other_filesystems(progress: &ProgressReporter, report: &ArchiveReport) {
+    for path in &report.other_filesystems {
+        progress.println(format!(
+            "   Skipped the filesystem mounted at {} (stored the empty directory)",
+            path.display()
+        ));
+    }
+}
+
+/// Open the byte range of a regular file starting at `offset`
+///
+/// The range runs for `length` bytes, or to the end of the file.
+fn open_range(path: &Path, offset: u64, length: Option<u64>) -> Result<Box<dyn Read + Send>> {
+    let mut file = File::open(path)?;
+    let metadata = file.metadata()?;
+    if !metadata.is_file() {
+        return Err(ResqryptError::InvalidArgument(format!(
+            "byte ranges can only be read from regular files: {}",
+            path.display()
+        )));
+    }
+
+    let size = metadata.len();
+    let length = length.unwrap_or(size.saturating_sub(offset));
+    if offset.checked_add(length).is_none_or(|end| end > size) {
+        return Err(ResqryptError::InvalidArgument(format!(
+            "byte range of {} bytes at offset {} exceeds the input size of {} bytes",
+            length, offset, size
+        )));
+    }
+
+    file.seek(SeekFrom::Start(offset))?;
+    Ok(Box::new(file.take(length)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_read_input_rejects_fifo() {
+        let temp_dir = TempDir::new().unwrap();
+        let fifo = temp_dir.path().join("pipe");
+        let status = std::process::Command::new("mkfifo").arg(&fifo).status().unwrap();
+        assert!(status.success());
+
+        let result = open_input(&fifo, &ArchiveOptions::default());
+        assert!(matches!(result, Err(ResqryptError::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn test_open_range() {
+        let temp_dir = TempDir::new().unwrap();
+        let file = temp_dir.path().join("blocks.bin");
+        fs::write(&file, "0123456789").unwrap();
+
+        let read = |offset, length| -> Result<String> {
+            let mut data = String::new();
+            open_range(&file, offset, length)?.read_to_string(&mut data)?;
+            Ok(data)
+        };
+        assert_eq!(read(3, Some(4)).unwrap(), "3456");
+        assert_eq!(read(7, None).unwrap(), "789");
+        assert_eq!(read(10, None).unwrap(), "");
+        assert!(matches!(read(8, Some(3)), Err(ResqryptError::InvalidArgument(_))));
+        assert!(matches!(read(11, None), Err(ResqryptError::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn test_read_input_regular_file() {
+        let temp_dir = TempDir::new().unwrap();
+        let file = temp_dir.path().join("plain.txt");
+        fs::write(&file, "regular").unwrap();
+
+        let (mut input, file_flags, _) = open_input(&file, &ArchiveOptions::default()).unwrap();
+        let mut data = Vec::new();
+        input.read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"regular");
+        assert_eq!(file_flags, HeaderFlags::empty());
+    }
+}
@@ -0,0 +1,188 @@
+//! Migrate command implementation
+//!
+//! Rewrites an encrypted file in the current format:
+//! 1. Read the header and derive the key from the password
+//! 2. Decrypt and decompress the payload as it is read
+//! 3. Recompress at the requested level and encrypt under a fresh salt and
+//!    nonce, keeping the cipher, Argon2id parameters, file flags and pepper
+//!
+//! This is a full re-encryption of the data, unlike a password change that
+//! would only re-wrap a key, so it takes about as long as decrypting and
+//! encrypting the file.
+
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter};
+
+use crate::cli::MigrateArgs;
+use crate::compression::zstd;
+use crate::crypto::HeaderFlags;
+use crate::error::{ResqryptError, Result};
+use crate::pipeline::{Pipeline, ZstdCompressor};
+use crate::utils::telemetry::SpanStats;
+use crate::utils::{
+    ProgressReporter, check_distinct_paths, check_output_path, is_stream_output, load_pepper,
+    write_atomic,
+};
+
+/// File flags describing the data itself, carried over to the new file
+const CARRIED_FLAGS: HeaderFlags = HeaderFlags::IS_DIRECTORY.union(HeaderFlags::FILTERED);
+
+/// Execute the migrate command
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(
+        skip_all,
+        fields(
+            input = %args.input.display(),
+            bytes_in = tracing::field::Empty,
+            elapsed_ms = tracing::field::Empty
+        )
+    )
+)]
+pub fn execute(args: MigrateArgs) -> Result<()> {
+    let stats = SpanStats::start();
+    stats.record_file_len("bytes_in", &args.input);
+    let progress = ProgressReporter::new(args.verbose);
+
+    // Validate input exists
+    if !args.input.exists() {
+        return Err(ResqryptError::NotFound(args.input.clone()));
+    }
+
+    // Refuse to overwrite the input with the output
+    check_distinct_paths(&args.input, &args.output)?;
+
+    // Check if output already exists (refusing symlinks)
+    check_output_path(&args.output)?;
+
+    let password = args.password.password("Enter password: ")?;
+
+    progress.set_message("Decrypting...");
+    let input_size = fs::metadata(&args.input)?.len();
+    let max_memory_cost = args.password.max_memory_cost();
+    let header = Pipeline::new()
+        .max_memory_cost(max_memory_cost)
+        .read_header(&mut BufReader::new(File::open(&args.input)?))?;
+    let pepper = load_pepper(args.password.pepper_file.as_deref(), &header)?;
+    let mut decrypting = Pipeline::new().max_memory_cost(max_memory_cost);
+    if let Some(pepper) = &pepper {
+        decrypting = decrypting.pepper(pepper.clone());
+    }
+    let (old, original) =
+        decrypting.decrypt_stream(BufReader::new(File::open(&args.input)?), &password)?;
+
+    let compression_level = args.compression_level.unwrap_or(zstd::DEFAULT_LEVEL);
+    let mut pipeline = Pipeline::new()
+        .compressor(ZstdCompressor::new(compression_level))
+        .cipher(old.cipher)
+        .kdf_params(old.kdf_params.clone())
+        .rng(args.rng.clone());
+    if let Some(offset) = old.source_offset {
+        pipeline = pipeline.source_offset(offset);
+    }
+    if let Some(name) = &old.stored_name {
+        pipeline = pipeline.stored_name(name.clone());
+    }
+    if old.is_merkle() {
+        pipeline = pipeline.merkle();
+    }
+    if let Some(pepper) = pepper {
+        pipeline = pipeline.pepper(pepper);
+    }
+    if progress.verbosity() >= 2 {
+        let progress = progress.clone();
+        pipeline = pipeline.trace(move |event| progress.debug(event.level(), event.to_string()));
+    }
+
+    if old.is_padded() {
+        progress.println(
+            "⚠️  Warning: the input was padded to hide its size; the migrated file is not",
+        );
+    }
+
+    progress.set_message("Recompressing and encrypting...");
+    let mut header = None;
+    write_atomic(&args.output, |file| {
+        let out = BufWriter::new(file);
+        header =
+            Some(pipeline.encrypt_stream(original, old.flags & CARRIED_FLAGS, &password, out)?);
+        Ok(())
+    })?;
+    let header = header.expect("header is set when the output was written");
+
+    progress.finish("Done!");
+    progress.println(format!("✅ Migrated: {} -> {}", args.input.display(), args.output.display()));
+    if is_stream_output(&args.output) {
+        // A FIFO or device keeps no size to compare
+        progress.println(format!("   Format: v{} -> v{}", old.version, header.version));
+    } else {
+        let output_size = fs::metadata(&args.output)?.len();
+        progress.println(format!(
+            "   Format: v{} -> v{}, Size: {} -> {} bytes ({:+.1}%)",
+            old.version,
+            header.version,
+            input_size,
+            output_size,
+            (output_size as f64 / input_size as f64 - 1.0) * 100.0
+        ));
+    }
+
+    if args.verbose > 0 {
+        progress.println(format!("   Cipher: {}", header.cipher));
+        progress.println(format!("   Argon2id: {}", header.kdf_params));
+        if header.is_already_zstd() {
+            progress.println("   Compression: skipped (data already zstd)");
+        } else {
+            progress.println(format!("   Compression level: {}", compression_level));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::KdfParams;
+    use clap::Parser;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_migrate_sealed_file() {
+        let temp_dir = TempDir::new().unwrap();
+        let input = temp_dir.path().join("old.resqrypt");
+        let output = temp_dir.path().join("new.resqrypt");
+        let data = b"migrate me ".repeat(100);
+
+        // Write a single-message (v2) file
+        let pipeline = Pipeline::new().kdf_params(KdfParams {
+            memory_cost: 1024,
+            time_cost: 1,
+            parallelism: 1,
+        });
+        let (payload, storage_flags) = pipeline.compress(data.clone()).unwrap();
+        let (header, ciphertext) =
+            pipeline.seal(&payload, storage_flags, data.len() as u64, b"pw").unwrap();
+        pipeline.write(&mut File::create(&input).unwrap(), &header, &ciphertext).unwrap();
+
+        let args = MigrateArgs::try_parse_from([
+            "migrate",
+            "-i",
+            input.to_str().unwrap(),
+            "-o",
+            output.to_str().unwrap(),
+            "-p",
+            "pw",
+            "--compression-level",
+            "19",
+        ])
+        .unwrap();
+        execute(args).unwrap();
+
+        let (header, decrypted) =
+            Pipeline::new().decrypt(File::open(&output).unwrap(), b"pw").unwrap();
+        assert!(header.is_chunked());
+        assert_eq!(header.kdf_params.memory_cost, 1024);
+        assert_eq!(decrypted, data);
+    }
+}
@@ -1,9 +1,20 @@
 //! Command implementations
 //!
-//! Contains the encrypt and decrypt command logic.
+//! Contains the encrypt, decrypt, migrate, recover, verify, selftest and
+//! capabilities command logic.
 
+pub mod capabilities;
 pub mod decrypt;
 pub mod encrypt;
+pub mod migrate;
+pub mod recover;
+pub mod selftest;
+pub mod verify;
 
+pub use capabilities::execute as capabilities;
 pub use decrypt::execute as decrypt;
 pub use encrypt::execute as encrypt;
+pub use migrate::execute as migrate;
+pub use recover::execute as recover;
+pub use selftest::execute as selftest;
+pub use verify::execute as verify;
@@ -0,0 +1,169 @@
+//! Selftest command implementation
+//!
+//! Runs built-in known-answer tests to validate a build on a new platform:
+//! 1. Argon2id key derivation against an embedded key
+//! 2. AES-256-GCM and XChaCha20-Poly1305 against embedded ciphertexts
+//! 3. Archive -> compress -> encrypt -> decrypt -> decompress round trip
+//!
+//! All inputs, including nonces, are fixed so every run is deterministic.
+
+use std::io::Read;
+
+use tar::{Archive, Builder, Header};
+
+use crate::compression::{compress, decompress};
+use crate::crypto::CipherKind;
+use crate::crypto::kdf::{KdfParams, derive_key};
+use crate::error::{ResqryptError, Result};
+use crate::utils::ProgressReporter;
+use crate::utils::telemetry::SpanStats;
+
+/// Argon2id parameters for the key derivation vector (small for speed)
+const KDF_PARAMS: KdfParams = KdfParams { memory_cost: 1024, time_cost: 1, parallelism: 1 };
+
+/// Expected key for password `resqrypt selftest` and a salt of 32 × 0x5a
+const KDF_EXPECTED: [u8; 32] = [
+    0x7e, 0xac, 0xb0, 0x69, 0xf4, 0x08, 0xe4, 0x73, 0x5f, 0x32, 0x1a, 0x4a, 0x24, 0x5f, 0xf2, 0x23,
+    0xcf, 0x9d, 0xcc, 0x17, 0x86, 0x32, 0x63, 0x20, 0xed, 0x9e, 0x5d, 0xec, 0xb1, 0xb2, 0xc4, 0x77,
+];
+
+/// AES-256-GCM with zero key, zero nonce and 16 zero bytes of plaintext
+/// (GCM specification test case 14): ciphertext followed by tag
+const AES_EXPECTED: [u8; 32] = [
+    0xce, 0xa7, 0x40, 0x3d, 0x4d, 0x60, 0x6b, 0x6e, 0x07, 0x4e, 0xc5, 0xd3, 0xba, 0xf3, 0x9d, 0x18,
+    0xd0, 0xd1, 0xc8, 0xa7, 0x99, 0x99, 0x6b, 0xf0, 0x26, 0x5b, 0x98, 0xb5, 0xd4, 0x8a, 0xb9, 0x19,
+];
+
+/// Plaintext for the XChaCha20-Poly1305 vector
+const XCHACHA_PLAINTEXT: &[u8] = b"resqrypt known-answer test";
+
+/// XChaCha20-Poly1305 with key 0x00..0x1f and a nonce of 24 × 0x24
+const XCHACHA_EXPECTED: [u8; 42] = [
+    0xd9, 0xa0, 0x2b, 0x0f, 0xe3, 0xd4, 0x1d, 0x10, 0x54, 0x29, 0xfc, 0x98, 0x74, 0x1a, 0xab, 0xcb,
+    0x85, 0x86, 0x30, 0x98, 0xfc, 0x5a, 0xd6, 0xad, 0xe1, 0x99, 0xab, 0x23, 0xdc, 0x8d, 0x0e, 0x51,
+    0xea, 0x01, 0xef, 0xeb, 0x60, 0x1e, 0x42, 0xde, 0x15, 0x7d,
+];
+
+/// Files placed in the round-trip archive
+const ARCHIVE_FILES: &[(&str, &[u8])] =
+    &[("selftest/hello.txt", b"Hello, World!"), ("selftest/nested/zeros.bin", &[0u8; 4096])];
+
+/// A named known-answer test
+struct Check {
+    name: &'static str,
+    run: fn() -> Result<bool>,
+}
+
+const CHECKS: &[Check] = &[
+    Check { name: "Argon2id key derivation", run: check_kdf },
+    Check { name: "AES-256-GCM", run: check_aes },
+    Check { name: "XChaCha20-Poly1305", run: check_xchacha },
+    Check { name: "Archive round trip", run: check_archive },
+];
+
+/// Execute the selftest command
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(skip_all, fields(elapsed_ms = tracing::field::Empty))
+)]
+pub fn execute() -> Result<()> {
+    let _stats = SpanStats::start();
+    let progress = ProgressReporter::new(0);
+    let mut failed = 0;
+
+    for check in CHECKS {
+        match (check.run)() {
+            Ok(true) => progress.println(format!("✅ {}", check.name)),
+            Ok(false) => {
+                failed += 1;
+                progress.println(format!("❌ {}: output mismatch", check.name));
+            }
+            Err(e) => {
+                failed += 1;
+                progress.println(format!("❌ {}: {}", check.name, e));
+            }
+        }
+    }
+
+    if failed > 0 {
+        return Err(ResqryptError::CryptoError(format!(
+            "{} of {} self-tests failed",
+            failed,
+            CHECKS.len()
+        )));
+    }
+
+    progress.println(format!("✅ All {} self-tests passed", CHECKS.len()));
+    Ok(())
+}
+
+fn check_kdf() -> Result<bool> {
+    let key = derive_key(b"resqrypt selftest", &[0x5a; 32], &KDF_PARAMS)?;
+    Ok(key.as_bytes() == KDF_EXPECTED)
+}
+
+fn check_aes() -> Result<bool> {
+    let cipher = CipherKind::Aes256Gcm;
+    let ciphertext = cipher.encrypt(&[0u8; 32], &[0u8; 12], &[0u8; 16])?;
+    let plaintext = cipher.decrypt(&[0u8; 32], &[0u8; 12], &AES_EXPECTED)?;
+    Ok(ciphertext == AES_EXPECTED && plaintext == [0u8; 16])
+}
+
+fn check_xchacha() -> Result<bool> {
+    let cipher = CipherKind::XChaCha20Poly1305;
+    let key: [u8; 32] = std::array::from_fn(|i| i as u8);
+    let nonce = [0x24u8; 24];
+    let ciphertext = cipher.encrypt(&key, &nonce, XCHACHA_PLAINTEXT)?;
+    let plaintext = cipher.decrypt(&key, &nonce, &XCHACHA_EXPECTED)?;
+    Ok(ciphertext == XCHACHA_EXPECTED && plaintext == XCHACHA_PLAINTEXT)
+}
+
+fn check_archive() -> Result<bool> {
+    let archive_err = |e: std::io::Error| ResqryptError::archive(e.to_string());
+
+    let mut builder = Builder::new(Vec::new());
+    for (path, contents) in ARCHIVE_FILES {
+        let mut header = Header::new_gnu();
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(0);
+        builder.append_data(&mut header, path, *contents).map_err(archive_err)?;
+    }
+    let archive_data = builder.into_inner().map_err(archive_err)?;
+
+    let cipher = CipherKind::default();
+    let key = [0x42u8; 32];
+    let nonce = vec![0x07u8; cipher.nonce_len()];
+    let ciphertext = cipher.encrypt(&key, &nonce, &compress(&archive_data)?)?;
+    let restored = decompress(&cipher.decrypt(&key, &nonce, &ciphertext)?)?;
+
+    let mut archive = Archive::new(restored.as_slice());
+    let mut found = 0;
+    for entry in archive.entries().map_err(archive_err)? {
+        let mut entry = entry.map_err(archive_err)?;
+        let path = entry.path().map_err(archive_err)?.to_string_lossy().to_string();
+        let mut contents = Vec::new();
+        entry.read_to_end(&mut contents).map_err(archive_err)?;
+
+        match ARCHIVE_FILES.get(found) {
+            Some((expected_path, expected)) if path == *expected_path && contents == *expected => {
+                found += 1;
+            }
+            _ => return Ok(false),
+        }
+    }
+
+    Ok(found == ARCHIVE_FILES.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_all_checks_pass() {
+        for check in CHECKS {
+            assert!((check.run)().unwrap(), "{} failed", check.name);
+        }
+    }
+}
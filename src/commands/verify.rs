@@ -0,0 +1,126 @@
+//! Verify command implementation
+//!
+//! Authenticates an encrypted file without writing its data:
+//! 1. Read the header and derive the key from the password
+//! 2. Open every chunk, checking the stream is complete, or with `--range`
+//!    only the first chunk and the chunks holding the range (see
+//!    [`crate::crypto::stream`] for the layout that makes this possible),
+//!    checked against the Merkle root if the file has one
+//!
+//! Nothing is decompressed, so a range is in payload bytes. These are the
+//! original bytes only for files stored uncompressed (or already zstd), and
+//! `--range` is refused for the rest.
+
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::time::Instant;
+
+use crate::cli::VerifyArgs;
+use crate::error::{ResqryptError, Result};
+use crate::pipeline::Pipeline;
+use crate::utils::telemetry::SpanStats;
+use crate::utils::{ProgressReporter, load_pepper};
+
+/// Execute the verify command
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(
+        skip_all,
+        fields(
+            input = %args.input.display(),
+            bytes_in = tracing::field::Empty,
+            elapsed_ms = tracing::field::Empty
+        )
+    )
+)]
+pub fn execute(args: VerifyArgs) -> Result<()> {
+    let stats = SpanStats::start();
+    stats.record_file_len("bytes_in", &args.input);
+    let progress = ProgressReporter::new(args.verbose);
+
+    // Validate input exists
+    if !args.input.exists() {
+        return Err(ResqryptError::NotFound(args.input.clone()));
+    }
+
+    let password = args.password.password("Enter password: ")?;
+
+    progress.set_message("Reading header...");
+    let mut pipeline = Pipeline::new().max_memory_cost(args.password.max_memory_cost());
+    let mut input = BufReader::new(File::open(&args.input)?);
+    let header = pipeline.read_header(&mut input)?;
+    progress.debug(3, format!("Header:\n{}", header));
+    if let Some(pepper) = load_pepper(args.password.pepper_file.as_deref(), &header)? {
+        pipeline = pipeline.pepper(pepper);
+    }
+    let password = pipeline.key_password(&header, &password)?;
+
+    progress.set_message("Authenticating...");
+    let started = Instant::now();
+    if !header.is_chunked() {
+        if args.range.is_some() {
+            return Err(ResqryptError::InvalidArgument(format!(
+                "{} is a v{} file sealed as a single message with one authentication tag; \
+                 verify it without --range",
+                args.input.display(),
+                header.version
+            )));
+        }
+        let (ciphertext, _) = pipeline.read_ciphertext(&mut input, &header)?;
+        pipeline.open(&header, &ciphertext, &password)?;
+        progress.finish("Done!");
+        progress.println(format!("✅ Verified: {}", args.input.display()));
+        return Ok(());
+    }
+
+    if args.range.is_some() && !header.is_uncompressed() && !header.is_already_zstd() {
+        return Err(ResqryptError::InvalidArgument(format!(
+            "{} is compressed, so --range would count compressed bytes rather than bytes \
+             of the original; verify it without --range, or encrypt with --no-compress",
+            args.input.display()
+        )));
+    }
+
+    let mut chunks = pipeline.open_stream(&header, input)?;
+    chunks.unlock(&password)?;
+    progress.debug(
+        2,
+        format!(
+            "Key derived in {:.3}s (fingerprint {})",
+            started.elapsed().as_secs_f64(),
+            chunks.key_fingerprint().unwrap_or_default()
+        ),
+    );
+
+    let summary = match &args.range {
+        Some(range) => {
+            let verified = chunks.verify_range(range.clone())?;
+            format!(
+                "   Authenticated payload bytes {}-{} ({} chunks read{})",
+                range.start,
+                range.end - 1,
+                verified,
+                if header.is_merkle() { ", each on its Merkle path" } else { "" }
+            )
+        }
+        None => {
+            io::copy(&mut chunks, &mut io::sink()).map_err(ResqryptError::from_io)?;
+            let verified = chunks.chunks_opened();
+            let trailing = chunks.finish()?;
+            if trailing > 0 {
+                progress.println(format!(
+                    "⚠️  Warning: ignoring {} trailing bytes after the ciphertext",
+                    trailing
+                ));
+            }
+            format!("   Authenticated all {} chunks", verified)
+        }
+    };
+    progress.debug(2, format!("Verified in {:.3}s", started.elapsed().as_secs_f64()));
+
+    progress.finish("Done!");
+    progress.println(format!("✅ Verified: {}", args.input.display()));
+    progress.println(summary);
+
+    Ok(())
+}
@@ -6,4 +6,4 @@ pub mod detect;
 pub mod zstd;
 
 pub use detect::is_zstd_compressed;
-pub use zstd::{compress, decompress};
+pub use zstd::{compress, compress_reader, compress_with_level, decompress, decompress_reader};
@@ -2,24 +2,142 @@
 //!
 //! Provides high-level compression/decompression functions.
 
+use std::io::{BufRead, BufReader, Read};
+
+use zstd::stream::read::{Decoder, Encoder};
+
 use crate::error::{ResqryptError, Result};
 
 /// Default compression level (3 is a good balance of speed and ratio)
-const DEFAULT_LEVEL: i32 = 3;
+pub const DEFAULT_LEVEL: i32 = 3;
+
+/// Highest supported compression level
+pub const MAX_LEVEL: i32 = 22;
+
+/// Smallest window log accepted for long-distance matching (1 KiB)
+pub const MIN_WINDOW_LOG: u32 = 10;
+
+/// Largest window log accepted for long-distance matching (1 GiB)
+///
+/// zstd allows 31 on 64-bit targets only; stopping at 30 keeps every file
+/// decodable on 32-bit ones too.
+pub const MAX_WINDOW_LOG: u32 = 30;
+
+/// Window log used by `--long` without a value (128 MiB), as in the zstd CLI
+pub const DEFAULT_LONG_WINDOW_LOG: u32 = 27;
 
 /// Compress data using zstd
 ///
 /// Uses the default compression level (3) which provides a good balance
 /// between compression ratio and speed.
 pub fn compress(data: &[u8]) -> Result<Vec<u8>> {
-    zstd::encode_all(data, DEFAULT_LEVEL)
+    compress_with_level(data, DEFAULT_LEVEL)
+}
+
+/// Compress data using zstd at the given level (1-22)
+pub fn compress_with_level(data: &[u8], level: i32) -> Result<Vec<u8>> {
+    check_level(level)?;
+
+    // Reserve the worst-case frame size so the output never reallocates
+    let mut compressed = Vec::with_capacity(zstd::zstd_safe::compress_bound(data.len()));
+    zstd::stream::copy_encode(data, &mut compressed, level)
+        .map_err(|e| ResqryptError::CompressionError(format!("Compression failed: {}", e)))?;
+    Ok(compressed)
+}
+
+/// Wrap a reader so that reading from it yields zstd-compressed data
+///
+/// Only a bounded window of the input is held in memory, however large the
+/// input is.
+pub fn compress_reader<R: BufRead>(reader: R, level: i32) -> Result<Encoder<'static, R>> {
+    check_level(level)?;
+
+    Encoder::with_buffer(reader, level)
         .map_err(|e| ResqryptError::CompressionError(format!("Compression failed: {}", e)))
 }
 
+/// Like [`compress_reader`], with long-distance matching over a window of
+/// `2^window_log` bytes
+///
+/// Finds repetitions far apart in large inputs, at the cost of holding the
+/// whole window in memory, both here and when decompressing.
+pub fn compress_reader_long<R: BufRead>(
+    reader: R,
+    level: i32,
+    window_log: u32,
+) -> Result<Encoder<'static, R>> {
+    check_window_log(window_log)?;
+    let mut encoder = compress_reader(reader, level)?;
+
+    encoder
+        .long_distance_matching(true)
+        .and_then(|()| encoder.window_log(window_log))
+        .map_err(|e| ResqryptError::CompressionError(format!("Compression failed: {}", e)))?;
+    Ok(encoder)
+}
+
+/// Window log zstd picks at `level` for inputs larger than 256 KiB
+///
+/// From zstd's table of default parameters; smaller inputs get smaller
+/// windows, so this is an upper bound for memory estimates.
+pub fn window_log_for_level(level: i32) -> u32 {
+    match level {
+        ..=1 => 19,
+        2 => 20,
+        3..=7 => 21,
+        8..=15 => 22,
+        16..=19 => 23,
+        20 => 25,
+        21 => 26,
+        _ => 27,
+    }
+}
+
+/// Check that a long-distance matching window log is in the supported range
+pub fn check_window_log(window_log: u32) -> Result<()> {
+    if !(MIN_WINDOW_LOG..=MAX_WINDOW_LOG).contains(&window_log) {
+        return Err(ResqryptError::InvalidArgument(format!(
+            "Window log must be between {} and {}, got {}",
+            MIN_WINDOW_LOG, MAX_WINDOW_LOG, window_log
+        )));
+    }
+
+    Ok(())
+}
+
+/// Check that a compression level is in the supported range
+fn check_level(level: i32) -> Result<()> {
+    if !(1..=MAX_LEVEL).contains(&level) {
+        return Err(ResqryptError::InvalidArgument(format!(
+            "Compression level must be between 1 and {}, got {}",
+            MAX_LEVEL, level
+        )));
+    }
+
+    Ok(())
+}
+
 /// Decompress zstd-compressed data
 pub fn decompress(data: &[u8]) -> Result<Vec<u8>> {
-    zstd::decode_all(data)
-        .map_err(|e| ResqryptError::CompressionError(format!("Decompression failed: {}", e)))
+    let mut decompressed = Vec::new();
+    decompress_reader(data)?
+        .read_to_end(&mut decompressed)
+        .map_err(|e| ResqryptError::CompressionError(format!("Decompression failed: {}", e)))?;
+    Ok(decompressed)
+}
+
+/// Wrap a reader so that reading from it yields decompressed data
+///
+/// Lets callers consume a zstd stream incrementally instead of decompressing
+/// it into memory first. Windows up to [`MAX_WINDOW_LOG`] are accepted, so
+/// data compressed with long-distance matching decodes as well.
+pub fn decompress_reader<R: Read>(reader: R) -> Result<Decoder<'static, BufReader<R>>> {
+    let mut decoder = Decoder::new(reader)
+        .map_err(|e| ResqryptError::CompressionError(format!("Decompression failed: {}", e)))?;
+    decoder
+        .window_log_max(MAX_WINDOW_LOG)
+        .map_err(|e| ResqryptError::CompressionError(format!("Decompression failed: {}", e)))?;
+    Ok(decoder)
 }
 
 #[cfg(test)]
@@ -64,6 +182,57 @@ mod tests {
         let decompressed = decompress(&compressed).unwrap();
 
         assert_eq!(original, decompressed);
+        // Allocated once, at the bound, and never grown
+        assert_eq!(compressed.capacity(), zstd::zstd_safe::compress_bound(original.len()));
+    }
+
+    #[test]
+    fn test_long_distance_roundtrip() {
+        // A random block repeated 4 MiB apart: beyond the default window at
+        // level 1, within a 2^23 window
+        let block: Vec<u8> =
+            (0..1024 * 1024u32).map(|i| (i.wrapping_mul(2654435761) >> 13) as u8).collect();
+        let mut original = block.clone();
+        original.resize(5 * 1024 * 1024, 0);
+        original.extend_from_slice(&block);
+
+        let mut plain = Vec::new();
+        compress_reader(&original[..], 1).unwrap().read_to_end(&mut plain).unwrap();
+        let mut long = Vec::new();
+        compress_reader_long(&original[..], 1, 23).unwrap().read_to_end(&mut long).unwrap();
+        assert!(long.len() < plain.len(), "{} >= {}", long.len(), plain.len());
+
+        assert_eq!(decompress(&long).unwrap(), original);
+        assert!(compress_reader_long(&original[..], 1, MAX_WINDOW_LOG + 1).is_err());
+    }
+
+    #[test]
+    fn test_window_log_for_level() {
+        assert_eq!(window_log_for_level(DEFAULT_LEVEL), 21);
+        assert_eq!(window_log_for_level(19), 23);
+        assert_eq!(window_log_for_level(MAX_LEVEL), DEFAULT_LONG_WINDOW_LOG);
+    }
+
+    #[test]
+    fn test_decompress_reader() {
+        let original = b"Streamed through a decoder".repeat(100);
+        let compressed = compress(&original).unwrap();
+
+        let mut decoder = decompress_reader(compressed.as_slice()).unwrap();
+        let mut decompressed = Vec::new();
+        decoder.read_to_end(&mut decompressed).unwrap();
+
+        assert_eq!(original, decompressed);
+    }
+
+    #[test]
+    fn test_compress_with_level() {
+        let original = b"Level nineteen squeezes harder".repeat(100);
+        let compressed = compress_with_level(&original, 19).unwrap();
+
+        assert_eq!(decompress(&compressed).unwrap(), original);
+        assert!(compress_with_level(&original, 0).is_err());
+        assert!(compress_with_level(&original, MAX_LEVEL + 1).is_err());
     }
 
     #[test]
@@ -73,4 +242,16 @@ mod tests {
         let result = decompress(invalid);
         assert!(result.is_err());
     }
+
+    #[test]
+    fn test_compress_reader_roundtrip() {
+        let original = b"streamed through the encoder ".repeat(1000);
+
+        let mut compressed = Vec::new();
+        compress_reader(original.as_slice(), 5).unwrap().read_to_end(&mut compressed).unwrap();
+        assert!(compressed.len() < original.len());
+
+        assert_eq!(decompress(&compressed).unwrap(), original);
+        assert!(compress_reader(original.as_slice(), 0).is_err());
+    }
 }
@@ -0,0 +1,489 @@
+//! Configuration file support
+//!
+//! Loads default parameters from `~/.config/resqrypt/config.toml` (or the
+//! path given with `--config`).
+//!
+//! Precedence, highest first:
+//! 1. Command-line flags
+//! 2. Security profile (`--profile`)
+//! 3. Config file
+//! 4. Policy files (see below)
+//! 5. Built-in defaults
+//!
+//! The file is a TOML document with the settings as top-level keys:
+//!
+//! ```toml
+//! argon2_memory = 128
+//! argon2_iterations = 4
+//! argon2_parallelism = 4
+//! compression_level = 9
+//! cipher = "xchacha20poly1305"
+//! color = "never"
+//! max_files = 250000
+//! max_size = "100G"
+//! ```
+//!
+//! Unknown keys, and tables, which no setting uses, are collected in
+//! [`Config::unknown_keys`] so the caller can warn about them instead of
+//! failing.
+//!
+//! A [`Policy`] file uses the same syntax for organization-wide settings,
+//! read from `/etc/resqrypt/policy.toml` and `--kdf-params-from`. Its
+//! values are defaults below the config file; with `enforce = true` its
+//! Argon2id memory and iterations also become minimums and its cipher is
+//! required, whatever the command line, profile or config file say.
+
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use clap::ValueEnum;
+use toml::{Table, Value};
+
+use crate::cli::{ColorChoice, parse_size};
+use crate::compression::zstd::{DEFAULT_LEVEL, MAX_LEVEL};
+use crate::crypto::{CipherKind, KdfParams};
+use crate::error::{ResqryptError, Result};
+use crate::kdf_defaults;
+
+/// Keys recognized in the config file
+const KNOWN_KEYS: &[&str] = &[
+    "argon2_memory",
+    "argon2_iterations",
+    "argon2_parallelism",
+    "compression_level",
+    "cipher",
+    "color",
+    "max_files",
+    "max_size",
+];
+
+/// Keys recognized in a policy file
+const POLICY_KEYS: &[&str] = &[
+    "argon2_memory",
+    "argon2_iterations",
+    "argon2_parallelism",
+    "compression_level",
+    "cipher",
+    "enforce",
+];
+
+/// Defaults read from a config file
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    /// Argon2id memory cost in MiB
+    pub argon2_memory: Option<u32>,
+    /// Argon2id iteration count
+    pub argon2_iterations: Option<u32>,
+    /// Argon2id parallelism degree
+    pub argon2_parallelism: Option<u32>,
+    /// zstd compression level
+    pub compression_level: Option<i32>,
+    /// Encryption cipher
+    pub cipher: Option<CipherKind>,
+    /// Colored output setting
+    pub color: Option<ColorChoice>,
+    /// Entry count above which archiving asks for confirmation
+    pub max_files: Option<u64>,
+    /// Total file size in bytes above which archiving asks for confirmation
+    pub max_size: Option<u64>,
+    /// Keys present in the file that were not recognized
+    pub unknown_keys: Vec<String>,
+}
+
+impl Config {
+    /// Default config file location
+    ///
+    /// Uses `$XDG_CONFIG_HOME/resqrypt/config.toml`, falling back to
+    /// `$HOME/.config/resqrypt/config.toml`.
+    pub fn default_path() -> Option<PathBuf> {
+        let base = env::var_os("XDG_CONFIG_HOME")
+            .filter(|v| !v.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+
+        Some(base.join("resqrypt").join("config.toml"))
+    }
+
+    /// Load the config file
+    ///
+    /// An explicitly given `path` must exist. When no path is given the
+    /// default location is used, and a missing file yields an empty config.
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let path = match path {
+            Some(path) => {
+                if !path.exists() {
+                    return Err(ResqryptError::NotFound(path.to_path_buf()));
+                }
+                path.to_path_buf()
+            }
+            None => match Self::default_path() {
+                Some(path) if path.exists() => path,
+                _ => return Ok(Self::default()),
+            },
+        };
+
+        let contents = fs::read_to_string(&path)?;
+        Self::parse(&contents)
+    }
+
+    /// Parse config file contents
+    pub fn parse(contents: &str) -> Result<Self> {
+        parse_keys(contents, KNOWN_KEYS).map(|(config, _)| config)
+    }
+}
+
+/// Well-known location of the system-wide policy file
+pub const SYSTEM_POLICY_PATH: &str = "/etc/resqrypt/policy.toml";
+
+/// Organization-wide encryption settings
+///
+/// Unlike a [`Config`], an enforced policy is not just a set of defaults:
+/// its Argon2id memory and iterations are minimums and its cipher is
+/// required.
+#[derive(Debug, Clone, Default)]
+pub struct Policy {
+    /// Settings, used as defaults below the config file
+    pub config: Config,
+    /// Whether the settings are enforced rather than merely defaults
+    pub enforced: bool,
+    /// File the policy was read from
+    pub path: PathBuf,
+}
+
+impl Policy {
+    /// Load the policy given with `--kdf-params-from` and the system policy
+    ///
+    /// An explicitly given `path` must exist; the system policy is read
+    /// when present. Both apply, the explicit one first, so a personal
+    /// policy can never lift an enforced system one.
+    pub fn load_all(path: Option<&Path>) -> Result<Vec<Self>> {
+        let mut policies = Vec::new();
+        if let Some(path) = path {
+            if !path.exists() {
+                return Err(ResqryptError::NotFound(path.to_path_buf()));
+            }
+            policies.push(Self::load(path)?);
+        }
+
+        let system = Path::new(SYSTEM_POLICY_PATH);
+        if system.exists() && path != Some(system) {
+            policies.push(Self::load(system)?);
+        }
+        Ok(policies)
+    }
+
+    /// Load a policy file, naming it in parse errors
+    fn load(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)?;
+        let mut policy = Self::parse(&contents).map_err(|e| match e {
+            ResqryptError::ConfigError(msg) => {
+                ResqryptError::ConfigError(format!("{}: {}", path.display(), msg))
+            }
+            e => e,
+        })?;
+        policy.path = path.to_path_buf();
+        Ok(policy)
+    }
+
+    /// Parse policy file contents
+    pub fn parse(contents: &str) -> Result<Self> {
+        let (config, enforce) = parse_keys(contents, POLICY_KEYS)?;
+        Ok(Self { config, enforced: enforce.unwrap_or(false), path: PathBuf::new() })
+    }
+
+    /// Check the effective encryption settings against the policy
+    ///
+    /// Always passes unless the policy is enforced.
+    pub fn check(&self, memory_mib: u32, iterations: u32, cipher: CipherKind) -> Result<()> {
+        if !self.enforced {
+            return Ok(());
+        }
+
+        let violation = |msg: String| {
+            ResqryptError::InvalidArgument(format!(
+                "{}, which the policy in {} enforces",
+                msg,
+                self.path.display()
+            ))
+        };
+        if let Some(min) = self.config.argon2_memory.filter(|&min| memory_mib < min) {
+            return Err(violation(format!(
+                "Argon2id memory {} MiB is below the minimum of {} MiB",
+                memory_mib, min
+            )));
+        }
+        if let Some(min) = self.config.argon2_iterations.filter(|&min| iterations < min) {
+            return Err(violation(format!(
+                "{} Argon2id iterations are below the minimum of {}",
+                iterations, min
+            )));
+        }
+        if let Some(required) = self.config.cipher.filter(|&required| cipher != required) {
+            return Err(violation(format!("cipher {} is not the required {}", cipher, required)));
+        }
+        Ok(())
+    }
+}
+
+/// Parse a TOML document, accepting only `known` top-level keys
+///
+/// Returns the settings and the value of `enforce`, if given. Unknown keys,
+/// including whole tables, are collected rather than rejected.
+fn parse_keys(contents: &str, known: &[&str]) -> Result<(Config, Option<bool>)> {
+    let table: Table =
+        contents.parse().map_err(|e: toml::de::Error| ResqryptError::ConfigError(e.to_string()))?;
+    let mut config = Config::default();
+    let mut enforce = None;
+
+    for (key, value) in &table {
+        if !known.contains(&key.as_str()) {
+            config.unknown_keys.push(key.clone());
+            continue;
+        }
+
+        let result = match key.as_str() {
+            "argon2_memory" => as_u32(value).map(|v| config.argon2_memory = Some(v)),
+            "argon2_iterations" => as_u32(value).map(|v| config.argon2_iterations = Some(v)),
+            "argon2_parallelism" => as_u32(value).map(|v| config.argon2_parallelism = Some(v)),
+            "compression_level" => as_i64(value).and_then(|v| {
+                if (1..=MAX_LEVEL as i64).contains(&v) {
+                    config.compression_level = Some(v as i32);
+                    Ok(())
+                } else {
+                    Err(format!("must be between 1 and {}", MAX_LEVEL))
+                }
+            }),
+            "cipher" => as_enum(value).map(|v| config.cipher = Some(v)),
+            "color" => as_enum(value).map(|v| config.color = Some(v)),
+            "max_files" => as_u64(value).map(|v| config.max_files = Some(v)),
+            "max_size" => as_size(value).map(|v| config.max_size = Some(v)),
+            "enforce" => {
+                value.as_bool().map(|v| enforce = Some(v)).ok_or("must be true or false".into())
+            }
+            _ => unreachable!("key checked against the known keys"),
+        };
+
+        result.map_err(|e| ResqryptError::ConfigError(format!("`{}` {}", key, e)))?;
+    }
+
+    Ok((config, enforce))
+}
+
+/// Named bundles of security parameters
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Profile {
+    /// Argon2id 19 MiB / 2 iterations, zstd level 1
+    Fast,
+    /// The built-in defaults
+    Balanced,
+    /// Argon2id 256 MiB / 4 iterations, zstd level 19, XChaCha20-Poly1305
+    Paranoid,
+}
+
+impl Profile {
+    /// Parameters set by this profile
+    pub fn config(self) -> Config {
+        match self {
+            Self::Fast => Config {
+                argon2_memory: Some(19),
+                argon2_iterations: Some(2),
+                compression_level: Some(1),
+                ..Config::default()
+            },
+            Self::Balanced => Config {
+                argon2_memory: Some(KdfParams::default().memory_mib()),
+                argon2_iterations: Some(kdf_defaults::TIME_COST),
+                argon2_parallelism: Some(kdf_defaults::PARALLELISM),
+                compression_level: Some(DEFAULT_LEVEL),
+                cipher: Some(CipherKind::Aes256Gcm),
+                ..Config::default()
+            },
+            Self::Paranoid => Config {
+                argon2_memory: Some(256),
+                argon2_iterations: Some(4),
+                compression_level: Some(19),
+                cipher: Some(CipherKind::XChaCha20Poly1305),
+                ..Config::default()
+            },
+        }
+    }
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Fast => write!(f, "fast"),
+            Self::Balanced => write!(f, "balanced"),
+            Self::Paranoid => write!(f, "paranoid"),
+        }
+    }
+}
+
+fn as_i64(value: &Value) -> std::result::Result<i64, String> {
+    value.as_integer().ok_or_else(|| "must be an integer".to_string())
+}
+
+fn as_u32(value: &Value) -> std::result::Result<u32, String> {
+    u32::try_from(as_i64(value)?).map_err(|_| "is out of range".to_string())
+}
+
+fn as_u64(value: &Value) -> std::result::Result<u64, String> {
+    u64::try_from(as_i64(value)?).map_err(|_| "is out of range".to_string())
+}
+
+/// A byte count, or a string with a size suffix such as `"50G"`
+fn as_size(value: &Value) -> std::result::Result<u64, String> {
+    match value {
+        Value::String(s) => parse_size(s),
+        _ => {
+            as_u64(value).map_err(|_| "must be a byte count or a size such as \"50G\"".to_string())
+        }
+    }
+}
+
+fn as_enum<T: ValueEnum>(value: &Value) -> std::result::Result<T, String> {
+    match value {
+        Value::String(s) => T::from_str(s, true).map_err(|_| format!("has unknown value {:?}", s)),
+        _ => Err("must be a string".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_all_keys() {
+        let config = Config::parse(
+            r#"
+            # resqrypt defaults
+            argon2_memory = 128
+            argon2_iterations = 4   # slower
+            argon2_parallelism = 2
+            compression_level = 19
+            cipher = "xchacha20poly1305"
+            color = "never"
+            max_files = 250_000
+            max_size = "100G"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.argon2_memory, Some(128));
+        assert_eq!(config.argon2_iterations, Some(4));
+        assert_eq!(config.argon2_parallelism, Some(2));
+        assert_eq!(config.compression_level, Some(19));
+        assert_eq!(config.cipher, Some(CipherKind::XChaCha20Poly1305));
+        assert_eq!(config.color, Some(ColorChoice::Never));
+        assert_eq!(config.max_files, Some(250_000));
+        assert_eq!(config.max_size, Some(100 << 30));
+        assert!(config.unknown_keys.is_empty());
+    }
+
+    #[test]
+    fn test_unknown_keys_collected() {
+        let config = Config::parse("argon2_memory = 32\nfavorite_color = true\n").unwrap();
+
+        assert_eq!(config.argon2_memory, Some(32));
+        assert_eq!(config.unknown_keys, vec!["favorite_color".to_string()]);
+    }
+
+    #[test]
+    fn test_parse_full_toml() {
+        let config = Config::parse(
+            r#"
+            cipher = 'aes256gcm'
+            color = "nev\u0065r"
+            max_size = """
+            2G"""
+
+            [backup]
+            argon2_memory = 512
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.cipher, Some(CipherKind::Aes256Gcm));
+        assert_eq!(config.color, Some(ColorChoice::Never));
+        assert_eq!(config.max_size, Some(2 << 30));
+        // Settings are only read from the top level
+        assert_eq!(config.argon2_memory, None);
+        assert_eq!(config.unknown_keys, vec!["backup".to_string()]);
+    }
+
+    #[test]
+    fn test_invalid_values_rejected() {
+        for contents in [
+            "argon2_memory = \"lots\"",
+            "argon2_memory = -1",
+            "compression_level = 30",
+            "cipher = \"rot13\"",
+            "not a pair",
+            "argon2_memory = 32\nargon2_memory = 64",
+        ] {
+            let result = Config::parse(contents);
+            assert!(matches!(result, Err(ResqryptError::ConfigError(_))), "{}", contents);
+        }
+    }
+
+    #[test]
+    fn test_profiles() {
+        let fast = Profile::Fast.config();
+        assert_eq!(fast.argon2_memory, Some(19));
+        assert_eq!(fast.argon2_iterations, Some(2));
+        assert_eq!(fast.compression_level, Some(1));
+
+        let balanced = Profile::Balanced.config();
+        assert_eq!(balanced.argon2_memory, Some(KdfParams::default().memory_mib()));
+        assert_eq!(balanced.cipher, Some(CipherKind::Aes256Gcm));
+
+        let paranoid = Profile::Paranoid.config();
+        assert_eq!(paranoid.argon2_memory, Some(256));
+        assert_eq!(paranoid.argon2_iterations, Some(4));
+        assert_eq!(paranoid.compression_level, Some(19));
+        assert_eq!(paranoid.cipher, Some(CipherKind::XChaCha20Poly1305));
+    }
+
+    #[test]
+    fn test_parse_policy() {
+        let policy = Policy::parse(
+            "argon2_memory = 128\nargon2_iterations = 4\ncipher = \"xchacha20poly1305\"\nenforce = true\n",
+        )
+        .unwrap();
+        assert!(policy.enforced);
+        assert_eq!(policy.config.argon2_memory, Some(128));
+
+        // `enforce` belongs to policies, `color` to the user config
+        assert_eq!(Config::parse("enforce = true").unwrap().unknown_keys, vec!["enforce"]);
+        assert_eq!(Policy::parse("color = \"never\"").unwrap().config.unknown_keys, vec!["color"]);
+        assert!(Policy::parse("enforce = 1").is_err());
+        assert!(!Policy::parse("argon2_memory = 128").unwrap().enforced);
+    }
+
+    #[test]
+    fn test_policy_check() {
+        let mut policy = Policy::parse(
+            "argon2_memory = 128\nargon2_iterations = 4\ncipher = \"xchacha20poly1305\"\n",
+        )
+        .unwrap();
+        let strong = CipherKind::XChaCha20Poly1305;
+
+        // Defaults only: nothing is rejected
+        assert!(policy.check(19, 2, CipherKind::Aes256Gcm).is_ok());
+
+        policy.enforced = true;
+        assert!(policy.check(128, 4, strong).is_ok());
+        assert!(policy.check(256, 8, strong).is_ok());
+        assert!(policy.check(64, 4, strong).is_err());
+        assert!(policy.check(128, 3, strong).is_err());
+        assert!(policy.check(128, 4, CipherKind::Aes256Gcm).is_err());
+    }
+
+    #[test]
+    fn test_load_missing_explicit_path() {
+        let result = Config::load(Some(Path::new("/nonexistent/config.toml")));
+        assert!(matches!(result, Err(ResqryptError::NotFound(_))));
+    }
+}
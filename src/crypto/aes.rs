@@ -4,16 +4,20 @@
 
 use aes_gcm::{
     Aes256Gcm, Nonce,
-    aead::{Aead, KeyInit},
+    aead::{Aead, KeyInit, Payload},
 };
-use rand::Rng;
 
 use crate::aes_params;
+use crate::crypto::rng::random_bytes;
 use crate::error::{ResqryptError, Result};
 
 /// Generate a random nonce for AES-GCM
+///
+/// Only use random nonces when each key seals a small number of messages.
+/// When sealing many messages under one key, use
+/// [`NonceSequence`](crate::crypto::nonce::NonceSequence) instead.
 pub fn generate_nonce() -> [u8; 12] {
-    rand::rng().random()
+    random_bytes()
 }
 
 /// Encrypt data using AES-256-GCM
@@ -26,13 +30,23 @@ pub fn generate_nonce() -> [u8; 12] {
 /// # Returns
 /// Ciphertext with authentication tag appended (plaintext.len() + 16 bytes)
 pub fn encrypt_data(key: &[u8; 32], nonce: &[u8; 12], plaintext: &[u8]) -> Result<Vec<u8>> {
+    encrypt_data_with_aad(key, nonce, plaintext, &[])
+}
+
+/// Encrypt data, additionally authenticating `aad` without encrypting it
+pub fn encrypt_data_with_aad(
+    key: &[u8; 32],
+    nonce: &[u8; 12],
+    plaintext: &[u8],
+    aad: &[u8],
+) -> Result<Vec<u8>> {
     let cipher = Aes256Gcm::new_from_slice(key)
         .map_err(|e| ResqryptError::CryptoError(format!("Failed to create cipher: {}", e)))?;
 
     let nonce = Nonce::from_slice(nonce);
 
     cipher
-        .encrypt(nonce, plaintext)
+        .encrypt(nonce, Payload { msg: plaintext, aad })
         .map_err(|e| ResqryptError::CryptoError(format!("Encryption failed: {}", e)))
 }
 
@@ -49,6 +63,20 @@ pub fn encrypt_data(key: &[u8; 32], nonce: &[u8; 12], plaintext: &[u8]) -> Resul
 /// # Errors
 /// Returns an error if authentication fails (wrong password or tampered data)
 pub fn decrypt_data(key: &[u8; 32], nonce: &[u8; 12], ciphertext: &[u8]) -> Result<Vec<u8>> {
+    decrypt_data_with_aad(key, nonce, ciphertext, &[])
+}
+
+/// Decrypt data that was encrypted with [`encrypt_data_with_aad`]
+///
+/// # Errors
+/// Returns an error if authentication fails, including when `aad` differs
+/// from the value used for encryption
+pub fn decrypt_data_with_aad(
+    key: &[u8; 32],
+    nonce: &[u8; 12],
+    ciphertext: &[u8],
+    aad: &[u8],
+) -> Result<Vec<u8>> {
     if ciphertext.len() < aes_params::TAG_LEN {
         return Err(ResqryptError::CryptoError("Ciphertext too short".to_string()));
     }
@@ -58,7 +86,7 @@ pub fn decrypt_data(key: &[u8; 32], nonce: &[u8; 12], ciphertext: &[u8]) -> Resu
 
     let nonce = Nonce::from_slice(nonce);
 
-    cipher.decrypt(nonce, ciphertext).map_err(|_| {
+    cipher.decrypt(nonce, Payload { msg: ciphertext, aad }).map_err(|_| {
         ResqryptError::PasswordError(
             "Decryption failed: wrong password or corrupted data".to_string(),
         )
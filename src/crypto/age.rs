@@ -0,0 +1,131 @@
+//! Export to the age format
+//!
+//! With the `age` feature, a payload can be written as an
+//! [age](https://age-encryption.org/v1) file instead of a resqrypt one, so
+//! that `age` or `rage` can decrypt it: to a passphrase, through an scrypt
+//! stanza whose work factor age picks to take about a second, or to X25519
+//! public keys (`age1...`).
+//!
+//! Only the container and its cryptography change; the CLI still archives
+//! and compresses in front of it. The age header has no room for anything
+//! resqrypt keeps in its own, so none of it is carried: the stored name,
+//! source offset and storage flags are lost, and the cipher, Argon2id,
+//! chunking, padding, pepper and Merkle settings do not apply, as age
+//! seals 64 KiB chunks with ChaCha20-Poly1305 under its own key schedule.
+
+use std::io::{self, Read, Write};
+
+use ::age::secrecy::SecretString;
+use ::age::{Encryptor, x25519};
+
+use crate::error::{ResqryptError, Result};
+
+/// Who can decrypt an age file
+pub enum AgeRecipients {
+    /// Anyone with the passphrase
+    Passphrase(SecretString),
+    /// The holders of the identities of these public keys
+    X25519(Vec<x25519::Recipient>),
+}
+
+impl AgeRecipients {
+    /// Recipient for `password`, which age takes as text
+    ///
+    /// # Errors
+    /// Returns [`ResqryptError::InvalidArgument`] when the password is not
+    /// UTF-8, e.g. a binary key.
+    pub fn passphrase(password: &[u8]) -> Result<Self> {
+        let passphrase = std::str::from_utf8(password).map_err(|_| {
+            ResqryptError::InvalidArgument("an age passphrase must be UTF-8 text".to_string())
+        })?;
+        Ok(Self::Passphrase(SecretString::from(passphrase.to_string())))
+    }
+
+    /// Recipients for `age1...` public keys
+    pub fn x25519<S: AsRef<str>>(keys: &[S]) -> Result<Self> {
+        if keys.is_empty() {
+            return Err(ResqryptError::InvalidArgument("no age recipient given".to_string()));
+        }
+        keys.iter()
+            .map(|key| {
+                key.as_ref().parse().map_err(|e| {
+                    ResqryptError::InvalidArgument(format!(
+                        "invalid age recipient `{}`: {}",
+                        key.as_ref(),
+                        e
+                    ))
+                })
+            })
+            .collect::<Result<_>>()
+            .map(Self::X25519)
+    }
+
+    /// Encryptor wrapping a fresh file key for these recipients
+    fn encryptor(&self) -> Result<Encryptor> {
+        match self {
+            Self::Passphrase(passphrase) => Ok(Encryptor::with_user_passphrase(passphrase.clone())),
+            Self::X25519(recipients) => {
+                Encryptor::with_recipients(recipients.iter().map(|r| r as &dyn ::age::Recipient))
+                    .map_err(|e| ResqryptError::CryptoError(e.to_string()))
+            }
+        }
+    }
+}
+
+/// Encrypt everything read from `input` into `out` as an age file
+///
+/// Returns `out`, flushed, once the final chunk is written.
+pub fn encrypt_age<R: Read, W: Write>(
+    mut input: R,
+    recipients: &AgeRecipients,
+    out: W,
+) -> Result<W> {
+    let mut writer = recipients.encryptor()?.wrap_output(out)?;
+    io::copy(&mut input, &mut writer).map_err(ResqryptError::from_io)?;
+    let mut out = writer.finish()?;
+    out.flush()?;
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::age::{Decryptor, Identity, scrypt};
+
+    fn decrypt(file: &[u8], identity: &dyn Identity) -> Vec<u8> {
+        let decryptor = Decryptor::new(file).unwrap();
+        let mut plaintext = Vec::new();
+        decryptor.decrypt(std::iter::once(identity)).unwrap().read_to_end(&mut plaintext).unwrap();
+        plaintext
+    }
+
+    #[test]
+    fn test_age_passphrase() {
+        let data = vec![5u8; 100_000];
+        let recipients = AgeRecipients::passphrase(b"correct horse").unwrap();
+        let file = encrypt_age(data.as_slice(), &recipients, Vec::new()).unwrap();
+        assert!(file.starts_with(b"age-encryption.org/v1\n-> scrypt "));
+
+        let identity = scrypt::Identity::new(SecretString::from("correct horse".to_string()));
+        assert_eq!(decrypt(&file, &identity), data);
+
+        let result = AgeRecipients::passphrase(&[0xff, 0xfe]);
+        assert!(matches!(result, Err(ResqryptError::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn test_age_x25519() {
+        let (first, second) = (x25519::Identity::generate(), x25519::Identity::generate());
+        let keys = [first.to_public().to_string(), second.to_public().to_string()];
+        let recipients = AgeRecipients::x25519(&keys).unwrap();
+        let file = encrypt_age(&b"to both"[..], &recipients, Vec::new()).unwrap();
+
+        assert_eq!(decrypt(&file, &first), b"to both");
+        assert_eq!(decrypt(&file, &second), b"to both");
+
+        let result = AgeRecipients::x25519(&["age1notakey"]);
+        assert!(matches!(result, Err(ResqryptError::InvalidArgument(_))));
+        let result = AgeRecipients::x25519::<&str>(&[]);
+        assert!(matches!(result, Err(ResqryptError::InvalidArgument(_))));
+    }
+}
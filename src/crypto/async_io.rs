@@ -0,0 +1,412 @@
+//! Tokio adapters over the chunked format
+//!
+//! With the `async` feature, [`AsyncEncryptWriter`] implements tokio's
+//! [`AsyncWrite`] and [`AsyncDecryptReader`] its [`AsyncRead`], so a server
+//! can encrypt an upload, or decrypt a download, as it streams. Both drive
+//! a [`ChunkWriter`] or [`ChunkReader`] over an in-memory buffer that is
+//! written out or filled without blocking, so the files are the usual
+//! chunked format and at most about two chunks are held in memory.
+//!
+//! Argon2id is CPU-bound and takes from tens of milliseconds to seconds
+//! with the default parameters, so the constructors run the key derivation
+//! on tokio's blocking thread pool with [`spawn_blocking`] and must be
+//! called within a tokio runtime. Chunks are sealed and opened on the
+//! polling task: one chunk at the default 64 KiB takes well under a
+//! millisecond.
+//!
+//! The payload is stored as is, neither compressed nor padded. The reader
+//! therefore only reads chunked files stored that way, such as those of
+//! [`AsyncEncryptWriter`] or of a [`Pipeline`] with [`NoCompression`];
+//! others are rejected with [`ResqryptError::InvalidArgument`] and can be
+//! decrypted with [`Pipeline::decrypt_stream`] on [`spawn_blocking`].
+//!
+//! [`Pipeline`]: crate::pipeline::Pipeline
+//! [`NoCompression`]: crate::pipeline::NoCompression
+//! [`Pipeline::decrypt_stream`]: crate::pipeline::Pipeline::decrypt_stream
+
+use std::collections::VecDeque;
+use std::future::poll_fn;
+use std::io::{self, Read, Write};
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+use tokio::task::{JoinError, spawn_blocking};
+use zeroize::Zeroizing;
+
+use crate::crypto::cipher::{Cipher, CipherKind};
+use crate::crypto::format::{FileHeader, HeaderFlags, read_header, write_header};
+use crate::crypto::kdf::{DEFAULT_MAX_MEMORY_COST, KdfParams, derive_key_len};
+use crate::crypto::rng::RandomSource;
+use crate::crypto::stream::{ChunkReader, ChunkWriter, check_chunk_size, stored_chunk_len};
+use crate::error::{ResqryptError, Result};
+
+/// Bytes read from the input at a time
+const READ_SIZE: usize = 8 * 1024;
+
+/// Writer encrypting everything written to it into `out` as chunks
+///
+/// Shut the writer down (e.g. with
+/// [`AsyncWriteExt::shutdown`](tokio::io::AsyncWriteExt::shutdown)) once
+/// all data is written: that seals the final chunk. Dropping it without
+/// leaves the stream without a final chunk, which fails to decrypt.
+pub struct AsyncEncryptWriter<W> {
+    out: W,
+    header: FileHeader,
+    /// `None` once the final chunk is sealed
+    chunks: Option<ChunkWriter<'static, Vec<u8>>>,
+    /// Sealed bytes not yet written to `out`, from `pos` on
+    pending: Vec<u8>,
+    pos: usize,
+}
+
+impl<W: AsyncWrite + Unpin> AsyncEncryptWriter<W> {
+    /// Create a writer sealing chunks of `chunk_size` bytes with `cipher`,
+    /// under a key derived from `password` on the blocking thread pool
+    ///
+    /// The salt and nonce come from the operating system's RNG. Nothing is
+    /// written to `out` before the first write, which starts with the
+    /// header.
+    pub async fn new(
+        out: W,
+        password: &[u8],
+        cipher: CipherKind,
+        kdf_params: KdfParams,
+        chunk_size: u32,
+    ) -> Result<Self> {
+        kdf_params.validate()?;
+        check_chunk_size(chunk_size)?;
+
+        let rng = RandomSource::os();
+        let salt = rng.salt();
+        let header = FileHeader::new(
+            HeaderFlags::UNCOMPRESSED,
+            cipher,
+            kdf_params.clone(),
+            salt,
+            rng.nonce(&cipher),
+        )
+        .with_chunk_size(chunk_size);
+
+        let password = Zeroizing::new(password.to_vec());
+        let key =
+            spawn_blocking(move || derive_key_len(&password, &salt, &kdf_params, cipher.key_len()))
+                .await
+                .map_err(join_error)??;
+
+        let mut pending = Vec::with_capacity(header.size());
+        write_header(&mut pending, &header)?;
+        let chunks = ChunkWriter::new(Vec::new(), static_cipher(cipher), key, &header)?;
+        Ok(Self { out, header, chunks: Some(chunks), pending, pos: 0 })
+    }
+
+    /// Header of the file being written
+    pub fn header(&self) -> &FileHeader {
+        &self.header
+    }
+
+    /// The output, once the writer has been shut down
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+
+    /// Write the pending sealed bytes to the output
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.pos < self.pending.len() {
+            let n = ready!(Pin::new(&mut self.out).poll_write(cx, &self.pending[self.pos..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.pos += n;
+        }
+        self.pending.clear();
+        self.pos = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for AsyncEncryptWriter<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        data: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        // Take more data only once the last chunk sealed is written out
+        ready!(this.poll_drain(cx))?;
+        let chunks =
+            this.chunks.as_mut().ok_or_else(|| io::Error::other("write after shutdown"))?;
+        let n = chunks.write(data)?;
+        this.pending.append(chunks.get_mut());
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.out).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if let Some(chunks) = this.chunks.take() {
+            let sealed = chunks.finish().map_err(io::Error::other)?;
+            this.pending.extend_from_slice(&sealed);
+        }
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.out).poll_shutdown(cx)
+    }
+}
+
+/// Reader decrypting a chunked file read from `input`
+///
+/// Read to the end: truncation is only detected once the final chunk is
+/// reached, as with [`ChunkReader`].
+pub struct AsyncDecryptReader<R> {
+    input: R,
+    header: FileHeader,
+    /// Opens the chunks gathered from `input`
+    chunks: ChunkReader<VecDeque<u8>>,
+    /// Whether `input` has reached its end
+    eof: bool,
+}
+
+impl<R: AsyncRead + Unpin> AsyncDecryptReader<R> {
+    /// Read the header and first chunk from `input`, and unlock it with
+    /// `password` on the blocking thread pool
+    ///
+    /// For a peppered file, pass the password from
+    /// [`Pipeline::key_password`](crate::pipeline::Pipeline::key_password).
+    ///
+    /// # Errors
+    /// Returns [`ResqryptError::PasswordError`] when the password is wrong,
+    /// and [`ResqryptError::InvalidArgument`] for a file that is not
+    /// chunked or was compressed or padded (see the module docs), or whose
+    /// Argon2 memory cost exceeds [`DEFAULT_MAX_MEMORY_COST`].
+    pub async fn new(input: R, password: &[u8]) -> Result<Self> {
+        Self::with_max_memory_cost(input, password, DEFAULT_MAX_MEMORY_COST).await
+    }
+
+    /// Like [`AsyncDecryptReader::new`], accepting headers that ask for up
+    /// to `max_kib` KiB of Argon2 memory, for files known to need more
+    pub async fn with_max_memory_cost(mut input: R, password: &[u8], max_kib: u32) -> Result<Self> {
+        // The header has variable-length fields: parse it again as more
+        // arrives until it is complete
+        let mut buf = Vec::new();
+        let (header, header_len) = loop {
+            let mut unread = buf.as_slice();
+            match read_header(&mut unread) {
+                Ok(header) => break (header, buf.len() - unread.len()),
+                Err(ResqryptError::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                    if input.read_buf(&mut buf).await? == 0 {
+                        return Err(e.into());
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        };
+        header.kdf_params.check_memory_limit(max_kib)?;
+        if !header.is_chunked() || header.needs_decompression() || header.is_padded() {
+            return Err(ResqryptError::InvalidArgument(
+                "only chunked files stored without compression or padding can be read \
+                 asynchronously"
+                    .to_string(),
+            ));
+        }
+
+        let chunks = ChunkReader::new(VecDeque::from(buf.split_off(header_len)), &header)?;
+        let mut reader = Self { input, header, chunks, eof: false };
+        poll_fn(|cx| reader.poll_gather(cx)).await?;
+
+        let password = Zeroizing::new(password.to_vec());
+        let mut chunks = reader.chunks;
+        reader.chunks = spawn_blocking(move || chunks.unlock(&password).map(|()| chunks))
+            .await
+            .map_err(join_error)??;
+        Ok(reader)
+    }
+
+    /// Header of the file being read
+    pub fn header(&self) -> &FileHeader {
+        &self.header
+    }
+
+    /// Whether the next chunk has been read from the input in full
+    ///
+    /// The final chunk is followed by the trailer, the Merkle footer if
+    /// any and whatever comes after, so it is only complete at the end of
+    /// the input.
+    fn is_gathered(&self) -> io::Result<bool> {
+        let feed = self.chunks.get_ref();
+        if feed.len() < 4 {
+            return Ok(false);
+        }
+        let mut prefix = [0u8; 4];
+        prefix.iter_mut().zip(feed).for_each(|(byte, fed)| *byte = *fed);
+        let (len, last) = stored_chunk_len(&self.header, prefix).map_err(io::Error::other)?;
+        Ok(!last && feed.len() >= 4 + len)
+    }
+
+    /// Read from the input until the next chunk is complete or the input
+    /// ends
+    fn poll_gather(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.eof && !self.is_gathered()? {
+            let mut scratch = [0u8; READ_SIZE];
+            let mut buf = ReadBuf::new(&mut scratch);
+            ready!(Pin::new(&mut self.input).poll_read(cx, &mut buf))?;
+            self.eof = buf.filled().is_empty();
+            self.chunks.get_mut().extend(buf.filled());
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for AsyncDecryptReader<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.chunks.is_buffered() {
+            ready!(this.poll_gather(cx))?;
+        }
+        let n = this.chunks.read(buf.initialize_unfilled())?;
+        buf.advance(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// The built-in stage for `cipher`, borrowed for as long as the writer
+/// that seals with it
+fn static_cipher(cipher: CipherKind) -> &'static dyn Cipher {
+    match cipher {
+        CipherKind::Aes256Gcm => &CipherKind::Aes256Gcm,
+        CipherKind::XChaCha20Poly1305 => &CipherKind::XChaCha20Poly1305,
+    }
+}
+
+/// Resume the panic of a blocking task, or report it cancelled
+fn join_error(e: JoinError) -> ResqryptError {
+    match e.try_into_panic() {
+        Ok(panic) => std::panic::resume_unwind(panic),
+        Err(e) => ResqryptError::CryptoError(format!("Key derivation did not complete: {}", e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline::{NoCompression, Pipeline};
+    use tokio::io::AsyncWriteExt;
+
+    fn fast_kdf() -> KdfParams {
+        KdfParams { memory_cost: 1024, time_cost: 1, parallelism: 1 }
+    }
+
+    /// Input yielding at most 7 bytes per read, and pending every other
+    /// poll
+    struct Trickle<'a> {
+        data: &'a [u8],
+        pending: bool,
+    }
+
+    impl<'a> Trickle<'a> {
+        fn new(data: &'a [u8]) -> Self {
+            Self { data, pending: false }
+        }
+    }
+
+    impl AsyncRead for Trickle<'_> {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            self.pending = !self.pending;
+            if self.pending {
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            let n = buf.remaining().min(7).min(self.data.len());
+            let (head, rest) = self.data.split_at(n);
+            buf.put_slice(head);
+            self.data = rest;
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    async fn read_all(file: &[u8], password: &[u8]) -> Result<Vec<u8>> {
+        let mut reader = AsyncDecryptReader::new(Trickle::new(file), password).await?;
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).await.map_err(ResqryptError::from_io)?;
+        Ok(data)
+    }
+
+    #[test]
+    fn test_adapters_are_send() {
+        fn assert_send<T: Send>() {}
+        assert_send::<AsyncEncryptWriter<Vec<u8>>>();
+        assert_send::<AsyncDecryptReader<&[u8]>>();
+    }
+
+    #[tokio::test]
+    async fn test_async_roundtrip() {
+        let data: Vec<u8> = (0..1000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let mut writer = AsyncEncryptWriter::new(
+            Vec::new(),
+            b"pw",
+            CipherKind::XChaCha20Poly1305,
+            fast_kdf(),
+            64,
+        )
+        .await
+        .unwrap();
+        for part in data.chunks(100) {
+            writer.write_all(part).await.unwrap();
+        }
+        writer.shutdown().await.unwrap();
+        assert!(writer.header().is_uncompressed());
+        let file = writer.into_inner();
+
+        // The synchronous pipeline reads the same format
+        let (_, plaintext) = Pipeline::new().decrypt(file.as_slice(), b"pw").unwrap();
+        assert_eq!(plaintext, data);
+        assert_eq!(read_all(&file, b"pw").await.unwrap(), data);
+
+        let result = read_all(&file, b"wrong").await;
+        assert!(matches!(result, Err(ResqryptError::PasswordError(_))));
+
+        // Truncation is caught at the end
+        let result = read_all(&file[..file.len() - 10], b"pw").await;
+        assert!(matches!(result, Err(ResqryptError::InvalidFormat(_))));
+    }
+
+    #[tokio::test]
+    async fn test_async_reads_pipeline_files() {
+        let data: Vec<u8> = (0..5000u32).map(|i| (i * 13 % 256) as u8).collect();
+        let pipeline = Pipeline::new()
+            .compressor(NoCompression)
+            .kdf_params(fast_kdf())
+            .chunk_size(256)
+            .content_defined()
+            .merkle();
+        let mut file = Vec::new();
+        pipeline.encrypt(data.clone(), HeaderFlags::empty(), b"pw", &mut file).unwrap();
+        assert_eq!(read_all(&file, b"pw").await.unwrap(), data);
+
+        // An empty payload is a single empty final chunk
+        let mut file = Vec::new();
+        pipeline.encrypt(Vec::new(), HeaderFlags::empty(), b"pw", &mut file).unwrap();
+        assert!(read_all(&file, b"pw").await.unwrap().is_empty());
+
+        // Compressed payloads need the synchronous pipeline
+        let mut file = Vec::new();
+        Pipeline::new()
+            .kdf_params(fast_kdf())
+            .encrypt(data, HeaderFlags::empty(), b"pw", &mut file)
+            .unwrap();
+        let result = read_all(&file, b"pw").await;
+        assert!(matches!(result, Err(ResqryptError::InvalidArgument(_))));
+    }
+}
@@ -0,0 +1,295 @@
+//! Cipher selection
+//!
+//! Identifies the AEAD cipher used for a file. The cipher id is stored in the
+//! file header and determines the nonce length that follows it.
+
+use std::fmt;
+
+use crate::crypto::{aes, xchacha};
+use crate::error::{ResqryptError, Result};
+use crate::{aes_params, xchacha_params};
+
+/// Supported AEAD ciphers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum CipherKind {
+    /// AES-256-GCM with a 96-bit nonce
+    #[default]
+    #[cfg_attr(feature = "cli", value(name = "aes256gcm"))]
+    Aes256Gcm,
+    /// XChaCha20-Poly1305 with a 192-bit nonce
+    #[cfg_attr(feature = "cli", value(name = "xchacha20poly1305"))]
+    XChaCha20Poly1305,
+}
+
+impl CipherKind {
+    /// Identifier stored in the file header
+    pub fn id(self) -> u8 {
+        match self {
+            Self::Aes256Gcm => 0x01,
+            Self::XChaCha20Poly1305 => 0x02,
+        }
+    }
+
+    /// Look up a cipher by its header identifier
+    pub fn from_id(id: u8) -> Result<Self> {
+        match id {
+            0x01 => Ok(Self::Aes256Gcm),
+            0x02 => Ok(Self::XChaCha20Poly1305),
+            _ => Err(ResqryptError::InvalidFormat(format!("Unknown cipher id: {}", id))),
+        }
+    }
+
+    /// Key length in bytes, the length Argon2id derives for this cipher
+    pub fn key_len(self) -> usize {
+        match self {
+            Self::Aes256Gcm => aes_params::KEY_LEN,
+            Self::XChaCha20Poly1305 => xchacha_params::KEY_LEN,
+        }
+    }
+
+    /// Nonce length in bytes
+    pub fn nonce_len(self) -> usize {
+        match self {
+            Self::Aes256Gcm => aes_params::NONCE_LEN,
+            Self::XChaCha20Poly1305 => xchacha_params::NONCE_LEN,
+        }
+    }
+
+    /// Authentication tag length in bytes
+    pub fn tag_len(self) -> usize {
+        match self {
+            Self::Aes256Gcm => aes_params::TAG_LEN,
+            Self::XChaCha20Poly1305 => xchacha_params::TAG_LEN,
+        }
+    }
+
+    /// The faster cipher on this CPU: AES-256-GCM with hardware AES,
+    /// XChaCha20-Poly1305 without it (or when it cannot be detected)
+    ///
+    /// The result depends on the machine, so the same command may produce
+    /// files with different ciphers on different machines. Decryption is
+    /// unaffected since the cipher is recorded in the header.
+    pub fn fastest() -> Self {
+        match hardware_aes() {
+            Some(true) => Self::Aes256Gcm,
+            _ => Self::XChaCha20Poly1305,
+        }
+    }
+
+    /// Generate a random nonce of the right length for this cipher
+    pub fn generate_nonce(self) -> Vec<u8> {
+        match self {
+            Self::Aes256Gcm => aes::generate_nonce().to_vec(),
+            Self::XChaCha20Poly1305 => xchacha::generate_nonce().to_vec(),
+        }
+    }
+
+    /// Encrypt data with this cipher
+    pub fn encrypt(self, key: &[u8], nonce: &[u8], plaintext: &[u8]) -> Result<Vec<u8>> {
+        self.encrypt_with_aad(key, nonce, plaintext, &[])
+    }
+
+    /// Decrypt data with this cipher
+    pub fn decrypt(self, key: &[u8], nonce: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>> {
+        self.decrypt_with_aad(key, nonce, ciphertext, &[])
+    }
+
+    /// Encrypt data with this cipher, additionally authenticating `aad`
+    pub fn encrypt_with_aad(
+        self,
+        key: &[u8],
+        nonce: &[u8],
+        plaintext: &[u8],
+        aad: &[u8],
+    ) -> Result<Vec<u8>> {
+        match self {
+            Self::Aes256Gcm => aes::encrypt_data_with_aad(
+                self.fixed_key(key)?,
+                self.fixed_nonce(nonce)?,
+                plaintext,
+                aad,
+            ),
+            Self::XChaCha20Poly1305 => xchacha::encrypt_data_with_aad(
+                self.fixed_key(key)?,
+                self.fixed_nonce(nonce)?,
+                plaintext,
+                aad,
+            ),
+        }
+    }
+
+    /// Decrypt data with this cipher, checking the authenticated `aad`
+    pub fn decrypt_with_aad(
+        self,
+        key: &[u8],
+        nonce: &[u8],
+        ciphertext: &[u8],
+        aad: &[u8],
+    ) -> Result<Vec<u8>> {
+        match self {
+            Self::Aes256Gcm => aes::decrypt_data_with_aad(
+                self.fixed_key(key)?,
+                self.fixed_nonce(nonce)?,
+                ciphertext,
+                aad,
+            ),
+            Self::XChaCha20Poly1305 => xchacha::decrypt_data_with_aad(
+                self.fixed_key(key)?,
+                self.fixed_nonce(nonce)?,
+                ciphertext,
+                aad,
+            ),
+        }
+    }
+
+    /// Convert a key slice into the fixed-size array a cipher expects
+    fn fixed_key<const N: usize>(self, key: &[u8]) -> Result<&[u8; N]> {
+        key.try_into().map_err(|_| {
+            ResqryptError::CryptoError(format!(
+                "Invalid key length for {}: {} (expected {})",
+                self,
+                key.len(),
+                self.key_len()
+            ))
+        })
+    }
+
+    /// Convert a nonce slice into the fixed-size array a cipher expects
+    fn fixed_nonce<const N: usize>(self, nonce: &[u8]) -> Result<&[u8; N]> {
+        nonce.try_into().map_err(|_| {
+            ResqryptError::CryptoError(format!(
+                "Invalid nonce length for {}: {} (expected {})",
+                self,
+                nonce.len(),
+                self.nonce_len()
+            ))
+        })
+    }
+}
+
+/// Whether the CPU has AES instructions (`None` if not detectable here)
+pub fn hardware_aes() -> Option<bool> {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    return Some(std::arch::is_x86_feature_detected!("aes"));
+    #[cfg(target_arch = "aarch64")]
+    return Some(std::arch::is_aarch64_feature_detected!("aes"));
+    #[cfg(not(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")))]
+    None
+}
+
+impl fmt::Display for CipherKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Aes256Gcm => write!(f, "AES-256-GCM"),
+            Self::XChaCha20Poly1305 => write!(f, "XChaCha20-Poly1305"),
+        }
+    }
+}
+
+/// AEAD encryption stage, implemented by [`CipherKind`]
+///
+/// Stages are `Send + Sync`, so the chunk readers and writers holding one
+/// can move between threads, as the async adapters do.
+pub trait Cipher: Send + Sync {
+    /// Cipher recorded in the file header
+    fn kind(&self) -> CipherKind;
+
+    /// Generate a fresh nonce
+    fn generate_nonce(&self) -> Vec<u8>;
+
+    /// Key length in bytes, the length Argon2id derives for this cipher
+    fn key_len(&self) -> usize;
+
+    /// Authentication tag length in bytes
+    fn tag_len(&self) -> usize;
+
+    /// Encrypt `plaintext` under a key of [`Cipher::key_len`] bytes,
+    /// authenticating `aad`
+    fn seal(&self, key: &[u8], nonce: &[u8], plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>>;
+
+    /// Decrypt `ciphertext`, checking `aad`
+    fn open(&self, key: &[u8], nonce: &[u8], ciphertext: &[u8], aad: &[u8]) -> Result<Vec<u8>>;
+}
+
+impl Cipher for CipherKind {
+    fn kind(&self) -> CipherKind {
+        *self
+    }
+
+    fn generate_nonce(&self) -> Vec<u8> {
+        CipherKind::generate_nonce(*self)
+    }
+
+    fn key_len(&self) -> usize {
+        CipherKind::key_len(*self)
+    }
+
+    fn tag_len(&self) -> usize {
+        CipherKind::tag_len(*self)
+    }
+
+    fn seal(&self, key: &[u8], nonce: &[u8], plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        self.encrypt_with_aad(key, nonce, plaintext, aad)
+    }
+
+    fn open(&self, key: &[u8], nonce: &[u8], ciphertext: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        self.decrypt_with_aad(key, nonce, ciphertext, aad)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_id_roundtrip() {
+        for cipher in [CipherKind::Aes256Gcm, CipherKind::XChaCha20Poly1305] {
+            assert_eq!(CipherKind::from_id(cipher.id()).unwrap(), cipher);
+        }
+    }
+
+    #[test]
+    fn test_unknown_id() {
+        let result = CipherKind::from_id(0xFF);
+        assert!(matches!(result, Err(ResqryptError::InvalidFormat(_))));
+    }
+
+    #[test]
+    fn test_nonce_lengths() {
+        assert_eq!(CipherKind::Aes256Gcm.generate_nonce().len(), 12);
+        assert_eq!(CipherKind::XChaCha20Poly1305.generate_nonce().len(), 24);
+    }
+
+    #[test]
+    fn test_roundtrip_each_cipher() {
+        let key = [3u8; 32];
+        for cipher in [CipherKind::Aes256Gcm, CipherKind::XChaCha20Poly1305] {
+            let nonce = cipher.generate_nonce();
+            let ciphertext = cipher.encrypt(&key, &nonce, b"payload").unwrap();
+            let plaintext = cipher.decrypt(&key, &nonce, &ciphertext).unwrap();
+            assert_eq!(plaintext, b"payload");
+        }
+    }
+
+    #[test]
+    fn test_aad_is_authenticated() {
+        let key = [5u8; 32];
+        for cipher in [CipherKind::Aes256Gcm, CipherKind::XChaCha20Poly1305] {
+            let nonce = cipher.generate_nonce();
+            let ciphertext = cipher.encrypt_with_aad(&key, &nonce, b"payload", b"header").unwrap();
+
+            let plaintext = cipher.decrypt_with_aad(&key, &nonce, &ciphertext, b"header").unwrap();
+            assert_eq!(plaintext, b"payload");
+
+            let result = cipher.decrypt_with_aad(&key, &nonce, &ciphertext, b"tampered");
+            assert!(matches!(result, Err(ResqryptError::PasswordError(_))));
+        }
+    }
+
+    #[test]
+    fn test_wrong_nonce_length() {
+        let result = CipherKind::XChaCha20Poly1305.encrypt(&[0u8; 32], &[0u8; 12], b"data");
+        assert!(matches!(result, Err(ResqryptError::CryptoError(_))));
+    }
+}
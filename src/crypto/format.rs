@@ -2,72 +2,543 @@
 //!
 //! Handles reading and writing the resqrypt file format header.
 //!
-//! File format v1:
+//! File format v3:
 //! - Magic (8 bytes): "RESQRYPT"
-//! - Version (1 byte): 0x01
+//! - Version (1 byte): 0x03
 //! - Flags (1 byte): compression/archive flags
+//! - Cipher (1 byte): 0x01 = AES-256-GCM, 0x02 = XChaCha20-Poly1305
 //! - KDF memory cost (4 bytes, LE): Argon2id memory in KiB
 //! - KDF time cost (4 bytes, LE): Argon2id iterations
 //! - KDF parallelism (4 bytes, LE): Argon2id parallelism
 //! - Salt (32 bytes): Argon2id salt
-//! - Nonce (12 bytes): AES-GCM nonce
-//! - Encrypted data: payload + 16-byte auth tag
+//! - Nonce (12 or 24 bytes): base nonce, length implied by the cipher
+//! - Chunk size (4 bytes, LE): plaintext bytes per chunk
+//! - Source offset (8 bytes, LE): only with the range flag, the offset of
+//!   the encrypted byte range in the source file
+//! - Extended flags (1 byte): see [`ExtendedFlags`]
+//! - Stored name length (2 bytes, LE) and stored name (UTF-8): only with
+//!   the stored name flag, the relative path of the encrypted file
+//! - Encrypted data: a sequence of chunks, see [`crate::crypto::stream`];
+//!   with the padded flag the payload ends in padding, see
+//!   [`crate::crypto::padding`]
+//! - Length trailer (16 bytes): unless padded, the length of the data
+//!   before compression and of the payload (8 bytes each, LE), so the
+//!   compression ratio can be read without the password
+//! - Merkle footer: only with the Merkle flag, a hash tree over the chunk
+//!   tags, see [`crate::crypto::merkle`]
+//!
+//! The serialized header is authenticated as AEAD associated data, so none
+//! of its fields can be altered without failing decryption.
+//!
+//! File format v2 seals the whole payload as one message. In place of the
+//! chunk size, source offset, extended flags and stored name it stores the
+//! ciphertext length (8 bytes, LE) and the length of the data before
+//! compression (8 bytes, LE), and the encrypted data is the payload
+//! followed by a 16-byte auth tag, with no trailer or footer. Bytes after
+//! the declared ciphertext length are ignored, so files with padding
+//! appended in transit still decrypt.
+//!
+//! Headers are fully validated when read: magic, version and every field
+//! that bounds later work (KDF parameters, chunk size) are checked before
+//! [`read_header`] returns, so a malformed or hostile header is rejected
+//! before any key is derived. The only key-dependent check is the AEAD tag
+//! verification of the ciphers, which compares tags in constant time. Any
+//! future key-dependent check (such as a key-check value in the header)
+//! must likewise run after structural validation and compare in constant
+//! time, so that timing reveals nothing beyond "wrong password".
+//!
+//! Test vectors with fixed inputs and the exact header and chunk bytes for
+//! both ciphers are kept in `src/crypto/vectors.rs`; the password, salt,
+//! nonces and parameters are listed there, so other implementations can be
+//! checked byte for byte (see also [`build_header_bytes`]).
+//!
+//! File format v1, written by resqrypt 0.1, has no cipher byte and always
+//! uses AES-256-GCM with a 12-byte nonce. Its encrypted data is one message
+//! running to the end of the file, and its header is not authenticated. It
+//! is still accepted when reading.
 
-use std::io::{Read, Write};
+use std::fmt;
+use std::io::{self, Read, Write};
 
+use crate::crypto::cipher::CipherKind;
 use crate::crypto::kdf::KdfParams;
+use crate::crypto::stream::check_chunk_size;
 use crate::error::{ResqryptError, Result};
-use crate::{FORMAT_VERSION, MAGIC_BYTES, aes_params, flags, kdf_defaults};
+use crate::{FORMAT_VERSION, MAGIC_BYTES, ext_flags, flags, kdf_defaults};
+
+/// Longest stored name in bytes
+pub const MAX_STORED_NAME_LEN: usize = 4096;
+
+bitflags::bitflags! {
+    /// Flags byte of the header
+    ///
+    /// Each flag has the value of the raw constant of the same name in
+    /// [`crate::flags`], which documents its meaning. All eight bits of the
+    /// byte are assigned.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+    pub struct HeaderFlags: u8 {
+        const ALREADY_ZSTD = flags::ALREADY_ZSTD;
+        const IS_DIRECTORY = flags::IS_DIRECTORY;
+        const UNCOMPRESSED = flags::UNCOMPRESSED;
+        const FILTERED = flags::FILTERED;
+        const RANGE = flags::RANGE;
+        const PADDED = flags::PADDED;
+        const PEPPERED = flags::PEPPERED;
+        const CONTENT_DEFINED = flags::CONTENT_DEFINED;
+    }
+}
+
+bitflags::bitflags! {
+    /// Extended flags byte of the header (v3 and later)
+    ///
+    /// Each flag has the value of the raw constant of the same name in
+    /// [`crate::ext_flags`]. Unknown bits are rejected when reading, as
+    /// they may announce fields this release cannot parse.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+    pub struct ExtendedFlags: u8 {
+        const STORED_NAME = ext_flags::STORED_NAME;
+        const MERKLE = ext_flags::MERKLE;
+    }
+}
+
+/// Version byte of the header
+///
+/// Versions are ordered, so `version >= FormatVersion::V2` reads as "v2 and
+/// later".
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[repr(u8)]
+pub enum FormatVersion {
+    /// AES-256-GCM only, data runs to the end of the file, header not
+    /// authenticated (resqrypt 0.1)
+    V1 = 1,
+    /// Cipher byte, payload sealed as one message with its lengths in the
+    /// header, header authenticated
+    V2 = 2,
+    /// Payload sealed in chunks followed by a length trailer, extended
+    /// flags byte and optional stored name
+    V3 = 3,
+}
+
+impl FormatVersion {
+    /// Version written by this release ([`FORMAT_VERSION`])
+    pub const CURRENT: Self = Self::V3;
+    /// Version sealing the payload as one message ([`crate::SEALED_VERSION`])
+    pub const SEALED: Self = Self::V2;
+
+    /// Raw version byte
+    pub fn as_u8(self) -> u8 {
+        self as u8
+    }
+}
+
+impl TryFrom<u8> for FormatVersion {
+    type Error = ResqryptError;
+
+    fn try_from(byte: u8) -> Result<Self> {
+        match byte {
+            1 => Ok(Self::V1),
+            2 => Ok(Self::V2),
+            3 => Ok(Self::V3),
+            _ => Err(ResqryptError::InvalidFormat(format!(
+                "Unsupported file format version: {} (expected {})",
+                byte, FORMAT_VERSION
+            ))),
+        }
+    }
+}
+
+impl From<FormatVersion> for u8 {
+    fn from(version: FormatVersion) -> u8 {
+        version.as_u8()
+    }
+}
+
+impl fmt::Display for FormatVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_u8())
+    }
+}
 
 /// File header for encrypted files
 #[derive(Debug, Clone)]
 pub struct FileHeader {
     /// Format version
-    pub version: u8,
+    pub version: FormatVersion,
     /// Flags indicating compression and archive type
-    pub flags: u8,
+    pub flags: HeaderFlags,
+    /// Cipher used for the payload
+    pub cipher: CipherKind,
     /// KDF parameters used for encryption
     pub kdf_params: KdfParams,
     /// Salt for key derivation
     pub salt: [u8; 32],
-    /// Nonce for AES-GCM
-    pub nonce: [u8; 12],
+    /// Nonce for the cipher (length depends on `cipher`)
+    pub nonce: Vec<u8>,
+    /// Declared length of the encrypted data (v2)
+    pub ciphertext_len: Option<u64>,
+    /// Length of the data before compression (v2)
+    pub original_len: Option<u64>,
+    /// Plaintext bytes per chunk (v3 and later)
+    pub chunk_size: Option<u32>,
+    /// Offset of the encrypted byte range in the source file (v3 and
+    /// later, with the range flag)
+    pub source_offset: Option<u64>,
+    /// Extended flags (v3 and later)
+    pub ext_flags: ExtendedFlags,
+    /// Relative path of the encrypted file, `/`-separated (v3 and later,
+    /// with the stored name flag)
+    pub stored_name: Option<String>,
 }
 
 impl FileHeader {
-    /// Header size in bytes: 8 (magic) + 1 (version) + 1 (flags) + 12 (kdf params) + 32 (salt) + 12 (nonce) = 66
-    pub const SIZE: usize = 8 + 1 + 1 + 12 + kdf_defaults::SALT_LEN + aes_params::NONCE_LEN;
+    /// Size in bytes of everything before the nonce in a v1 header:
+    /// 8 (magic) + 1 (version) + 1 (flags) + 12 (kdf params) + 32 (salt)
+    const V1_FIXED_SIZE: usize = 8 + 1 + 1 + 12 + kdf_defaults::SALT_LEN;
 
     /// Create a new header for encryption
-    pub fn new(flags: u8, kdf_params: KdfParams, salt: [u8; 32], nonce: [u8; 12]) -> Self {
-        Self { version: FORMAT_VERSION, flags, kdf_params, salt, nonce }
+    pub fn new(
+        flags: HeaderFlags,
+        cipher: CipherKind,
+        kdf_params: KdfParams,
+        salt: [u8; 32],
+        nonce: Vec<u8>,
+    ) -> Self {
+        Self {
+            version: FormatVersion::CURRENT,
+            flags,
+            cipher,
+            kdf_params,
+            salt,
+            nonce,
+            ciphertext_len: None,
+            original_len: None,
+            chunk_size: None,
+            source_offset: None,
+            ext_flags: ExtendedFlags::empty(),
+            stored_name: None,
+        }
+    }
+
+    /// Set the length of the encrypted data that follows the header
+    pub fn with_ciphertext_len(mut self, len: u64) -> Self {
+        self.ciphertext_len = Some(len);
+        self
+    }
+
+    /// Set the length of the data before compression
+    pub fn with_original_len(mut self, len: u64) -> Self {
+        self.original_len = Some(len);
+        self
+    }
+
+    /// Set the number of plaintext bytes per chunk
+    pub fn with_chunk_size(mut self, size: u32) -> Self {
+        self.chunk_size = Some(size);
+        self
+    }
+
+    /// Mark the payload as a byte range of the source starting at `offset`
+    pub fn with_source_offset(mut self, offset: u64) -> Self {
+        self.flags |= HeaderFlags::RANGE;
+        self.source_offset = Some(offset);
+        self
+    }
+
+    /// Store `name`, the relative path of the encrypted file (see
+    /// [`check_stored_name`])
+    pub fn with_stored_name(mut self, name: impl Into<String>) -> Self {
+        self.ext_flags |= ExtendedFlags::STORED_NAME;
+        self.stored_name = Some(name.into());
+        self
+    }
+
+    /// Follow the chunks with a Merkle tree footer (see
+    /// [`crate::crypto::merkle`])
+    pub fn with_merkle(mut self) -> Self {
+        self.ext_flags |= ExtendedFlags::MERKLE;
+        self
+    }
+
+    /// Serialized header size in bytes
+    pub fn size(&self) -> usize {
+        let cipher_byte = if self.version >= FormatVersion::V2 { 1 } else { 0 };
+        let ciphertext_len_field = if self.has_lengths() { 8 } else { 0 };
+        let original_len_field = if self.has_lengths() { 8 } else { 0 };
+        let chunk_size_field = if self.is_chunked() { 4 } else { 0 };
+        let source_offset_field = if self.is_chunked() && self.is_range() { 8 } else { 0 };
+        let ext_flags_field = if self.has_ext_flags() { 1 } else { 0 };
+        let stored_name_field = match (&self.stored_name, self.has_ext_flags()) {
+            (Some(name), true) => 2 + name.len(),
+            _ => 0,
+        };
+        Self::V1_FIXED_SIZE
+            + cipher_byte
+            + self.nonce.len()
+            + ciphertext_len_field
+            + original_len_field
+            + chunk_size_field
+            + source_offset_field
+            + ext_flags_field
+            + stored_name_field
+    }
+
+    /// Check if the encrypted data is a sequence of chunks (v3 and later)
+    pub fn is_chunked(&self) -> bool {
+        self.version > FormatVersion::SEALED
+    }
+
+    /// Check if the final chunk is followed by a length trailer (v3 and
+    /// later, except for padded payloads)
+    pub fn has_length_trailer(&self) -> bool {
+        self.is_chunked() && !self.is_padded()
+    }
+
+    /// Check if this version has the extended flags byte (v3 and later)
+    fn has_ext_flags(&self) -> bool {
+        self.is_chunked()
+    }
+
+    /// Check if this version stores the ciphertext and original lengths
+    /// (v2)
+    fn has_lengths(&self) -> bool {
+        self.version == FormatVersion::SEALED
+    }
+
+    /// Associated data authenticated alongside the payload
+    ///
+    /// For v2 and later this is the serialized header; v1 did not
+    /// authenticate its header.
+    pub fn aad(&self) -> Result<Vec<u8>> {
+        if self.version >= FormatVersion::V2 { build_header_bytes(self) } else { Ok(Vec::new()) }
     }
 
     /// Check if the source was already zstd compressed
     pub fn is_already_zstd(&self) -> bool {
-        self.flags & flags::ALREADY_ZSTD != 0
+        self.flags.contains(HeaderFlags::ALREADY_ZSTD)
     }
 
     /// Check if the source was a directory
     pub fn is_directory(&self) -> bool {
-        self.flags & flags::IS_DIRECTORY != 0
+        self.flags.contains(HeaderFlags::IS_DIRECTORY)
+    }
+
+    /// Check if the data was stored without compression
+    pub fn is_uncompressed(&self) -> bool {
+        self.flags.contains(HeaderFlags::UNCOMPRESSED)
+    }
+
+    /// Check if the payload is a byte range of the source file
+    pub fn is_range(&self) -> bool {
+        self.flags.contains(HeaderFlags::RANGE)
+    }
+
+    /// Check if the plaintext was passed through a filter command
+    pub fn is_filtered(&self) -> bool {
+        self.flags.contains(HeaderFlags::FILTERED)
+    }
+
+    /// Check if the payload ends in length-hiding padding
+    pub fn is_padded(&self) -> bool {
+        self.flags.contains(HeaderFlags::PADDED)
+    }
+
+    /// Check if the key was derived from a password mixed with a pepper
+    pub fn is_peppered(&self) -> bool {
+        self.flags.contains(HeaderFlags::PEPPERED)
+    }
+
+    /// Check if the chunks were cut at content-defined boundaries
+    pub fn is_content_defined(&self) -> bool {
+        self.flags.contains(HeaderFlags::CONTENT_DEFINED)
+    }
+
+    /// Check if the chunks are followed by a Merkle tree footer (see
+    /// [`crate::crypto::merkle`])
+    pub fn is_merkle(&self) -> bool {
+        self.ext_flags.contains(ExtendedFlags::MERKLE)
+    }
+
+    /// Check if the payload must be zstd-decompressed after decryption
+    pub fn needs_decompression(&self) -> bool {
+        !self.is_already_zstd() && !self.is_uncompressed()
+    }
+}
+
+impl fmt::Display for FileHeader {
+    /// Every field, one per line, with the salt and nonce in hex
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let hex = |bytes: &[u8]| bytes.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+
+        writeln!(f, "version: {}", self.version)?;
+        writeln!(f, "flags: {:#010b}", self.flags.bits())?;
+        writeln!(f, "cipher: {}", self.cipher)?;
+        writeln!(f, "kdf: Argon2id {}", self.kdf_params)?;
+        writeln!(f, "salt: {}", hex(&self.salt))?;
+        write!(f, "nonce: {}", hex(&self.nonce))?;
+        if let Some(len) = self.ciphertext_len {
+            write!(f, "\nciphertext length: {}", len)?;
+        }
+        if let Some(len) = self.original_len {
+            write!(f, "\noriginal length: {}", len)?;
+        }
+        if let Some(size) = self.chunk_size {
+            write!(f, "\nchunk size: {}", size)?;
+        }
+        if let Some(offset) = self.source_offset {
+            write!(f, "\nsource offset: {}", offset)?;
+        }
+        if self.has_ext_flags() {
+            write!(f, "\nextended flags: {:#010b}", self.ext_flags.bits())?;
+        }
+        if let Some(name) = &self.stored_name {
+            write!(f, "\nstored name: {}", name)?;
+        }
+        Ok(())
     }
 }
 
 /// Write the file header to a writer
 pub fn write_header<W: Write>(writer: &mut W, header: &FileHeader) -> Result<()> {
+    if header.nonce.len() != header.cipher.nonce_len() {
+        return Err(ResqryptError::InvalidArgument(format!(
+            "Nonce length {} does not match {} (expected {})",
+            header.nonce.len(),
+            header.cipher,
+            header.cipher.nonce_len()
+        )));
+    }
+
+    let ciphertext_len = match (header.has_lengths(), header.ciphertext_len) {
+        (true, None) => {
+            return Err(ResqryptError::InvalidArgument(
+                "Ciphertext length is required for this format version".to_string(),
+            ));
+        }
+        (true, Some(len)) => Some(len),
+        (false, _) => None,
+    };
+
+    let original_len = match (header.has_lengths(), header.original_len) {
+        (true, None) => {
+            return Err(ResqryptError::InvalidArgument(
+                "Original length is required for this format version".to_string(),
+            ));
+        }
+        (true, Some(len)) => Some(len),
+        (false, _) => None,
+    };
+
+    let chunk_size = match (header.is_chunked(), header.chunk_size) {
+        (true, None) => {
+            return Err(ResqryptError::InvalidArgument(
+                "Chunk size is required for this format version".to_string(),
+            ));
+        }
+        (true, Some(size)) => Some(size),
+        (false, _) => None,
+    };
+
+    let source_offset = match (header.is_range(), header.source_offset) {
+        (true, Some(offset)) if header.is_chunked() => Some(offset),
+        (false, None) => None,
+        _ => {
+            return Err(ResqryptError::InvalidArgument(
+                "Source offset requires the range flag and a chunked format version".to_string(),
+            ));
+        }
+    };
+
+    let ext = header.ext_flags.contains(ExtendedFlags::STORED_NAME);
+    let stored_name = match (ext, &header.stored_name) {
+        (true, Some(name)) if header.has_ext_flags() => {
+            check_stored_name(name)?;
+            Some(name)
+        }
+        (false, None) => None,
+        _ => {
+            return Err(ResqryptError::InvalidArgument(
+                "A stored name requires its flag and a chunked format version".to_string(),
+            ));
+        }
+    };
+    if !header.has_ext_flags() && !header.ext_flags.is_empty() {
+        return Err(ResqryptError::InvalidArgument(
+            "Extended flags require a chunked format version".to_string(),
+        ));
+    }
+
     writer.write_all(MAGIC_BYTES)?;
-    writer.write_all(&[header.version])?;
-    writer.write_all(&[header.flags])?;
+    writer.write_all(&[header.version.as_u8()])?;
+    writer.write_all(&[header.flags.bits()])?;
+    if header.version >= FormatVersion::V2 {
+        writer.write_all(&[header.cipher.id()])?;
+    }
     // Write KDF params as little-endian u32
     writer.write_all(&header.kdf_params.memory_cost.to_le_bytes())?;
     writer.write_all(&header.kdf_params.time_cost.to_le_bytes())?;
     writer.write_all(&header.kdf_params.parallelism.to_le_bytes())?;
     writer.write_all(&header.salt)?;
     writer.write_all(&header.nonce)?;
+    if let Some(len) = ciphertext_len {
+        writer.write_all(&len.to_le_bytes())?;
+    }
+    if let Some(len) = original_len {
+        writer.write_all(&len.to_le_bytes())?;
+    }
+    if let Some(size) = chunk_size {
+        writer.write_all(&size.to_le_bytes())?;
+    }
+    if let Some(offset) = source_offset {
+        writer.write_all(&offset.to_le_bytes())?;
+    }
+    if header.has_ext_flags() {
+        writer.write_all(&[header.ext_flags.bits()])?;
+    }
+    if let Some(name) = stored_name {
+        writer.write_all(&(name.len() as u16).to_le_bytes())?;
+        writer.write_all(name.as_bytes())?;
+    }
     Ok(())
 }
 
+/// Serialize a header to the exact bytes written at the start of a file
+///
+/// For v2 and later these bytes are also the AEAD associated data of the
+/// payload. External implementations can compare their own serialization
+/// against this byte for byte.
+///
+/// # Example
+///
+/// The header of the AES-256-GCM test vector (see the module docs):
+///
+/// ```
+/// use resqrypt::crypto::{CipherKind, FileHeader, HeaderFlags, KdfParams, build_header_bytes};
+///
+/// let kdf = KdfParams { memory_cost: 1024, time_cost: 1, parallelism: 1 };
+/// let flags = HeaderFlags::UNCOMPRESSED;
+/// let header = FileHeader::new(flags, CipherKind::Aes256Gcm, kdf, [0x11; 32], vec![0x22; 12])
+///     .with_chunk_size(16);
+///
+/// let bytes = build_header_bytes(&header)?;
+/// assert_eq!(&bytes[..9], b"RESQRYPT\x03");
+/// assert_eq!(&bytes[9..23], &[
+///     0x04, 0x01, // flags (uncompressed), cipher (AES-256-GCM)
+///     0x00, 0x04, 0x00, 0x00, // memory cost 1024 KiB
+///     0x01, 0x00, 0x00, 0x00, // time cost 1
+///     0x01, 0x00, 0x00, 0x00, // parallelism 1
+/// ]);
+/// assert_eq!(&bytes[23..55], &[0x11; 32]);
+/// assert_eq!(&bytes[55..67], &[0x22; 12]);
+/// assert_eq!(&bytes[67..71], &[0x10, 0x00, 0x00, 0x00]); // chunk size 16
+/// assert_eq!(&bytes[71..], &[0x00]); // no extended flags
+/// # Ok::<(), resqrypt::ResqryptError>(())
+/// ```
+pub fn build_header_bytes(header: &FileHeader) -> Result<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(header.size());
+    write_header(&mut bytes, header)?;
+    Ok(bytes)
+}
+
 /// Read and validate the file header from a reader
 pub fn read_header<R: Read>(reader: &mut R) -> Result<FileHeader> {
     // Read magic bytes
@@ -83,19 +554,21 @@ pub fn read_header<R: Read>(reader: &mut R) -> Result<FileHeader> {
     // Read version
     let mut version = [0u8; 1];
     reader.read_exact(&mut version)?;
-    let version = version[0];
-
-    if version != FORMAT_VERSION {
-        return Err(ResqryptError::InvalidFormat(format!(
-            "Unsupported file format version: {} (expected {})",
-            version, FORMAT_VERSION
-        )));
-    }
+    let version = FormatVersion::try_from(version[0])?;
 
     // Read flags
     let mut flags_buf = [0u8; 1];
     reader.read_exact(&mut flags_buf)?;
-    let flags = flags_buf[0];
+    let flags = HeaderFlags::from_bits_retain(flags_buf[0]);
+
+    // Read cipher (v1 files are always AES-256-GCM)
+    let cipher = if version >= FormatVersion::V2 {
+        let mut cipher_buf = [0u8; 1];
+        reader.read_exact(&mut cipher_buf)?;
+        CipherKind::from_id(cipher_buf[0])?
+    } else {
+        CipherKind::Aes256Gcm
+    };
 
     // Read KDF params
     let mut memory_cost_buf = [0u8; 4];
@@ -117,10 +590,189 @@ pub fn read_header<R: Read>(reader: &mut R) -> Result<FileHeader> {
     reader.read_exact(&mut salt)?;
 
     // Read nonce
-    let mut nonce = [0u8; 12];
+    let mut nonce = vec![0u8; cipher.nonce_len()];
     reader.read_exact(&mut nonce)?;
 
-    Ok(FileHeader { version, flags, kdf_params, salt, nonce })
+    // Read ciphertext length (v1 files run to the end of the file)
+    let ciphertext_len = if version == FormatVersion::SEALED {
+        let mut len_buf = [0u8; 8];
+        reader.read_exact(&mut len_buf)?;
+        Some(u64::from_le_bytes(len_buf))
+    } else {
+        None
+    };
+
+    // Read original length
+    let original_len = if version == FormatVersion::SEALED {
+        let mut len_buf = [0u8; 8];
+        reader.read_exact(&mut len_buf)?;
+        Some(u64::from_le_bytes(len_buf))
+    } else {
+        None
+    };
+
+    // Read chunk size
+    let chunk_size = if version > FormatVersion::SEALED {
+        let mut size_buf = [0u8; 4];
+        reader.read_exact(&mut size_buf)?;
+        Some(u32::from_le_bytes(size_buf))
+    } else {
+        None
+    };
+
+    // Read source offset of a ranged payload
+    let source_offset = if version > FormatVersion::SEALED && flags.contains(HeaderFlags::RANGE) {
+        let mut offset_buf = [0u8; 8];
+        reader.read_exact(&mut offset_buf)?;
+        Some(u64::from_le_bytes(offset_buf))
+    } else {
+        None
+    };
+
+    // Read extended flags and the stored name
+    let mut ext_flags = ExtendedFlags::empty();
+    let mut stored_name = None;
+    if version > FormatVersion::SEALED {
+        let mut ext_buf = [0u8; 1];
+        reader.read_exact(&mut ext_buf)?;
+        ext_flags = ExtendedFlags::from_bits(ext_buf[0]).ok_or_else(|| {
+            ResqryptError::InvalidFormat(format!("Unknown extended flags: {:#010b}", ext_buf[0]))
+        })?;
+        if ext_flags.contains(ExtendedFlags::STORED_NAME) {
+            stored_name = Some(read_stored_name(reader)?);
+        }
+    }
+
+    let header = FileHeader {
+        version,
+        flags,
+        cipher,
+        kdf_params,
+        salt,
+        nonce,
+        ciphertext_len,
+        original_len,
+        chunk_size,
+        source_offset,
+        ext_flags,
+        stored_name,
+    };
+    check_structure(&header)?;
+
+    Ok(header)
+}
+
+/// Read a length-prefixed stored name
+///
+/// The length is checked before anything is allocated for the name.
+fn read_stored_name<R: Read>(reader: &mut R) -> Result<String> {
+    let mut len_buf = [0u8; 2];
+    reader.read_exact(&mut len_buf)?;
+    let len = u16::from_le_bytes(len_buf) as usize;
+    if len > MAX_STORED_NAME_LEN {
+        return Err(ResqryptError::InvalidFormat(format!("Stored name is too long: {}", len)));
+    }
+    let mut name = vec![0u8; len];
+    reader.read_exact(&mut name)?;
+    let name = String::from_utf8(name)
+        .map_err(|_| ResqryptError::InvalidFormat("Stored name is not valid UTF-8".to_string()))?;
+    check_stored_name(&name).map_err(|e| match e {
+        ResqryptError::InvalidArgument(message) => ResqryptError::InvalidFormat(message),
+        e => e,
+    })?;
+    Ok(name)
+}
+
+/// Check that `name` is a relative path that stays below the directory it
+/// is recreated in
+///
+/// Components are separated by `/` and must be non-empty, neither `.` nor
+/// `..`, and free of `\`, `:` and NUL, so a hostile header cannot point
+/// outside the output directory on any platform.
+pub fn check_stored_name(name: &str) -> Result<()> {
+    let invalid = |reason: &str| {
+        Err(ResqryptError::InvalidArgument(format!("Invalid stored name {:?}: {}", name, reason)))
+    };
+    if name.is_empty() {
+        return invalid("empty");
+    }
+    if name.len() > MAX_STORED_NAME_LEN {
+        return invalid("too long");
+    }
+    for component in name.split('/') {
+        if component.is_empty() || component == "." || component == ".." {
+            return invalid("not a plain relative path");
+        }
+        if component.contains(['\\', ':', '\0']) {
+            return invalid("contains a reserved character");
+        }
+    }
+    Ok(())
+}
+
+/// Reject header fields that cannot describe a valid file
+///
+/// Runs before the header is handed out, so no key is ever derived from
+/// parameters Argon2 would refuse or for a chunk size that is out of range.
+fn check_structure(header: &FileHeader) -> Result<()> {
+    header
+        .kdf_params
+        .validate()
+        .map_err(|e| ResqryptError::InvalidFormat(format!("Invalid KDF parameters: {}", e)))?;
+
+    if let Some(size) = header.chunk_size {
+        check_chunk_size(size)
+            .map_err(|_| ResqryptError::InvalidFormat(format!("Invalid chunk size: {}", size)))?;
+    }
+
+    Ok(())
+}
+
+/// Read encrypted data from any reader and parse its header
+///
+/// The header is consumed with exact-size reads and the ciphertext is then
+/// read sequentially, so non-seekable sources such as pipes work too. Also
+/// returns the number of trailing bytes found after the declared ciphertext
+/// length, which are ignored.
+pub fn read_encrypted<R: Read>(mut reader: R) -> Result<(FileHeader, Vec<u8>, u64)> {
+    let header = read_header(&mut reader)?;
+    let (ciphertext, trailing) = read_ciphertext(&mut reader, &header)?;
+    Ok((header, ciphertext, trailing))
+}
+
+/// Read the ciphertext following an already parsed header
+///
+/// Chunked (v3 and later) data is not a single message and must be read
+/// with [`crate::crypto::stream::ChunkReader`] instead.
+pub fn read_ciphertext<R: Read>(mut reader: R, header: &FileHeader) -> Result<(Vec<u8>, u64)> {
+    if header.is_chunked() {
+        return Err(ResqryptError::InvalidArgument(format!(
+            "Format version {} is chunked and must be read as a stream",
+            header.version
+        )));
+    }
+
+    // Read ciphertext, up to the declared length when the header has one
+    let mut ciphertext = Vec::new();
+    let trailing = match header.ciphertext_len {
+        Some(len) => {
+            (&mut reader).take(len).read_to_end(&mut ciphertext)?;
+            if (ciphertext.len() as u64) < len {
+                return Err(ResqryptError::InvalidFormat(format!(
+                    "File is truncated (expected {} bytes of ciphertext, found {})",
+                    len,
+                    ciphertext.len()
+                )));
+            }
+            io::copy(&mut reader, &mut io::sink())?
+        }
+        None => {
+            reader.read_to_end(&mut ciphertext)?;
+            0
+        }
+    };
+
+    Ok((ciphertext, trailing))
 }
 
 #[cfg(test)]
@@ -128,32 +780,95 @@ mod tests {
     use super::*;
     use std::io::Cursor;
 
+    /// v2 header sealing the payload as one message
+    fn sealed_header(cipher: CipherKind, nonce: Vec<u8>) -> FileHeader {
+        FileHeader {
+            version: FormatVersion::SEALED,
+            ..FileHeader::new(HeaderFlags::empty(), cipher, KdfParams::default(), [0u8; 32], nonce)
+        }
+    }
+
     #[test]
     fn test_header_roundtrip() {
         let kdf_params = KdfParams::default();
-        let header = FileHeader::new(0, kdf_params.clone(), [1u8; 32], [2u8; 12]);
+        let header = FileHeader::new(
+            HeaderFlags::empty(),
+            CipherKind::Aes256Gcm,
+            kdf_params.clone(),
+            [1u8; 32],
+            vec![2u8; 12],
+        )
+        .with_chunk_size(65536);
 
         let mut buffer = Vec::new();
         write_header(&mut buffer, &header).unwrap();
 
-        assert_eq!(buffer.len(), FileHeader::SIZE);
+        assert_eq!(buffer.len(), header.size());
 
-        let mut cursor = Cursor::new(buffer);
+        let read_header = read_header(&mut Cursor::new(buffer.clone())).unwrap();
+
+        assert_eq!(read_header.version, FormatVersion::CURRENT);
+        assert!(read_header.is_chunked());
+        assert_eq!(read_header.flags, HeaderFlags::empty());
+        assert_eq!(read_header.kdf_params.memory_cost, kdf_params.memory_cost);
+        assert_eq!(read_header.salt, [1u8; 32]);
+        assert_eq!(read_header.nonce, vec![2u8; 12]);
+        assert_eq!(read_header.chunk_size, Some(65536));
+        assert_eq!(read_header.ciphertext_len, None);
+        assert_eq!(read_header.original_len, None);
+        assert_eq!(read_header.aad().unwrap(), buffer);
+    }
+
+    #[test]
+    fn test_sealed_header_roundtrip() {
+        let kdf_params = KdfParams::default();
+        let header = FileHeader {
+            version: FormatVersion::SEALED,
+            ..FileHeader::new(
+                HeaderFlags::empty(),
+                CipherKind::Aes256Gcm,
+                kdf_params.clone(),
+                [1u8; 32],
+                vec![2u8; 12],
+            )
+        }
+        .with_ciphertext_len(1234)
+        .with_original_len(5678);
+
+        let mut buffer = Vec::new();
+        write_header(&mut buffer, &header).unwrap();
+
+        assert_eq!(buffer.len(), header.size());
+
+        let mut cursor = Cursor::new(buffer.clone());
         let read_header = read_header(&mut cursor).unwrap();
 
-        assert_eq!(read_header.version, FORMAT_VERSION);
-        assert_eq!(read_header.flags, 0);
+        assert_eq!(read_header.version, FormatVersion::SEALED);
+        assert!(!read_header.is_chunked());
+        assert_eq!(read_header.chunk_size, None);
+        assert_eq!(read_header.flags, HeaderFlags::empty());
         assert_eq!(read_header.kdf_params.memory_cost, kdf_params.memory_cost);
         assert_eq!(read_header.kdf_params.time_cost, kdf_params.time_cost);
         assert_eq!(read_header.kdf_params.parallelism, kdf_params.parallelism);
         assert_eq!(read_header.salt, [1u8; 32]);
-        assert_eq!(read_header.nonce, [2u8; 12]);
+        assert_eq!(read_header.cipher, CipherKind::Aes256Gcm);
+        assert_eq!(read_header.nonce, vec![2u8; 12]);
+        assert_eq!(read_header.ciphertext_len, Some(1234));
+        assert_eq!(read_header.original_len, Some(5678));
+        assert_eq!(read_header.aad().unwrap(), buffer);
     }
 
     #[test]
     fn test_header_with_custom_kdf() {
         let kdf_params = KdfParams { memory_cost: 32 * 1024, time_cost: 5, parallelism: 2 };
-        let header = FileHeader::new(0, kdf_params.clone(), [0u8; 32], [0u8; 12]);
+        let header = FileHeader::new(
+            HeaderFlags::empty(),
+            CipherKind::Aes256Gcm,
+            kdf_params.clone(),
+            [0u8; 32],
+            vec![0u8; 12],
+        )
+        .with_chunk_size(1024);
 
         let mut buffer = Vec::new();
         write_header(&mut buffer, &header).unwrap();
@@ -169,23 +884,41 @@ mod tests {
     #[test]
     fn test_header_with_flags() {
         let header = FileHeader::new(
-            flags::ALREADY_ZSTD | flags::IS_DIRECTORY,
+            HeaderFlags::ALREADY_ZSTD | HeaderFlags::IS_DIRECTORY,
+            CipherKind::Aes256Gcm,
             KdfParams::default(),
             [0u8; 32],
-            [0u8; 12],
+            vec![0u8; 12],
         );
 
         assert!(header.is_already_zstd());
         assert!(header.is_directory());
 
-        let header2 = FileHeader::new(0, KdfParams::default(), [0u8; 32], [0u8; 12]);
+        let header2 = FileHeader::new(
+            HeaderFlags::empty(),
+            CipherKind::Aes256Gcm,
+            KdfParams::default(),
+            [0u8; 32],
+            vec![0u8; 12],
+        );
         assert!(!header2.is_already_zstd());
         assert!(!header2.is_directory());
+        assert!(header2.needs_decompression());
+
+        let header3 = FileHeader::new(
+            HeaderFlags::UNCOMPRESSED,
+            CipherKind::Aes256Gcm,
+            KdfParams::default(),
+            [0u8; 32],
+            vec![0u8; 12],
+        );
+        assert!(header3.is_uncompressed());
+        assert!(!header3.needs_decompression());
     }
 
     #[test]
     fn test_invalid_magic() {
-        let mut buffer = vec![0u8; FileHeader::SIZE];
+        let mut buffer = vec![0u8; 67];
         buffer[..8].copy_from_slice(b"INVALID!");
 
         let mut cursor = Cursor::new(buffer);
@@ -211,8 +944,358 @@ mod tests {
         assert!(result.is_err());
     }
 
+    #[test]
+    fn test_typed_version_and_flags() {
+        assert_eq!(FormatVersion::CURRENT.as_u8(), FORMAT_VERSION);
+        assert_eq!(FormatVersion::SEALED.as_u8(), crate::SEALED_VERSION);
+        for byte in 1..=FORMAT_VERSION {
+            assert_eq!(u8::from(FormatVersion::try_from(byte).unwrap()), byte);
+        }
+        assert!(matches!(FormatVersion::try_from(0), Err(ResqryptError::InvalidFormat(_))));
+        assert!(FormatVersion::try_from(FORMAT_VERSION + 1).is_err());
+        assert!(FormatVersion::V1 < FormatVersion::SEALED);
+        assert!(FormatVersion::SEALED < FormatVersion::CURRENT);
+
+        // Flags survive a read and write unchanged
+        let header = FileHeader::new(
+            HeaderFlags::IS_DIRECTORY | HeaderFlags::CONTENT_DEFINED,
+            CipherKind::Aes256Gcm,
+            KdfParams::default(),
+            [0u8; 32],
+            vec![0u8; 12],
+        )
+        .with_chunk_size(4096);
+        let bytes = build_header_bytes(&header).unwrap();
+        assert_eq!(bytes[9], flags::IS_DIRECTORY | flags::CONTENT_DEFINED);
+        let read_back = read_header(&mut bytes.as_slice()).unwrap();
+        assert_eq!(read_back.flags, HeaderFlags::IS_DIRECTORY | HeaderFlags::CONTENT_DEFINED);
+        assert_eq!(build_header_bytes(&read_back).unwrap(), bytes);
+    }
+
     #[test]
     fn test_header_size() {
-        assert_eq!(FileHeader::SIZE, 66);
+        let aes = FileHeader::new(
+            HeaderFlags::empty(),
+            CipherKind::Aes256Gcm,
+            KdfParams::default(),
+            [0u8; 32],
+            vec![0u8; 12],
+        );
+        assert_eq!(aes.size(), 72);
+        assert_eq!(aes.clone().with_stored_name("docs/a.txt").size(), 84);
+        assert_eq!(sealed_header(CipherKind::Aes256Gcm, vec![0u8; 12]).size(), 83);
+
+        let xchacha = FileHeader::new(
+            HeaderFlags::empty(),
+            CipherKind::XChaCha20Poly1305,
+            KdfParams::default(),
+            [0u8; 32],
+            vec![0u8; 24],
+        );
+        assert_eq!(xchacha.size(), 84);
+        assert_eq!(sealed_header(CipherKind::XChaCha20Poly1305, vec![0u8; 24]).size(), 95);
+    }
+
+    #[test]
+    fn test_xchacha_header_roundtrip() {
+        let header = FileHeader::new(
+            HeaderFlags::empty(),
+            CipherKind::XChaCha20Poly1305,
+            KdfParams::default(),
+            [1u8; 32],
+            vec![9u8; 24],
+        )
+        .with_chunk_size(1024);
+
+        let mut buffer = Vec::new();
+        write_header(&mut buffer, &header).unwrap();
+
+        let read_header = read_header(&mut Cursor::new(buffer)).unwrap();
+        assert_eq!(read_header.cipher, CipherKind::XChaCha20Poly1305);
+        assert_eq!(read_header.nonce, vec![9u8; 24]);
+    }
+
+    #[test]
+    fn test_nonce_length_mismatch_rejected() {
+        let header = FileHeader::new(
+            HeaderFlags::empty(),
+            CipherKind::XChaCha20Poly1305,
+            KdfParams::default(),
+            [0u8; 32],
+            vec![0u8; 12],
+        );
+
+        let result = write_header(&mut Vec::new(), &header);
+        assert!(matches!(result, Err(ResqryptError::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn test_missing_lengths_rejected() {
+        let header = sealed_header(CipherKind::Aes256Gcm, vec![0u8; 12]);
+
+        let result = write_header(&mut Vec::new(), &header);
+        assert!(matches!(result, Err(ResqryptError::InvalidArgument(_))));
+
+        let result = write_header(&mut Vec::new(), &header.with_ciphertext_len(16));
+        assert!(matches!(result, Err(ResqryptError::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn test_missing_chunk_size_rejected() {
+        let header = FileHeader::new(
+            HeaderFlags::empty(),
+            CipherKind::Aes256Gcm,
+            KdfParams::default(),
+            [0u8; 32],
+            vec![0u8; 12],
+        );
+
+        let result = write_header(&mut Vec::new(), &header);
+        assert!(matches!(result, Err(ResqryptError::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn test_source_offset_roundtrip() {
+        let header = FileHeader::new(
+            HeaderFlags::FILTERED,
+            CipherKind::Aes256Gcm,
+            KdfParams::default(),
+            [0u8; 32],
+            vec![0u8; 12],
+        )
+        .with_chunk_size(4096)
+        .with_source_offset(1 << 40);
+
+        let mut buffer = Vec::new();
+        write_header(&mut buffer, &header).unwrap();
+        assert_eq!(buffer.len(), header.size());
+
+        let read_back = read_header(&mut buffer.as_slice()).unwrap();
+        assert!(read_back.is_range());
+        assert!(read_back.is_filtered());
+        assert_eq!(read_back.source_offset, Some(1 << 40));
+        assert_eq!(read_back.chunk_size, Some(4096));
+
+        // The offset cannot be stored without the flag, or in a v2 header
+        let unflagged = FileHeader { flags: HeaderFlags::empty(), ..header.clone() };
+        assert!(write_header(&mut Vec::new(), &unflagged).is_err());
+        let sealed = FileHeader { version: FormatVersion::SEALED, ..header }
+            .with_ciphertext_len(16)
+            .with_original_len(0);
+        assert!(write_header(&mut Vec::new(), &sealed).is_err());
+    }
+
+    #[test]
+    fn test_header_display() {
+        let header = FileHeader::new(
+            HeaderFlags::IS_DIRECTORY,
+            CipherKind::Aes256Gcm,
+            KdfParams::default(),
+            [0xab; 32],
+            vec![1; 12],
+        )
+        .with_chunk_size(4096);
+        let text = header.to_string();
+
+        assert!(text.starts_with("version: 3\nflags: 0b00000010\n"));
+        assert!(text.contains(&format!("salt: {}\n", "ab".repeat(32))));
+        assert!(text.contains(&format!("nonce: {}\n", "01".repeat(12))));
+        assert!(text.ends_with("chunk size: 4096\nextended flags: 0b00000000"));
+
+        let named = header.with_stored_name("docs/a.txt").to_string();
+        assert!(named.ends_with("extended flags: 0b00000001\nstored name: docs/a.txt"));
+    }
+
+    #[test]
+    fn test_stored_name_roundtrip() {
+        let header = FileHeader::new(
+            HeaderFlags::empty(),
+            CipherKind::Aes256Gcm,
+            KdfParams::default(),
+            [0u8; 32],
+            vec![0u8; 12],
+        )
+        .with_chunk_size(4096)
+        .with_stored_name("docs/report.txt");
+
+        let bytes = build_header_bytes(&header).unwrap();
+        assert_eq!(bytes.len(), header.size());
+        assert_eq!(&bytes[71..74], &[ext_flags::STORED_NAME, 15, 0]);
+        assert_eq!(&bytes[74..], b"docs/report.txt");
+
+        let read_back = read_header(&mut bytes.as_slice()).unwrap();
+        assert_eq!(read_back.ext_flags, ExtendedFlags::STORED_NAME);
+        assert_eq!(read_back.stored_name.as_deref(), Some("docs/report.txt"));
+
+        // A name without its flag, or the flag without a name, is refused
+        let mut unflagged = header.clone();
+        unflagged.ext_flags = ExtendedFlags::empty();
+        assert!(build_header_bytes(&unflagged).is_err());
+        let mut unnamed = header.clone();
+        unnamed.stored_name = None;
+        assert!(build_header_bytes(&unnamed).is_err());
+    }
+
+    #[test]
+    fn test_merkle_flag_roundtrip() {
+        let header = FileHeader::new(
+            HeaderFlags::empty(),
+            CipherKind::Aes256Gcm,
+            KdfParams::default(),
+            [0u8; 32],
+            vec![0u8; 12],
+        )
+        .with_chunk_size(4096)
+        .with_merkle();
+
+        let bytes = build_header_bytes(&header).unwrap();
+        assert_eq!(bytes[71], ext_flags::MERKLE);
+        let read_back = read_header(&mut bytes.as_slice()).unwrap();
+        assert!(read_back.is_merkle());
+
+        // Bits no release has defined are still refused
+        let mut unknown = bytes.clone();
+        unknown[71] = 0b1000_0000;
+        assert!(matches!(
+            read_header(&mut unknown.as_slice()),
+            Err(ResqryptError::InvalidFormat(_))
+        ));
+    }
+
+    #[test]
+    fn test_hostile_stored_names_rejected() {
+        for name in ["", "/etc/passwd", "../up", "a/../../b", "a//b", "./a", "a\\b", "C:x", "a\0"] {
+            assert!(check_stored_name(name).is_err(), "{:?}", name);
+        }
+        assert!(check_stored_name("dir/sub/file.txt").is_ok());
+        assert!(check_stored_name(&"a".repeat(MAX_STORED_NAME_LEN + 1)).is_err());
+
+        // Written by another implementation, they fail as malformed files
+        let header = FileHeader::new(
+            HeaderFlags::empty(),
+            CipherKind::Aes256Gcm,
+            KdfParams::default(),
+            [0u8; 32],
+            vec![0u8; 12],
+        )
+        .with_chunk_size(4096)
+        .with_stored_name("ok/xx");
+        let mut bytes = build_header_bytes(&header).unwrap();
+        let len = bytes.len();
+        bytes[len - 5..].copy_from_slice(b"../xx");
+        assert!(matches!(read_header(&mut bytes.as_slice()), Err(ResqryptError::InvalidFormat(_))));
+
+        // So do extended flags this release does not know
+        bytes.truncate(71);
+        bytes.push(0b1000_0000);
+        assert!(matches!(read_header(&mut bytes.as_slice()), Err(ResqryptError::InvalidFormat(_))));
+    }
+
+    #[test]
+    fn test_structural_validation_before_key_use() {
+        let mut header = sealed_header(CipherKind::Aes256Gcm, vec![0u8; 12])
+            .with_ciphertext_len(0)
+            .with_original_len(0);
+        header.kdf_params = KdfParams { memory_cost: 0, time_cost: 0, parallelism: 0 };
+        let mut buffer = Vec::new();
+        write_header(&mut buffer, &header).unwrap();
+        assert!(matches!(
+            read_header(&mut Cursor::new(buffer)),
+            Err(ResqryptError::InvalidFormat(_))
+        ));
+
+        let header = FileHeader::new(
+            HeaderFlags::empty(),
+            CipherKind::Aes256Gcm,
+            KdfParams::default(),
+            [1u8; 32],
+            vec![2u8; 12],
+        )
+        .with_chunk_size(0);
+        let mut buffer = Vec::new();
+        write_header(&mut buffer, &header).unwrap();
+        assert!(matches!(
+            read_header(&mut Cursor::new(buffer)),
+            Err(ResqryptError::InvalidFormat(_))
+        ));
+
+        // A chunk size past the supported bound would size the first read
+        let header = header.with_chunk_size(crate::crypto::stream::MAX_CHUNK_SIZE + 1);
+        let mut buffer = Vec::new();
+        write_header(&mut buffer, &header).unwrap();
+        assert!(matches!(
+            read_header(&mut Cursor::new(buffer)),
+            Err(ResqryptError::InvalidFormat(_))
+        ));
+    }
+
+    #[test]
+    fn test_read_v1_header() {
+        let mut buffer = Vec::new();
+        buffer.extend_from_slice(MAGIC_BYTES);
+        buffer.push(0x01); // v1
+        buffer.push(flags::IS_DIRECTORY);
+        buffer.extend_from_slice(&(64 * 1024u32).to_le_bytes());
+        buffer.extend_from_slice(&3u32.to_le_bytes());
+        buffer.extend_from_slice(&4u32.to_le_bytes());
+        buffer.extend_from_slice(&[5u8; 32]); // salt
+        buffer.extend_from_slice(&[6u8; 12]); // nonce
+        assert_eq!(buffer.len(), 66);
+
+        let header = read_header(&mut Cursor::new(buffer)).unwrap();
+        assert_eq!(header.version, FormatVersion::V1);
+        assert_eq!(header.cipher, CipherKind::Aes256Gcm);
+        assert!(header.is_directory());
+        assert_eq!(header.nonce, vec![6u8; 12]);
+        assert_eq!(header.size(), 66);
+    }
+
+    fn encrypted_blob(ciphertext_len: usize, trailing: &[u8]) -> Vec<u8> {
+        let header = sealed_header(CipherKind::Aes256Gcm, vec![0u8; 12])
+            .with_ciphertext_len(ciphertext_len as u64)
+            .with_original_len(0);
+
+        let mut blob = Vec::new();
+        write_header(&mut blob, &header).unwrap();
+        blob.extend(std::iter::repeat_n(0xAB, ciphertext_len));
+        blob.extend_from_slice(trailing);
+        blob
+    }
+
+    #[test]
+    fn test_read_encrypted_from_cursor() {
+        let blob = encrypted_blob(32, b"");
+
+        let (header, ciphertext, trailing) = read_encrypted(Cursor::new(blob)).unwrap();
+        assert_eq!(header.ciphertext_len, Some(32));
+        assert_eq!(ciphertext, vec![0xAB; 32]);
+        assert_eq!(trailing, 0);
+    }
+
+    #[test]
+    fn test_read_encrypted_trailing_and_truncated() {
+        let blob = encrypted_blob(32, b"\xef\xbb\xbf");
+
+        let (_, ciphertext, trailing) = read_encrypted(blob.as_slice()).unwrap();
+        assert_eq!(ciphertext.len(), 32);
+        assert_eq!(trailing, 3);
+
+        let result = read_encrypted(&blob[..blob.len() - 10]);
+        assert!(matches!(result, Err(ResqryptError::InvalidFormat(_))));
+    }
+
+    #[test]
+    fn test_read_ciphertext_rejects_chunked() {
+        let header = FileHeader::new(
+            HeaderFlags::empty(),
+            CipherKind::Aes256Gcm,
+            KdfParams::default(),
+            [0u8; 32],
+            vec![0u8; 12],
+        )
+        .with_chunk_size(1024);
+
+        let result = read_ciphertext(&[0u8; 32][..], &header);
+        assert!(matches!(result, Err(ResqryptError::InvalidArgument(_))));
     }
 }
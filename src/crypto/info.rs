@@ -0,0 +1,241 @@
+//! Encrypted file inspection
+//!
+//! Reads the public metadata of an encrypted file without a password.
+
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+use serde::ser::{Serialize, SerializeStruct, Serializer};
+
+use crate::crypto::cipher::CipherKind;
+use crate::crypto::format::read_header;
+use crate::crypto::kdf::KdfParams;
+use crate::crypto::stream::chunked_len;
+use crate::error::{ResqryptError, Result};
+
+/// Metadata stored in the header of an encrypted file
+#[derive(Debug, Clone)]
+pub struct FileInfo {
+    /// Format version
+    pub version: u8,
+    /// Cipher used for the payload
+    pub cipher: CipherKind,
+    /// KDF parameters used for encryption
+    pub kdf_params: KdfParams,
+    /// Whether the source was already zstd compressed
+    pub already_zstd: bool,
+    /// Whether the source was a directory (tar archive)
+    pub is_directory: bool,
+    /// Whether the data was stored without compression
+    pub uncompressed: bool,
+    /// Whether the payload ends in length-hiding padding
+    pub padded: bool,
+    /// Whether decrypting requires the pepper the file was encrypted with
+    pub peppered: bool,
+    /// Whether the chunks were cut at content-defined boundaries
+    pub content_defined: bool,
+    /// Whether the chunks are followed by a Merkle tree footer
+    pub merkle: bool,
+    /// Length of the encrypted payload including the auth tag
+    pub ciphertext_len: u64,
+    /// Bytes after the ciphertext that are ignored on decryption
+    pub trailing_bytes: u64,
+    /// Length of the data before compression (v2, and chunked files
+    /// unless padded)
+    pub original_len: Option<u64>,
+    /// Length of the compressed payload inside the ciphertext (v2, and
+    /// chunked files unless padded)
+    pub payload_len: Option<u64>,
+    /// Plaintext bytes per chunk (v3 and later)
+    pub chunk_size: Option<u32>,
+    /// Offset of the encrypted byte range in the source file
+    pub source_offset: Option<u64>,
+    /// Relative path of the encrypted file, recreated on decryption
+    pub stored_name: Option<String>,
+}
+
+impl Serialize for FileInfo {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("FileInfo", 20)?;
+        state.serialize_field("version", &self.version)?;
+        state.serialize_field("cipher", &self.cipher.to_string())?;
+        state.serialize_field("memory_cost_kib", &self.kdf_params.memory_cost)?;
+        state.serialize_field("time_cost", &self.kdf_params.time_cost)?;
+        state.serialize_field("parallelism", &self.kdf_params.parallelism)?;
+        state.serialize_field("already_zstd", &self.already_zstd)?;
+        state.serialize_field("is_directory", &self.is_directory)?;
+        state.serialize_field("uncompressed", &self.uncompressed)?;
+        state.serialize_field("padded", &self.padded)?;
+        state.serialize_field("peppered", &self.peppered)?;
+        state.serialize_field("content_defined", &self.content_defined)?;
+        state.serialize_field("merkle", &self.merkle)?;
+        state.serialize_field("ciphertext_len", &self.ciphertext_len)?;
+        state.serialize_field("trailing_bytes", &self.trailing_bytes)?;
+        state.serialize_field("original_len", &self.original_len)?;
+        state.serialize_field("payload_len", &self.payload_len)?;
+        state.serialize_field("compression_ratio", &self.compression_ratio())?;
+        state.serialize_field("chunk_size", &self.chunk_size)?;
+        state.serialize_field("source_offset", &self.source_offset)?;
+        state.serialize_field("stored_name", &self.stored_name)?;
+        state.end()
+    }
+}
+
+impl FileInfo {
+    /// Payload size as a fraction of the original size, e.g. 0.34 for data
+    /// compressed to 34% (`None` if the lengths are not recorded or the
+    /// data is empty)
+    pub fn compression_ratio(&self) -> Option<f64> {
+        match (self.payload_len, self.original_len) {
+            (Some(payload), Some(original)) if original > 0 => {
+                Some(payload as f64 / original as f64)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Read the metadata of an encrypted file without decrypting it
+pub fn inspect<P: AsRef<Path>>(path: P) -> Result<FileInfo> {
+    let path = path.as_ref();
+
+    if !path.exists() {
+        return Err(ResqryptError::NotFound(path.to_path_buf()));
+    }
+
+    let file = File::open(path)?;
+    let file_len = file.metadata()?.len();
+    let mut reader = BufReader::new(file);
+    let header = read_header(&mut reader)?;
+
+    let available = file_len.saturating_sub(header.size() as u64);
+    let (ciphertext_len, lengths) = if header.is_chunked() {
+        // Chunks and their length prefixes, up to the final chunk, its
+        // length trailer and the Merkle footer
+        let (len, lengths) = chunked_len(&mut reader, &header)?;
+        (len, lengths.map(|lengths| (lengths.original_len, lengths.payload_len)))
+    } else {
+        let len = header.ciphertext_len.unwrap_or(available);
+        let tag_len = header.cipher.tag_len() as u64;
+        let lengths = header.original_len.map(|original| (original, len.saturating_sub(tag_len)));
+        (len, lengths)
+    };
+    if ciphertext_len > available || ciphertext_len < header.cipher.tag_len() as u64 {
+        return Err(ResqryptError::InvalidFormat(
+            "File is truncated (missing ciphertext)".to_string(),
+        ));
+    }
+
+    Ok(FileInfo {
+        version: header.version.as_u8(),
+        cipher: header.cipher,
+        already_zstd: header.is_already_zstd(),
+        is_directory: header.is_directory(),
+        uncompressed: header.is_uncompressed(),
+        padded: header.is_padded(),
+        peppered: header.is_peppered(),
+        content_defined: header.is_content_defined(),
+        merkle: header.is_merkle(),
+        kdf_params: header.kdf_params,
+        ciphertext_len,
+        trailing_bytes: available - ciphertext_len,
+        original_len: lengths.map(|(original, _)| original),
+        payload_len: lengths.map(|(_, payload)| payload),
+        chunk_size: header.chunk_size,
+        source_offset: header.source_offset,
+        stored_name: header.stored_name,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::format::{FileHeader, FormatVersion, HeaderFlags, write_header};
+    use crate::crypto::stream::ChunkWriter;
+    use std::io::Write;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_inspect() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("test.resqrypt");
+
+        let header = FileHeader {
+            version: FormatVersion::SEALED,
+            ..FileHeader::new(
+                HeaderFlags::IS_DIRECTORY,
+                CipherKind::XChaCha20Poly1305,
+                KdfParams { memory_cost: 1024, time_cost: 2, parallelism: 1 },
+                [0u8; 32],
+                vec![0u8; 24],
+            )
+        }
+        .with_ciphertext_len(40)
+        .with_original_len(100);
+        let mut data = Vec::new();
+        write_header(&mut data, &header).unwrap();
+        data.extend_from_slice(&[0u8; 40]);
+        data.extend_from_slice(b"\r\n");
+        std::fs::write(&path, &data).unwrap();
+
+        let info = inspect(&path).unwrap();
+        assert_eq!(info.cipher, CipherKind::XChaCha20Poly1305);
+        assert_eq!(info.kdf_params.memory_cost, 1024);
+        assert_eq!(info.kdf_params.time_cost, 2);
+        assert!(info.is_directory);
+        assert!(!info.already_zstd);
+        assert!(!info.uncompressed);
+        assert!(!info.padded);
+        assert!(!info.peppered);
+        assert!(!info.content_defined);
+        assert_eq!(info.ciphertext_len, 40);
+        assert_eq!(info.trailing_bytes, 2);
+        assert_eq!(info.original_len, Some(100));
+        assert_eq!(info.payload_len, Some(24));
+        assert_eq!(info.compression_ratio(), Some(0.24));
+        assert_eq!(info.chunk_size, None);
+    }
+
+    #[test]
+    fn test_inspect_chunked() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("test.resqrypt");
+
+        let cipher = CipherKind::Aes256Gcm;
+        let header = FileHeader::new(
+            HeaderFlags::empty(),
+            cipher,
+            KdfParams { memory_cost: 1024, time_cost: 1, parallelism: 1 },
+            [0u8; 32],
+            cipher.generate_nonce(),
+        )
+        .with_chunk_size(16);
+        let mut data = Vec::new();
+        write_header(&mut data, &header).unwrap();
+        let mut chunks = ChunkWriter::new(data, &cipher, [1u8; 32].into(), &header).unwrap();
+        chunks.write_all(&[7u8; 40]).unwrap();
+        chunks.set_original_len(160);
+        let mut data = chunks.finish().unwrap();
+        let data_len = data.len() - header.size();
+        data.push(b'\n');
+        std::fs::write(&path, &data).unwrap();
+
+        let info = inspect(&path).unwrap();
+        assert_eq!(info.version, header.version.as_u8());
+        assert_eq!(info.chunk_size, Some(16));
+        // Three chunks, each with a 4-byte prefix and a tag, and the trailer
+        assert_eq!(info.ciphertext_len, data_len as u64);
+        assert_eq!(data_len, 40 + 3 * (4 + cipher.tag_len()) + 16);
+        assert_eq!(info.trailing_bytes, 1);
+        assert_eq!(info.original_len, Some(160));
+        assert_eq!(info.payload_len, Some(40));
+        assert_eq!(info.compression_ratio(), Some(0.25));
+    }
+
+    #[test]
+    fn test_inspect_missing_file() {
+        let result = inspect("/nonexistent/file.resqrypt");
+        assert!(matches!(result, Err(ResqryptError::NotFound(_))));
+    }
+}
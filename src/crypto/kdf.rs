@@ -3,11 +3,37 @@
 //! Provides secure password-based key derivation using Argon2id,
 //! which is resistant to GPU and ASIC attacks.
 
+use std::fmt;
+
 use argon2::{Algorithm, Argon2, Params, Version};
-use rand::Rng;
+use blake2::digest::{KeyInit, Mac};
+use blake2::{Blake2b512, Blake2bMac512, Digest};
+use zeroize::{Zeroize, Zeroizing};
 
+use crate::crypto::rng::random_bytes;
 use crate::error::{ResqryptError, Result};
 use crate::kdf_defaults;
+use crate::utils::telemetry::SpanStats;
+
+/// Largest accepted ratio of Argon2 lanes to logical CPUs when encrypting
+pub const MAX_PARALLELISM_PER_CPU: u32 = 4;
+
+/// Work factor of the default parameters, in MiB × iterations (64 MiB × 3)
+pub const DEFAULT_MIN_WORK_FACTOR: u32 = kdf_defaults::MEMORY_COST / 1024 * kdf_defaults::TIME_COST;
+
+/// Largest Argon2 memory cost, in KiB, accepted from a file header unless
+/// raised (2 GiB, RFC 9106's first recommended setting)
+///
+/// A header decides how much memory deriving its key takes, before
+/// anything in the file is authenticated, so a crafted one could otherwise
+/// ask for up to 4 TiB.
+pub const DEFAULT_MAX_MEMORY_COST: u32 = 2 * 1024 * 1024;
+
+/// Smallest recommended memory cost in MiB (OWASP's Argon2id minimum)
+pub const RECOMMENDED_MIN_MEMORY_MIB: u32 = 19;
+
+/// Smallest recommended iteration count at that memory cost
+pub const RECOMMENDED_MIN_ITERATIONS: u32 = 2;
 
 /// Parameters for Argon2id key derivation
 #[derive(Debug, Clone)]
@@ -32,31 +58,229 @@ impl Default for KdfParams {
 
 impl KdfParams {
     /// Create new KdfParams with custom values
-    pub fn new(memory_mb: u32, iterations: u32, parallelism: u32) -> Self {
-        Self {
-            memory_cost: memory_mb * 1024, // Convert MB to KiB
-            time_cost: iterations,
-            parallelism,
+    ///
+    /// `memory_mib` is in MiB, matching the `--argon2-memory` flag.
+    ///
+    /// # Errors
+    /// Returns [`ResqryptError::InvalidArgument`] when `memory_mib` is too
+    /// large to express in KiB, as [`KdfParams::from_mib`] does.
+    pub fn new(memory_mib: u32, iterations: u32, parallelism: u32) -> Result<Self> {
+        Ok(Self { time_cost: iterations, parallelism, ..Self::from_mib(memory_mib)? })
+    }
+
+    /// Default parameters with the memory cost given in MiB
+    ///
+    /// # Errors
+    /// Returns [`ResqryptError::InvalidArgument`] when `memory_mib` is too
+    /// large to express in KiB, rather than clamping it to about 4 TiB.
+    pub fn from_mib(memory_mib: u32) -> Result<Self> {
+        let memory_kib = memory_mib.checked_mul(1024).ok_or_else(|| {
+            ResqryptError::InvalidArgument(format!(
+                "Argon2 memory cost of {} MiB is too large (maximum {} MiB)",
+                memory_mib,
+                u32::MAX / 1024
+            ))
+        })?;
+        Ok(Self::from_kib(memory_kib))
+    }
+
+    /// Default parameters with the memory cost given in KiB
+    pub fn from_kib(memory_kib: u32) -> Self {
+        Self { memory_cost: memory_kib, ..Self::default() }
+    }
+
+    /// Memory cost in MiB, rounded down
+    pub fn memory_mib(&self) -> u32 {
+        self.memory_cost / 1024
+    }
+
+    /// Memory cost times iterations, in KiB × iterations
+    ///
+    /// A rough measure of the work an attacker spends per password guess.
+    pub fn work_factor(&self) -> u64 {
+        u64::from(self.memory_cost) * u64::from(self.time_cost)
+    }
+
+    /// Raise the iteration count until [`KdfParams::work_factor`] reaches
+    /// `min_mib` MiB × iterations
+    ///
+    /// Compensates for a lowered memory cost, so a config with little
+    /// memory is not accidentally weak. Memory and parallelism are left as
+    /// they are. Returns whether the iteration count was raised.
+    pub fn ensure_min_work_factor(&mut self, min_mib: u32) -> bool {
+        let min = u64::from(min_mib) * 1024;
+        if self.work_factor() >= min {
+            return false;
         }
+
+        let needed = min.div_ceil(u64::from(self.memory_cost.max(1)));
+        self.time_cost = u32::try_from(needed).unwrap_or(u32::MAX);
+        true
     }
 
-    /// Build Argon2 instance with these parameters
-    fn build_argon2(&self) -> Result<Argon2<'static>> {
-        let params = Params::new(
-            self.memory_cost,
-            self.time_cost,
-            self.parallelism,
-            Some(kdf_defaults::OUTPUT_LEN),
-        )
-        .map_err(|e| ResqryptError::CryptoError(format!("Invalid Argon2 params: {}", e)))?;
+    /// Describe how the parameters fall short of the recommended minimum
+    ///
+    /// Purely advisory, unlike [`KdfParams::validate`]: files with weaker
+    /// parameters still decrypt, but deserve re-encrypting.
+    pub fn weakness(&self) -> Option<String> {
+        if self.memory_cost < RECOMMENDED_MIN_MEMORY_MIB * 1024 {
+            let memory = if self.memory_cost % 1024 == 0 {
+                format!("{} MiB", self.memory_mib())
+            } else {
+                format!("{} KiB", self.memory_cost)
+            };
+            return Some(format!(
+                "Argon2id memory {}, below the recommended {} MiB",
+                memory, RECOMMENDED_MIN_MEMORY_MIB
+            ));
+        }
+        if self.time_cost < RECOMMENDED_MIN_ITERATIONS {
+            return Some(format!(
+                "{} Argon2id iteration, below the recommended {}",
+                self.time_cost, RECOMMENDED_MIN_ITERATIONS
+            ));
+        }
+        None
+    }
+
+    /// Check the parameters against Argon2's limits
+    ///
+    /// Argon2 needs at least 8 KiB of memory per lane, so `memory_cost` must
+    /// be at least `8 * parallelism` KiB.
+    pub fn validate(&self) -> Result<()> {
+        if self.time_cost == 0 {
+            return Err(ResqryptError::InvalidArgument(
+                "Argon2 iterations must be at least 1".to_string(),
+            ));
+        }
+        if self.parallelism == 0 {
+            return Err(ResqryptError::InvalidArgument(
+                "Argon2 parallelism must be at least 1".to_string(),
+            ));
+        }
+        if self.parallelism > Params::MAX_P_COST {
+            return Err(ResqryptError::InvalidArgument(format!(
+                "Argon2 parallelism {} exceeds the maximum of {}",
+                self.parallelism,
+                Params::MAX_P_COST
+            )));
+        }
+
+        let min_kib = self.parallelism.saturating_mul(8);
+        if self.memory_cost < min_kib {
+            // `--argon2-memory` is given in MiB, so also name the minimum in MiB
+            return Err(ResqryptError::InvalidArgument(format!(
+                "Argon2 memory cost {} KiB is below the minimum of {} KiB (8 KiB × parallelism \
+                 {}); use at least {} MiB",
+                self.memory_cost,
+                min_kib,
+                self.parallelism,
+                min_kib.div_ceil(1024)
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Check that the memory cost is at most `max_kib` KiB, as for
+    /// parameters read from a header that is not yet authenticated
+    ///
+    /// # Errors
+    /// Returns [`ResqryptError::InvalidArgument`] above the limit.
+    pub fn check_memory_limit(&self, max_kib: u32) -> Result<()> {
+        if self.memory_cost > max_kib {
+            return Err(ResqryptError::InvalidArgument(format!(
+                "Argon2 memory cost of {} MiB exceeds the limit of {} MiB; raise the limit \
+                 (--max-argon2-memory) only for a file you trust to need that much",
+                self.memory_mib(),
+                max_kib / 1024
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Check that parallelism is reasonable for a machine with `cpus`
+    /// logical CPUs
+    ///
+    /// Lanes beyond [`MAX_PARALLELISM_PER_CPU`] per CPU only slow derivation
+    /// down without adding security. This is only checked when encrypting;
+    /// existing files must still decrypt on smaller machines.
+    pub fn check_parallelism(&self, cpus: u32) -> Result<()> {
+        let max = cpus.max(1).saturating_mul(MAX_PARALLELISM_PER_CPU);
+        if self.parallelism > max {
+            return Err(ResqryptError::InvalidArgument(format!(
+                "Argon2 parallelism {} is more than {}× the {} available CPUs (maximum {})",
+                self.parallelism, MAX_PARALLELISM_PER_CPU, cpus, max
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Build Argon2 instance with these parameters, producing `output_len`
+    /// bytes
+    fn build_argon2(&self, output_len: usize) -> Result<Argon2<'static>> {
+        self.validate()?;
+
+        let params =
+            Params::new(self.memory_cost, self.time_cost, self.parallelism, Some(output_len))
+                .map_err(|e| ResqryptError::CryptoError(format!("Invalid Argon2 params: {}", e)))?;
 
         Ok(Argon2::new(Algorithm::Argon2id, Version::V0x13, params))
     }
 }
 
+impl fmt::Display for KdfParams {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.memory_cost % 1024 == 0 {
+            write!(f, "{} MiB ({} KiB)", self.memory_mib(), self.memory_cost)?;
+        } else {
+            write!(f, "{} KiB", self.memory_cost)?;
+        }
+        write!(f, ", {} iterations, parallelism {}", self.time_cost, self.parallelism)
+    }
+}
+
 /// Generate a random salt for key derivation
 pub fn generate_salt() -> [u8; 32] {
-    rand::rng().random()
+    random_bytes()
+}
+
+/// A derived key
+///
+/// The bytes are zeroed when the key is dropped, and `Debug` prints
+/// `SecretKey([REDACTED])` so keys never end up in logs. There is
+/// deliberately no `Display`, `Copy` or `PartialEq`; use
+/// [`SecretKey::as_bytes`] where the raw key is needed. Keys are usually
+/// [`kdf_defaults::OUTPUT_LEN`] bytes, but [`derive_key_len`] derives any
+/// length.
+#[derive(Clone)]
+pub struct SecretKey(Box<[u8]>);
+
+impl SecretKey {
+    /// The raw key bytes
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl<const N: usize> From<[u8; N]> for SecretKey {
+    fn from(bytes: [u8; N]) -> Self {
+        Self(Box::new(bytes))
+    }
+}
+
+impl fmt::Debug for SecretKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SecretKey([REDACTED])")
+    }
+}
+
+impl Drop for SecretKey {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
 }
 
 /// Derive an encryption key from a password using Argon2id
@@ -67,21 +291,107 @@ pub fn generate_salt() -> [u8; 32] {
 /// * `params` - KDF parameters (use `KdfParams::default()` for standard security)
 ///
 /// # Returns
-/// A 32-byte key suitable for AES-256
-pub fn derive_key(password: &[u8], salt: &[u8; 32], params: &KdfParams) -> Result<[u8; 32]> {
-    let argon2 = params.build_argon2()?;
+/// A 32-byte key suitable for AES-256, zeroed when dropped
+pub fn derive_key(password: &[u8], salt: &[u8; 32], params: &KdfParams) -> Result<SecretKey> {
+    derive_key_len(password, salt, params, kdf_defaults::OUTPUT_LEN)
+}
+
+/// Derive a key of `len` bytes, such as a cipher's
+/// [`CipherKind::key_len`](crate::crypto::CipherKind::key_len)
+///
+/// The output is not a prefix of a longer key: Argon2 mixes the length
+/// into the hash, so each length gives an unrelated key. Argon2 accepts
+/// lengths from 4 bytes up.
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(
+            memory_kib = params.memory_cost,
+            time_cost = params.time_cost,
+            parallelism = params.parallelism,
+            key_len = len,
+            elapsed_ms = tracing::field::Empty
+        )
+    )
+)]
+pub fn derive_key_len(
+    password: &[u8],
+    salt: &[u8; 32],
+    params: &KdfParams,
+    len: usize,
+) -> Result<SecretKey> {
+    let _stats = SpanStats::start();
+    let mut key = SecretKey(vec![0u8; len].into_boxed_slice());
+    derive_key_into(password, salt, params, &mut key.0)?;
+    Ok(key)
+}
+
+/// Derive a key filling `key`
+fn derive_key_into(
+    password: &[u8],
+    salt: &[u8; 32],
+    params: &KdfParams,
+    key: &mut [u8],
+) -> Result<()> {
+    let argon2 = params.build_argon2(key.len())?;
 
-    let mut key = [0u8; 32];
     argon2
-        .hash_password_into(password, salt, &mut key)
+        .hash_password_into(password, salt, key)
         .map_err(|e| ResqryptError::CryptoError(format!("Key derivation failed: {}", e)))?;
+    check_key(key)
+}
 
-    Ok(key)
+/// Mix a secret pepper into a password before key derivation
+///
+/// Returns the keyed BLAKE2b-512 MAC of the password under the pepper,
+/// which Argon2id then stretches in place of the password. Peppers longer
+/// than a BLAKE2b key (64 bytes) are hashed first, as HMAC does with long
+/// keys. The pepper is never stored, so a stolen file cannot be attacked
+/// offline without it.
+pub fn apply_pepper(password: &[u8], pepper: &[u8]) -> Zeroizing<Vec<u8>> {
+    let hashed;
+    let key = if pepper.len() > 64 {
+        hashed = Zeroizing::new(Blake2b512::digest(pepper).to_vec());
+        hashed.as_slice()
+    } else {
+        pepper
+    };
+    let mut mac = <Blake2bMac512 as KeyInit>::new_from_slice(key).expect("key is at most 64 bytes");
+    Mac::update(&mut mac, password);
+    Zeroizing::new(mac.finalize().into_bytes().to_vec())
+}
+
+/// Short fingerprint of a derived key, for diagnostics
+///
+/// The first 8 bytes of a domain-separated BLAKE2b-512 hash of the key, in
+/// hex: enough to tell whether two runs derived the same key, without
+/// revealing anything usable about it.
+pub fn key_fingerprint(key: &[u8]) -> String {
+    let digest =
+        Blake2b512::new().chain_update(b"resqrypt key fingerprint").chain_update(key).finalize();
+    digest[..8].iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Refuse a degenerate all-zero key
+///
+/// Argon2 never produces one in practice, so this only fires on a
+/// misconfiguration or a bug that zeroed the output buffer.
+fn check_key(key: &[u8]) -> Result<()> {
+    if key.iter().all(|&b| b == 0) {
+        return Err(ResqryptError::CryptoError(
+            "Key derivation produced an all-zero key".to_string(),
+        ));
+    }
+
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::crypto::CipherKind;
 
     #[test]
     fn test_derive_key_basic() {
@@ -91,9 +401,31 @@ mod tests {
 
         let key = derive_key(password, &salt, &params).unwrap();
 
-        assert_eq!(key.len(), 32);
+        assert_eq!(key.as_bytes().len(), 32);
         // Key should not be all zeros
-        assert!(key.iter().any(|&b| b != 0));
+        assert!(key.as_bytes().iter().any(|&b| b != 0));
+    }
+
+    #[test]
+    fn test_secret_key_debug_redacted() {
+        let key = SecretKey::from([0x41; 32]);
+        assert_eq!(format!("{:?}", key), "SecretKey([REDACTED])");
+        assert_eq!(key.clone().as_bytes(), &[0x41; 32]);
+
+        let params = KdfParams { memory_cost: 1024, time_cost: 1, parallelism: 1 };
+        let key = derive_key_len(b"pw", &[3u8; 32], &params, 64).unwrap();
+        assert_eq!(format!("{:?}", key), "SecretKey([REDACTED])");
+    }
+
+    #[test]
+    fn test_key_fingerprint() {
+        let key = [7u8; 32];
+        let fingerprint = key_fingerprint(&key);
+
+        assert_eq!(fingerprint.len(), 16);
+        assert_eq!(fingerprint, key_fingerprint(&key));
+        assert_ne!(fingerprint, key_fingerprint(&[8u8; 32]));
+        assert!(!fingerprint.starts_with("0707"));
     }
 
     #[test]
@@ -105,7 +437,7 @@ mod tests {
         let key1 = derive_key(password, &salt, &params).unwrap();
         let key2 = derive_key(password, &salt, &params).unwrap();
 
-        assert_eq!(key1, key2);
+        assert_eq!(key1.as_bytes(), key2.as_bytes());
     }
 
     #[test]
@@ -116,7 +448,7 @@ mod tests {
         let key1 = derive_key(b"password1", &salt, &params).unwrap();
         let key2 = derive_key(b"password2", &salt, &params).unwrap();
 
-        assert_ne!(key1, key2);
+        assert_ne!(key1.as_bytes(), key2.as_bytes());
     }
 
     #[test]
@@ -130,16 +462,209 @@ mod tests {
         let key1 = derive_key(password, &salt1, &params).unwrap();
         let key2 = derive_key(password, &salt2, &params).unwrap();
 
-        assert_ne!(key1, key2);
+        assert_ne!(key1.as_bytes(), key2.as_bytes());
     }
 
     #[test]
     fn test_custom_params() {
         let password = b"test";
         let salt = generate_salt();
-        let params = KdfParams::new(32, 2, 2); // Lower params for faster testing
+        let params = KdfParams::new(32, 2, 2).unwrap(); // Lower params for faster testing
 
         let key = derive_key(password, &salt, &params).unwrap();
-        assert_eq!(key.len(), 32);
+        assert_eq!(key.as_bytes().len(), 32);
+    }
+
+    #[test]
+    fn test_memory_unit_conversions() {
+        let params = KdfParams::from_mib(64).unwrap();
+        assert_eq!(params.memory_cost, 64 * 1024);
+        assert_eq!(params.memory_mib(), 64);
+        assert_eq!(params.time_cost, kdf_defaults::TIME_COST);
+
+        let params = KdfParams::from_kib(1536);
+        assert_eq!(params.memory_cost, 1536);
+        assert_eq!(params.memory_mib(), 1);
+
+        let params = KdfParams::new(32, 2, 1).unwrap();
+        assert_eq!(params.memory_cost, 32 * 1024);
+        assert_eq!(params.memory_mib(), 32);
+
+        // Too large for KiB in a u32: an error, not a clamped 4 TiB
+        assert_eq!(KdfParams::from_mib(u32::MAX / 1024).unwrap().memory_mib(), u32::MAX / 1024);
+        for memory_mib in [u32::MAX / 1024 + 1, u32::MAX] {
+            assert!(matches!(
+                KdfParams::new(memory_mib, 1, 1),
+                Err(ResqryptError::InvalidArgument(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_display_labels_units() {
+        assert_eq!(
+            KdfParams::new(64, 3, 4).unwrap().to_string(),
+            "64 MiB (65536 KiB), 3 iterations, parallelism 4"
+        );
+        assert_eq!(
+            KdfParams::from_kib(1000).to_string(),
+            format!(
+                "1000 KiB, {} iterations, parallelism {}",
+                kdf_defaults::TIME_COST,
+                kdf_defaults::PARALLELISM
+            )
+        );
+    }
+
+    #[test]
+    fn test_validate_minimum_memory() {
+        let params = KdfParams { memory_cost: 31, time_cost: 1, parallelism: 4 };
+        assert!(matches!(params.validate(), Err(ResqryptError::InvalidArgument(_))));
+
+        let params = KdfParams { memory_cost: 32, time_cost: 1, parallelism: 4 };
+        assert!(params.validate().is_ok());
+
+        let params = KdfParams { memory_cost: 1024, time_cost: 0, parallelism: 1 };
+        assert!(params.validate().is_err());
+
+        let params = KdfParams { memory_cost: 1024, time_cost: 1, parallelism: 0 };
+        assert!(params.validate().is_err());
+    }
+
+    #[test]
+    fn test_minimum_memory_boundaries() {
+        // Argon2's own floor is 8 KiB for a single lane
+        assert!(KdfParams { memory_cost: 7, time_cost: 1, parallelism: 1 }.validate().is_err());
+        assert!(KdfParams { memory_cost: 8, time_cost: 1, parallelism: 1 }.validate().is_ok());
+
+        // `--argon2-memory 0` and the MiB boundary at 128 lanes
+        assert!(KdfParams::new(0, 1, 1).unwrap().validate().is_err());
+        assert!(KdfParams::new(1, 1, 128).unwrap().validate().is_ok());
+        match KdfParams::new(1, 1, 129).unwrap().validate() {
+            Err(ResqryptError::InvalidArgument(msg)) => {
+                assert!(msg.contains("1032 KiB"), "{}", msg);
+                assert!(msg.contains("at least 2 MiB"), "{}", msg);
+            }
+            other => panic!("expected InvalidArgument, got {:?}", other),
+        }
+
+        // Rejected before any derivation is attempted
+        let params = KdfParams::new(0, 1, 1).unwrap();
+        assert!(matches!(
+            derive_key(b"pw", &[0u8; 32], &params),
+            Err(ResqryptError::InvalidArgument(_))
+        ));
+    }
+
+    #[test]
+    fn test_validate_parallelism_bounds() {
+        let params = KdfParams { memory_cost: 1024, time_cost: 1, parallelism: 0 };
+        assert!(matches!(params.validate(), Err(ResqryptError::InvalidArgument(_))));
+
+        let params =
+            KdfParams { memory_cost: u32::MAX, time_cost: 1, parallelism: Params::MAX_P_COST + 1 };
+        assert!(matches!(params.validate(), Err(ResqryptError::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn test_check_memory_limit() {
+        let params = KdfParams::from_kib(DEFAULT_MAX_MEMORY_COST);
+        assert!(params.check_memory_limit(DEFAULT_MAX_MEMORY_COST).is_ok());
+
+        let params = KdfParams::from_kib(u32::MAX);
+        match params.check_memory_limit(DEFAULT_MAX_MEMORY_COST) {
+            Err(ResqryptError::InvalidArgument(msg)) => {
+                assert!(msg.contains("limit of 2048 MiB"), "{}", msg)
+            }
+            other => panic!("expected InvalidArgument, got {:?}", other),
+        }
+        assert!(params.check_memory_limit(u32::MAX).is_ok());
+    }
+
+    #[test]
+    fn test_check_parallelism_against_cpus() {
+        let params = KdfParams::new(64, 3, 16).unwrap();
+        assert!(params.check_parallelism(4).is_ok());
+        assert!(matches!(params.check_parallelism(3), Err(ResqryptError::InvalidArgument(_))));
+
+        // An unknown CPU count is treated as a single CPU
+        let params = KdfParams::new(64, 3, 4).unwrap();
+        assert!(params.check_parallelism(0).is_ok());
+    }
+
+    #[test]
+    fn test_apply_pepper() {
+        let peppered = apply_pepper(b"pw", b"site secret");
+        assert_eq!(peppered.len(), 64);
+        assert_eq!(apply_pepper(b"pw", b"site secret"), peppered);
+        assert_ne!(apply_pepper(b"pw", b"other secret"), peppered);
+        assert_ne!(apply_pepper(b"pw2", b"site secret"), peppered);
+
+        // Long peppers are hashed down to a key instead of rejected
+        let long = [7u8; 100];
+        assert_eq!(apply_pepper(b"pw", &long), apply_pepper(b"pw", &long));
+        assert_ne!(apply_pepper(b"pw", &long), apply_pepper(b"pw", &long[..99]));
+    }
+
+    #[test]
+    fn test_derive_key_len() {
+        let params = KdfParams { memory_cost: 1024, time_cost: 1, parallelism: 1 };
+        let salt = [3u8; 32];
+
+        // The default length matches derive_key and every cipher's key size
+        let key = derive_key(b"pw", &salt, &params).unwrap();
+        for cipher in [CipherKind::Aes256Gcm, CipherKind::XChaCha20Poly1305] {
+            let len = cipher.key_len();
+            let derived = derive_key_len(b"pw", &salt, &params, len).unwrap();
+            assert_eq!(derived.as_bytes(), key.as_bytes());
+        }
+
+        // Other lengths give unrelated keys, not extensions of it
+        let long = derive_key_len(b"pw", &salt, &params, 64).unwrap();
+        assert_eq!(long.as_bytes().len(), 64);
+        assert_ne!(&long.as_bytes()[..32], key.as_bytes());
+        assert!(derive_key_len(b"pw", &salt, &params, 2).is_err());
+    }
+
+    #[test]
+    fn test_ensure_min_work_factor() {
+        // The defaults already meet the default minimum
+        let mut params = KdfParams::default();
+        assert!(!params.ensure_min_work_factor(DEFAULT_MIN_WORK_FACTOR));
+        assert_eq!(params.time_cost, kdf_defaults::TIME_COST);
+
+        // 16 MiB needs 12 iterations to match 64 MiB × 3
+        let mut params = KdfParams::new(16, 3, 4).unwrap();
+        assert!(params.ensure_min_work_factor(DEFAULT_MIN_WORK_FACTOR));
+        assert_eq!(params.time_cost, 12);
+        assert_eq!(params.memory_cost, 16 * 1024);
+
+        // Rounded up when memory does not divide the minimum
+        let mut params = KdfParams::new(50, 1, 4).unwrap();
+        assert!(params.ensure_min_work_factor(DEFAULT_MIN_WORK_FACTOR));
+        assert_eq!(params.time_cost, 4);
+        assert!(params.work_factor() >= u64::from(DEFAULT_MIN_WORK_FACTOR) * 1024);
+    }
+
+    #[test]
+    fn test_weakness() {
+        assert!(KdfParams::default().weakness().is_none());
+        assert!(KdfParams::new(19, 2, 1).unwrap().weakness().is_none());
+
+        let weak = KdfParams::new(8, 3, 1).unwrap().weakness().unwrap();
+        assert!(weak.contains("memory 8 MiB"), "{}", weak);
+        assert!(KdfParams::from_kib(512).weakness().unwrap().contains("512 KiB"));
+        assert!(
+            KdfParams::new(64, 1, 4).unwrap().weakness().unwrap().contains("1 Argon2id iteration")
+        );
+    }
+
+    #[test]
+    fn test_check_key_rejects_all_zero() {
+        assert!(matches!(check_key(&[0u8; 32]), Err(ResqryptError::CryptoError(_))));
+
+        let mut key = [0u8; 32];
+        key[31] = 1;
+        assert!(check_key(&key).is_ok());
     }
 }
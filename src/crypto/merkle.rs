@@ -0,0 +1,286 @@
+//! Merkle tree over chunk tags
+//!
+//! With the Merkle flag ([`crate::ext_flags::MERKLE`]), the
+//! chunks are followed by a footer holding a binary hash tree over their
+//! nonces and auth tags. Any chunk can then be bound to its position with
+//! a path of about `log2(chunks)` hashes, without reading the chunks
+//! before it (see [`ChunkReader::verify_range`]), which content-defined
+//! chunks otherwise only get from the final chunk.
+//!
+//! The tree:
+//! - Leaf `i` = BLAKE2b-256(0x00 || nonce || tag) of chunk `i`, with the
+//!   nonce it was sealed with, stored or derived, and its 16-byte tag
+//! - Node = BLAKE2b-256(0x01 || left || right); the last node of a level
+//!   with an odd number of nodes moves up unchanged
+//! - The root is the single node of the top level
+//!
+//! The footer follows the final chunk and its length trailer:
+//! - Version (1 byte): [`FOOTER_VERSION`]
+//! - Leaf count (8 bytes, LE): the number of chunks
+//! - Root (32 bytes)
+//! - MAC (32 bytes): BLAKE2b-256 keyed with the file key over a domain
+//!   string, the serialized header, the version, the leaf count and the
+//!   root
+//! - Tree: every level below the root, from the leaves up, at 32 bytes
+//!   per node; each level holds half of the one below, rounded up
+//!
+//! The root cannot be associated data of the final chunk, whose tag is
+//! one of its leaves, so the footer carries its own MAC; covering the
+//! header ties it to this file. The tree nodes are not authenticated by
+//! themselves: a wrong node only yields a path that misses the root.
+//!
+//! A full decryption recomputes the tree from the chunks and checks the
+//! footer and every stored node. A ranged check authenticates the footer,
+//! hashes the leaf of each chunk it opens and walks up to the root,
+//! reading the sibling at each level from the stored tree.
+//!
+//! Both sides keep 32 bytes per chunk until the footer, e.g. 16 MiB for a
+//! 32 GiB payload in 64 KiB chunks, and twice that once the tree is built.
+//!
+//! [`ChunkReader::verify_range`]: crate::crypto::stream::ChunkReader::verify_range
+
+use std::io::{Read, Write};
+
+use blake2::digest::consts::U32;
+use blake2::digest::{KeyInit, Mac};
+use blake2::{Blake2b, Blake2bMac, Digest};
+
+use crate::crypto::kdf::SecretKey;
+use crate::error::{ResqryptError, Result};
+
+/// Footer version this release writes and reads
+pub const FOOTER_VERSION: u8 = 1;
+
+/// Size of a tree node
+pub const HASH_LEN: usize = 32;
+
+/// Size of the footer before the tree
+pub const FOOTER_LEN: usize = 1 + 8 + 2 * HASH_LEN;
+
+/// A tree node
+pub type Hash = [u8; HASH_LEN];
+
+type Blake2b256 = Blake2b<U32>;
+type Blake2bMac256 = Blake2bMac<U32>;
+
+/// Leaf of a chunk sealed with `nonce` whose ciphertext ends in `tag`
+pub fn leaf_hash(nonce: &[u8], tag: &[u8]) -> Hash {
+    let mut hash = Blake2b256::new();
+    Digest::update(&mut hash, [0u8]);
+    Digest::update(&mut hash, nonce);
+    Digest::update(&mut hash, tag);
+    hash.finalize().into()
+}
+
+/// Parent of two nodes
+fn node_hash(left: &Hash, right: &Hash) -> Hash {
+    let mut hash = Blake2b256::new();
+    Digest::update(&mut hash, [1u8]);
+    Digest::update(&mut hash, left);
+    Digest::update(&mut hash, right);
+    hash.finalize().into()
+}
+
+/// Every level of the tree over `leaves`, from the leaves up to the root
+pub fn levels(leaves: Vec<Hash>) -> Vec<Vec<Hash>> {
+    let mut levels = vec![leaves];
+    while let Some(level) = levels.last().filter(|level| level.len() > 1) {
+        let parents = level
+            .chunks(2)
+            .map(|pair| match pair {
+                [left, right] => node_hash(left, right),
+                [only] => *only,
+                _ => unreachable!("chunks of two"),
+            })
+            .collect();
+        levels.push(parents);
+    }
+    levels
+}
+
+/// Number of nodes of each level below the root, from the leaves up
+fn level_sizes(leaf_count: u64) -> Vec<u64> {
+    let mut sizes = Vec::new();
+    let mut size = leaf_count;
+    while size > 1 {
+        sizes.push(size);
+        size = size.div_ceil(2);
+    }
+    sizes
+}
+
+/// Size of the stored tree of `leaf_count` leaves
+pub fn tree_len(leaf_count: u64) -> u64 {
+    level_sizes(leaf_count).iter().sum::<u64>() * HASH_LEN as u64
+}
+
+/// Siblings on the path from leaf `index` up to the root, as node
+/// positions in the stored tree and whether each is the left one
+///
+/// Levels where the node moves up unchanged have no sibling.
+pub fn path(leaf_count: u64, index: u64) -> Vec<(u64, bool)> {
+    let mut siblings = Vec::new();
+    let (mut offset, mut index) = (0, index);
+    for size in level_sizes(leaf_count) {
+        let sibling = index ^ 1;
+        if sibling < size {
+            siblings.push((offset + sibling, sibling < index));
+        }
+        offset += size;
+        index /= 2;
+    }
+    siblings
+}
+
+/// Root reached from `leaf` through `siblings`, as [`path`] orders them
+pub fn root_from_path(leaf: Hash, siblings: impl IntoIterator<Item = (Hash, bool)>) -> Hash {
+    siblings.into_iter().fold(leaf, |node, (sibling, left)| {
+        if left { node_hash(&sibling, &node) } else { node_hash(&node, &sibling) }
+    })
+}
+
+/// Write every level of `levels` below the root
+pub fn write_tree<W: Write>(out: &mut W, levels: &[Vec<Hash>]) -> Result<()> {
+    for node in levels[..levels.len() - 1].iter().flatten() {
+        out.write_all(node)?;
+    }
+    Ok(())
+}
+
+/// The fixed part of the footer: leaf count and root, with their MAC
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Footer {
+    /// Number of chunks
+    pub leaf_count: u64,
+    /// Root of the tree over their tags
+    pub root: Hash,
+    mac: Hash,
+}
+
+impl Footer {
+    /// Footer for `root`, authenticated with `key` for the file whose
+    /// serialized header is `header_aad`
+    pub fn new(key: &SecretKey, header_aad: &[u8], leaf_count: u64, root: Hash) -> Self {
+        let mac = footer_mac(key, header_aad, leaf_count, &root).finalize().into_bytes().into();
+        Self { leaf_count, root, mac }
+    }
+
+    /// Read a footer, checking its version but not its MAC
+    pub fn read<R: Read>(reader: &mut R) -> Result<Self> {
+        let mut bytes = [0u8; FOOTER_LEN];
+        reader.read_exact(&mut bytes).map_err(|e| match e.kind() {
+            std::io::ErrorKind::UnexpectedEof => ResqryptError::InvalidFormat(
+                "File is truncated (missing Merkle footer)".to_string(),
+            ),
+            _ => e.into(),
+        })?;
+        if bytes[0] != FOOTER_VERSION {
+            return Err(ResqryptError::InvalidFormat(format!(
+                "Unsupported Merkle footer version {}",
+                bytes[0]
+            )));
+        }
+
+        let (count, hashes) = bytes[1..].split_at(8);
+        let (root, mac) = hashes.split_at(HASH_LEN);
+        Ok(Self {
+            leaf_count: u64::from_le_bytes(count.try_into().expect("8 bytes")),
+            root: root.try_into().expect("32 bytes"),
+            mac: mac.try_into().expect("32 bytes"),
+        })
+    }
+
+    /// Serialized footer, without the tree
+    pub fn to_bytes(&self) -> [u8; FOOTER_LEN] {
+        let mut bytes = [0u8; FOOTER_LEN];
+        bytes[0] = FOOTER_VERSION;
+        bytes[1..9].copy_from_slice(&self.leaf_count.to_le_bytes());
+        bytes[9..9 + HASH_LEN].copy_from_slice(&self.root);
+        bytes[9 + HASH_LEN..].copy_from_slice(&self.mac);
+        bytes
+    }
+
+    /// Check the MAC, in constant time
+    pub fn authenticate(&self, key: &SecretKey, header_aad: &[u8]) -> Result<()> {
+        footer_mac(key, header_aad, self.leaf_count, &self.root)
+            .verify_slice(&self.mac)
+            .map_err(|_| ResqryptError::CryptoError("Merkle footer failed authentication".into()))
+    }
+}
+
+/// MAC state over the footer fields, per the module docs
+fn footer_mac(key: &SecretKey, header_aad: &[u8], leaf_count: u64, root: &Hash) -> Blake2bMac256 {
+    let mut mac = <Blake2bMac256 as KeyInit>::new_from_slice(key.as_bytes())
+        .expect("BLAKE2b accepts 32-byte keys");
+    Mac::update(&mut mac, b"resqrypt merkle footer");
+    Mac::update(&mut mac, &(header_aad.len() as u64).to_le_bytes());
+    Mac::update(&mut mac, header_aad);
+    Mac::update(&mut mac, &[FOOTER_VERSION]);
+    Mac::update(&mut mac, &leaf_count.to_le_bytes());
+    Mac::update(&mut mac, root);
+    mac
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaves(count: u64) -> Vec<Hash> {
+        (0..count).map(|i| leaf_hash(&i.to_le_bytes(), b"tag")).collect()
+    }
+
+    #[test]
+    fn test_paths_reach_root() {
+        for count in 1..=20u64 {
+            let levels = levels(leaves(count));
+            let root = levels.last().unwrap()[0];
+            let mut tree = Vec::new();
+            write_tree(&mut tree, &levels).unwrap();
+            assert_eq!(tree.len() as u64, tree_len(count), "{} leaves", count);
+
+            for (index, leaf) in leaves(count).into_iter().enumerate() {
+                let siblings = path(count, index as u64).into_iter().map(|(node, left)| {
+                    let start = node as usize * HASH_LEN;
+                    (tree[start..start + HASH_LEN].try_into().unwrap(), left)
+                });
+                assert_eq!(root_from_path(leaf, siblings), root, "leaf {} of {}", index, count);
+            }
+        }
+    }
+
+    #[test]
+    fn test_path_binds_position() {
+        let levels = levels(leaves(5));
+        let root = levels.last().unwrap()[0];
+        let mut tree = Vec::new();
+        write_tree(&mut tree, &levels).unwrap();
+        let siblings = |index| {
+            path(5, index).into_iter().map(|(node, left)| {
+                let start = node as usize * HASH_LEN;
+                (<Hash>::try_from(&tree[start..start + HASH_LEN]).unwrap(), left)
+            })
+        };
+
+        // Leaf 1 on the path of leaf 2 misses the root
+        assert_ne!(root_from_path(leaves(5)[1], siblings(2)), root);
+    }
+
+    #[test]
+    fn test_footer_authenticated() {
+        let key = SecretKey::from([7u8; 32]);
+        let footer = Footer::new(&key, b"header", 3, [1u8; HASH_LEN]);
+        let read = Footer::read(&mut &footer.to_bytes()[..]).unwrap();
+        assert_eq!(read, footer);
+        read.authenticate(&key, b"header").unwrap();
+
+        assert!(read.authenticate(&key, b"other header").is_err());
+        assert!(read.authenticate(&SecretKey::from([8u8; 32]), b"header").is_err());
+
+        let mut bytes = footer.to_bytes();
+        bytes[1] ^= 1;
+        assert!(Footer::read(&mut &bytes[..]).unwrap().authenticate(&key, b"header").is_err());
+
+        bytes[0] = 2;
+        assert!(matches!(Footer::read(&mut &bytes[..]), Err(ResqryptError::InvalidFormat(_))));
+    }
+}
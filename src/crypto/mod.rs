@@ -2,12 +2,40 @@
 //!
 //! This module provides encryption and decryption functionality using:
 //! - Argon2id for password-based key derivation
-//! - AES-256-GCM for authenticated encryption
+//! - AES-256-GCM or XChaCha20-Poly1305 for authenticated encryption
 
 pub mod aes;
+#[cfg(feature = "age")]
+pub mod age;
+#[cfg(feature = "async")]
+pub mod async_io;
+pub mod cipher;
 pub mod format;
+pub mod info;
 pub mod kdf;
+pub mod merkle;
+pub mod nonce;
+pub mod padding;
+pub mod rng;
+pub mod stream;
+#[cfg(test)]
+mod vectors;
+pub mod xchacha;
 
 pub use aes::{decrypt_data, encrypt_data};
-pub use format::{FileHeader, read_header, write_header};
-pub use kdf::{KdfParams, derive_key};
+#[cfg(feature = "async")]
+pub use async_io::{AsyncDecryptReader, AsyncEncryptWriter};
+pub use cipher::{Cipher, CipherKind, hardware_aes};
+pub use format::{
+    ExtendedFlags, FileHeader, FormatVersion, HeaderFlags, build_header_bytes, read_ciphertext,
+    read_encrypted, read_header, write_header,
+};
+pub use info::{FileInfo, inspect};
+pub use kdf::{
+    DEFAULT_MAX_MEMORY_COST, KdfParams, SecretKey, apply_pepper, derive_key, derive_key_len,
+    key_fingerprint,
+};
+pub use nonce::{NonceLedger, NonceSequence};
+pub use padding::{Padding, UnpadReader};
+pub use rng::{DETERMINISTIC_SEED_ENV, RandomSource};
+pub use stream::{ChunkReader, ChunkWriter, StreamLengths};
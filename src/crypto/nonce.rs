@@ -0,0 +1,266 @@
+//! Nonce management
+//!
+//! AES-GCM uses a 96-bit nonce. A fresh random nonce is safe when a key seals
+//! a single message, which is how the CLI mostly works: every file gets its
+//! own random salt and therefore its own derived key.
+//!
+//! The exception is [`crate::pipeline::Pipeline::reuse_salt`], which
+//! `encrypt --update` uses to rewrite a `--cdc` archive under the salt, and
+//! so the key, of the previous version. Its chunks are still safe, as they
+//! are not sealed with random nonces: each nonce is a keyed BLAKE2b over the
+//! final-chunk flag, the associated data and the plaintext. The same nonce
+//! therefore recurs only for an identical (associated data, plaintext) pair,
+//! which seals to an identical ciphertext and reveals nothing new.
+//!
+//! Library users who seal many messages under the *same* key must not rely on
+//! random nonces: the birthday bound makes a collision likely after roughly
+//! 2^32 messages, and a single repeated nonce breaks both confidentiality and
+//! authenticity. Use [`NonceSequence`] for that case, which derives each nonce
+//! from a base value and a counter and refuses to continue once the counter
+//! is exhausted.
+//!
+//! Callers who pick nonces themselves, passing them to
+//! [`crate::crypto::aes::encrypt_data`] or
+//! [`crate::crypto::xchacha::encrypt_data`] for deterministic output or test
+//! fixtures, can record each one in a [`NonceLedger`]. The ledger persists
+//! the (key fingerprint, nonce) pairs it has seen and refuses a repeat, so
+//! the same nonce is never used twice under one key, even across runs. The
+//! random nonces of normal operation need no ledger.
+
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use crate::crypto::aes::generate_nonce;
+use crate::crypto::kdf::key_fingerprint;
+use crate::error::{ResqryptError, Result};
+
+/// Counter-based nonce generator guaranteeing uniqueness under one key
+///
+/// Nonce `i` is the base nonce with the big-endian 32-bit counter `i` XORed
+/// into its last four bytes, so up to 2^32 distinct nonces can be produced.
+/// The base may have any cipher's nonce length; the chunks of a stream
+/// take their nonces from a sequence over the header's base nonce.
+#[derive(Debug, Clone)]
+pub struct NonceSequence {
+    base: Vec<u8>,
+    counter: u64,
+}
+
+impl NonceSequence {
+    /// Maximum number of nonces a single sequence can produce
+    pub const MAX_NONCES: u64 = 1 << 32;
+
+    /// Create a sequence starting from the given base nonce
+    ///
+    /// # Panics
+    /// Panics if `base` is shorter than the 4-byte counter.
+    pub fn new(base: impl Into<Vec<u8>>) -> Self {
+        let base = base.into();
+        assert!(base.len() >= 4, "a nonce sequence needs a base of at least 4 bytes");
+        Self { base, counter: 0 }
+    }
+
+    /// Create a sequence starting from a random AES-GCM base nonce
+    pub fn random() -> Self {
+        Self::new(generate_nonce())
+    }
+
+    /// Length of the nonces produced
+    pub fn nonce_len(&self) -> usize {
+        self.base.len()
+    }
+
+    /// Number of nonces handed out so far
+    pub fn position(&self) -> u64 {
+        self.counter
+    }
+
+    /// Number of nonces that can still be produced
+    pub fn remaining(&self) -> u64 {
+        Self::MAX_NONCES - self.counter
+    }
+
+    /// Produce the next nonce
+    ///
+    /// # Errors
+    /// Returns an error once [`NonceSequence::MAX_NONCES`] nonces have been
+    /// produced; the key must be rotated before sealing more messages.
+    pub fn next_nonce(&mut self) -> Result<Vec<u8>> {
+        let nonce = self.nonce_at(self.counter)?;
+        self.counter += 1;
+        Ok(nonce)
+    }
+
+    /// Nonce `index` of the sequence, without advancing it
+    ///
+    /// For opening messages out of order, such as the chunks of a stream
+    /// read from the middle.
+    ///
+    /// # Errors
+    /// Returns an error if `index` is past the last nonce.
+    pub fn nonce_at(&self, index: u64) -> Result<Vec<u8>> {
+        if index >= Self::MAX_NONCES {
+            return Err(ResqryptError::CryptoError(
+                "Nonce sequence exhausted: rotate the key before encrypting more data".to_string(),
+            ));
+        }
+
+        let mut nonce = self.base.clone();
+        let counter = (index as u32).to_be_bytes();
+        let tail = nonce.len() - counter.len();
+        for (byte, c) in nonce[tail..].iter_mut().zip(counter) {
+            *byte ^= c;
+        }
+        Ok(nonce)
+    }
+}
+
+/// Persistent record of the nonces used under each key
+///
+/// Stored as a text file with one `<key fingerprint> <nonce>` line per
+/// use, both in hex. Keys are identified only by their
+/// [`key_fingerprint`], so the file reveals nothing usable about them.
+#[derive(Debug)]
+pub struct NonceLedger {
+    file: File,
+    path: PathBuf,
+    used: HashSet<(String, String)>,
+}
+
+impl NonceLedger {
+    /// Open the ledger at `path`, creating it if needed
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let used = match fs::read_to_string(&path) {
+            Ok(contents) => contents
+                .lines()
+                .enumerate()
+                .filter(|(_, line)| !line.trim().is_empty())
+                .map(|(index, line)| {
+                    let (key, nonce) = line.trim().split_once(' ').ok_or_else(|| {
+                        ResqryptError::InvalidFormat(format!(
+                            "{} line {}: expected `<key fingerprint> <nonce>`",
+                            path.display(),
+                            index + 1
+                        ))
+                    })?;
+                    Ok((key.to_string(), nonce.to_string()))
+                })
+                .collect::<Result<_>>()?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashSet::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+
+        Ok(Self { file, path, used })
+    }
+
+    /// Number of recorded nonces
+    pub fn len(&self) -> usize {
+        self.used.len()
+    }
+
+    /// Whether no nonce has been recorded
+    pub fn is_empty(&self) -> bool {
+        self.used.is_empty()
+    }
+
+    /// Record that `nonce` is about to be used under `key`
+    ///
+    /// Call before encrypting. The entry is flushed to disk before this
+    /// returns, so a crash right after cannot lose it.
+    ///
+    /// # Errors
+    /// Returns an error if `nonce` was already recorded for `key`.
+    pub fn record(&mut self, key: &[u8], nonce: &[u8]) -> Result<()> {
+        let entry = (key_fingerprint(key), nonce.iter().map(|b| format!("{:02x}", b)).collect());
+        if self.used.contains(&entry) {
+            return Err(ResqryptError::CryptoError(format!(
+                "Nonce {} was already used with this key (recorded in {})",
+                entry.1,
+                self.path.display()
+            )));
+        }
+
+        writeln!(self.file, "{} {}", entry.0, entry.1)?;
+        self.file.sync_data()?;
+        self.used.insert(entry);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_first_nonce_is_base() {
+        let mut seq = NonceSequence::new([7u8; 12]);
+        assert_eq!(seq.next_nonce().unwrap(), [7u8; 12]);
+    }
+
+    #[test]
+    fn test_nonces_are_unique() {
+        let mut seq = NonceSequence::random();
+        let nonces: HashSet<_> = (0..1000).map(|_| seq.next_nonce().unwrap()).collect();
+
+        assert_eq!(nonces.len(), 1000);
+        assert_eq!(seq.position(), 1000);
+    }
+
+    #[test]
+    fn test_counter_only_touches_last_bytes() {
+        let mut seq = NonceSequence::new([0u8; 12]);
+        seq.next_nonce().unwrap();
+        let nonce = seq.next_nonce().unwrap();
+
+        assert_eq!(&nonce[..8], &[0u8; 8]);
+        assert_eq!(&nonce[8..], &[0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn test_nonce_at_matches_sequence() {
+        let mut seq = NonceSequence::new([9u8; 24]);
+        let nonces: Vec<_> = (0..3).map(|_| seq.next_nonce().unwrap()).collect();
+
+        assert_eq!(seq.nonce_len(), 24);
+        assert_eq!(seq.nonce_at(2).unwrap(), nonces[2]);
+        assert!(seq.nonce_at(NonceSequence::MAX_NONCES).is_err());
+    }
+
+    #[test]
+    fn test_exhaustion_errors() {
+        let mut seq = NonceSequence::new([0u8; 12]);
+        seq.counter = NonceSequence::MAX_NONCES - 1;
+
+        assert!(seq.next_nonce().is_ok());
+        assert_eq!(seq.remaining(), 0);
+
+        let result = seq.next_nonce();
+        assert!(matches!(result, Err(ResqryptError::CryptoError(_))));
+    }
+
+    #[test]
+    fn test_ledger_refuses_reuse() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("nonces");
+        let (key, other_key) = ([1u8; 32], [2u8; 32]);
+
+        let mut ledger = NonceLedger::open(&path).unwrap();
+        ledger.record(&key, &[7u8; 12]).unwrap();
+        ledger.record(&key, &[8u8; 12]).unwrap();
+        ledger.record(&other_key, &[7u8; 12]).unwrap();
+        assert!(matches!(ledger.record(&key, &[7u8; 12]), Err(ResqryptError::CryptoError(_))));
+        assert_eq!(ledger.len(), 3);
+
+        // Remembered across runs
+        drop(ledger);
+        let mut ledger = NonceLedger::open(&path).unwrap();
+        assert_eq!(ledger.len(), 3);
+        assert!(ledger.record(&key, &[8u8; 12]).is_err());
+        assert!(ledger.record(&key, &[9u8; 12]).is_ok());
+    }
+}
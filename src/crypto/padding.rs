@@ -0,0 +1,254 @@
+//! Length-hiding padding
+//!
+//! The size of an encrypted file follows the size of its compressed
+//! payload. With the padded flag, the payload is followed by a `0x80` byte
+//! and zero bytes up to a bucket boundary before it is encrypted, so files
+//! whose payloads fall in the same bucket have the same size.
+//!
+//! The padding is part of the encrypted stream: it is authenticated with
+//! the payload, and its start is found again after decryption by stripping
+//! the trailing zeros and the `0x80` before them (ISO/IEC 7816-4). The true
+//! length is therefore never stored in the clear. Trailing zeros are only
+//! counted while they are held back, so unpadding needs no memory beyond a
+//! read buffer however large the padding is.
+//!
+//! Padding trades size for privacy: rounding to a power of two can nearly
+//! double a file, and rounding to a multiple of a fixed size costs up to
+//! that size per file, while only revealing which bucket the payload falls
+//! in.
+
+use std::fmt;
+use std::io::{self, Read, Write};
+
+use crate::error::{ResqryptError, Result};
+
+/// Byte marking the start of the padding
+const MARKER: u8 = 0x80;
+
+/// Bucket scheme deciding the padded length
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Padding {
+    /// Pad to the next power of two
+    PowerOfTwo,
+    /// Pad to the next multiple of this many bytes
+    Multiple(u64),
+}
+
+impl Padding {
+    /// Length of a payload of `len` bytes once padded
+    ///
+    /// Always at least one byte longer than `len`, for the marker.
+    pub fn padded_len(&self, len: u64) -> Result<u64> {
+        let padded = len.checked_add(1).and_then(|min| match *self {
+            Padding::PowerOfTwo => min.checked_next_power_of_two(),
+            Padding::Multiple(size) if size > 0 => min.div_ceil(size).checked_mul(size),
+            Padding::Multiple(_) => None,
+        });
+
+        padded.ok_or_else(|| {
+            ResqryptError::InvalidArgument(format!("cannot pad {} bytes to a {}", len, self))
+        })
+    }
+}
+
+impl fmt::Display for Padding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Padding::PowerOfTwo => write!(f, "power of two"),
+            Padding::Multiple(size) => write!(f, "multiple of {} bytes", size),
+        }
+    }
+}
+
+/// Write the padding following a payload of `len` bytes
+///
+/// Returns the number of padding bytes written.
+pub fn write_padding<W: Write>(out: &mut W, len: u64, padding: Padding) -> Result<u64> {
+    let pad_len = padding.padded_len(len)? - len;
+
+    out.write_all(&[MARKER])?;
+    io::copy(&mut io::repeat(0).take(pad_len - 1), out)?;
+    Ok(pad_len)
+}
+
+/// Reader stripping the padding from the end of a decrypted payload
+///
+/// A `0x80` byte and the zeros after it are held back until a non-zero byte
+/// shows they are data, or the end of the input shows they are padding.
+pub struct UnpadReader<R: Read> {
+    inner: R,
+    buf: Box<[u8]>,
+    start: usize,
+    end: usize,
+    /// Number of zeros held back after a held-back marker
+    held: Option<u64>,
+    /// Held-back marker and zeros that turned out to be data
+    release: Option<u64>,
+    done: bool,
+}
+
+impl<R: Read> UnpadReader<R> {
+    /// Wrap a reader over a padded payload
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            buf: vec![0u8; 8192].into_boxed_slice(),
+            start: 0,
+            end: 0,
+            held: None,
+            release: None,
+            done: false,
+        }
+    }
+
+    /// Copy held-back bytes that turned out to be data into `out`
+    fn released(&mut self, out: &mut [u8], zeros: u64) -> usize {
+        // The marker goes first, on its own
+        if self.release.is_none() {
+            out[0] = MARKER;
+            self.release = Some(zeros);
+            return 1;
+        }
+
+        let n = zeros.min(out.len() as u64) as usize;
+        out[..n].fill(0);
+        self.release = (zeros > n as u64).then(|| zeros - n as u64);
+        n
+    }
+}
+
+impl<R: Read> Read for UnpadReader<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if out.is_empty() {
+            return Ok(0);
+        }
+
+        loop {
+            if let Some(zeros) = self.release {
+                if zeros > 0 {
+                    return Ok(self.released(out, zeros));
+                }
+                self.release = None;
+            }
+
+            if self.start == self.end {
+                if self.done {
+                    return Ok(0);
+                }
+                self.start = 0;
+                self.end = self.inner.read(&mut self.buf)?;
+                if self.end == 0 {
+                    // Whatever is held back is the padding
+                    self.done = true;
+                    if self.held.take().is_none() {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            ResqryptError::InvalidFormat("Padding marker missing".to_string()),
+                        ));
+                    }
+                }
+                continue;
+            }
+
+            let data = &self.buf[self.start..self.end];
+            if let Some(zeros) = self.held {
+                let run = data.iter().take_while(|&&b| b == 0).count();
+                self.start += run;
+                self.held = Some(zeros + run as u64);
+                if self.start < self.end {
+                    // A non-zero byte follows, so the held-back bytes are data
+                    self.held = None;
+                    self.release = None;
+                    return Ok(self.released(out, zeros + run as u64));
+                }
+                continue;
+            }
+
+            match data.iter().position(|&b| b == MARKER) {
+                Some(0) => {
+                    self.start += 1;
+                    self.held = Some(0);
+                }
+                found => {
+                    let n = found.unwrap_or(data.len()).min(out.len());
+                    out[..n].copy_from_slice(&data[..n]);
+                    self.start += n;
+                    return Ok(n);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pad(data: &[u8], padding: Padding) -> Vec<u8> {
+        let mut padded = data.to_vec();
+        write_padding(&mut padded, data.len() as u64, padding).unwrap();
+        padded
+    }
+
+    fn unpad(padded: &[u8]) -> io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        UnpadReader::new(padded).read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    #[test]
+    fn test_padded_len() {
+        assert_eq!(Padding::PowerOfTwo.padded_len(0).unwrap(), 1);
+        assert_eq!(Padding::PowerOfTwo.padded_len(1000).unwrap(), 1024);
+        assert_eq!(Padding::PowerOfTwo.padded_len(1023).unwrap(), 1024);
+        assert_eq!(Padding::PowerOfTwo.padded_len(1024).unwrap(), 2048);
+        assert_eq!(Padding::Multiple(4096).padded_len(0).unwrap(), 4096);
+        assert_eq!(Padding::Multiple(4096).padded_len(4096).unwrap(), 8192);
+        assert!(Padding::PowerOfTwo.padded_len(u64::MAX).is_err());
+        assert!(Padding::Multiple(0).padded_len(1).is_err());
+    }
+
+    #[test]
+    fn test_padding_roundtrip() {
+        // Data that itself ends like padding, or contains markers and zeros
+        let cases: [&[u8]; 5] =
+            [b"", b"plain", &[0x80, 0, 0], &[1, 0x80, 0x80, 0, 0x80], &[0x80; 3]];
+        for data in cases {
+            for padding in [Padding::PowerOfTwo, Padding::Multiple(7)] {
+                let padded = pad(data, padding);
+                assert_eq!(padded.len() as u64, padding.padded_len(data.len() as u64).unwrap());
+                assert_eq!(unpad(&padded).unwrap(), data);
+            }
+        }
+    }
+
+    #[test]
+    fn test_unpad_across_reads() {
+        // Zero runs and padding far longer than the read buffer
+        let mut data = vec![5u8; 10_000];
+        data.push(0x80);
+        data.extend_from_slice(&[0u8; 20_000]);
+        data.push(9);
+        let padded = pad(&data, Padding::Multiple(100_000));
+        assert_eq!(padded.len(), 100_000);
+
+        let mut reader = UnpadReader::new(padded.as_slice());
+        let mut unpadded = Vec::new();
+        let mut buf = [0u8; 333];
+        loop {
+            let n = reader.read(&mut buf).unwrap();
+            if n == 0 {
+                break;
+            }
+            unpadded.extend_from_slice(&buf[..n]);
+        }
+        assert_eq!(unpadded, data);
+    }
+
+    #[test]
+    fn test_unpad_missing_marker() {
+        let err = unpad(b"no marker\0\0").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(matches!(ResqryptError::from_io(err), ResqryptError::InvalidFormat(_)));
+    }
+}
@@ -0,0 +1,156 @@
+//! Random source for salts and nonces
+//!
+//! Salts and nonces come from the operating system's RNG unless a
+//! [`Pipeline`](crate::pipeline::Pipeline) is given another
+//! [`RandomSource`] with [`Pipeline::rng`](crate::pipeline::Pipeline::rng).
+//! [`RandomSource::seeded`] is a ChaCha RNG seeded from a string, so a run
+//! with the same inputs produces byte-identical output, e.g. for cached CI
+//! artifacts. The library never uses one on its own; the CLI does when
+//! [`DETERMINISTIC_SEED_ENV`] is set.
+//!
+//! **A seeded source is insecure.** Every file encrypted under the same
+//! seed reuses the same salts and nonces, so it must never be used for real
+//! data.
+
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use blake2::{Blake2b512, Digest};
+use rand::rngs::StdRng;
+use rand::{Rng, RngCore, SeedableRng};
+
+use crate::crypto::cipher::Cipher;
+
+/// Environment variable the CLI reads to make salts and nonces reproducible
+pub const DETERMINISTIC_SEED_ENV: &str = "RESQRYPT_DETERMINISTIC_SEED";
+
+/// An RNG seeded from `seed`, yielding the same bytes for the same seed
+///
+/// The seed is hashed, so any string works and similar seeds diverge.
+pub fn seeded_rng(seed: &str) -> StdRng {
+    let digest = Blake2b512::digest(seed.as_bytes());
+    let mut key = [0u8; 32];
+    key.copy_from_slice(&digest[..32]);
+    StdRng::from_seed(key)
+}
+
+/// Where a pipeline draws the salts and nonces of new files from
+///
+/// The default is the operating system's RNG. Clones share one generator,
+/// so pipelines built from the same source continue its stream rather than
+/// repeat it.
+#[derive(Clone, Default)]
+pub struct RandomSource {
+    rng: Option<Arc<Mutex<dyn RngCore + Send>>>,
+}
+
+impl RandomSource {
+    /// The operating system's RNG
+    pub fn os() -> Self {
+        Self::default()
+    }
+
+    /// Draw from `rng` instead of the operating system's RNG
+    ///
+    /// `rng` must be a cryptographically secure generator with a secret
+    /// seed, or the salts and nonces it draws are predictable.
+    pub fn from_rng(rng: impl RngCore + Send + 'static) -> Self {
+        Self { rng: Some(Arc::new(Mutex::new(rng))) }
+    }
+
+    /// Draw from an RNG seeded with `seed` (see [`seeded_rng`])
+    ///
+    /// **Insecure**, see the module docs: only for tests and fixtures.
+    pub fn seeded(seed: &str) -> Self {
+        Self::from_rng(seeded_rng(seed))
+    }
+
+    /// Whether this is the operating system's RNG
+    pub fn is_os(&self) -> bool {
+        self.rng.is_none()
+    }
+
+    /// Fill `buf` with random bytes
+    pub fn fill(&self, buf: &mut [u8]) {
+        match &self.rng {
+            Some(rng) => rng.lock().unwrap_or_else(|e| e.into_inner()).fill_bytes(buf),
+            None => fill_random(buf),
+        }
+    }
+
+    /// A salt for key derivation
+    pub fn salt(&self) -> [u8; 32] {
+        let mut salt = [0u8; 32];
+        self.fill(&mut salt);
+        salt
+    }
+
+    /// A nonce for `cipher`: its own fresh nonce when drawing from the
+    /// operating system, otherwise one of its nonce length
+    pub fn nonce(&self, cipher: &dyn Cipher) -> Vec<u8> {
+        if self.is_os() {
+            return cipher.generate_nonce();
+        }
+        let mut nonce = vec![0u8; cipher.kind().nonce_len()];
+        self.fill(&mut nonce);
+        nonce
+    }
+}
+
+impl fmt::Debug for RandomSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.rng {
+            Some(_) => write!(f, "RandomSource(custom)"),
+            None => write!(f, "RandomSource(os)"),
+        }
+    }
+}
+
+/// Fill `buf` with random bytes from the operating system's RNG
+pub fn fill_random(buf: &mut [u8]) {
+    rand::rng().fill(buf);
+}
+
+/// An array of random bytes, as [`fill_random`] produces
+pub fn random_bytes<const N: usize>() -> [u8; N] {
+    let mut bytes = [0u8; N];
+    fill_random(&mut bytes);
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::CipherKind;
+
+    #[test]
+    fn test_seeded_rng_is_reproducible() {
+        let draw = |seed: &str| {
+            let mut rng = seeded_rng(seed);
+            let mut bytes = [0u8; 64];
+            rng.fill_bytes(&mut bytes);
+            bytes
+        };
+
+        assert_eq!(draw("ci-fixtures"), draw("ci-fixtures"));
+        assert_ne!(draw("ci-fixtures"), draw("ci-fixtures2"));
+    }
+
+    #[test]
+    fn test_random_source() {
+        let seeded = RandomSource::seeded("ci");
+        assert!(!seeded.is_os());
+        assert_eq!(seeded.salt(), RandomSource::seeded("ci").salt());
+
+        // Clones continue one stream
+        let first = seeded.salt();
+        assert_ne!(seeded.clone().salt(), first);
+        assert_eq!(seeded.nonce(&CipherKind::XChaCha20Poly1305).len(), 24);
+
+        // A seeded source elsewhere leaves the default alone
+        let os = RandomSource::default();
+        assert!(os.is_os());
+        assert_ne!(os.salt(), os.salt());
+        assert_eq!(os.nonce(&CipherKind::Aes256Gcm).len(), 12);
+    }
+}
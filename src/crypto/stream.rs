@@ -0,0 +1,1575 @@
+//! Chunked streaming encryption
+//!
+//! Format v3 splits the payload into chunks of `chunk_size` plaintext bytes
+//! that are sealed independently, so neither side ever holds more than one
+//! chunk in memory. Each chunk is stored as:
+//! - Length (4 bytes, LE): ciphertext length, with the top bit set on the
+//!   final chunk
+//! - Ciphertext: chunk + 16-byte auth tag
+//!
+//! Chunk `i` is sealed with nonce `i` of a [`NonceSequence`] over the
+//! header's base nonce (the big-endian 32-bit `i` XORed into its last 4
+//! bytes, so a stream holds at most 2^32 chunks), and with the serialized
+//! header plus a final-chunk byte (0 or 1) as associated data. Reordering,
+//! dropping or truncating chunks therefore fails authentication. Only the
+//! final chunk may be shorter than `chunk_size`, and it may be empty.
+//!
+//! The final chunk is followed by a 16-byte length trailer:
+//! the length of the data before compression and the length of the
+//! payload sealed in the chunks (8 bytes each, LE). The trailer is appended
+//! to the associated data of the final chunk, so it is authenticated like
+//! the header, yet can be read without the password (see
+//! [`chunked_len`]). Padded payloads have no trailer, since the lengths
+//! would reveal the size the padding hides.
+//!
+//! With the content-defined flag, chunk boundaries follow the content
+//! instead (see [`Chunker`]): chunks hold between a quarter of `chunk_size`
+//! and `chunk_size` bytes, so inserting or removing data only changes the
+//! chunks around the edit. To let equal chunks encrypt to equal bytes,
+//! each chunk is stored as:
+//! - Length (4 bytes, LE): as above
+//! - Nonce: a keyed BLAKE2b hash of the final-chunk byte, the associated
+//!   data and the chunk's plaintext, cut to the cipher's nonce length
+//! - Ciphertext: chunk + 16-byte auth tag
+//!
+//! and only the final-chunk byte is associated data, except for the final
+//! chunk, which also authenticates the header, the trailer and a BLAKE2b
+//! hash chaining the nonce and tag of every earlier chunk. Reordering,
+//! dropping or splicing chunks, or altering the header, therefore fails,
+//! but only once the final chunk is reached rather than at the first
+//! chunk out of place. Equal chunks also reveal themselves as equal,
+//! which is the point for deduplication and the cost for privacy.
+//!
+//! Since a fixed-size chunk is bound to its position by its nonce, any
+//! byte range of the payload can be authenticated on its own (see
+//! [`ChunkReader::verify_range`]): every chunk but the final one holds
+//! exactly `chunk_size` bytes, so chunk `i` starts `i * (4 + chunk_size +
+//! 16)` bytes after the header and holds payload bytes from `i *
+//! chunk_size`. Only the first chunk, which checks the password, and the
+//! chunks overlapping the range are read. Content-defined chunks have no
+//! such layout and are only bound to their position by the final chunk.
+//!
+//! With the Merkle flag, a footer after the trailer holds a
+//! hash tree over the nonce and tag of every chunk, authenticated with the
+//! file key (see [`crate::crypto::merkle`]). Decryption checks it against
+//! all chunks; a range check walks the length prefixes to locate the
+//! chunks, content-defined ones included, and checks each chunk it opens
+//! against the root through its path in the tree.
+//!
+//! Each chunk adds 20 bytes (4-byte length + 16-byte tag): about 0.12% of
+//! the payload at 16 KiB, 0.03% at the default 64 KiB and 0.0001% at
+//! 16 MiB, plus its nonce when content-defined. Larger chunks also mean
+//! fewer writes and AEAD calls, while smaller ones lower memory use and the
+//! delay before the first plaintext of a stream can be released.
+
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::sync::Arc;
+
+use blake2::digest::{KeyInit, Mac};
+use blake2::{Blake2b512, Blake2bMac512, Digest};
+
+use crate::crypto::cipher::Cipher;
+use crate::crypto::format::FileHeader;
+use crate::crypto::kdf::{KdfParams, SecretKey, derive_key_len, key_fingerprint};
+use crate::crypto::merkle::{self, FOOTER_LEN, Footer, HASH_LEN, Hash};
+use crate::crypto::nonce::NonceSequence;
+use crate::error::{ResqryptError, Result};
+
+/// Default number of plaintext bytes per chunk
+pub const DEFAULT_CHUNK_SIZE: u32 = 64 * 1024;
+
+/// Largest supported chunk size
+///
+/// Also bounds the chunk size read from a header, which is only
+/// authenticated once a chunk has been read into memory.
+pub const MAX_CHUNK_SIZE: u32 = 16 * 1024 * 1024;
+
+/// Length prefix bit marking the final chunk
+const LAST_CHUNK: u32 = 1 << 31;
+
+/// Size of the length trailer after the final chunk
+pub const TRAILER_LEN: usize = 16;
+
+/// Lengths recorded in the trailer after the final chunk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StreamLengths {
+    /// Length of the data before compression
+    pub original_len: u64,
+    /// Length of the payload sealed in the chunks
+    pub payload_len: u64,
+}
+
+impl StreamLengths {
+    fn to_bytes(self) -> [u8; TRAILER_LEN] {
+        let mut bytes = [0u8; TRAILER_LEN];
+        bytes[..8].copy_from_slice(&self.original_len.to_le_bytes());
+        bytes[8..].copy_from_slice(&self.payload_len.to_le_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: [u8; TRAILER_LEN]) -> Self {
+        let (original, payload) = bytes.split_at(8);
+        Self {
+            original_len: u64::from_le_bytes(original.try_into().expect("8 bytes")),
+            payload_len: u64::from_le_bytes(payload.try_into().expect("8 bytes")),
+        }
+    }
+}
+
+/// Associated data of a chunk: the header, the final-chunk byte and, for
+/// the final chunk of a stream with a trailer, the trailer
+fn chunk_aad(header_aad: &[u8], last: bool, trailer: Option<StreamLengths>) -> Vec<u8> {
+    let mut aad = header_aad.to_vec();
+    aad.push(last as u8);
+    if let Some(lengths) = trailer {
+        aad.extend_from_slice(&lengths.to_bytes());
+    }
+    aad
+}
+
+/// Gear table of the content-defined chunker, 256 SplitMix64 outputs
+///
+/// Fixed so the same data is always cut at the same places; readers never
+/// need it.
+const GEAR: [u64; 256] = {
+    let mut table = [0u64; 256];
+    let mut state = 0x5265_7351_7279_7074u64;
+    let mut i = 0;
+    while i < table.len() {
+        state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+};
+
+/// Content-defined chunk boundaries, in the style of FastCDC
+///
+/// A gear rolling hash over the last 64 bytes picks the cut points, so the
+/// boundaries move with the data rather than with its offset. Chunks are
+/// at least a quarter of the maximum size and average about half of it:
+/// a stricter mask applies before the halfway point and a looser one after.
+#[derive(Debug, Clone)]
+pub struct Chunker {
+    min: usize,
+    normal: usize,
+    strict_mask: u64,
+    loose_mask: u64,
+    hash: u64,
+}
+
+impl Chunker {
+    /// Chunker for chunks of at most `max` bytes
+    pub fn new(max: usize) -> Self {
+        let min = max / 4;
+        // Past the minimum, a mask of `bits` bits cuts every 2^bits bytes
+        let bits = (max / 4).max(1).ilog2();
+        Self {
+            min,
+            normal: max / 2,
+            strict_mask: top_bits(bits + 1),
+            loose_mask: top_bits(bits.saturating_sub(1)),
+            hash: 0,
+        }
+    }
+
+    /// Scan `data`, which follows `filled` bytes of the current chunk
+    ///
+    /// Returns how many bytes of `data` belong to the chunk, and whether
+    /// the chunk ends there.
+    pub fn scan(&mut self, filled: usize, data: &[u8]) -> (usize, bool) {
+        for (i, &byte) in data.iter().enumerate() {
+            self.hash = (self.hash << 1).wrapping_add(GEAR[usize::from(byte)]);
+            let len = filled + i + 1;
+            if len < self.min {
+                continue;
+            }
+            let mask = if len < self.normal { self.strict_mask } else { self.loose_mask };
+            if self.hash & mask == 0 {
+                self.hash = 0;
+                return (i + 1, true);
+            }
+        }
+        (data.len(), false)
+    }
+
+    /// Start a new chunk
+    pub fn reset(&mut self) {
+        self.hash = 0;
+    }
+}
+
+/// Mask of the top `bits` bits, which depend on the most input bytes
+fn top_bits(bits: u32) -> u64 {
+    if bits == 0 { 0 } else { u64::MAX << (64 - bits.min(64)) }
+}
+
+/// Per-stream state of content-defined chunks: the key for their nonces
+/// and the chain over the chunks so far
+struct ContentDefined {
+    nonce_key: Blake2bMac512,
+    chain: Blake2b512,
+}
+
+impl ContentDefined {
+    fn new(key: &SecretKey) -> Self {
+        let mut mac = <Blake2bMac512 as KeyInit>::new_from_slice(key.as_bytes())
+            .expect("BLAKE2b accepts 32-byte keys");
+        Mac::update(&mut mac, b"resqrypt content-defined chunk nonce");
+        Self { nonce_key: mac, chain: Blake2b512::new() }
+    }
+
+    /// Nonce of a chunk holding `plain`, sealed with `aad`
+    ///
+    /// Covering the associated data as well as the plaintext keeps the
+    /// same plaintext from being sealed twice under one nonce, as the
+    /// final chunk and as any other.
+    fn nonce(&self, last: bool, aad: &[u8], plain: &[u8], len: usize) -> Vec<u8> {
+        let mut mac = self.nonce_key.clone();
+        Mac::update(&mut mac, &[u8::from(last)]);
+        Mac::update(&mut mac, &(aad.len() as u64).to_le_bytes());
+        Mac::update(&mut mac, aad);
+        Mac::update(&mut mac, plain);
+        mac.finalize().into_bytes()[..len].to_vec()
+    }
+
+    /// Associated data of a chunk, per the module docs
+    fn aad(&self, header_aad: &[u8], last: bool, trailer: Option<StreamLengths>) -> Vec<u8> {
+        if !last {
+            return vec![0];
+        }
+        let mut aad = chunk_aad(header_aad, true, trailer);
+        aad.extend_from_slice(&self.chain.clone().finalize());
+        aad
+    }
+
+    /// Chain a sealed or opened chunk into the hash
+    fn chain(&mut self, nonce: &[u8], ciphertext: &[u8], tag_len: usize) {
+        Digest::update(&mut self.chain, nonce);
+        Digest::update(&mut self.chain, &ciphertext[ciphertext.len() - tag_len..]);
+    }
+}
+
+/// Check that a chunk size is in the supported range
+pub fn check_chunk_size(size: u32) -> Result<()> {
+    if !(1..=MAX_CHUNK_SIZE).contains(&size) {
+        return Err(ResqryptError::InvalidArgument(format!(
+            "Chunk size must be between 1 and {} bytes, got {}",
+            MAX_CHUNK_SIZE, size
+        )));
+    }
+
+    Ok(())
+}
+
+/// Chunk size of a chunked header, validated
+fn header_chunk_size(header: &FileHeader) -> Result<usize> {
+    match header.chunk_size {
+        Some(size) if header.is_chunked() => {
+            check_chunk_size(size).map_err(|_| {
+                ResqryptError::InvalidFormat(format!("Invalid chunk size: {}", size))
+            })?;
+            Ok(size as usize)
+        }
+        _ => Err(ResqryptError::InvalidArgument(format!(
+            "Format version {} is not chunked",
+            header.version
+        ))),
+    }
+}
+
+/// Bytes stored after the length prefix `prefix` of a chunk following
+/// `header`, nonce and ciphertext, and whether it is the final chunk
+///
+/// The length is capped at the longest chunk the header allows, as a
+/// reader rejects a longer one before reading past its prefix.
+#[cfg(feature = "async")]
+pub(crate) fn stored_chunk_len(header: &FileHeader, prefix: [u8; 4]) -> Result<(usize, bool)> {
+    let prefix = u32::from_le_bytes(prefix);
+    let max_len = header_chunk_size(header)? + header.cipher.tag_len();
+    let nonce_len = if header.is_content_defined() { header.cipher.nonce_len() } else { 0 };
+    let len = ((prefix & !LAST_CHUNK) as usize).min(max_len);
+    Ok((nonce_len + len, prefix & LAST_CHUNK != 0))
+}
+
+/// A stretch of the payload that could not be decrypted while recovering
+/// (see [`ChunkReader::recovering`])
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LostChunk {
+    /// Index of the first chunk lost
+    pub index: u64,
+    /// Offset of the lost data in the payload
+    pub offset: u64,
+    /// Length of the lost data, replaced by as many zeros, or `None` when
+    /// the chunk framing itself was lost and nothing from `offset` on could
+    /// be read
+    pub len: Option<u64>,
+}
+
+/// A chunk as read: its stored nonce, if any, ciphertext and whether it
+/// is the final chunk
+type RawChunk = (Option<Vec<u8>>, Vec<u8>, bool);
+
+/// Writer sealing everything written to it as chunks
+///
+/// Call [`ChunkWriter::finish`] once all data is written; dropping the
+/// writer without it leaves the stream without a final chunk, which fails
+/// to decrypt.
+pub struct ChunkWriter<'c, W: Write> {
+    out: W,
+    cipher: &'c dyn Cipher,
+    key: SecretKey,
+    nonces: NonceSequence,
+    aad: Vec<u8>,
+    chunk_size: usize,
+    buf: Vec<u8>,
+    index: u64,
+    /// Whether the final chunk is followed by a length trailer
+    trailer: bool,
+    written: u64,
+    original_len: Option<u64>,
+    /// Boundaries and nonce state of content-defined chunks
+    content: Option<(Chunker, ContentDefined)>,
+    /// Whether the buffered chunk is complete
+    cut: bool,
+    /// Merkle leaves of the chunks sealed so far, when the header asks for
+    /// a Merkle footer
+    leaves: Option<Vec<Hash>>,
+}
+
+impl<'c, W: Write> ChunkWriter<'c, W> {
+    /// Create a writer for the chunks following `header`
+    ///
+    /// The header itself must already have been written to `out`.
+    pub fn new(
+        out: W,
+        cipher: &'c dyn Cipher,
+        key: SecretKey,
+        header: &FileHeader,
+    ) -> Result<Self> {
+        let chunk_size = header_chunk_size(header)?;
+        let content = header
+            .is_content_defined()
+            .then(|| (Chunker::new(chunk_size), ContentDefined::new(&key)));
+
+        Ok(Self {
+            out,
+            cipher,
+            key,
+            nonces: NonceSequence::new(header.nonce.clone()),
+            aad: header.aad()?,
+            chunk_size,
+            buf: Vec::with_capacity(chunk_size),
+            index: 0,
+            trailer: header.has_length_trailer(),
+            written: 0,
+            original_len: None,
+            content,
+            cut: false,
+            leaves: header.is_merkle().then(Vec::new),
+        })
+    }
+
+    /// Record the length of the data before compression for the trailer
+    ///
+    /// Without it, the trailer gives the payload length for both, as is
+    /// right for uncompressed data.
+    pub fn set_original_len(&mut self, len: u64) {
+        self.original_len = Some(len);
+    }
+
+    /// Seal the buffered data as the next chunk
+    fn seal_chunk(&mut self, last: bool) -> Result<()> {
+        let trailer = (last && self.trailer).then(|| StreamLengths {
+            original_len: self.original_len.unwrap_or(self.written),
+            payload_len: self.written,
+        });
+        let (nonce, aad) = match &self.content {
+            Some((_, content)) => {
+                let aad = content.aad(&self.aad, last, trailer);
+                (content.nonce(last, &aad, &self.buf, self.nonces.nonce_len()), aad)
+            }
+            None => (self.nonces.nonce_at(self.index)?, chunk_aad(&self.aad, last, trailer)),
+        };
+        let ciphertext = self.cipher.seal(self.key.as_bytes(), &nonce, &self.buf, &aad)?;
+
+        let mut prefix = ciphertext.len() as u32;
+        if last {
+            prefix |= LAST_CHUNK;
+        }
+        self.out.write_all(&prefix.to_le_bytes())?;
+        if let Some((chunker, content)) = &mut self.content {
+            self.out.write_all(&nonce)?;
+            content.chain(&nonce, &ciphertext, self.cipher.tag_len());
+            chunker.reset();
+        }
+        if let Some(leaves) = &mut self.leaves {
+            let tag = &ciphertext[ciphertext.len() - self.cipher.tag_len()..];
+            leaves.push(merkle::leaf_hash(&nonce, tag));
+        }
+        self.out.write_all(&ciphertext)?;
+        if let Some(lengths) = trailer {
+            self.out.write_all(&lengths.to_bytes())?;
+        }
+
+        self.buf.clear();
+        self.index += 1;
+        self.cut = false;
+        Ok(())
+    }
+
+    /// Number of chunks sealed so far, not counting the final one
+    pub fn chunks_sealed(&self) -> u64 {
+        self.index
+    }
+
+    /// The inner writer, for the async adapter to drain
+    #[cfg(feature = "async")]
+    pub(crate) fn get_mut(&mut self) -> &mut W {
+        &mut self.out
+    }
+
+    /// Seal the remaining data as the final chunk, followed by the Merkle
+    /// footer if any, and return the inner writer
+    pub fn finish(mut self) -> Result<W> {
+        self.seal_chunk(true)?;
+        if let Some(leaves) = self.leaves.take() {
+            let levels = merkle::levels(leaves);
+            let root = levels[levels.len() - 1][0];
+            let footer = Footer::new(&self.key, &self.aad, levels[0].len() as u64, root);
+            self.out.write_all(&footer.to_bytes())?;
+            merkle::write_tree(&mut self.out, &levels)?;
+        }
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+impl<W: Write> Write for ChunkWriter<'_, W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        // A complete chunk is only sealed once more data arrives, so that
+        // the final chunk can always be marked as such in `finish`
+        if self.cut && !data.is_empty() {
+            self.seal_chunk(false).map_err(io::Error::other)?;
+        }
+
+        let data = &data[..data.len().min(self.chunk_size - self.buf.len())];
+        let (n, boundary) = match &mut self.content {
+            Some((chunker, _)) => chunker.scan(self.buf.len(), data),
+            None => (data.len(), false),
+        };
+        self.buf.extend_from_slice(&data[..n]);
+        self.written += n as u64;
+        self.cut = boundary || self.buf.len() == self.chunk_size;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// Reader decrypting the chunks following a header
+///
+/// The reader starts locked; [`ChunkReader::unlock`] derives the key and
+/// checks it against the first chunk, and can be retried with another
+/// password.
+pub struct ChunkReader<R: Read> {
+    input: R,
+    cipher: Arc<dyn Cipher>,
+    salt: [u8; 32],
+    kdf_params: KdfParams,
+    key: Option<SecretKey>,
+    nonces: NonceSequence,
+    aad: Vec<u8>,
+    max_len: usize,
+    index: u64,
+    first: Option<RawChunk>,
+    plain: Vec<u8>,
+    pos: usize,
+    done: bool,
+    /// Whether the final chunk is followed by a length trailer
+    trailer: bool,
+    lengths: Option<StreamLengths>,
+    opened_len: u64,
+    /// Whether each chunk stores its own nonce (content-defined chunks)
+    stored_nonces: bool,
+    content: Option<ContentDefined>,
+    /// Whether chunks failing authentication are replaced by zeros
+    recover: bool,
+    lost: Vec<LostChunk>,
+    /// Whether the final chunk is followed by a Merkle footer
+    merkle: bool,
+    /// Merkle leaves of the chunks opened so far
+    leaves: Vec<Hash>,
+    /// Whether only some chunks are opened, so the footer is checked by
+    /// path rather than in full (see [`ChunkReader::verify_range`])
+    ranged: bool,
+}
+
+impl<R: Read> ChunkReader<R> {
+    /// Create a reader for the chunks following `header`
+    ///
+    /// `input` must be positioned just after the header.
+    pub fn new(input: R, header: &FileHeader) -> Result<Self> {
+        Self::with_cipher(input, header, Arc::new(header.cipher))
+    }
+
+    /// Create a reader opening the chunks through `cipher`, which must
+    /// implement the cipher recorded in `header`
+    pub fn with_cipher(input: R, header: &FileHeader, cipher: Arc<dyn Cipher>) -> Result<Self> {
+        let chunk_size = header_chunk_size(header)?;
+        if cipher.kind() != header.cipher {
+            return Err(ResqryptError::InvalidArgument(format!(
+                "the file was encrypted with {}, not {}",
+                header.cipher,
+                cipher.kind()
+            )));
+        }
+
+        Ok(Self {
+            input,
+            cipher,
+            salt: header.salt,
+            kdf_params: header.kdf_params.clone(),
+            key: None,
+            nonces: NonceSequence::new(header.nonce.clone()),
+            aad: header.aad()?,
+            max_len: chunk_size + header.cipher.tag_len(),
+            index: 0,
+            first: None,
+            plain: Vec::new(),
+            pos: 0,
+            done: false,
+            trailer: header.has_length_trailer(),
+            lengths: None,
+            opened_len: 0,
+            stored_nonces: header.is_content_defined(),
+            content: None,
+            recover: false,
+            lost: Vec::new(),
+            merkle: header.is_merkle(),
+            leaves: Vec::new(),
+            ranged: false,
+        })
+    }
+
+    /// Replace chunks that fail authentication with zeros instead of
+    /// failing, recording them in [`ChunkReader::lost_chunks`]
+    ///
+    /// A damaged length prefix of a fixed-size chunk is taken for a full
+    /// chunk, since only the final chunk may be shorter; any other framing
+    /// damage loses the rest of the stream. Unlocking no longer checks the
+    /// password, so a wrong one shows as every chunk lost. The recovered
+    /// data is NOT authenticated as a whole: use it only to salvage what
+    /// is left of a damaged file.
+    pub fn recovering(mut self) -> Self {
+        self.recover = true;
+        self
+    }
+
+    /// Chunks lost so far while recovering
+    pub fn lost_chunks(&self) -> &[LostChunk] {
+        &self.lost
+    }
+
+    /// Derive the key from `password` and decrypt the first chunk with it
+    ///
+    /// # Errors
+    /// Returns [`ResqryptError::PasswordError`] when the password is wrong;
+    /// the reader stays locked and `unlock` may be called again.
+    pub fn unlock(&mut self, password: &[u8]) -> Result<()> {
+        if self.key.is_some() {
+            return Ok(());
+        }
+
+        let key = derive_key_len(password, &self.salt, &self.kdf_params, self.cipher.key_len())?;
+        if self.stored_nonces {
+            self.content = Some(ContentDefined::new(&key));
+        }
+        if self.recover {
+            self.key = Some(key);
+            return self.next_chunk();
+        }
+
+        let chunk = match self.first.take() {
+            Some(chunk) => chunk,
+            None => self.read_raw_chunk()?,
+        };
+
+        match self.open_chunk(&key, &chunk) {
+            Ok(plain) => {
+                self.plain = plain;
+                self.key = Some(key);
+                Ok(())
+            }
+            Err(e) => {
+                // Keep the raw first chunk so that a retry with another
+                // password does not re-read it
+                if matches!(e, ResqryptError::PasswordError(_)) {
+                    self.first = Some(chunk);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Number of chunks decrypted so far
+    pub fn chunks_opened(&self) -> u64 {
+        self.index
+    }
+
+    /// Lengths from the trailer, once the final chunk has been opened
+    /// (unless padded)
+    pub fn lengths(&self) -> Option<StreamLengths> {
+        self.lengths.filter(|_| self.done)
+    }
+
+    /// Fingerprint of the key once unlocked, for diagnostics (see
+    /// [`key_fingerprint`])
+    pub fn key_fingerprint(&self) -> Option<String> {
+        self.key.as_ref().map(|key| key_fingerprint(key.as_bytes()))
+    }
+
+    /// The inner reader, for the async adapter to fill
+    #[cfg(feature = "async")]
+    pub(crate) fn get_ref(&self) -> &R {
+        &self.input
+    }
+
+    /// The inner reader, for the async adapter to fill
+    #[cfg(feature = "async")]
+    pub(crate) fn get_mut(&mut self) -> &mut R {
+        &mut self.input
+    }
+
+    /// Whether the next read is served without reading the input: decrypted
+    /// data is buffered or the stream is complete
+    #[cfg(feature = "async")]
+    pub(crate) fn is_buffered(&self) -> bool {
+        self.pos < self.plain.len() || self.done
+    }
+
+    /// Read the remaining chunks, checking that the stream is complete
+    ///
+    /// Returns the number of trailing bytes after the final chunk and the
+    /// Merkle footer, which are ignored.
+    pub fn finish(mut self) -> Result<u64> {
+        while !self.done {
+            self.next_chunk()?;
+        }
+
+        Ok(io::copy(&mut self.input, &mut io::sink())?)
+    }
+
+    /// Read the length prefix, nonce if stored, and ciphertext of the next
+    /// chunk
+    fn read_raw_chunk(&mut self) -> Result<RawChunk> {
+        let truncated = |e: io::Error| match e.kind() {
+            io::ErrorKind::UnexpectedEof => {
+                ResqryptError::InvalidFormat("File is truncated (missing final chunk)".to_string())
+            }
+            _ => e.into(),
+        };
+
+        let mut prefix = [0u8; 4];
+        self.input.read_exact(&mut prefix).map_err(truncated)?;
+        let prefix = u32::from_le_bytes(prefix);
+
+        let mut len = (prefix & !LAST_CHUNK) as usize;
+        let mut last = prefix & LAST_CHUNK != 0;
+        let invalid = len > self.max_len || len < self.cipher.tag_len();
+        if self.recover && !self.stored_nonces && (invalid || (!last && len != self.max_len)) {
+            // Only the final chunk may be shorter, so assume a full one
+            (len, last) = (self.max_len, false);
+        } else if invalid {
+            return Err(ResqryptError::InvalidFormat(format!(
+                "Invalid chunk length {} in chunk {}",
+                len, self.index
+            )));
+        }
+
+        let nonce = if self.stored_nonces {
+            let mut nonce = vec![0u8; self.nonces.nonce_len()];
+            self.input.read_exact(&mut nonce).map_err(truncated)?;
+            Some(nonce)
+        } else {
+            None
+        };
+        let mut ciphertext = vec![0u8; len];
+        self.input.read_exact(&mut ciphertext).map_err(truncated)?;
+        if last && self.trailer {
+            let mut trailer = [0u8; TRAILER_LEN];
+            self.input.read_exact(&mut trailer).map_err(truncated)?;
+            self.lengths = Some(StreamLengths::from_bytes(trailer));
+        }
+        Ok((nonce, ciphertext, last))
+    }
+
+    /// Decrypt chunk `self.index` and advance past it
+    fn open_chunk(&mut self, key: &SecretKey, chunk: &RawChunk) -> Result<Vec<u8>> {
+        let (stored_nonce, ciphertext, last) = chunk;
+        let last = *last;
+        let trailer = if last { self.lengths } else { None };
+        let (nonce, aad) = match (&self.content, stored_nonce) {
+            (Some(content), Some(nonce)) => (nonce.clone(), content.aad(&self.aad, last, trailer)),
+            _ => (self.nonces.nonce_at(self.index)?, chunk_aad(&self.aad, last, trailer)),
+        };
+        let plain = self.cipher.open(key.as_bytes(), &nonce, ciphertext, &aad)?;
+        if let Some(content) = &mut self.content {
+            content.chain(&nonce, ciphertext, self.cipher.tag_len());
+        }
+        self.push_leaf(&nonce, ciphertext);
+
+        self.opened_len += plain.len() as u64;
+        if let Some(lengths) = trailer.filter(|_| !self.recover) {
+            // Authenticated, so only a faulty writer gets here
+            if lengths.payload_len != self.opened_len {
+                return Err(ResqryptError::InvalidFormat(format!(
+                    "Trailer records {} payload bytes but the chunks hold {}",
+                    lengths.payload_len, self.opened_len
+                )));
+            }
+        }
+
+        if last && !self.ranged {
+            // Authenticated chunks with a bad footer are still salvageable
+            let checked = self.check_footer(key);
+            if !self.recover {
+                checked?;
+            }
+        }
+
+        self.index += 1;
+        self.pos = 0;
+        self.done = last;
+        Ok(plain)
+    }
+
+    /// Record the Merkle leaf of chunk `self.index`
+    fn push_leaf(&mut self, nonce: &[u8], ciphertext: &[u8]) {
+        if self.merkle {
+            let tag = &ciphertext[ciphertext.len() - self.cipher.tag_len()..];
+            self.leaves.push(merkle::leaf_hash(nonce, tag));
+        }
+    }
+
+    /// Read the Merkle footer after the final chunk and check it, and every
+    /// node of its tree, against the chunks opened
+    fn check_footer(&mut self, key: &SecretKey) -> Result<()> {
+        if !self.merkle {
+            return Ok(());
+        }
+
+        let footer = Footer::read(&mut self.input)?;
+        footer.authenticate(key, &self.aad)?;
+        let levels = merkle::levels(std::mem::take(&mut self.leaves));
+        if footer.leaf_count != levels[0].len() as u64 || footer.root != levels[levels.len() - 1][0]
+        {
+            return Err(ResqryptError::CryptoError(format!(
+                "Merkle footer does not match the {} chunks",
+                levels[0].len()
+            )));
+        }
+        for node in levels[..levels.len() - 1].iter().flatten() {
+            let mut stored = [0u8; HASH_LEN];
+            self.input.read_exact(&mut stored).map_err(|_| {
+                ResqryptError::InvalidFormat("File is truncated (missing Merkle tree)".to_string())
+            })?;
+            if stored != *node {
+                return Err(ResqryptError::CryptoError(
+                    "Merkle tree does not match the chunks".to_string(),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Decrypt the next chunk into the plaintext buffer
+    fn next_chunk(&mut self) -> Result<()> {
+        // Taken out for the call, as `open_chunk` borrows the reader
+        let key = self.key.take().ok_or_else(|| {
+            ResqryptError::CryptoError("Chunk reader used before unlock".to_string())
+        })?;
+
+        let opened = match self.read_raw_chunk() {
+            Ok(chunk) => self.open_or_zero(&key, &chunk),
+            Err(_) if self.recover => {
+                self.lost.push(LostChunk { index: self.index, offset: self.opened_len, len: None });
+                self.pos = 0;
+                self.done = true;
+                Ok(Vec::new())
+            }
+            Err(e) => Err(e),
+        };
+        self.key = Some(key);
+        self.plain = opened?;
+        Ok(())
+    }
+
+    /// Decrypt a chunk, or when recovering replace it by zeros if it fails
+    /// authentication
+    fn open_or_zero(&mut self, key: &SecretKey, chunk: &RawChunk) -> Result<Vec<u8>> {
+        match self.open_chunk(key, chunk) {
+            Err(_) if self.recover => {
+                let (nonce, ciphertext, last) = chunk;
+                let nonce = match nonce {
+                    Some(nonce) => nonce.clone(),
+                    None => self.nonces.nonce_at(self.index)?,
+                };
+                if let Some(content) = &mut self.content {
+                    content.chain(&nonce, ciphertext, self.cipher.tag_len());
+                }
+                self.push_leaf(&nonce, ciphertext);
+                if *last && !self.ranged {
+                    let _ = self.check_footer(key);
+                }
+                let len = ciphertext.len() - self.cipher.tag_len();
+                self.lost.push(LostChunk {
+                    index: self.index,
+                    offset: self.opened_len,
+                    len: Some(len as u64),
+                });
+                self.opened_len += len as u64;
+                self.index += 1;
+                self.pos = 0;
+                self.done = *last;
+                Ok(vec![0; len])
+            }
+            opened => opened,
+        }
+    }
+}
+
+impl<R: Read> Read for ChunkReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.plain.len() {
+            if self.done {
+                return Ok(0);
+            }
+            self.next_chunk().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        }
+
+        let n = buf.len().min(self.plain.len() - self.pos);
+        buf[..n].copy_from_slice(&self.plain[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+impl<R: Read + Seek> ChunkReader<R> {
+    /// Authenticate the chunks holding payload bytes `range`, seeking over
+    /// the chunks before it without reading them
+    ///
+    /// Call after [`ChunkReader::unlock`], which has already authenticated
+    /// the first chunk. The length prefixes are walked first, so seeks
+    /// never go past the final chunk, whatever follows the file. With a
+    /// Merkle footer, each chunk opened is also checked against the root,
+    /// which binds content-defined chunks to their position; without one,
+    /// content-defined chunks are refused, as only the final chunk binds
+    /// them. Returns the number of chunks authenticated, counting the first.
+    pub fn verify_range(mut self, range: Range<u64>) -> Result<u64> {
+        if self.stored_nonces && !self.merkle {
+            return Err(ResqryptError::InvalidArgument(
+                "content-defined chunks are only bound to their position by the final chunk; \
+                 verify the whole file instead, or encrypt with --merkle"
+                    .to_string(),
+            ));
+        }
+        // Opening the final chunk in `unlock` already checked the footer
+        let footer_checked = self.done;
+        self.ranged = true;
+
+        let nonce_len = if self.stored_nonces { self.nonces.nonce_len() as u64 } else { 0 };
+        let start = self.input.stream_position()?;
+        let mut records = Vec::new();
+        let mut count = self.index;
+        let end = if self.done {
+            start
+        } else {
+            let stored_nonces = self.stored_nonces;
+            let (end, _) = walk_chunks(
+                &mut self.input,
+                self.max_len as u64,
+                nonce_len,
+                self.trailer,
+                |pos, len| {
+                    count += 1;
+                    if stored_nonces {
+                        records.push((pos, len));
+                    }
+                },
+            )?;
+            self.input.seek(SeekFrom::Start(start))?;
+            end
+        };
+
+        let mut opened: Vec<u64> = (0..self.index).collect();
+        if self.stored_nonces {
+            self.open_content_defined(&range, &records, &mut opened)?;
+        } else {
+            let chunk_len = (self.max_len - self.cipher.tag_len()) as u64;
+            let record_len = 4 + self.max_len as u64;
+            while !self.done && self.opened_len < range.end {
+                // Never skip the final chunk
+                let wanted = range.start.saturating_sub(self.opened_len) / chunk_len;
+                let skip = wanted.min(count - 1 - self.index);
+                if skip > 0 {
+                    let pos = self.input.stream_position()?;
+                    self.input.seek(SeekFrom::Start(pos + skip * record_len))?;
+                    self.index += skip;
+                    self.opened_len += skip * chunk_len;
+                }
+                self.next_chunk()?;
+                opened.push(self.index - 1);
+            }
+        }
+
+        if self.opened_len < range.end {
+            return Err(ResqryptError::InvalidArgument(format!(
+                "range ends at byte {} but the payload holds {} bytes",
+                range.end, self.opened_len
+            )));
+        }
+        if self.merkle && !footer_checked {
+            self.check_paths(end, count, &opened)?;
+        }
+        Ok(opened.len() as u64)
+    }
+
+    /// Open the content-defined chunks at `records` that overlap `range`
+    ///
+    /// Chunks skipped still have their nonce and tag read for the chain,
+    /// in case the final chunk is opened.
+    fn open_content_defined(
+        &mut self,
+        range: &Range<u64>,
+        records: &[(u64, u64)],
+        opened: &mut Vec<u64>,
+    ) -> Result<()> {
+        let tag_len = self.cipher.tag_len();
+        for (i, &(pos, len)) in records.iter().enumerate() {
+            if self.opened_len >= range.end {
+                break;
+            }
+
+            let plain_len = len - tag_len as u64;
+            if self.opened_len + plain_len <= range.start && i + 1 < records.len() {
+                let mut nonce = vec![0u8; self.nonces.nonce_len()];
+                let mut tag = vec![0u8; tag_len];
+                self.input.seek(SeekFrom::Start(pos + 4))?;
+                self.input.read_exact(&mut nonce)?;
+                self.input.seek(SeekFrom::Current((len - tag_len as u64) as i64))?;
+                self.input.read_exact(&mut tag)?;
+                if let Some(content) = &mut self.content {
+                    content.chain(&nonce, &tag, tag_len);
+                }
+                self.index += 1;
+                self.opened_len += plain_len;
+                continue;
+            }
+
+            self.input.seek(SeekFrom::Start(pos))?;
+            self.next_chunk()?;
+            opened.push(self.index - 1);
+        }
+        Ok(())
+    }
+
+    /// Check the Merkle path of each chunk in `opened`, whose leaves were
+    /// recorded in that order, against the footer at `end`
+    fn check_paths(&mut self, end: u64, count: u64, opened: &[u64]) -> Result<()> {
+        let key = self.key.as_ref().ok_or_else(|| {
+            ResqryptError::CryptoError("Chunk reader used before unlock".to_string())
+        })?;
+        self.input.seek(SeekFrom::Start(end))?;
+        let footer = Footer::read(&mut self.input)?;
+        footer.authenticate(key, &self.aad)?;
+        if footer.leaf_count != count {
+            return Err(ResqryptError::CryptoError(format!(
+                "Merkle footer records {} chunks but the file holds {}",
+                footer.leaf_count, count
+            )));
+        }
+
+        let tree = end + FOOTER_LEN as u64;
+        for (&index, &leaf) in opened.iter().zip(&self.leaves) {
+            let mut siblings = Vec::new();
+            for (node, left) in merkle::path(count, index) {
+                let mut sibling = [0u8; HASH_LEN];
+                self.input.seek(SeekFrom::Start(tree + node * HASH_LEN as u64))?;
+                self.input.read_exact(&mut sibling).map_err(|_| {
+                    ResqryptError::InvalidFormat(
+                        "File is truncated (missing Merkle tree)".to_string(),
+                    )
+                })?;
+                siblings.push((sibling, left));
+            }
+            if merkle::root_from_path(leaf, siblings) != footer.root {
+                return Err(ResqryptError::CryptoError(format!(
+                    "Chunk {} does not match the Merkle root",
+                    index
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Walk the length prefixes from the current position to the end of the
+/// final chunk and its trailer, seeking over nonces and ciphertexts
+///
+/// Calls `visit` with the position and ciphertext length of each chunk, and
+/// returns the position after the trailer with the lengths it records. The
+/// walk never leaves the file, but nothing is authenticated.
+fn walk_chunks<R: Read + Seek>(
+    reader: &mut R,
+    max_len: u64,
+    nonce_len: u64,
+    trailer: bool,
+    mut visit: impl FnMut(u64, u64),
+) -> Result<(u64, Option<StreamLengths>)> {
+    let start = reader.stream_position()?;
+    let end = reader.seek(SeekFrom::End(0))?;
+    let truncated =
+        || ResqryptError::InvalidFormat("File is truncated (missing final chunk)".to_string());
+
+    let mut pos = reader.seek(SeekFrom::Start(start))?;
+    loop {
+        let mut prefix = [0u8; 4];
+        reader.read_exact(&mut prefix).map_err(|_| truncated())?;
+        let prefix = u32::from_le_bytes(prefix);
+        let len = u64::from(prefix & !LAST_CHUNK);
+
+        if len > max_len || pos + 4 + nonce_len + len > end {
+            return Err(truncated());
+        }
+
+        visit(pos, len);
+        pos = reader.seek(SeekFrom::Current((nonce_len + len) as i64))?;
+        if prefix & LAST_CHUNK != 0 {
+            if !trailer {
+                return Ok((pos, None));
+            }
+            let mut trailer = [0u8; TRAILER_LEN];
+            reader.read_exact(&mut trailer).map_err(|_| truncated())?;
+            return Ok((pos + TRAILER_LEN as u64, Some(StreamLengths::from_bytes(trailer))));
+        }
+    }
+}
+
+/// Total length of the chunks following `header` and the lengths from
+/// their trailer, without decrypting them
+///
+/// Walks the length prefixes, seeking over each ciphertext. `reader` must be
+/// positioned just after the header and is left after the final chunk, its
+/// trailer and the Merkle footer, if any, which count towards the length.
+/// Neither the trailer nor the footer is authenticated until the file is
+/// decrypted.
+pub fn chunked_len<R: Read + Seek>(
+    reader: &mut R,
+    header: &FileHeader,
+) -> Result<(u64, Option<StreamLengths>)> {
+    let max_len = (header_chunk_size(header)? + header.cipher.tag_len()) as u64;
+    let nonce_len = if header.is_content_defined() { header.cipher.nonce_len() as u64 } else { 0 };
+    let start = reader.stream_position()?;
+    let mut count = 0;
+    let (end, lengths) =
+        walk_chunks(reader, max_len, nonce_len, header.has_length_trailer(), |_, _| count += 1)?;
+    if !header.is_merkle() {
+        return Ok((end - start, lengths));
+    }
+
+    let footer = Footer::read(reader)?;
+    if footer.leaf_count != count {
+        return Err(ResqryptError::InvalidFormat(format!(
+            "Merkle footer records {} chunks but the file holds {}",
+            footer.leaf_count, count
+        )));
+    }
+    let footer_end = end + FOOTER_LEN as u64 + merkle::tree_len(count);
+    if reader.seek(SeekFrom::End(0))? < footer_end {
+        return Err(ResqryptError::InvalidFormat(
+            "File is truncated (missing Merkle tree)".to_string(),
+        ));
+    }
+    reader.seek(SeekFrom::Start(footer_end))?;
+    Ok((footer_end - start, lengths))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::cipher::CipherKind;
+    use crate::crypto::format::{HeaderFlags, write_header};
+    use crate::crypto::kdf::derive_key;
+    use std::io::Cursor;
+
+    fn test_header(cipher: CipherKind, chunk_size: u32) -> FileHeader {
+        FileHeader::new(
+            HeaderFlags::empty(),
+            cipher,
+            KdfParams { memory_cost: 1024, time_cost: 1, parallelism: 1 },
+            [3u8; 32],
+            cipher.generate_nonce(),
+        )
+        .with_chunk_size(chunk_size)
+    }
+
+    /// Header followed by `data` sealed in chunks
+    fn seal(header: &FileHeader, password: &[u8], data: &[u8]) -> Vec<u8> {
+        let key = derive_key(password, &header.salt, &header.kdf_params).unwrap();
+        let mut out = Vec::new();
+        write_header(&mut out, header).unwrap();
+
+        let mut writer = ChunkWriter::new(out, &header.cipher, key, header).unwrap();
+        writer.write_all(data).unwrap();
+        writer.finish().unwrap()
+    }
+
+    fn open(file: &[u8], password: &[u8]) -> Result<Vec<u8>> {
+        let mut input = Cursor::new(file);
+        let header = crate::crypto::format::read_header(&mut input)?;
+        let mut reader = ChunkReader::new(input, &header)?;
+        reader.unlock(password)?;
+
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        reader.finish()?;
+        Ok(data)
+    }
+
+    #[test]
+    fn test_roundtrip_chunk_boundaries() {
+        for cipher in [CipherKind::Aes256Gcm, CipherKind::XChaCha20Poly1305] {
+            let header = test_header(cipher, 16);
+            for len in [0, 1, 15, 16, 17, 32, 100] {
+                let data: Vec<u8> = (0..len as u8).collect();
+                let file = seal(&header, b"pw", &data);
+                assert_eq!(open(&file, b"pw").unwrap(), data, "{} bytes", len);
+            }
+        }
+    }
+
+    #[test]
+    fn test_chunk_nonces_differ() {
+        let nonces = NonceSequence::new([9u8; 24]);
+        assert_eq!(nonces.nonce_at(0).unwrap(), [9u8; 24]);
+        assert_ne!(nonces.nonce_at(1).unwrap(), nonces.nonce_at(2).unwrap());
+        assert_eq!(&nonces.nonce_at(1).unwrap()[..20], &[9u8; 20]);
+    }
+
+    #[test]
+    fn test_unlock_retries_wrong_password() {
+        let header = test_header(CipherKind::Aes256Gcm, 8);
+        let file = seal(&header, b"right", b"some chunked data");
+
+        let mut input = Cursor::new(&file);
+        let header = crate::crypto::format::read_header(&mut input).unwrap();
+        let mut reader = ChunkReader::new(input, &header).unwrap();
+
+        let result = reader.unlock(b"wrong");
+        assert!(matches!(result, Err(ResqryptError::PasswordError(_))));
+
+        reader.unlock(b"right").unwrap();
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"some chunked data");
+    }
+
+    #[test]
+    fn test_truncation_detected() {
+        let header = test_header(CipherKind::Aes256Gcm, 8);
+        let file = seal(&header, b"pw", &[7u8; 40]);
+
+        // Drop the final chunk entirely: every remaining chunk is intact
+        let final_chunk = 4 + 16 + TRAILER_LEN; // empty final chunk and trailer
+        let result = open(&file[..file.len() - final_chunk], b"pw");
+        assert!(matches!(result, Err(ResqryptError::Io(_) | ResqryptError::InvalidFormat(_))));
+
+        // Cut in the middle of a chunk
+        assert!(open(&file[..file.len() - 5], b"pw").is_err());
+    }
+
+    #[test]
+    fn test_reordered_chunks_rejected() {
+        let header = test_header(CipherKind::Aes256Gcm, 8);
+        let mut file = seal(&header, b"pw", &[1u8; 8].repeat(3));
+
+        // Swap the first two (equal-length) chunks
+        let start = header.size();
+        let chunk = 4 + 8 + 16;
+        let (first, second) = file[start..start + 2 * chunk].split_at_mut(chunk);
+        first.swap_with_slice(second);
+
+        assert!(open(&file, b"pw").is_err());
+    }
+
+    fn recover(file: &[u8], password: &[u8]) -> (Vec<u8>, Vec<LostChunk>) {
+        let mut input = Cursor::new(file);
+        let header = crate::crypto::format::read_header(&mut input).unwrap();
+        let mut reader = ChunkReader::new(input, &header).unwrap().recovering();
+        reader.unlock(password).unwrap();
+
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).unwrap();
+        (data, reader.lost_chunks().to_vec())
+    }
+
+    #[test]
+    fn test_recovering_zeroes_damaged_chunks() {
+        let header = test_header(CipherKind::Aes256Gcm, 8);
+        let data: Vec<u8> = (1..=30).collect();
+        let file = seal(&header, b"pw", &data);
+        let chunk = 4 + 8 + 16;
+
+        // Damaged ciphertext in the second chunk
+        let mut damaged = file.clone();
+        damaged[header.size() + chunk + 6] ^= 1;
+        assert!(open(&damaged, b"pw").is_err());
+        let (recovered, lost) = recover(&damaged, b"pw");
+        let mut expected = data.clone();
+        expected[8..16].fill(0);
+        assert_eq!(recovered, expected);
+        assert_eq!(lost, [LostChunk { index: 1, offset: 8, len: Some(8) }]);
+
+        // A damaged length prefix is taken for a full chunk
+        let mut damaged = file.clone();
+        damaged[header.size() + chunk] ^= 0x40;
+        assert_eq!(recover(&damaged, b"pw"), (data.clone(), Vec::new()));
+
+        // A cut loses everything from the broken chunk on
+        let (recovered, lost) = recover(&file[..header.size() + 2 * chunk + 3], b"pw");
+        assert_eq!(recovered, &data[..16]);
+        assert_eq!(lost, [LostChunk { index: 2, offset: 16, len: None }]);
+
+        // A wrong password loses every chunk
+        let (_, lost) = recover(&file, b"wrong");
+        assert_eq!(lost.len(), 4);
+    }
+
+    fn verify_range(file: &[u8], range: Range<u64>) -> Result<u64> {
+        let mut input = Cursor::new(file);
+        let header = crate::crypto::format::read_header(&mut input)?;
+        let mut reader = ChunkReader::new(input, &header)?;
+        reader.unlock(b"pw")?;
+        reader.verify_range(range)
+    }
+
+    #[test]
+    fn test_verify_range() {
+        let header = test_header(CipherKind::XChaCha20Poly1305, 8);
+        let file = seal(&header, b"pw", &[5u8; 60]);
+        let chunk = 4 + 8 + 16;
+
+        // Only the first chunk and those overlapping the range are opened
+        assert_eq!(verify_range(&file, 0..4).unwrap(), 1);
+        assert_eq!(verify_range(&file, 20..30).unwrap(), 3);
+        assert_eq!(verify_range(&file, 58..60).unwrap(), 2);
+        assert!(verify_range(&file, 58..61).is_err());
+
+        // Damage outside the range goes unnoticed, inside it does not
+        let mut damaged = file.clone();
+        damaged[header.size() + 3 * chunk + 6] ^= 1;
+        assert!(verify_range(&damaged, 40..60).is_ok());
+        assert!(verify_range(&damaged, 20..30).is_err());
+
+        // Bytes appended to the file do not move the final chunk
+        let mut appended = file.clone();
+        appended.extend_from_slice(&[0u8; 200]);
+        assert_eq!(verify_range(&appended, 58..60).unwrap(), 2);
+
+        // Content-defined chunks cannot be checked on their own
+        let header = FileHeader {
+            flags: HeaderFlags::CONTENT_DEFINED,
+            ..test_header(CipherKind::Aes256Gcm, 8)
+        };
+        let file = seal(&header, b"pw", &[5u8; 60]);
+        assert!(matches!(verify_range(&file, 0..4), Err(ResqryptError::InvalidArgument(_))));
+    }
+
+    /// Offset of the Merkle footer: after the final chunk and its trailer
+    fn footer_offset(file: &[u8], header: &FileHeader) -> usize {
+        let chunks = raw_chunks(file, header);
+        header.size() + chunks.iter().map(|chunk| 4 + chunk.len()).sum::<usize>() + TRAILER_LEN
+    }
+
+    #[test]
+    fn test_merkle_footer() {
+        for flags in [HeaderFlags::empty(), HeaderFlags::CONTENT_DEFINED] {
+            let header =
+                FileHeader { flags, ..test_header(CipherKind::Aes256Gcm, 256) }.with_merkle();
+            for len in [0, 100, 5000] {
+                let data = noise(len as u64, len);
+                let file = seal(&header, b"pw", &data);
+                assert_eq!(open(&file, b"pw").unwrap(), data, "{} bytes", len);
+
+                let mut input = Cursor::new(&file);
+                input.seek(SeekFrom::Start(header.size() as u64)).unwrap();
+                let (stream_len, _) = chunked_len(&mut input, &header).unwrap();
+                assert_eq!(stream_len, (file.len() - header.size()) as u64);
+            }
+
+            let data = noise(4, 5000);
+            let file = seal(&header, b"pw", &data);
+            let footer = footer_offset(&file, &header);
+            let chunks = raw_chunks(&file, &header).len() as u64;
+            assert_eq!(file.len() - footer, FOOTER_LEN + merkle::tree_len(chunks) as usize);
+
+            // The footer MAC and every tree node are checked
+            for offset in [footer + 1, footer + FOOTER_LEN - 1, file.len() - 1] {
+                let mut damaged = file.clone();
+                damaged[offset] ^= 1;
+                assert!(open(&damaged, b"pw").is_err(), "byte {} of {}", offset, file.len());
+            }
+            assert!(open(&file[..file.len() - 1], b"pw").is_err());
+        }
+    }
+
+    #[test]
+    fn test_merkle_verify_range() {
+        let header = FileHeader {
+            flags: HeaderFlags::CONTENT_DEFINED,
+            ..test_header(CipherKind::XChaCha20Poly1305, 256)
+        }
+        .with_merkle();
+        let data = noise(5, 5000);
+        let file = seal(&header, b"pw", &data);
+        let chunks = raw_chunks(&file, &header);
+        assert!(chunks.len() > 10);
+
+        // Only the first chunk and those overlapping the range are opened
+        assert_eq!(verify_range(&file, 0..4).unwrap(), 1);
+        assert!(verify_range(&file, 2000..2100).unwrap() <= 3);
+        assert!(verify_range(&file, 4990..5000).unwrap() <= 3);
+        assert!(verify_range(&file, 4990..5001).is_err());
+
+        // The final chunk is reached even with bytes appended
+        let mut appended = file.clone();
+        appended.extend_from_slice(&[0u8; 300]);
+        assert!(verify_range(&appended, 4990..5000).is_ok());
+
+        // A chunk in range spliced from another file opens, but misses the root
+        let other = seal(&header, b"pw", &noise(6, 5000));
+        let other_chunks = raw_chunks(&other, &header);
+        let start = header.size() + chunks[..3].iter().map(|chunk| 4 + chunk.len()).sum::<usize>();
+        let mut spliced = file[..start].to_vec();
+        let prefix = (other_chunks[3].len() - header.cipher.nonce_len()) as u32;
+        spliced.extend_from_slice(&prefix.to_le_bytes());
+        spliced.extend_from_slice(&other_chunks[3]);
+        spliced.extend_from_slice(&file[start + 4 + chunks[3].len()..]);
+        let offset: u64 = chunks[..3]
+            .iter()
+            .map(|chunk| (chunk.len() - header.cipher.nonce_len() - 16) as u64)
+            .sum();
+        assert!(matches!(
+            verify_range(&spliced, offset..offset + 1),
+            Err(ResqryptError::CryptoError(_))
+        ));
+
+        // So does a damaged node on its path
+        let mut damaged = file.clone();
+        let footer = footer_offset(&file, &header);
+        damaged[footer + FOOTER_LEN + 2 * HASH_LEN] ^= 1;
+        assert!(verify_range(&damaged, offset..offset + 1).is_err());
+        assert!(verify_range(&damaged, 0..4).is_ok());
+    }
+
+    /// Pseudo-random test data, the same for the same seed
+    fn noise(seed: u64, len: usize) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                (state >> 56) as u8
+            })
+            .collect()
+    }
+
+    /// Stored nonce, if any, and ciphertext of each chunk after the header
+    fn raw_chunks(file: &[u8], header: &FileHeader) -> Vec<Vec<u8>> {
+        let nonce_len = if header.is_content_defined() { header.cipher.nonce_len() } else { 0 };
+        let mut chunks = Vec::new();
+        let mut pos = header.size();
+        loop {
+            let prefix = u32::from_le_bytes(file[pos..pos + 4].try_into().unwrap());
+            let len = nonce_len + (prefix & !LAST_CHUNK) as usize;
+            chunks.push(file[pos + 4..pos + 4 + len].to_vec());
+            pos += 4 + len;
+            if prefix & LAST_CHUNK != 0 {
+                return chunks;
+            }
+        }
+    }
+
+    #[test]
+    fn test_content_defined_roundtrip() {
+        for cipher in [CipherKind::Aes256Gcm, CipherKind::XChaCha20Poly1305] {
+            let mut header = test_header(cipher, 256);
+            header.flags |= HeaderFlags::CONTENT_DEFINED;
+            for len in [0, 1, 63, 64, 255, 256, 257, 5000] {
+                let data = noise(len as u64, len);
+                let file = seal(&header, b"pw", &data);
+                assert_eq!(open(&file, b"pw").unwrap(), data, "{} bytes", len);
+
+                let mut input = Cursor::new(&file);
+                input.seek(SeekFrom::Start(header.size() as u64)).unwrap();
+                let (stream_len, _) = chunked_len(&mut input, &header).unwrap();
+                assert_eq!(stream_len, (file.len() - header.size()) as u64);
+            }
+        }
+    }
+
+    #[test]
+    fn test_content_defined_chunks_survive_insertion() {
+        let mut header = test_header(CipherKind::Aes256Gcm, 256);
+        header.flags |= HeaderFlags::CONTENT_DEFINED;
+        let original = noise(1, 20_000);
+        let mut edited = original.clone();
+        edited.splice(100..100, *b"inserted");
+
+        let before = raw_chunks(&seal(&header, b"pw", &original), &header);
+        let after = raw_chunks(&seal(&header, b"pw", &edited), &header);
+        assert!(before.len() > 20);
+        // Only the chunks up to where the boundaries realign and the final
+        // one differ
+        let shared = before.iter().filter(|chunk| after.contains(chunk)).count();
+        assert!(shared * 10 >= before.len() * 9, "{} of {} chunks shared", shared, before.len());
+    }
+
+    #[test]
+    fn test_content_defined_final_nonce_differs() {
+        let mut header = test_header(CipherKind::Aes256Gcm, 256);
+        header.flags |= HeaderFlags::CONTENT_DEFINED;
+        let nonce_len = header.cipher.nonce_len();
+
+        // The final chunk holds the same plaintext as the first, yet is
+        // sealed with other associated data, so it needs another nonce
+        let chunks = raw_chunks(&seal(&header, b"pw", &[0u8; 512]), &header);
+        let (first, last) = (&chunks[0], &chunks[chunks.len() - 1]);
+        assert_eq!(first.len(), last.len());
+        assert_ne!(first[..nonce_len], last[..nonce_len]);
+    }
+
+    #[test]
+    fn test_chunker_bounds() {
+        let data = noise(2, 100_000);
+        let mut chunker = Chunker::new(1024);
+        let mut sizes = Vec::new();
+        let mut rest = data.as_slice();
+        while !rest.is_empty() {
+            let mut filled = 0;
+            loop {
+                let room = &rest[..rest.len().min(1024 - filled)];
+                let (n, cut) = chunker.scan(filled, room);
+                filled += n;
+                rest = &rest[n..];
+                if cut || filled == 1024 || rest.is_empty() {
+                    break;
+                }
+            }
+            chunker.reset();
+            sizes.push(filled);
+        }
+
+        let last = sizes.pop().unwrap();
+        assert!(last <= 1024);
+        assert!(sizes.iter().all(|&size| (256..=1024).contains(&size)), "{:?}", sizes);
+        let average = sizes.iter().sum::<usize>() / sizes.len();
+        assert!((384..=768).contains(&average), "average {}", average);
+    }
+
+    #[test]
+    fn test_content_defined_tampering_rejected() {
+        let mut header = test_header(CipherKind::Aes256Gcm, 256);
+        header.flags |= HeaderFlags::CONTENT_DEFINED;
+        let data = noise(3, 4000);
+        let file = seal(&header, b"pw", &data);
+        let chunks = raw_chunks(&file, &header);
+
+        // Drop the first chunk: each remaining chunk still opens on its own
+        let first = 4 + chunks[0].len();
+        let mut dropped = file[..header.size()].to_vec();
+        dropped.extend_from_slice(&file[header.size() + first..]);
+        assert!(open(&dropped, b"pw").is_err());
+
+        // Alter the header: only the final chunk notices
+        let mut altered = file.clone();
+        altered[9] ^= crate::flags::IS_DIRECTORY;
+        assert!(open(&altered, b"pw").is_err());
+    }
+
+    #[test]
+    fn test_trailing_bytes_counted() {
+        let header = test_header(CipherKind::Aes256Gcm, 8);
+        let mut file = seal(&header, b"pw", b"payload");
+        file.extend_from_slice(b"pad");
+
+        let mut input = Cursor::new(&file);
+        let header = crate::crypto::format::read_header(&mut input).unwrap();
+        let mut reader = ChunkReader::new(input, &header).unwrap();
+        reader.unlock(b"pw").unwrap();
+        assert_eq!(reader.finish().unwrap(), 3);
+    }
+
+    #[test]
+    fn test_chunked_len() {
+        let header = test_header(CipherKind::Aes256Gcm, 8);
+        let mut file = seal(&header, b"pw", &[5u8; 20]);
+        let stream_len = (file.len() - header.size()) as u64;
+        file.extend_from_slice(b"xx");
+
+        let mut input = Cursor::new(&file);
+        input.seek(SeekFrom::Start(header.size() as u64)).unwrap();
+        let lengths = StreamLengths { original_len: 20, payload_len: 20 };
+        assert_eq!(chunked_len(&mut input, &header).unwrap(), (stream_len, Some(lengths)));
+
+        let mut truncated = Cursor::new(&file[..file.len() - 6]);
+        truncated.seek(SeekFrom::Start(header.size() as u64)).unwrap();
+        assert!(chunked_len(&mut truncated, &header).is_err());
+    }
+
+    #[test]
+    fn test_length_trailer() {
+        let header = test_header(CipherKind::XChaCha20Poly1305, 8);
+        let key = derive_key(b"pw", &header.salt, &header.kdf_params).unwrap();
+        let mut file = Vec::new();
+        write_header(&mut file, &header).unwrap();
+        let mut writer = ChunkWriter::new(file, &header.cipher, key, &header).unwrap();
+        writer.write_all(&[4u8; 12]).unwrap();
+        writer.set_original_len(30);
+        let mut file = writer.finish().unwrap();
+
+        let mut input = Cursor::new(&file);
+        let header = crate::crypto::format::read_header(&mut input).unwrap();
+        let mut reader = ChunkReader::new(input, &header).unwrap();
+        reader.unlock(b"pw").unwrap();
+        assert_eq!(reader.lengths(), None);
+        io::copy(&mut reader, &mut io::sink()).unwrap();
+        let lengths = reader.lengths().unwrap();
+        assert_eq!(lengths, StreamLengths { original_len: 30, payload_len: 12 });
+
+        // The trailer is authenticated with the final chunk
+        *file.last_mut().unwrap() ^= 1;
+        assert!(open(&file, b"pw").is_err());
+
+        // Padded payloads have none
+        let padded =
+            FileHeader { flags: HeaderFlags::PADDED, ..test_header(CipherKind::Aes256Gcm, 8) };
+        let file = seal(&padded, b"pw", b"data");
+        assert_eq!(file.len(), padded.size() + 4 + 4 + 16);
+        assert_eq!(open(&file, b"pw").unwrap(), b"data");
+    }
+
+    #[test]
+    fn test_chunk_size_bounds() {
+        assert!(check_chunk_size(0).is_err());
+        assert!(check_chunk_size(1).is_ok());
+        assert!(check_chunk_size(MAX_CHUNK_SIZE).is_ok());
+        assert!(check_chunk_size(MAX_CHUNK_SIZE + 1).is_err());
+    }
+}
@@ -0,0 +1,130 @@
+//! Format test vectors
+//!
+//! Fixed inputs and the exact bytes resqrypt produces for them, so other
+//! implementations can be checked byte for byte and accidental format
+//! changes fail here first. Every vector uses:
+//! - Password: `resqrypt test vector`
+//! - Argon2id: 1024 KiB, 1 iteration, parallelism 1
+//! - Salt: 32 × `0x11`
+//! - Flags: uncompressed (`0x04`), so the payload is the plaintext
+//! - Chunk size: 16 bytes
+//! - Plaintext: `The quick brown fox jumps over the lazy dog` (three
+//!   chunks of 16, 16 and 11 bytes)
+//!
+//! The AES-256-GCM vector uses a base nonce of 12 × `0x22`, the
+//! XChaCha20-Poly1305 vector one of 24 × `0x33`. Both are v3 files: the
+//! header ends in an empty extended flags byte, and the chunks in the
+//! length trailer (43 bytes before and after compression). All values are
+//! hex.
+
+use std::io::Write;
+
+use crate::crypto::cipher::CipherKind;
+use crate::crypto::format::{FileHeader, FormatVersion, HeaderFlags, build_header_bytes};
+use crate::crypto::kdf::{KdfParams, derive_key};
+use crate::crypto::stream::ChunkWriter;
+use crate::pipeline::Pipeline;
+
+const PASSWORD: &[u8] = b"resqrypt test vector";
+const SALT: [u8; 32] = [0x11; 32];
+const KDF_PARAMS: KdfParams = KdfParams { memory_cost: 1024, time_cost: 1, parallelism: 1 };
+const CHUNK_SIZE: u32 = 16;
+const PLAINTEXT: &[u8] = b"The quick brown fox jumps over the lazy dog";
+
+/// Argon2id key for the password and salt above
+const KEY: &str = "8fda98f73401ba192403f1ddd1af8777a399ce54f5e87dfff1c8b7e3af1cb03b";
+
+/// A vector: format version, cipher, base nonce, header bytes and the
+/// chunks after them
+struct Vector {
+    version: FormatVersion,
+    cipher: CipherKind,
+    nonce: &'static [u8],
+    header: &'static str,
+    chunks: &'static str,
+}
+
+const VECTORS: [Vector; 2] = [
+    Vector {
+        version: FormatVersion::V3,
+        cipher: CipherKind::Aes256Gcm,
+        nonce: &[0x22; 12],
+        header: "5245535152595054030401000400000100000001000000111111111111111111\
+                 1111111111111111111111111111111111111111111111222222222222222222\
+                 2222221000000000",
+        chunks: "20000000538528b94e8d31c119355c91d2dceba3ea2f143b7c99110a9a0e7e85\
+                 a6b7b17d200000001a13fb0dbec54b37c4f6691edbae00edbebf441d974536d1\
+                 e2a9f7ad818beeec1b0000804d5cbe40bd355dd231b335643dabf49fd197df80\
+                 f42da18b079d6d2b000000000000002b00000000000000",
+    },
+    Vector {
+        version: FormatVersion::V3,
+        cipher: CipherKind::XChaCha20Poly1305,
+        nonce: &[0x33; 24],
+        header: "5245535152595054030402000400000100000001000000111111111111111111\
+                 1111111111111111111111111111111111111111111111333333333333333333\
+                 3333333333333333333333333333331000000000",
+        chunks: "20000000ab02721cc254d2985c2a3d3b288927d5b84645da4a838ed93b1b9f6f\
+                 c686ec5f200000003a878ecba0225aacb963137431510487ccd620a892d12d34\
+                 ae99854703073fcf1b0000806fed30179e7cffa55154db4d9755d69858622350\
+                 dcaa37230c62bb2b000000000000002b00000000000000",
+    },
+];
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn header(vector: &Vector) -> FileHeader {
+    FileHeader {
+        version: vector.version,
+        ..FileHeader::new(
+            HeaderFlags::UNCOMPRESSED,
+            vector.cipher,
+            KDF_PARAMS,
+            SALT,
+            vector.nonce.to_vec(),
+        )
+    }
+    .with_chunk_size(CHUNK_SIZE)
+}
+
+#[test]
+fn test_vector_key() {
+    assert_eq!(hex(derive_key(PASSWORD, &SALT, &KDF_PARAMS).unwrap().as_bytes()), KEY);
+}
+
+#[test]
+fn test_vector_headers() {
+    for vector in &VECTORS {
+        assert_eq!(hex(&build_header_bytes(&header(vector)).unwrap()), vector.header);
+    }
+}
+
+#[test]
+fn test_vector_chunks() {
+    let key = derive_key(PASSWORD, &SALT, &KDF_PARAMS).unwrap();
+
+    for vector in &VECTORS {
+        let header = header(vector);
+        let mut chunks =
+            ChunkWriter::new(Vec::new(), &vector.cipher, key.clone(), &header).unwrap();
+        chunks.write_all(PLAINTEXT).unwrap();
+        let chunks = hex(&chunks.finish().unwrap());
+        assert_eq!(chunks, vector.chunks, "v{} {}", vector.version, vector.cipher);
+    }
+}
+
+#[test]
+fn test_vector_files_decrypt() {
+    for vector in &VECTORS {
+        let file = format!("{}{}", vector.header, vector.chunks);
+        let bytes: Vec<u8> = (0..file.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&file[i..i + 2], 16).unwrap())
+            .collect();
+
+        let (_, plaintext) = Pipeline::new().decrypt(bytes.as_slice(), PASSWORD).unwrap();
+        assert_eq!(plaintext, PLAINTEXT, "v{} {}", vector.version, vector.cipher);
+    }
+}
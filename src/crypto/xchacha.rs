@@ -0,0 +1,126 @@
+//! XChaCha20-Poly1305 encryption and decryption
+//!
+//! Provides authenticated encryption using XChaCha20-Poly1305 (AEAD). Its
+//! 192-bit nonce is large enough that random nonces never realistically
+//! collide, even across enormous numbers of messages.
+
+use chacha20poly1305::{
+    XChaCha20Poly1305, XNonce,
+    aead::{Aead, KeyInit, Payload},
+};
+
+use crate::crypto::rng::random_bytes;
+use crate::error::{ResqryptError, Result};
+use crate::xchacha_params;
+
+/// Generate a random nonce for XChaCha20-Poly1305
+pub fn generate_nonce() -> [u8; 24] {
+    random_bytes()
+}
+
+/// Encrypt data using XChaCha20-Poly1305
+///
+/// # Arguments
+/// * `key` - 32-byte encryption key (from KDF)
+/// * `nonce` - 24-byte nonce (use `generate_nonce()` for new encryptions)
+/// * `plaintext` - Data to encrypt
+///
+/// # Returns
+/// Ciphertext with authentication tag appended (plaintext.len() + 16 bytes)
+pub fn encrypt_data(key: &[u8; 32], nonce: &[u8; 24], plaintext: &[u8]) -> Result<Vec<u8>> {
+    encrypt_data_with_aad(key, nonce, plaintext, &[])
+}
+
+/// Encrypt data, additionally authenticating `aad` without encrypting it
+pub fn encrypt_data_with_aad(
+    key: &[u8; 32],
+    nonce: &[u8; 24],
+    plaintext: &[u8],
+    aad: &[u8],
+) -> Result<Vec<u8>> {
+    let cipher = XChaCha20Poly1305::new_from_slice(key)
+        .map_err(|e| ResqryptError::CryptoError(format!("Failed to create cipher: {}", e)))?;
+
+    let nonce = XNonce::from_slice(nonce);
+
+    cipher
+        .encrypt(nonce, Payload { msg: plaintext, aad })
+        .map_err(|e| ResqryptError::CryptoError(format!("Encryption failed: {}", e)))
+}
+
+/// Decrypt data using XChaCha20-Poly1305
+///
+/// # Arguments
+/// * `key` - 32-byte encryption key (from KDF)
+/// * `nonce` - 24-byte nonce (must match the nonce used for encryption)
+/// * `ciphertext` - Encrypted data with authentication tag
+///
+/// # Errors
+/// Returns an error if authentication fails (wrong password or tampered data)
+pub fn decrypt_data(key: &[u8; 32], nonce: &[u8; 24], ciphertext: &[u8]) -> Result<Vec<u8>> {
+    decrypt_data_with_aad(key, nonce, ciphertext, &[])
+}
+
+/// Decrypt data that was encrypted with [`encrypt_data_with_aad`]
+///
+/// # Errors
+/// Returns an error if authentication fails, including when `aad` differs
+/// from the value used for encryption
+pub fn decrypt_data_with_aad(
+    key: &[u8; 32],
+    nonce: &[u8; 24],
+    ciphertext: &[u8],
+    aad: &[u8],
+) -> Result<Vec<u8>> {
+    if ciphertext.len() < xchacha_params::TAG_LEN {
+        return Err(ResqryptError::CryptoError("Ciphertext too short".to_string()));
+    }
+
+    let cipher = XChaCha20Poly1305::new_from_slice(key)
+        .map_err(|e| ResqryptError::CryptoError(format!("Failed to create cipher: {}", e)))?;
+
+    let nonce = XNonce::from_slice(nonce);
+
+    cipher.decrypt(nonce, Payload { msg: ciphertext, aad }).map_err(|_| {
+        ResqryptError::PasswordError(
+            "Decryption failed: wrong password or corrupted data".to_string(),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encrypt_decrypt_roundtrip() {
+        let key = [0u8; 32];
+        let nonce = generate_nonce();
+        let plaintext = b"Hello, World!";
+
+        let ciphertext = encrypt_data(&key, &nonce, plaintext).unwrap();
+        let decrypted = decrypt_data(&key, &nonce, &ciphertext).unwrap();
+
+        assert_eq!(plaintext.as_slice(), decrypted.as_slice());
+        assert_eq!(ciphertext.len(), plaintext.len() + 16);
+    }
+
+    #[test]
+    fn test_wrong_key_fails() {
+        let nonce = generate_nonce();
+        let ciphertext = encrypt_data(&[0u8; 32], &nonce, b"Secret data").unwrap();
+
+        assert!(decrypt_data(&[1u8; 32], &nonce, &ciphertext).is_err());
+    }
+
+    #[test]
+    fn test_tampered_ciphertext_fails() {
+        let key = [0u8; 32];
+        let nonce = generate_nonce();
+
+        let mut ciphertext = encrypt_data(&key, &nonce, b"Secret data").unwrap();
+        ciphertext[0] ^= 0xFF;
+
+        assert!(decrypt_data(&key, &nonce, &ciphertext).is_err());
+    }
+}
@@ -2,6 +2,7 @@
 //!
 //! This module defines all error types used throughout the application.
 
+use std::fmt;
 use std::path::PathBuf;
 use thiserror::Error;
 
@@ -28,9 +29,9 @@ pub enum ResqryptError {
     #[error("Compression error: {0}")]
     CompressionError(String),
 
-    /// Archive operation failed
-    #[error("Archive error: {0}")]
-    ArchiveError(String),
+    /// Archive operation failed, at `path` when a single entry is to blame
+    #[error("Archive error{}: {message}", at_path(.path))]
+    ArchiveError { message: String, path: Option<PathBuf> },
 
     /// File or directory not found
     #[error("Not found: {}", .0.display())]
@@ -40,10 +41,88 @@ pub enum ResqryptError {
     #[error("File already exists: {}", .0.display())]
     AlreadyExists(PathBuf),
 
+    /// Configuration file could not be parsed
+    #[error("Config error: {0}")]
+    ConfigError(String),
+
+    /// External filter command failed
+    #[error("Filter command failed: {0}")]
+    FilterError(String),
+
     /// Invalid argument provided
     #[error("Invalid argument: {0}")]
     InvalidArgument(String),
+
+    /// The operation was cancelled through its cancellation token
+    #[error("Operation cancelled")]
+    Cancelled,
+
+    /// The user answered no when asked to confirm `0`, e.g. "archiving"
+    #[error("{0} declined by user")]
+    Declined(String),
+
+    /// Some files of a batch failed, each reported as it did
+    #[error("{failed} of {total} files failed")]
+    BatchFailed { failed: usize, total: usize },
+}
+
+/// ` at <path>`, or nothing without a path
+fn at_path(path: &Option<PathBuf>) -> String {
+    path.as_ref().map(|path| format!(" at {}", path.display())).unwrap_or_default()
+}
+
+impl ResqryptError {
+    /// Archive error not tied to one entry
+    pub fn archive(message: impl Into<String>) -> Self {
+        Self::ArchiveError { message: message.into(), path: None }
+    }
+
+    /// Archive error at `path`, described by its cause
+    pub fn archive_at(path: impl Into<PathBuf>, cause: impl fmt::Display) -> Self {
+        Self::ArchiveError { message: cause.to_string(), path: Some(path.into()) }
+    }
+
+    /// Convert an I/O error, recovering a resqrypt error that a reader or
+    /// writer adapter wrapped inside it
+    pub fn from_io(err: std::io::Error) -> Self {
+        match err.downcast::<Self>() {
+            Ok(e) => e,
+            Err(err) => Self::Io(err),
+        }
+    }
 }
 
 /// Result type alias for resqrypt operations
 pub type Result<T> = std::result::Result<T, ResqryptError>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    #[test]
+    fn test_from_io_recovers_wrapped_error() {
+        let wrapped = io::Error::new(
+            io::ErrorKind::InvalidData,
+            ResqryptError::PasswordError("bad tag".to_string()),
+        );
+        assert!(matches!(ResqryptError::from_io(wrapped), ResqryptError::PasswordError(_)));
+
+        let plain = io::Error::other("disk on fire");
+        assert!(matches!(ResqryptError::from_io(plain), ResqryptError::Io(_)));
+    }
+
+    #[test]
+    fn test_declined_is_not_cancelled() {
+        let err = ResqryptError::Declined("archiving".to_string());
+        assert_eq!(err.to_string(), "archiving declined by user");
+        assert_ne!(err.to_string(), ResqryptError::Cancelled.to_string());
+    }
+
+    #[test]
+    fn test_archive_error_path() {
+        let err = ResqryptError::archive_at("/data/locked", "Permission denied");
+        assert_eq!(err.to_string(), "Archive error at /data/locked: Permission denied");
+        assert_eq!(ResqryptError::archive("truncated").to_string(), "Archive error: truncated");
+    }
+}
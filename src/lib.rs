@@ -2,7 +2,7 @@
 //!
 //! A command-line tool for encrypting files and directories using:
 //! - **zstd** compression (with smart detection of already-compressed files)
-//! - **AES-256-GCM** authenticated encryption
+//! - **AES-256-GCM** or **XChaCha20-Poly1305** authenticated encryption
 //! - **Argon2id** password-based key derivation
 //!
 //! # Example
@@ -14,29 +14,86 @@
 //! # Decrypt a file
 //! resqrypt decrypt -i secret.txt.resqrypt -o secret.txt
 //! ```
+//!
+//! # Features
+//!
+//! - `cli` (default): the command-line interface in the `cli`, `commands`
+//!   and `config` modules, with progress reporting and password prompts.
+//!   Build with `default-features = false` for the library core alone.
+//! - `tracing`: `tracing` spans on the command entry points, streaming
+//!   encryption and decryption, key derivation and the archive builders,
+//!   with byte counts and `elapsed_ms` fields. No subscriber is installed.
+//! - `async`: tokio `AsyncWrite`/`AsyncRead` adapters over the chunked
+//!   format, `crypto::AsyncEncryptWriter` and `crypto::AsyncDecryptReader`,
+//!   which derive the key on the blocking thread pool.
+//! - `age`: `crypto::age`, writing files `age` and `rage` can decrypt, to a
+//!   passphrase or X25519 recipients, and `encrypt --format age`.
 
 pub mod archive;
+#[cfg(feature = "cli")]
 pub mod cli;
+#[cfg(feature = "cli")]
 pub mod commands;
 pub mod compression;
+#[cfg(feature = "cli")]
+pub mod config;
 pub mod crypto;
 pub mod error;
+pub mod pipeline;
 pub mod utils;
 
+pub use crypto::{FileInfo, inspect};
 pub use error::{ResqryptError, Result};
 
 /// File format magic bytes
 pub const MAGIC_BYTES: &[u8; 8] = b"RESQRYPT";
 
-/// Current file format version
-pub const FORMAT_VERSION: u8 = 0x01;
+/// Extension of encrypted files
+pub const FILE_EXTENSION: &str = "resqrypt";
+
+/// Current file format version (typed as [`crypto::FormatVersion::CURRENT`])
+pub const FORMAT_VERSION: u8 = 0x03;
+
+/// Format version that seals the payload as a single AEAD message
+pub const SEALED_VERSION: u8 = 0x02;
 
 /// Flags for the encrypted file format
+///
+/// Raw bit values of the flags byte; [`crate::crypto::HeaderFlags`] is the
+/// typed form held by [`crate::crypto::FileHeader`].
 pub mod flags {
     /// Bit 0: 0 = data was compressed, 1 = data was already zstd
     pub const ALREADY_ZSTD: u8 = 0b0000_0001;
     /// Bit 1: 0 = single file, 1 = directory (tar archive)
     pub const IS_DIRECTORY: u8 = 0b0000_0010;
+    /// Bit 2: 1 = data is stored raw because compression would enlarge it
+    pub const UNCOMPRESSED: u8 = 0b0000_0100;
+    /// Bit 3: 1 = plaintext was passed through `--filter-cmd` before encryption
+    pub const FILTERED: u8 = 0b0000_1000;
+    /// Bit 4: 1 = payload is a byte range of the source file, whose offset
+    /// is recorded in the header (v3 and later)
+    pub const RANGE: u8 = 0b0001_0000;
+    /// Bit 5: 1 = the encrypted payload ends in length-hiding padding
+    pub const PADDED: u8 = 0b0010_0000;
+    /// Bit 6: 1 = a secret pepper, not stored in the file, was mixed into
+    /// the password before key derivation
+    pub const PEPPERED: u8 = 0b0100_0000;
+    /// Bit 7: 1 = chunk boundaries follow the content, and each chunk
+    /// stores a nonce derived from its plaintext (v3 and later)
+    pub const CONTENT_DEFINED: u8 = 0b1000_0000;
+}
+
+/// Flags of the extended flags byte (v3 and later)
+///
+/// Raw bit values of the second flags byte, added once the first was full;
+/// [`crate::crypto::ExtendedFlags`] is the typed form.
+pub mod ext_flags {
+    /// Bit 0: 1 = the header stores the relative path of the encrypted
+    /// file, which decryption recreates below the output directory
+    pub const STORED_NAME: u8 = 0b0000_0001;
+    /// Bit 1: 1 = the chunks are followed by a Merkle tree over their auth
+    /// tags, so any chunk can be authenticated on its own
+    pub const MERKLE: u8 = 0b0000_0010;
 }
 
 /// zstd magic bytes for detection
@@ -44,13 +101,14 @@ pub const ZSTD_MAGIC: &[u8; 4] = &[0x28, 0xB5, 0x2F, 0xFD];
 
 /// Default Argon2id parameters
 pub mod kdf_defaults {
-    /// Memory cost in KiB (64 MB)
+    /// Memory cost in KiB (64 MiB)
     pub const MEMORY_COST: u32 = 64 * 1024;
     /// Number of iterations
     pub const TIME_COST: u32 = 3;
     /// Degree of parallelism
     pub const PARALLELISM: u32 = 4;
-    /// Output key length in bytes
+    /// Output key length in bytes, unless a cipher asks for another (see
+    /// [`crate::crypto::kdf::derive_key_len`])
     pub const OUTPUT_LEN: usize = 32;
     /// Salt length in bytes
     pub const SALT_LEN: usize = 32;
@@ -58,8 +116,20 @@ pub mod kdf_defaults {
 
 /// AES-256-GCM parameters
 pub mod aes_params {
+    /// Key length in bytes
+    pub const KEY_LEN: usize = 32;
     /// Nonce length in bytes
     pub const NONCE_LEN: usize = 12;
     /// Authentication tag length in bytes
     pub const TAG_LEN: usize = 16;
 }
+
+/// XChaCha20-Poly1305 parameters
+pub mod xchacha_params {
+    /// Key length in bytes
+    pub const KEY_LEN: usize = 32;
+    /// Nonce length in bytes
+    pub const NONCE_LEN: usize = 24;
+    /// Authentication tag length in bytes
+    pub const TAG_LEN: usize = 16;
+}
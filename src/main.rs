@@ -5,21 +5,98 @@
 use anyhow::Result;
 use clap::Parser;
 
-use resqrypt::cli::{Cli, Commands};
+use resqrypt::cli::{Cli, ColorChoice, Commands};
 use resqrypt::commands;
+use resqrypt::commands::decrypt::PasswordCheck;
+use resqrypt::config::{Config, Policy};
+use resqrypt::crypto::{self, RandomSource};
 
 fn main() -> Result<()> {
     let cli = Cli::parse();
+    let mut wrong_password = false;
 
-    let result = match cli.command {
-        Commands::Encrypt(args) => commands::encrypt(args),
-        Commands::Decrypt(args) => commands::decrypt(args),
-    };
+    // Only the CLI ever seeds the salts and nonces of new files
+    let mut rng = RandomSource::os();
+    if let Some(seed) =
+        std::env::var(crypto::DETERMINISTIC_SEED_ENV).ok().filter(|seed| !seed.is_empty())
+    {
+        rng = RandomSource::seeded(&seed);
+        eprintln!(
+            "⚠️  Warning: {} is set: salts and nonces are predictable and repeat across \
+             files. This is INSECURE; use it only for tests",
+            crypto::DETERMINISTIC_SEED_ENV
+        );
+    }
+
+    let result = Config::load(cli.config.as_deref()).and_then(|config| {
+        for key in &config.unknown_keys {
+            eprintln!("⚠️  Warning: ignoring unknown config key `{}`", key);
+        }
+
+        apply_color(cli.color.or(config.color).unwrap_or_default());
+
+        match cli.command {
+            Commands::Encrypt(mut args) => {
+                let policies = Policy::load_all(args.kdf_params_from.as_deref())?;
+                for policy in &policies {
+                    for key in &policy.config.unknown_keys {
+                        eprintln!(
+                            "⚠️  Warning: ignoring unknown policy key `{}` in {}",
+                            key,
+                            policy.path.display()
+                        );
+                    }
+                }
+
+                if let Some(profile) = args.profile {
+                    args.apply_config(&profile.config());
+                }
+                args.apply_config(&config);
+                for policy in &policies {
+                    args.apply_policy(policy)?;
+                }
+                args.rng = rng;
+                commands::encrypt(*args)
+            }
+            Commands::Decrypt(args) if args.test_password => {
+                // A wrong password exits with 2, distinct from other failures
+                commands::decrypt::test_password(&args)
+                    .map(|check| wrong_password = check == PasswordCheck::Incorrect)
+            }
+            Commands::Decrypt(args) => commands::decrypt(args),
+            Commands::Migrate(mut args) => {
+                args.rng = rng;
+                commands::migrate(args)
+            }
+            Commands::Recover(args) => commands::recover(args),
+            Commands::Verify(args) => commands::verify(args),
+            Commands::Selftest => commands::selftest(),
+            Commands::Capabilities(args) => commands::capabilities(args),
+        }
+    });
 
     if let Err(e) = result {
         eprintln!("❌ Error: {}", e);
         std::process::exit(1);
     }
+    if wrong_password {
+        std::process::exit(2);
+    }
 
     Ok(())
 }
+
+/// Apply the colored output setting to the terminal styling library
+fn apply_color(choice: ColorChoice) {
+    match choice {
+        ColorChoice::Auto => {}
+        ColorChoice::Always => {
+            console::set_colors_enabled(true);
+            console::set_colors_enabled_stderr(true);
+        }
+        ColorChoice::Never => {
+            console::set_colors_enabled(false);
+            console::set_colors_enabled_stderr(false);
+        }
+    }
+}
@@ -0,0 +1,1550 @@
+//! Composable encryption pipeline
+//!
+//! Chains the stages that turn plaintext into a resqrypt file and back:
+//! 1. [`Compressor`] - shrinks the data and records how it was stored
+//! 2. [`Cipher`] - seals the payload, authenticating the header as AAD, and
+//!    opens it again
+//! 3. [`Framing`] - writes and reads the header and ciphertext
+//!
+//! [`Pipeline`] wires the stages together with a builder so embedders can
+//! swap any of them. The CLI commands are expressed in terms of it.
+//!
+//! [`Pipeline::encrypt_stream`] pipes the input through a streaming
+//! compressor into fixed-size encrypted chunks (format v3 and later), so
+//! memory use does not grow with the input size. [`Pipeline::seal`] instead
+//! seals an in-memory payload as a single message (format v2); with
+//! [`Pipeline::single_shot_max`], `encrypt_stream` does so itself for
+//! inputs that turn out to be small.
+//!
+//! # Example
+//!
+//! ```
+//! use resqrypt::crypto::{CipherKind, HeaderFlags, KdfParams};
+//! use resqrypt::pipeline::{Pipeline, ZstdCompressor};
+//!
+//! let pipeline = Pipeline::new()
+//!     .compressor(ZstdCompressor::new(9))
+//!     .cipher(CipherKind::XChaCha20Poly1305)
+//!     .kdf_params(KdfParams { memory_cost: 1024, time_cost: 1, parallelism: 1 });
+//!
+//! let mut file = Vec::new();
+//! let flags = HeaderFlags::empty();
+//! pipeline.encrypt_stream(&b"secret"[..], flags, b"password", &mut file)?;
+//!
+//! let (_header, plaintext) = pipeline.decrypt(file.as_slice(), b"password")?;
+//! assert_eq!(plaintext, b"secret");
+//! # Ok::<(), resqrypt::ResqryptError>(())
+//! ```
+
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Cursor, Read, Write};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tar::Archive;
+use zeroize::Zeroizing;
+
+use crate::compression::is_zstd_compressed;
+use crate::compression::zstd::{
+    DEFAULT_LEVEL, compress_reader, compress_reader_long, compress_with_level, decompress_reader,
+};
+pub use crate::crypto::cipher::Cipher;
+use crate::crypto::cipher::CipherKind;
+use crate::crypto::format::{
+    FileHeader, FormatVersion, HeaderFlags, read_ciphertext, read_header, write_header,
+};
+use crate::crypto::kdf::{
+    DEFAULT_MAX_MEMORY_COST, KdfParams, apply_pepper, derive_key_len, key_fingerprint,
+};
+use crate::crypto::padding::{Padding, UnpadReader, write_padding};
+use crate::crypto::rng::RandomSource;
+use crate::crypto::stream::{ChunkReader, ChunkWriter, DEFAULT_CHUNK_SIZE, check_chunk_size};
+use crate::error::{ResqryptError, Result};
+use crate::utils::telemetry::{self, SpanStats};
+use crate::utils::{CancellableReader, CancellationToken, CountingReader};
+
+/// Compression stage
+pub trait Compressor {
+    /// Compress data, returning the payload and the header flags describing
+    /// how it was stored
+    fn compress(&self, data: Vec<u8>) -> Result<(Vec<u8>, HeaderFlags)>;
+
+    /// Reader yielding the compressed form of `input`, and the header flags
+    /// describing how it is stored
+    fn compress_reader<'a>(
+        &self,
+        input: Box<dyn BufRead + 'a>,
+    ) -> Result<(Box<dyn Read + 'a>, HeaderFlags)>;
+
+    /// Reader yielding the original data from a payload stored with `flags`
+    fn decompress_reader<'a>(
+        &self,
+        payload: Box<dyn Read + 'a>,
+        flags: HeaderFlags,
+    ) -> Result<Box<dyn Read + 'a>>;
+
+    /// Restore the original data from a payload stored with `flags`
+    fn decompress(&self, payload: &[u8], flags: HeaderFlags) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        self.decompress_reader(Box::new(payload), flags)?
+            .read_to_end(&mut data)
+            .map_err(|e| ResqryptError::CompressionError(format!("Decompression failed: {}", e)))?;
+        Ok(data)
+    }
+}
+
+/// Bytes at the start of a stream test-compressed to decide whether
+/// compressing the rest pays off
+const SAMPLE_LEN: usize = 128 * 1024;
+
+/// zstd compression that skips data already in zstd format
+///
+/// When compressing a whole buffer, data that compression would enlarge is
+/// also stored as-is. A stream is stored as-is when its first 128 KiB
+/// shrink by less than 1/32 at level 1, as with a tar of JPEGs, which
+/// starts with a tar header rather than the zstd magic.
+#[derive(Debug, Clone, Copy)]
+pub struct ZstdCompressor {
+    level: i32,
+    window_log: Option<u32>,
+}
+
+impl ZstdCompressor {
+    /// Create a compressor using the given zstd level (1-22)
+    pub fn new(level: i32) -> Self {
+        Self { level, window_log: None }
+    }
+
+    /// Enable long-distance matching over a window of `2^window_log` bytes
+    ///
+    /// Helps large inputs with repetitions far apart, but compressing and
+    /// decompressing then both hold the whole window in memory.
+    pub fn long_distance(mut self, window_log: u32) -> Self {
+        self.window_log = Some(window_log);
+        self
+    }
+
+    /// Compression level
+    pub fn level(&self) -> i32 {
+        self.level
+    }
+
+    /// Window log of long-distance matching, if enabled
+    pub fn window_log(&self) -> Option<u32> {
+        self.window_log
+    }
+}
+
+impl Default for ZstdCompressor {
+    fn default() -> Self {
+        Self::new(DEFAULT_LEVEL)
+    }
+}
+
+impl Compressor for ZstdCompressor {
+    fn compress(&self, data: Vec<u8>) -> Result<(Vec<u8>, HeaderFlags)> {
+        if is_zstd_compressed(&data) {
+            return Ok((data, HeaderFlags::ALREADY_ZSTD));
+        }
+
+        let compressed = match self.window_log {
+            Some(window_log) => {
+                let mut compressed = Vec::new();
+                compress_reader_long(&data[..], self.level, window_log)?
+                    .read_to_end(&mut compressed)
+                    .map_err(ResqryptError::from_io)?;
+                compressed
+            }
+            None => compress_with_level(&data, self.level)?,
+        };
+        if compressed.len() >= data.len() {
+            Ok((data, HeaderFlags::UNCOMPRESSED))
+        } else {
+            Ok((compressed, HeaderFlags::empty()))
+        }
+    }
+
+    fn compress_reader<'a>(
+        &self,
+        mut input: Box<dyn BufRead + 'a>,
+    ) -> Result<(Box<dyn Read + 'a>, HeaderFlags)> {
+        if is_zstd_compressed(input.fill_buf().map_err(ResqryptError::from_io)?) {
+            return Ok((input, HeaderFlags::ALREADY_ZSTD));
+        }
+
+        let mut sample = Vec::with_capacity(SAMPLE_LEN);
+        (&mut input)
+            .take(SAMPLE_LEN as u64)
+            .read_to_end(&mut sample)
+            .map_err(ResqryptError::from_io)?;
+        let incompressible = looks_incompressible(&sample)?;
+        let input = Cursor::new(sample).chain(input);
+        if incompressible {
+            return Ok((Box::new(input), HeaderFlags::UNCOMPRESSED));
+        }
+
+        let encoder = match self.window_log {
+            Some(window_log) => compress_reader_long(input, self.level, window_log)?,
+            None => compress_reader(input, self.level)?,
+        };
+        Ok((Box::new(encoder), HeaderFlags::empty()))
+    }
+
+    fn decompress_reader<'a>(
+        &self,
+        payload: Box<dyn Read + 'a>,
+        flags: HeaderFlags,
+    ) -> Result<Box<dyn Read + 'a>> {
+        if flags.intersects(HeaderFlags::ALREADY_ZSTD | HeaderFlags::UNCOMPRESSED) {
+            Ok(payload)
+        } else {
+            Ok(Box::new(decompress_reader(payload)?))
+        }
+    }
+}
+
+/// Whether `sample` shrinks by less than 1/32 when compressed at level 1
+fn looks_incompressible(sample: &[u8]) -> Result<bool> {
+    let compressed = compress_with_level(sample, 1)?;
+    Ok(compressed.len() >= sample.len() - sample.len() / 32)
+}
+
+/// Stores data as-is
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoCompression;
+
+impl Compressor for NoCompression {
+    fn compress(&self, data: Vec<u8>) -> Result<(Vec<u8>, HeaderFlags)> {
+        Ok((data, HeaderFlags::UNCOMPRESSED))
+    }
+
+    fn compress_reader<'a>(
+        &self,
+        input: Box<dyn BufRead + 'a>,
+    ) -> Result<(Box<dyn Read + 'a>, HeaderFlags)> {
+        Ok((input, HeaderFlags::UNCOMPRESSED))
+    }
+
+    fn decompress_reader<'a>(
+        &self,
+        payload: Box<dyn Read + 'a>,
+        flags: HeaderFlags,
+    ) -> Result<Box<dyn Read + 'a>> {
+        // Files written by other compressors can still be read back
+        ZstdCompressor::default().decompress_reader(payload, flags)
+    }
+}
+
+/// Header writing stage
+pub trait Framing {
+    /// Write the header
+    fn write_header(&self, out: &mut dyn Write, header: &FileHeader) -> Result<()>;
+
+    /// Read and validate a header
+    fn read_header(&self, input: &mut dyn Read) -> Result<FileHeader>;
+
+    /// Read the single-message ciphertext following `header`, also
+    /// returning the number of ignored trailing bytes
+    fn read_ciphertext(&self, input: &mut dyn Read, header: &FileHeader) -> Result<(Vec<u8>, u64)>;
+
+    /// Write the header followed by the ciphertext
+    fn write_frame(
+        &self,
+        out: &mut dyn Write,
+        header: &FileHeader,
+        ciphertext: &[u8],
+    ) -> Result<()> {
+        self.write_header(out, header)?;
+        out.write_all(ciphertext)?;
+        Ok(())
+    }
+
+    /// Read a header and its ciphertext, also returning the number of
+    /// ignored trailing bytes
+    fn read_frame(&self, input: &mut dyn Read) -> Result<(FileHeader, Vec<u8>, u64)> {
+        let header = self.read_header(input)?;
+        let (ciphertext, trailing) = self.read_ciphertext(input, &header)?;
+        Ok((header, ciphertext, trailing))
+    }
+}
+
+/// The resqrypt file format
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ResqryptFraming;
+
+impl Framing for ResqryptFraming {
+    fn write_header(&self, mut out: &mut dyn Write, header: &FileHeader) -> Result<()> {
+        write_header(&mut out, header)
+    }
+
+    fn read_header(&self, mut input: &mut dyn Read) -> Result<FileHeader> {
+        read_header(&mut input)
+    }
+
+    fn read_ciphertext(&self, input: &mut dyn Read, header: &FileHeader) -> Result<(Vec<u8>, u64)> {
+        read_ciphertext(input, header)
+    }
+}
+
+/// Chunks held in memory at once while streaming: the plaintext being
+/// sealed or opened, its ciphertext, and the buffered reader and writer
+/// around them
+pub const BUFFERED_CHUNKS: u64 = 4;
+
+/// Rough peak memory of one streaming encryption or decryption
+///
+/// Errs on the high side: the zstd estimate assumes the full window and
+/// match tables of about the same size, which small inputs never reach.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryEstimate {
+    /// Bytes of Argon2id memory used while deriving the key
+    pub argon2: u64,
+    /// Bytes of chunk buffers and compression state while streaming
+    pub buffers: u64,
+}
+
+impl MemoryEstimate {
+    /// Estimate for Argon2id with `kdf_params`, chunks of `chunk_size` and,
+    /// if the data is compressed, a zstd window of 2^`window_log` bytes
+    pub fn new(kdf_params: &KdfParams, chunk_size: u32, window_log: Option<u32>) -> Self {
+        let zstd = window_log.map_or(0, |log| 2u64 << log);
+        Self {
+            argon2: u64::from(kdf_params.memory_cost) * 1024,
+            buffers: BUFFERED_CHUNKS * u64::from(chunk_size) + zstd,
+        }
+    }
+
+    /// Estimated peak in bytes
+    pub fn total(&self) -> u64 {
+        self.argon2 + self.buffers
+    }
+}
+
+/// Diagnostic event reported by [`Pipeline::encrypt_stream`], and by
+/// [`Pipeline::decrypt_stream`] once the key of a chunked file is derived
+///
+/// The fields are meant for logging and metrics: a service can forward
+/// them to `log` or another logger from the hook. With the `tracing`
+/// feature, [`Trace::Sealed`] also fills in the fields of the
+/// [`Pipeline::encrypt_stream`] span.
+#[derive(Debug)]
+pub enum Trace<'a> {
+    /// The key was derived; only a fingerprint of it is reported
+    KeyDerived { fingerprint: String, elapsed: Duration },
+    /// The header is resolved and about to be written
+    Header(&'a FileHeader),
+    /// The payload was compressed and sealed: `original_len` bytes were
+    /// read and `payload_len` bytes (after compression and padding) sealed
+    Sealed { chunks: u64, original_len: u64, payload_len: u64, elapsed: Duration },
+}
+
+impl Trace<'_> {
+    /// Verbosity level at which the event is worth showing
+    pub fn level(&self) -> u8 {
+        match self {
+            Trace::Header(_) => 3,
+            Trace::KeyDerived { .. } | Trace::Sealed { .. } => 2,
+        }
+    }
+}
+
+impl fmt::Display for Trace<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Trace::KeyDerived { fingerprint, elapsed } => write!(
+                f,
+                "Key derived in {:.3}s (fingerprint {})",
+                elapsed.as_secs_f64(),
+                fingerprint
+            ),
+            Trace::Header(header) => write!(f, "Header:\n{}", header),
+            Trace::Sealed { chunks, original_len, payload_len, elapsed } => write!(
+                f,
+                "Compressed {} bytes to {} and sealed {} chunks in {:.3}s",
+                original_len,
+                payload_len,
+                chunks,
+                elapsed.as_secs_f64()
+            ),
+        }
+    }
+}
+
+/// Callback receiving [`Trace`] events
+type TraceHook = dyn Fn(&Trace<'_>);
+
+/// Compression, encryption and framing wired together
+pub struct Pipeline {
+    compressor: Box<dyn Compressor>,
+    cipher: Arc<dyn Cipher>,
+    /// Whether the encryption stage was replaced, and so must open files
+    custom_cipher: bool,
+    framing: Box<dyn Framing>,
+    kdf_params: KdfParams,
+    chunk_size: u32,
+    single_shot_max: Option<u64>,
+    source_offset: Option<u64>,
+    stored_name: Option<String>,
+    padding: Option<Padding>,
+    trace: Option<Box<TraceHook>>,
+    cancel: Option<CancellationToken>,
+    pepper: Option<Zeroizing<Vec<u8>>>,
+    content_defined: bool,
+    merkle: bool,
+    salt: Option<[u8; 32]>,
+    rng: RandomSource,
+    max_memory_cost: u32,
+}
+
+impl Default for Pipeline {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Pipeline {
+    /// Create a pipeline with the default stages: zstd level 3, AES-256-GCM,
+    /// the resqrypt file format, default Argon2id parameters and 64 KiB
+    /// chunks
+    pub fn new() -> Self {
+        Self {
+            compressor: Box::new(ZstdCompressor::default()),
+            cipher: Arc::new(CipherKind::default()),
+            custom_cipher: false,
+            framing: Box::new(ResqryptFraming),
+            kdf_params: KdfParams::default(),
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            single_shot_max: None,
+            source_offset: None,
+            stored_name: None,
+            padding: None,
+            trace: None,
+            cancel: None,
+            pepper: None,
+            content_defined: false,
+            merkle: false,
+            salt: None,
+            rng: RandomSource::default(),
+            max_memory_cost: DEFAULT_MAX_MEMORY_COST,
+        }
+    }
+
+    /// Replace the compression stage
+    pub fn compressor(mut self, compressor: impl Compressor + 'static) -> Self {
+        self.compressor = Box::new(compressor);
+        self
+    }
+
+    /// Replace the encryption stage
+    ///
+    /// The stage also decrypts, so it must implement the cipher recorded
+    /// in the files given to [`Pipeline::open`] and the other decryption
+    /// methods. Without it, they use the header's built-in cipher.
+    pub fn cipher(mut self, cipher: impl Cipher + 'static) -> Self {
+        self.cipher = Arc::new(cipher);
+        self.custom_cipher = true;
+        self
+    }
+
+    /// Replace the framing stage
+    pub fn framing(mut self, framing: impl Framing + 'static) -> Self {
+        self.framing = Box::new(framing);
+        self
+    }
+
+    /// Set the Argon2id parameters used when encrypting
+    pub fn kdf_params(mut self, kdf_params: KdfParams) -> Self {
+        self.kdf_params = kdf_params;
+        self
+    }
+
+    /// Set the number of plaintext bytes per chunk used when streaming
+    pub fn chunk_size(mut self, chunk_size: u32) -> Self {
+        self.chunk_size = chunk_size;
+        self
+    }
+
+    /// Seal streamed inputs of at most `max_len` bytes as a single message
+    /// (format v2) instead of in chunks
+    ///
+    /// Up to `max_len` bytes of the input are buffered to find out; larger
+    /// inputs continue as a stream. Small files then skip the per-chunk
+    /// overhead. Padding, content-defined chunks, a source offset, a
+    /// stored name and a Merkle footer all need the chunked format and
+    /// disable this.
+    pub fn single_shot_max(mut self, max_len: u64) -> Self {
+        self.single_shot_max = Some(max_len);
+        self
+    }
+
+    /// Record that streamed input is a byte range of its source starting at
+    /// `offset`
+    pub fn source_offset(mut self, offset: u64) -> Self {
+        self.source_offset = Some(offset);
+        self
+    }
+
+    /// Store `name`, the relative path of the streamed file, in the header
+    /// (see [`crate::crypto::format::check_stored_name`])
+    pub fn stored_name(mut self, name: impl Into<String>) -> Self {
+        self.stored_name = Some(name.into());
+        self
+    }
+
+    /// Pad streamed payloads to a bucket boundary to hide their length
+    pub fn padding(mut self, padding: Padding) -> Self {
+        self.padding = Some(padding);
+        self
+    }
+
+    /// Report diagnostic events while encrypting and decrypting (see
+    /// [`Trace`])
+    pub fn trace(mut self, trace: impl Fn(&Trace<'_>) + 'static) -> Self {
+        self.trace = Some(Box::new(trace));
+        self
+    }
+
+    /// Stop streaming with [`ResqryptError::Cancelled`] once `token` is
+    /// cancelled
+    ///
+    /// The token is checked between reads of the input when encrypting and
+    /// of the decrypted payload when decrypting, and after key derivation,
+    /// which cannot itself be interrupted. Whatever was already written to
+    /// the output is left to the caller to discard, as with any other
+    /// error; [`crate::utils::write_atomic`] does so.
+    pub fn cancel_on(mut self, token: CancellationToken) -> Self {
+        self.cancel = Some(token);
+        self
+    }
+
+    /// Mix a secret pepper into the password before deriving keys
+    ///
+    /// Files encrypted by this pipeline are marked as peppered and can only
+    /// be decrypted by a pipeline given the same pepper. The pepper itself
+    /// is never written to the file.
+    pub fn pepper(mut self, pepper: impl Into<Vec<u8>>) -> Self {
+        self.pepper = Some(Zeroizing::new(pepper.into()));
+        self
+    }
+
+    /// Cut streamed chunks at content-defined boundaries instead of every
+    /// chunk size bytes (see [`crate::crypto::stream`])
+    ///
+    /// The chunk size becomes the largest chunk. An edit then only changes
+    /// the chunks around it, and equal chunks encrypt to equal bytes under
+    /// the same key: backup tools can deduplicate them, but anyone can also
+    /// see which parts of the output repeat.
+    pub fn content_defined(mut self) -> Self {
+        self.content_defined = true;
+        self
+    }
+
+    /// Follow streamed chunks with a Merkle tree over their tags (see
+    /// [`crate::crypto::merkle`])
+    ///
+    /// Any chunk can then be authenticated on its own, content-defined ones
+    /// included, for 32 bytes per chunk in the file and in memory while
+    /// encrypting.
+    pub fn merkle(mut self) -> Self {
+        self.merkle = true;
+        self
+    }
+
+    /// Derive the key from `salt` instead of a fresh random one
+    ///
+    /// Meant for rewriting a content-defined file so its unchanged chunks
+    /// keep their bytes, as the same password and salt give the same key.
+    /// Anywhere else this gives up what a fresh salt is for.
+    pub fn reuse_salt(mut self, salt: [u8; 32]) -> Self {
+        self.salt = Some(salt);
+        self
+    }
+
+    /// Draw the salts and nonces of new files from `rng` instead of the
+    /// operating system's RNG
+    ///
+    /// Only for reproducible test output: see [`crate::crypto::rng`] for
+    /// why a seeded source must not encrypt real data.
+    pub fn rng(mut self, rng: RandomSource) -> Self {
+        self.rng = rng;
+        self
+    }
+
+    /// Accept headers asking for up to `max_kib` KiB of Argon2 memory,
+    /// instead of [`DEFAULT_MAX_MEMORY_COST`]
+    ///
+    /// Only for files known to need it: the limit is what keeps a crafted
+    /// header from making key derivation allocate terabytes.
+    pub fn max_memory_cost(mut self, max_kib: u32) -> Self {
+        self.max_memory_cost = max_kib;
+        self
+    }
+
+    /// Password to derive the key of a file with `header` from: mixed with
+    /// the pepper if the file was encrypted with one
+    ///
+    /// [`Pipeline::open`] and [`ChunkReader::unlock`] take the returned
+    /// bytes; [`Pipeline::decrypt_stream`] calls this itself.
+    pub fn key_password(&self, header: &FileHeader, password: &[u8]) -> Result<Zeroizing<Vec<u8>>> {
+        if !header.is_peppered() {
+            return Ok(Zeroizing::new(password.to_vec()));
+        }
+        match &self.pepper {
+            Some(pepper) => Ok(apply_pepper(password, pepper)),
+            None => Err(ResqryptError::PasswordError("this file requires a pepper".to_string())),
+        }
+    }
+
+    /// Password and extra header flags to encrypt with
+    fn sealing_password(&self, password: &[u8]) -> (Zeroizing<Vec<u8>>, HeaderFlags) {
+        match &self.pepper {
+            Some(pepper) => (apply_pepper(password, pepper), HeaderFlags::PEPPERED),
+            None => (Zeroizing::new(password.to_vec()), HeaderFlags::empty()),
+        }
+    }
+
+    /// Fail if the cancellation token, if any, was cancelled
+    fn check_cancelled(&self) -> Result<()> {
+        self.cancel.as_ref().map_or(Ok(()), CancellationToken::check)
+    }
+
+    /// Pass an event to the trace hook, if any
+    fn emit(&self, event: Trace<'_>) {
+        if let Trace::Sealed { chunks, original_len, payload_len, .. } = event {
+            telemetry::record("chunks", chunks);
+            telemetry::record("original_len", original_len);
+            telemetry::record("payload_len", payload_len);
+        }
+        if let Some(trace) = &self.trace {
+            trace(&event);
+        }
+    }
+
+    /// Compress data, returning the payload and its storage flags
+    pub fn compress(&self, data: Vec<u8>) -> Result<(Vec<u8>, HeaderFlags)> {
+        self.compressor.compress(data)
+    }
+
+    /// Derive a key from `password` and seal a compressed payload as a
+    /// single message (format v2)
+    ///
+    /// `flags` must include the storage flags returned by
+    /// [`Pipeline::compress`]; `original_len` is the length before
+    /// compression.
+    pub fn seal(
+        &self,
+        payload: &[u8],
+        flags: HeaderFlags,
+        original_len: u64,
+        password: &[u8],
+    ) -> Result<(FileHeader, Vec<u8>)> {
+        self.kdf_params.validate()?;
+
+        let (password, pepper_flag) = self.sealing_password(password);
+        let salt = self.salt.unwrap_or_else(|| self.rng.salt());
+        let key = derive_key_len(&password, &salt, &self.kdf_params, self.cipher.key_len())?;
+
+        let header = FileHeader {
+            version: FormatVersion::SEALED,
+            ..FileHeader::new(
+                flags | pepper_flag,
+                self.cipher.kind(),
+                self.kdf_params.clone(),
+                salt,
+                self.rng.nonce(&*self.cipher),
+            )
+        }
+        .with_ciphertext_len((payload.len() + self.cipher.tag_len()) as u64)
+        .with_original_len(original_len);
+
+        let ciphertext =
+            self.cipher.seal(key.as_bytes(), &header.nonce, payload, &header.aad()?)?;
+        Ok((header, ciphertext))
+    }
+
+    /// Write a sealed payload
+    pub fn write<W: Write>(
+        &self,
+        out: &mut W,
+        header: &FileHeader,
+        ciphertext: &[u8],
+    ) -> Result<()> {
+        self.framing.write_frame(out, header, ciphertext)
+    }
+
+    /// Compress, encrypt and write everything read from `input`
+    ///
+    /// The input is compressed as it is read and sealed in chunks, so only
+    /// one chunk and the compressor's window are held in memory. With
+    /// [`Pipeline::padding`], the compressed payload is padded before it is
+    /// sealed. With [`Pipeline::single_shot_max`], small inputs are sealed
+    /// as one message instead.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            skip_all,
+            fields(
+                cipher = %self.cipher.kind(),
+                chunk_size = self.chunk_size,
+                chunks = tracing::field::Empty,
+                original_len = tracing::field::Empty,
+                payload_len = tracing::field::Empty,
+                elapsed_ms = tracing::field::Empty
+            )
+        )
+    )]
+    pub fn encrypt_stream<R: Read, W: Write>(
+        &self,
+        input: R,
+        flags: HeaderFlags,
+        password: &[u8],
+        out: W,
+    ) -> Result<FileHeader> {
+        let _stats = SpanStats::start();
+        self.kdf_params.validate()?;
+        check_chunk_size(self.chunk_size)?;
+
+        let mut input = CancellableReader::new(input, self.cancel.clone());
+        let Some(max_len) = self.single_shot_max.filter(|_| self.can_seal()) else {
+            return self.encrypt_chunks(input, flags, password, out);
+        };
+
+        // Buffer one byte past the limit to tell whether the input fits
+        let mut data = Vec::new();
+        (&mut input)
+            .take(max_len.saturating_add(1))
+            .read_to_end(&mut data)
+            .map_err(ResqryptError::from_io)?;
+        if data.len() as u64 > max_len {
+            return self.encrypt_chunks(Cursor::new(data).chain(input), flags, password, out);
+        }
+        self.encrypt_single_shot(data, flags, password, out)
+    }
+
+    /// Whether streamed input may be sealed as one message, as nothing
+    /// asked for that needs the chunked format
+    fn can_seal(&self) -> bool {
+        self.padding.is_none()
+            && !self.content_defined
+            && !self.merkle
+            && self.source_offset.is_none()
+            && self.stored_name.is_none()
+    }
+
+    /// Compress and seal `data` as a single message (format v2)
+    fn encrypt_single_shot<W: Write>(
+        &self,
+        data: Vec<u8>,
+        flags: HeaderFlags,
+        password: &[u8],
+        mut out: W,
+    ) -> Result<FileHeader> {
+        let started = Instant::now();
+        let original_len = data.len() as u64;
+        let (payload, storage_flags) = self.compress(data)?;
+        let (header, ciphertext) =
+            self.seal(&payload, flags | storage_flags, original_len, password)?;
+        self.check_cancelled()?;
+
+        self.emit(Trace::Header(&header));
+        self.write(&mut out, &header, &ciphertext)?;
+        out.flush()?;
+        self.emit(Trace::Sealed {
+            chunks: 1,
+            original_len,
+            payload_len: payload.len() as u64,
+            elapsed: started.elapsed(),
+        });
+
+        Ok(header)
+    }
+
+    /// Compress and seal everything read from `input` in chunks
+    fn encrypt_chunks<R: Read, W: Write>(
+        &self,
+        input: R,
+        flags: HeaderFlags,
+        password: &[u8],
+        mut out: W,
+    ) -> Result<FileHeader> {
+        // Count the input for the length trailer
+        let mut input = CountingReader::new(input);
+        let (mut payload, storage_flags) =
+            self.compressor.compress_reader(Box::new(BufReader::new(&mut input)))?;
+
+        let (password, pepper_flag) = self.sealing_password(password);
+        let salt = self.salt.unwrap_or_else(|| self.rng.salt());
+        let started = Instant::now();
+        let key = derive_key_len(&password, &salt, &self.kdf_params, self.cipher.key_len())?;
+        self.check_cancelled()?;
+        self.emit(Trace::KeyDerived {
+            fingerprint: key_fingerprint(key.as_bytes()),
+            elapsed: started.elapsed(),
+        });
+
+        let mut chunking_flags = HeaderFlags::empty();
+        chunking_flags.set(HeaderFlags::PADDED, self.padding.is_some());
+        chunking_flags.set(HeaderFlags::CONTENT_DEFINED, self.content_defined);
+        let mut header = FileHeader::new(
+            flags | storage_flags | chunking_flags | pepper_flag,
+            self.cipher.kind(),
+            self.kdf_params.clone(),
+            salt,
+            self.rng.nonce(&*self.cipher),
+        )
+        .with_chunk_size(self.chunk_size);
+        if let Some(offset) = self.source_offset {
+            header = header.with_source_offset(offset);
+        }
+        if let Some(name) = &self.stored_name {
+            header = header.with_stored_name(name.clone());
+        }
+        if self.merkle {
+            header = header.with_merkle();
+        }
+
+        self.emit(Trace::Header(&header));
+        self.framing.write_header(&mut out, &header)?;
+        let started = Instant::now();
+        let mut chunks = ChunkWriter::new(out, &*self.cipher, key, &header)?;
+        let mut len = io::copy(&mut payload, &mut chunks).map_err(ResqryptError::from_io)?;
+        if let Some(padding) = self.padding {
+            len += write_padding(&mut chunks, len, padding)?;
+        }
+        drop(payload);
+        let original_len = input.count();
+        chunks.set_original_len(original_len);
+        let count = chunks.chunks_sealed() + 1;
+        chunks.finish()?;
+        self.emit(Trace::Sealed {
+            chunks: count,
+            original_len,
+            payload_len: len,
+            elapsed: started.elapsed(),
+        });
+
+        Ok(header)
+    }
+
+    /// Compress, encrypt and write data in one step
+    pub fn encrypt<W: Write>(
+        &self,
+        data: Vec<u8>,
+        flags: HeaderFlags,
+        password: &[u8],
+        out: &mut W,
+    ) -> Result<FileHeader> {
+        self.encrypt_stream(data.as_slice(), flags, password, out)
+    }
+
+    /// Read and validate a header
+    ///
+    /// Headers whose Argon2 memory cost exceeds
+    /// [`Pipeline::max_memory_cost`] are refused.
+    pub fn read_header<R: Read>(&self, input: &mut R) -> Result<FileHeader> {
+        let header = self.framing.read_header(input)?;
+        header.kdf_params.check_memory_limit(self.max_memory_cost)?;
+        Ok(header)
+    }
+
+    /// Read the single-message ciphertext following `header`, also
+    /// returning the number of ignored trailing bytes
+    pub fn read_ciphertext<R: Read>(
+        &self,
+        input: &mut R,
+        header: &FileHeader,
+    ) -> Result<(Vec<u8>, u64)> {
+        self.framing.read_ciphertext(input, header)
+    }
+
+    /// Read a header and its single-message ciphertext, also returning the
+    /// number of ignored trailing bytes
+    pub fn read<R: Read>(&self, mut input: R) -> Result<(FileHeader, Vec<u8>, u64)> {
+        let header = self.read_header(&mut input)?;
+        let (ciphertext, trailing) = self.read_ciphertext(&mut input, &header)?;
+        Ok((header, ciphertext, trailing))
+    }
+
+    /// Derive the key from `password` and decrypt a single-message payload
+    ///
+    /// For a peppered file, pass the password from
+    /// [`Pipeline::key_password`].
+    ///
+    /// See [`Pipeline::cipher`] for the stage that decrypts.
+    pub fn open(&self, header: &FileHeader, ciphertext: &[u8], password: &[u8]) -> Result<Vec<u8>> {
+        let cipher = self.opening_cipher(header)?;
+        let key = derive_key_len(password, &header.salt, &header.kdf_params, cipher.key_len())?;
+        cipher.open(key.as_bytes(), &header.nonce, ciphertext, &header.aad()?)
+    }
+
+    /// Reader over the chunks following a chunked header
+    ///
+    /// The reader must be unlocked with the password before use.
+    pub fn open_stream<R: Read>(&self, header: &FileHeader, input: R) -> Result<ChunkReader<R>> {
+        ChunkReader::with_cipher(input, header, self.opening_cipher(header)?)
+    }
+
+    /// The encryption stage if it was replaced, which must then be the
+    /// cipher of `header`, or else the header's built-in cipher
+    fn opening_cipher(&self, header: &FileHeader) -> Result<Arc<dyn Cipher>> {
+        if self.cipher.kind() == header.cipher {
+            Ok(Arc::clone(&self.cipher))
+        } else if self.custom_cipher {
+            Err(ResqryptError::InvalidArgument(format!(
+                "the file was encrypted with {}, not the pipeline's {}",
+                header.cipher,
+                self.cipher.kind()
+            )))
+        } else {
+            Ok(Arc::new(header.cipher))
+        }
+    }
+
+    /// Reader yielding the original data from a decrypted payload
+    ///
+    /// Padding is stripped before decompression.
+    pub fn decompress_reader<'a>(
+        &self,
+        header: &FileHeader,
+        payload: impl Read + 'a,
+    ) -> Result<Box<dyn Read + 'a>> {
+        let payload: Box<dyn Read + 'a> = if header.is_padded() {
+            Box::new(UnpadReader::new(payload))
+        } else {
+            Box::new(payload)
+        };
+        self.compressor.decompress_reader(payload, header.flags)
+    }
+
+    /// Read the header and return a reader yielding the original data
+    ///
+    /// Chunked files are decrypted as they are read; single-message
+    /// files are decrypted up front. Read to the end: truncation of a
+    /// chunked file is only detected once the final chunk is reached.
+    ///
+    /// With the `tracing` feature, the span stays open until the reader is
+    /// dropped, and then records the bytes read from it as `bytes_out`.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            skip_all,
+            fields(
+                version = tracing::field::Empty,
+                bytes_out = tracing::field::Empty,
+                elapsed_ms = tracing::field::Empty
+            )
+        )
+    )]
+    pub fn decrypt_stream<'a, R: Read + 'a>(
+        &self,
+        mut input: R,
+        password: &[u8],
+    ) -> Result<(FileHeader, Box<dyn Read + 'a>)> {
+        let stats = SpanStats::start();
+        let header = self.read_header(&mut input)?;
+        stats.record("version", header.version.as_u8().into());
+        let password = self.key_password(&header, password)?;
+
+        let reader = if header.is_chunked() {
+            let mut chunks = self.open_stream(&header, input)?;
+            let started = Instant::now();
+            chunks.unlock(&password)?;
+            self.emit(Trace::KeyDerived {
+                fingerprint: chunks.key_fingerprint().unwrap_or_default(),
+                elapsed: started.elapsed(),
+            });
+            self.decompress_reader(&header, chunks)?
+        } else {
+            let (ciphertext, _) = self.read_ciphertext(&mut input, &header)?;
+            let payload = self.open(&header, &ciphertext, &password)?;
+            self.decompress_reader(&header, Cursor::new(payload))?
+        };
+        self.check_cancelled()?;
+
+        let reader = Box::new(CancellableReader::new(reader, self.cancel.clone()));
+        Ok((header, stats.reader(reader, "bytes_out")))
+    }
+
+    /// Read the header and return the original data, as the file or the
+    /// directory archive it was encrypted from
+    ///
+    /// Archives can be read entry by entry instead of being extracted to
+    /// disk. Read to the end, as with [`Pipeline::decrypt_stream`].
+    pub fn decrypt_to_reader<'a, R: Read + 'a>(
+        &self,
+        input: R,
+        password: &[u8],
+    ) -> Result<(FileHeader, DecryptedPayload<'a>)> {
+        let (header, reader) = self.decrypt_stream(input, password)?;
+        let payload = DecryptedPayload::new(&header, reader);
+        Ok((header, payload))
+    }
+
+    /// Read, decrypt and decompress in one step
+    pub fn decrypt<R: Read>(&self, input: R, password: &[u8]) -> Result<(FileHeader, Vec<u8>)> {
+        let (header, mut reader) = self.decrypt_stream(input, password)?;
+
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).map_err(ResqryptError::from_io)?;
+        Ok((header, data))
+    }
+}
+
+/// Original data of a decrypted file, by what was encrypted
+pub enum DecryptedPayload<'a> {
+    /// Contents of a single file
+    File(Box<dyn Read + 'a>),
+    /// Tar archive of a directory, whose entries can be read one at a time
+    /// with [`Archive::entries`] or extracted with
+    /// [`crate::archive::extract_archive_entries`]
+    Archive(Archive<Box<dyn Read + 'a>>),
+}
+
+impl<'a> DecryptedPayload<'a> {
+    /// Classify the original data read from a file with `header`
+    pub fn new(header: &FileHeader, reader: Box<dyn Read + 'a>) -> Self {
+        if header.is_directory() { Self::Archive(Archive::new(reader)) } else { Self::File(reader) }
+    }
+
+    /// Reader over the raw data: the file contents or the tar archive bytes
+    pub fn into_reader(self) -> Box<dyn Read + 'a> {
+        match self {
+            Self::File(reader) => reader,
+            Self::Archive(archive) => archive.into_inner(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compression::compress;
+    use std::io::Cursor;
+    use std::path::Path;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn fast_kdf() -> KdfParams {
+        KdfParams { memory_cost: 1024, time_cost: 1, parallelism: 1 }
+    }
+
+    #[test]
+    fn test_zstd_compressor() {
+        let compressor = ZstdCompressor::new(5);
+        let data = b"compress me ".repeat(100);
+
+        let (payload, stored) = compressor.compress(data.clone()).unwrap();
+        assert_eq!(stored, HeaderFlags::empty());
+        assert!(payload.len() < data.len());
+        assert_eq!(compressor.decompress(&payload, stored).unwrap(), data);
+    }
+
+    #[test]
+    fn test_zstd_compressor_passthrough() {
+        let compressor = ZstdCompressor::default();
+
+        let zstd_data = compress(b"already compressed").unwrap();
+        let (payload, stored) = compressor.compress(zstd_data.clone()).unwrap();
+        assert_eq!(stored, HeaderFlags::ALREADY_ZSTD);
+        assert_eq!(payload, zstd_data);
+        assert_eq!(compressor.decompress(&payload, stored).unwrap(), zstd_data);
+
+        let tiny = b"x".to_vec();
+        let (payload, stored) = compressor.compress(tiny.clone()).unwrap();
+        assert_eq!(stored, HeaderFlags::UNCOMPRESSED);
+        assert_eq!(payload, tiny);
+    }
+
+    #[test]
+    fn test_zstd_compressor_skips_incompressible_stream() {
+        let compressor = ZstdCompressor::default();
+        let mut state = 1u32;
+        let noise: Vec<u8> = (0..300_000)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (state >> 16) as u8
+            })
+            .collect();
+
+        let (mut payload, stored) = compressor.compress_reader(Box::new(&noise[..])).unwrap();
+        assert_eq!(stored, HeaderFlags::UNCOMPRESSED);
+        let mut data = Vec::new();
+        payload.read_to_end(&mut data).unwrap();
+        assert_eq!(data, noise);
+
+        let text = b"compress me ".repeat(20_000);
+        let (_, stored) = compressor.compress_reader(Box::new(&text[..])).unwrap();
+        assert_eq!(stored, HeaderFlags::empty());
+    }
+
+    #[test]
+    fn test_no_compression() {
+        let data = b"left alone ".repeat(100);
+        let (payload, stored) = NoCompression.compress(data.clone()).unwrap();
+
+        assert_eq!(stored, HeaderFlags::UNCOMPRESSED);
+        assert_eq!(payload, data);
+        assert_eq!(NoCompression.decompress(&payload, stored).unwrap(), data);
+    }
+
+    #[test]
+    fn test_cipher_stage() {
+        let cipher: &dyn Cipher = &CipherKind::XChaCha20Poly1305;
+        let key = [1u8; 32];
+        let nonce = cipher.generate_nonce();
+
+        let sealed = cipher.seal(&key, &nonce, b"payload", b"aad").unwrap();
+        assert_eq!(sealed.len(), 7 + cipher.tag_len());
+        assert_eq!(cipher.open(&key, &nonce, &sealed, b"aad").unwrap(), b"payload");
+        assert!(cipher.open(&key, &nonce, &sealed, b"other").is_err());
+    }
+
+    #[test]
+    fn test_framing_stage() {
+        let header = FileHeader {
+            version: FormatVersion::SEALED,
+            ..FileHeader::new(
+                HeaderFlags::empty(),
+                CipherKind::Aes256Gcm,
+                fast_kdf(),
+                [0u8; 32],
+                vec![0u8; 12],
+            )
+        }
+        .with_ciphertext_len(20)
+        .with_original_len(4);
+
+        let mut out = Vec::new();
+        ResqryptFraming.write_frame(&mut out, &header, &[7u8; 20]).unwrap();
+        out.push(0);
+
+        let (read_back, ciphertext, trailing) =
+            ResqryptFraming.read_frame(&mut out.as_slice()).unwrap();
+        assert_eq!(read_back.original_len, Some(4));
+        assert_eq!(ciphertext, vec![7u8; 20]);
+        assert_eq!(trailing, 1);
+    }
+
+    #[test]
+    fn test_pipeline_roundtrip() {
+        let pipeline = Pipeline::new()
+            .compressor(ZstdCompressor::new(1))
+            .cipher(CipherKind::XChaCha20Poly1305)
+            .kdf_params(fast_kdf());
+        let data = b"through every stage ".repeat(50);
+
+        let mut file = Vec::new();
+        let header =
+            pipeline.encrypt(data.clone(), HeaderFlags::IS_DIRECTORY, b"pw", &mut file).unwrap();
+        assert!(header.is_directory());
+        assert_eq!(header.cipher, CipherKind::XChaCha20Poly1305);
+        assert!(header.is_chunked());
+        assert_eq!(header.chunk_size, Some(DEFAULT_CHUNK_SIZE));
+
+        // Without a replaced cipher stage, decryption follows the header
+        let (_, plaintext) = Pipeline::new().decrypt(Cursor::new(&file), b"pw").unwrap();
+        assert_eq!(plaintext, data);
+
+        let result = pipeline.decrypt(file.as_slice(), b"wrong");
+        assert!(matches!(result, Err(ResqryptError::PasswordError(_))));
+    }
+
+    /// XChaCha20-Poly1305 counting the chunks it opens
+    struct CountingCipher(Arc<AtomicUsize>);
+
+    impl Cipher for CountingCipher {
+        fn kind(&self) -> CipherKind {
+            CipherKind::XChaCha20Poly1305
+        }
+
+        fn generate_nonce(&self) -> Vec<u8> {
+            self.kind().generate_nonce()
+        }
+
+        fn key_len(&self) -> usize {
+            self.kind().key_len()
+        }
+
+        fn tag_len(&self) -> usize {
+            self.kind().tag_len()
+        }
+
+        fn seal(&self, key: &[u8], nonce: &[u8], plain: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+            self.kind().seal(key, nonce, plain, aad)
+        }
+
+        fn open(&self, key: &[u8], nonce: &[u8], sealed: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            self.kind().open(key, nonce, sealed, aad)
+        }
+    }
+
+    #[test]
+    fn test_custom_cipher_roundtrip() {
+        let opened = Arc::new(AtomicUsize::new(0));
+        let pipeline = Pipeline::new()
+            .compressor(NoCompression)
+            .cipher(CountingCipher(Arc::clone(&opened)))
+            .chunk_size(64)
+            .kdf_params(fast_kdf());
+        let data = vec![7u8; 200];
+
+        let mut file = Vec::new();
+        pipeline.encrypt(data.clone(), HeaderFlags::empty(), b"pw", &mut file).unwrap();
+        let (_, plaintext) = pipeline.decrypt(file.as_slice(), b"pw").unwrap();
+        assert_eq!(plaintext, data);
+        assert_eq!(opened.load(Ordering::Relaxed), 4);
+
+        let (header, ciphertext) = pipeline.seal(b"data", HeaderFlags::empty(), 4, b"pw").unwrap();
+        assert_eq!(pipeline.open(&header, &ciphertext, b"pw").unwrap(), b"data");
+        assert_eq!(opened.load(Ordering::Relaxed), 5);
+
+        // A replaced stage must match the file's cipher
+        let other = Pipeline::new().cipher(CipherKind::Aes256Gcm).kdf_params(fast_kdf());
+        let result = other.decrypt(file.as_slice(), b"pw");
+        assert!(matches!(result, Err(ResqryptError::InvalidArgument(_))));
+        let result = other.open(&header, &ciphertext, b"pw");
+        assert!(matches!(result, Err(ResqryptError::InvalidArgument(_))));
+    }
+
+    /// XChaCha20-Poly1305 under the first half of a 64-byte key
+    struct WideKeyCipher;
+
+    impl Cipher for WideKeyCipher {
+        fn kind(&self) -> CipherKind {
+            CipherKind::XChaCha20Poly1305
+        }
+
+        fn generate_nonce(&self) -> Vec<u8> {
+            self.kind().generate_nonce()
+        }
+
+        fn key_len(&self) -> usize {
+            64
+        }
+
+        fn tag_len(&self) -> usize {
+            self.kind().tag_len()
+        }
+
+        fn seal(&self, key: &[u8], nonce: &[u8], plain: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+            assert_eq!(key.len(), 64);
+            self.kind().seal(&key[..32], nonce, plain, aad)
+        }
+
+        fn open(&self, key: &[u8], nonce: &[u8], sealed: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+            assert_eq!(key.len(), 64);
+            self.kind().open(&key[..32], nonce, sealed, aad)
+        }
+    }
+
+    #[test]
+    fn test_cipher_key_len() {
+        let pipeline = Pipeline::new().cipher(WideKeyCipher).kdf_params(fast_kdf());
+        let data = b"wide key".repeat(10);
+
+        let mut file = Vec::new();
+        pipeline.encrypt(data.clone(), HeaderFlags::empty(), b"pw", &mut file).unwrap();
+        assert_eq!(pipeline.decrypt(file.as_slice(), b"pw").unwrap().1, data);
+
+        // A 64-byte key is not an extension of the 32-byte one
+        let result = Pipeline::new().decrypt(file.as_slice(), b"pw");
+        assert!(matches!(result, Err(ResqryptError::PasswordError(_))));
+    }
+
+    #[test]
+    fn test_decrypt_to_reader() {
+        let pipeline = Pipeline::new().kdf_params(fast_kdf());
+
+        let mut file = Vec::new();
+        pipeline.encrypt(b"plain".to_vec(), HeaderFlags::empty(), b"pw", &mut file).unwrap();
+        let (_, payload) = pipeline.decrypt_to_reader(file.as_slice(), b"pw").unwrap();
+        let DecryptedPayload::File(mut reader) = payload else { panic!("expected a file") };
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"plain");
+
+        // A directory archive is read entry by entry, without touching disk
+        let mut tar = tar::Builder::new(Vec::new());
+        let mut entry = tar::Header::new_gnu();
+        entry.set_size(5);
+        entry.set_cksum();
+        tar.append_data(&mut entry, "root/a.txt", &b"alpha"[..]).unwrap();
+        let tar = tar.into_inner().unwrap();
+
+        let mut file = Vec::new();
+        pipeline.encrypt(tar, HeaderFlags::IS_DIRECTORY, b"pw", &mut file).unwrap();
+        let (_, payload) = pipeline.decrypt_to_reader(file.as_slice(), b"pw").unwrap();
+        let DecryptedPayload::Archive(mut archive) = payload else { panic!("expected an archive") };
+        let mut entries = archive.entries().unwrap();
+        let mut entry = entries.next().unwrap().unwrap();
+        assert_eq!(entry.path().unwrap(), Path::new("root/a.txt"));
+        let mut contents = String::new();
+        entry.read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "alpha");
+        drop(entry);
+        assert!(entries.next().is_none());
+    }
+
+    #[test]
+    fn test_pipeline_padding() {
+        let encrypted_len = |data: &[u8], padding| {
+            let pipeline = Pipeline::new()
+                .compressor(NoCompression)
+                .kdf_params(fast_kdf())
+                .chunk_size(64)
+                .padding(padding);
+            let mut file = Vec::new();
+            let header =
+                pipeline.encrypt_stream(data, HeaderFlags::empty(), b"pw", &mut file).unwrap();
+            assert!(header.is_padded());
+
+            let (_, plaintext) = pipeline.decrypt(file.as_slice(), b"pw").unwrap();
+            assert_eq!(plaintext, data);
+            file.len()
+        };
+
+        // Payloads in the same bucket encrypt to the same size
+        let pow2 = encrypted_len(b"short", Padding::PowerOfTwo);
+        assert_eq!(encrypted_len(b"shorter", Padding::PowerOfTwo), pow2);
+        assert!(encrypted_len(&[1u8; 16], Padding::PowerOfTwo) > pow2);
+
+        let fixed = encrypted_len(b"", Padding::Multiple(1000));
+        assert_eq!(encrypted_len(&[0x80u8; 999], Padding::Multiple(1000)), fixed);
+    }
+
+    #[test]
+    fn test_single_shot_by_size() {
+        let pipeline =
+            Pipeline::new().kdf_params(fast_kdf()).chunk_size(16 * 1024).single_shot_max(1000);
+        let encrypt = |pipeline: &Pipeline, data: &[u8]| {
+            let mut file = Vec::new();
+            let header =
+                pipeline.encrypt_stream(data, HeaderFlags::empty(), b"pw", &mut file).unwrap();
+            let (_, plaintext) = pipeline.decrypt(file.as_slice(), b"pw").unwrap();
+            assert_eq!(plaintext, data);
+            header.version
+        };
+
+        // Inputs up to the limit are sealed whole, larger ones stream on
+        // after the buffered bytes
+        assert_eq!(encrypt(&pipeline, &[7u8; 1000]), FormatVersion::SEALED);
+        assert_eq!(encrypt(&pipeline, b""), FormatVersion::SEALED);
+        let large: Vec<u8> = (0..50_000u32).map(|i| (i % 251) as u8).collect();
+        assert_eq!(encrypt(&pipeline, &large), FormatVersion::CURRENT);
+
+        // Options only the chunked format records keep it
+        assert_eq!(encrypt(&pipeline.content_defined(), b"small"), FormatVersion::CURRENT);
+    }
+
+    #[test]
+    fn test_merkle_footer() {
+        let data: Vec<u8> = (0..50_000u32).map(|i| (i % 251) as u8).collect();
+        for pipeline in [Pipeline::new().merkle(), Pipeline::new().merkle().content_defined()] {
+            let pipeline = pipeline.kdf_params(fast_kdf()).chunk_size(1024).single_shot_max(1000);
+            let mut file = Vec::new();
+            let header =
+                pipeline.encrypt_stream(&data[..], HeaderFlags::empty(), b"pw", &mut file).unwrap();
+            assert!(header.is_merkle());
+            let (_, plaintext) = pipeline.decrypt(file.as_slice(), b"pw").unwrap();
+            assert_eq!(plaintext, data);
+
+            // A damaged tree node fails decryption although every chunk opens
+            let last = file.len() - 1;
+            file[last] ^= 1;
+            assert!(pipeline.decrypt(file.as_slice(), b"pw").is_err());
+        }
+    }
+
+    #[test]
+    fn test_trace_events() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let recorded = Rc::clone(&events);
+        let pipeline = Pipeline::new()
+            .compressor(NoCompression)
+            .kdf_params(fast_kdf())
+            .chunk_size(64)
+            .padding(Padding::Multiple(256))
+            .trace(move |event| {
+                recorded.borrow_mut().push(match event {
+                    Trace::KeyDerived { .. } => (event.level(), None),
+                    Trace::Header(_) => (event.level(), None),
+                    Trace::Sealed { chunks, original_len, payload_len, .. } => {
+                        (event.level(), Some((*chunks, *original_len, *payload_len)))
+                    }
+                })
+            });
+
+        let mut file = Vec::new();
+        pipeline.encrypt_stream(&[7u8; 100][..], HeaderFlags::empty(), b"pw", &mut file).unwrap();
+        assert_eq!(*events.borrow(), [(2, None), (3, None), (2, Some((4, 100, 256)))]);
+
+        // Decrypting a chunked file reports its key derivation
+        events.borrow_mut().clear();
+        pipeline.decrypt(file.as_slice(), b"pw").unwrap();
+        assert_eq!(*events.borrow(), [(2, None)]);
+    }
+
+    #[test]
+    fn test_cancel_mid_stream() {
+        /// Endless input that cancels the token after `limit` bytes
+        struct CancelAfter {
+            token: CancellationToken,
+            read: usize,
+            limit: usize,
+        }
+
+        impl Read for CancelAfter {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                self.read += buf.len();
+                if self.read >= self.limit {
+                    self.token.cancel();
+                }
+                buf.fill(0x5a);
+                Ok(buf.len())
+            }
+        }
+
+        let token = CancellationToken::new();
+        let pipeline = Pipeline::new().kdf_params(fast_kdf()).cancel_on(token.clone());
+        let input = CancelAfter { token: token.clone(), read: 0, limit: 1 << 20 };
+        let result = pipeline.encrypt_stream(input, HeaderFlags::empty(), b"pw", io::sink());
+        assert!(matches!(result, Err(ResqryptError::Cancelled)));
+
+        // Decryption stops between reads of the payload
+        let token = CancellationToken::new();
+        let pipeline =
+            Pipeline::new().kdf_params(fast_kdf()).chunk_size(64).cancel_on(token.clone());
+        let mut file = Vec::new();
+        pipeline.encrypt(vec![1u8; 4096], HeaderFlags::empty(), b"pw", &mut file).unwrap();
+        let (_, mut reader) = pipeline.decrypt_stream(file.as_slice(), b"pw").unwrap();
+        let mut buf = [0u8; 64];
+        reader.read_exact(&mut buf).unwrap();
+        token.cancel();
+        let err = reader.read_exact(&mut buf).unwrap_err();
+        assert!(matches!(ResqryptError::from_io(err), ResqryptError::Cancelled));
+        assert!(matches!(
+            pipeline.decrypt_stream(file.as_slice(), b"pw").map(|_| ()),
+            Err(ResqryptError::Cancelled)
+        ));
+    }
+
+    #[test]
+    fn test_pepper() {
+        let peppered = Pipeline::new().kdf_params(fast_kdf()).pepper(b"site secret".to_vec());
+        let mut file = Vec::new();
+        let header =
+            peppered.encrypt(b"spiced".to_vec(), HeaderFlags::empty(), b"pw", &mut file).unwrap();
+        assert!(header.is_peppered());
+        assert_eq!(peppered.decrypt(file.as_slice(), b"pw").unwrap().1, b"spiced");
+
+        // Missing or different peppers are told apart from each other
+        let result = Pipeline::new().decrypt(file.as_slice(), b"pw");
+        assert!(matches!(result, Err(ResqryptError::PasswordError(msg)) if msg.contains("pepper")));
+        let other = Pipeline::new().pepper(b"other secret".to_vec());
+        assert!(matches!(
+            other.decrypt(file.as_slice(), b"pw"),
+            Err(ResqryptError::PasswordError(_))
+        ));
+
+        // A pepper does not get in the way of files encrypted without one
+        let mut plain = Vec::new();
+        Pipeline::new()
+            .kdf_params(fast_kdf())
+            .encrypt(b"mild".to_vec(), HeaderFlags::empty(), b"pw", &mut plain)
+            .unwrap();
+        assert_eq!(peppered.decrypt(plain.as_slice(), b"pw").unwrap().1, b"mild");
+    }
+
+    #[test]
+    fn test_content_defined_reused_salt() {
+        let data: Vec<u8> =
+            (0..200_000u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8).collect();
+        let encrypt = |pipeline: &Pipeline, data: &[u8]| {
+            let mut file = Vec::new();
+            let header =
+                pipeline.encrypt_stream(data, HeaderFlags::empty(), b"pw", &mut file).unwrap();
+            (header, file)
+        };
+
+        let pipeline = Pipeline::new()
+            .compressor(NoCompression)
+            .kdf_params(fast_kdf())
+            .chunk_size(4096)
+            .content_defined();
+        let (header, first) = encrypt(&pipeline, &data);
+        assert!(header.is_content_defined());
+        assert_eq!(pipeline.decrypt(first.as_slice(), b"pw").unwrap().1, data);
+
+        // Under the same key, an unchanged tail encrypts to the same bytes
+        let mut edited = data.clone();
+        edited.splice(10..10, *b"edit");
+        let (_, second) = encrypt(&pipeline.reuse_salt(header.salt), &edited);
+        let tail = 100_000;
+        let needle = &first[first.len() - tail..first.len() - tail + 64];
+        assert!(second.windows(64).any(|window| window == needle));
+    }
+
+    #[test]
+    fn test_seeded_rng_is_reproducible() {
+        let encrypt = |pipeline: Pipeline| {
+            let mut file = Vec::new();
+            let pipeline = pipeline.kdf_params(fast_kdf());
+            pipeline
+                .encrypt_stream(&b"fixture"[..], HeaderFlags::empty(), b"pw", &mut file)
+                .unwrap();
+            file
+        };
+
+        let seeded = || Pipeline::new().rng(RandomSource::seeded("ci"));
+        assert_eq!(encrypt(seeded()), encrypt(seeded()));
+        assert_ne!(encrypt(seeded()), encrypt(Pipeline::new().rng(RandomSource::seeded("ci2"))));
+
+        // Other pipelines keep drawing from the operating system
+        assert_ne!(encrypt(Pipeline::new()), encrypt(Pipeline::new()));
+    }
+
+    #[test]
+    fn test_hostile_memory_cost_refused() {
+        let pipeline = Pipeline::new().kdf_params(fast_kdf()).chunk_size(1024);
+        let mut file = Vec::new();
+        pipeline.encrypt(vec![3u8; 5000], HeaderFlags::empty(), b"pw", &mut file).unwrap();
+
+        // Rewrite the header to ask for 4 TiB of Argon2 memory
+        let mut rest = file.as_slice();
+        let mut header = read_header(&mut rest).unwrap();
+        header.kdf_params.memory_cost = u32::MAX;
+        let mut hostile = Vec::new();
+        write_header(&mut hostile, &header).unwrap();
+        hostile.extend_from_slice(rest);
+
+        // Refused from the header alone, before any key is derived
+        let result = Pipeline::new().read_header(&mut hostile.as_slice());
+        assert!(matches!(result, Err(ResqryptError::InvalidArgument(_))));
+        let result = Pipeline::new().decrypt(hostile.as_slice(), b"pw");
+        assert!(matches!(result, Err(ResqryptError::InvalidArgument(_))));
+
+        // Unless the limit is raised for a file known to need it
+        let raised = Pipeline::new().max_memory_cost(u32::MAX);
+        assert_eq!(
+            raised.read_header(&mut hostile.as_slice()).unwrap().kdf_params.memory_cost,
+            u32::MAX
+        );
+        assert!(Pipeline::new().read_header(&mut file.as_slice()).is_ok());
+    }
+
+    #[test]
+    fn test_memory_estimate() {
+        let params = KdfParams { memory_cost: 64 * 1024, time_cost: 3, parallelism: 4 };
+        let estimate = MemoryEstimate::new(&params, DEFAULT_CHUNK_SIZE, None);
+        assert_eq!(estimate.argon2, 64 << 20);
+        assert_eq!(estimate.buffers, 4 * 64 * 1024);
+        assert_eq!(estimate.total(), (64 << 20) + (256 << 10));
+
+        // Compression adds its window and match tables
+        let compressed = MemoryEstimate::new(&params, DEFAULT_CHUNK_SIZE, Some(21));
+        assert_eq!(compressed.buffers - estimate.buffers, 4 << 20);
+    }
+}
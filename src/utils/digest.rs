@@ -0,0 +1,132 @@
+//! Output digests
+//!
+//! Hashes data as it is written, so the digest of an output is known
+//! without reading the file back.
+
+use std::fmt;
+use std::io::{self, Write};
+
+use blake2::{Blake2b512, Blake2s256, Digest};
+use sha2::Sha256;
+
+/// Supported digest algorithms
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum HashAlgo {
+    /// SHA-256, as printed by `sha256sum`
+    #[default]
+    #[cfg_attr(feature = "cli", value(name = "sha256"))]
+    Sha256,
+    /// BLAKE3 with a 256-bit digest, as printed by `b3sum`
+    #[cfg_attr(feature = "cli", value(name = "blake3"))]
+    Blake3,
+    /// BLAKE2b with a 512-bit digest
+    #[cfg_attr(feature = "cli", value(name = "blake2b"))]
+    Blake2b,
+    /// BLAKE2s with a 256-bit digest
+    #[cfg_attr(feature = "cli", value(name = "blake2s"))]
+    Blake2s,
+}
+
+impl fmt::Display for HashAlgo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Sha256 => write!(f, "SHA-256"),
+            Self::Blake3 => write!(f, "BLAKE3"),
+            Self::Blake2b => write!(f, "BLAKE2b-512"),
+            Self::Blake2s => write!(f, "BLAKE2s-256"),
+        }
+    }
+}
+
+/// Running hash state of one algorithm
+enum Hasher {
+    Sha256(Sha256),
+    Blake3(Box<blake3::Hasher>),
+    Blake2b(Blake2b512),
+    Blake2s(Blake2s256),
+}
+
+/// Writer adapter hashing every byte written through it
+pub struct HashingWriter<W> {
+    inner: W,
+    hasher: Hasher,
+}
+
+impl<W: Write> HashingWriter<W> {
+    /// Wrap a writer
+    pub fn new(inner: W, algo: HashAlgo) -> Self {
+        let hasher = match algo {
+            HashAlgo::Sha256 => Hasher::Sha256(Sha256::new()),
+            HashAlgo::Blake3 => Hasher::Blake3(Box::default()),
+            HashAlgo::Blake2b => Hasher::Blake2b(Blake2b512::new()),
+            HashAlgo::Blake2s => Hasher::Blake2s(Blake2s256::new()),
+        };
+        Self { inner, hasher }
+    }
+
+    /// Lowercase hex digest of everything written so far
+    pub fn hex_digest(self) -> String {
+        let digest = match self.hasher {
+            Hasher::Sha256(hasher) => hasher.finalize().to_vec(),
+            Hasher::Blake3(hasher) => hasher.finalize().as_bytes().to_vec(),
+            Hasher::Blake2b(hasher) => hasher.finalize().to_vec(),
+            Hasher::Blake2s(hasher) => hasher.finalize().to_vec(),
+        };
+        digest.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // Only hash what the inner writer accepted
+        let n = self.inner.write(buf)?;
+        match &mut self.hasher {
+            Hasher::Sha256(hasher) => hasher.update(&buf[..n]),
+            Hasher::Blake3(hasher) => {
+                hasher.update(&buf[..n]);
+            }
+            Hasher::Blake2b(hasher) => hasher.update(&buf[..n]),
+            Hasher::Blake2s(hasher) => hasher.update(&buf[..n]),
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_known_digests() {
+        let mut writer = HashingWriter::new(Vec::new(), HashAlgo::Sha256);
+        writer.write_all(b"abc").unwrap();
+        assert_eq!(
+            writer.hex_digest(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+
+        let mut writer = HashingWriter::new(Vec::new(), HashAlgo::Blake3);
+        writer.write_all(b"abc").unwrap();
+        assert_eq!(
+            writer.hex_digest(),
+            "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85"
+        );
+
+        let mut writer = HashingWriter::new(Vec::new(), HashAlgo::Blake2s);
+        writer.write_all(b"abc").unwrap();
+        assert_eq!(
+            writer.hex_digest(),
+            "508c5e8c327c14e2e1a72ba34eeb452f37458b209ed63a294d999b4c86675982"
+        );
+
+        let mut writer = HashingWriter::new(Vec::new(), HashAlgo::Blake2b);
+        writer.write_all(b"ab").unwrap();
+        writer.write_all(b"c").unwrap();
+        assert!(writer.hex_digest().starts_with("ba80a53f981c4d0d6a2797b69f12f6e9"));
+    }
+}
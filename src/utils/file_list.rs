@@ -0,0 +1,93 @@
+//! File lists
+//!
+//! Reads the paths to archive from a list, one per line or NUL-terminated
+//! as written by `find -print0`, in the manner of `tar --files-from`.
+//! Listed paths are taken relative to a base directory and must stay
+//! inside it.
+
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+
+use crate::error::{ResqryptError, Result};
+
+/// Read a list of paths separated by newlines, or by NUL bytes if `nul`
+///
+/// Empty entries are skipped. In newline-separated lists a trailing `\r`
+/// is dropped, so lists written on Windows work as well.
+pub fn read_file_list<R: Read>(mut reader: R, nul: bool) -> Result<Vec<PathBuf>> {
+    let mut data = Vec::new();
+    reader.read_to_end(&mut data)?;
+
+    let separator = if nul { b'\0' } else { b'\n' };
+    data.split(|&byte| byte == separator)
+        .map(|entry| if nul { entry } else { entry.strip_suffix(b"\r").unwrap_or(entry) })
+        .filter(|entry| !entry.is_empty())
+        .map(path_from_bytes)
+        .collect()
+}
+
+#[cfg(unix)]
+fn path_from_bytes(bytes: &[u8]) -> Result<PathBuf> {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    Ok(PathBuf::from(OsStr::from_bytes(bytes)))
+}
+
+#[cfg(not(unix))]
+fn path_from_bytes(bytes: &[u8]) -> Result<PathBuf> {
+    String::from_utf8(bytes.to_vec()).map(PathBuf::from).map_err(|_| {
+        ResqryptError::InvalidArgument(format!(
+            "listed path is not valid UTF-8: {}",
+            String::from_utf8_lossy(bytes)
+        ))
+    })
+}
+
+/// Resolve a listed path against `base`
+///
+/// Relative paths are joined onto `base`; absolute paths must already lie
+/// under it. Paths with `..` components are refused, since they could
+/// name files outside `base`.
+pub fn resolve_listed(base: &Path, path: &Path) -> Result<PathBuf> {
+    let outside = || {
+        ResqryptError::InvalidArgument(format!(
+            "listed path {} is outside {}",
+            path.display(),
+            base.display()
+        ))
+    };
+    if path.components().any(|component| component == Component::ParentDir) {
+        return Err(outside());
+    }
+    if path.is_absolute() {
+        if !path.starts_with(base) {
+            return Err(outside());
+        }
+        return Ok(path.to_path_buf());
+    }
+    Ok(base.join(path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_file_list() {
+        let list = read_file_list(&b"a.txt\r\n\nsub/b c.txt\n"[..], false).unwrap();
+        assert_eq!(list, vec![PathBuf::from("a.txt"), PathBuf::from("sub/b c.txt")]);
+
+        let list = read_file_list(&b"line\nbreak\0other\0"[..], true).unwrap();
+        assert_eq!(list, vec![PathBuf::from("line\nbreak"), PathBuf::from("other")]);
+    }
+
+    #[test]
+    fn test_resolve_listed() {
+        let base = Path::new("/data");
+        assert_eq!(resolve_listed(base, Path::new("a/b")).unwrap(), Path::new("/data/a/b"));
+        assert_eq!(resolve_listed(base, Path::new("/data/c")).unwrap(), Path::new("/data/c"));
+        assert!(resolve_listed(base, Path::new("/etc/passwd")).is_err());
+        assert!(resolve_listed(base, Path::new("a/../../x")).is_err());
+    }
+}
@@ -0,0 +1,183 @@
+//! External filter commands
+//!
+//! Runs a user-supplied shell command with data piped to its stdin and its
+//! stdout taken as the result. Data is streamed through the pipes, so the
+//! command's input and output are never buffered in full.
+//!
+//! The command runs with the user's privileges and sees the plaintext, so
+//! it must be trusted. Its stderr is passed through to the terminal.
+
+use std::io::{self, Read, Write};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::thread::{self, JoinHandle};
+
+use crate::error::{ResqryptError, Result};
+
+/// Reader over the stdout of a filter command fed from another reader
+///
+/// The input is copied to the command's stdin on a background thread.
+/// Reaching the end of the output waits for the command and fails if it
+/// exited unsuccessfully.
+pub struct FilterReader {
+    command: String,
+    child: Child,
+    stdout: ChildStdout,
+    feeder: Option<JoinHandle<io::Result<u64>>>,
+}
+
+impl FilterReader {
+    /// Spawn `command` and start feeding it `input`
+    pub fn spawn<R: Read + Send + 'static>(command: &str, mut input: R) -> Result<Self> {
+        let (child, mut stdin, stdout) = spawn(command)?;
+        let feeder = thread::spawn(move || copy_to_stdin(&mut input, &mut stdin));
+
+        Ok(Self { command: command.to_string(), child, stdout, feeder: Some(feeder) })
+    }
+
+    /// Wait for the command and the feeding thread
+    fn finish(&mut self) -> Result<()> {
+        let Some(feeder) = self.feeder.take() else { return Ok(()) };
+
+        let status = self.child.wait()?;
+        let fed =
+            feeder.join().map_err(|_| filter_error(&self.command, "input thread panicked"))?;
+        fed.map_err(ResqryptError::from_io)?;
+        check_status(&self.command, status)
+    }
+}
+
+impl Read for FilterReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.stdout.read(buf)?;
+        if n == 0 && !buf.is_empty() {
+            self.finish().map_err(io::Error::other)?;
+        }
+        Ok(n)
+    }
+}
+
+impl Drop for FilterReader {
+    fn drop(&mut self) {
+        // Abandoned early (e.g. after an error): do not leave the command
+        // blocked on a full pipe
+        if self.feeder.is_some() {
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+        }
+    }
+}
+
+/// Pipe `input` through `command`, handing its stdout to `consume`
+///
+/// `input` is copied on the calling thread while `consume` runs on a scoped
+/// thread, so `input` need not be `Send`. Errors reading `input` are only
+/// reported once `consume` has returned, so it must not publish its result
+/// (e.g. rename an output into place) before this function succeeds.
+pub fn filter_into<R, T, F>(command: &str, mut input: R, consume: F) -> Result<T>
+where
+    R: Read,
+    T: Send,
+    F: FnOnce(ChildStdout) -> Result<T> + Send,
+{
+    let (mut child, mut stdin, stdout) = spawn(command)?;
+
+    let (fed, consumed) = thread::scope(|scope| {
+        let consumer = scope.spawn(move || consume(stdout));
+        let fed = copy_to_stdin(&mut input, &mut stdin);
+        drop(stdin);
+        (fed, consumer.join())
+    });
+
+    // An input error explains a failing command, which explains bad output
+    let status = child.wait()?;
+    let consumed = consumed.map_err(|_| filter_error(command, "output thread panicked"))?;
+    fed.map_err(ResqryptError::from_io)?;
+    check_status(command, status)?;
+    consumed
+}
+
+/// Spawn `command` through the platform shell with piped stdin and stdout
+fn spawn(command: &str) -> Result<(Child, ChildStdin, ChildStdout)> {
+    #[cfg(unix)]
+    let mut shell = {
+        let mut shell = Command::new("sh");
+        shell.arg("-c").arg(command);
+        shell
+    };
+    #[cfg(not(unix))]
+    let mut shell = {
+        let mut shell = Command::new("cmd");
+        shell.arg("/C").arg(command);
+        shell
+    };
+
+    let mut child = shell
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|e| filter_error(command, &format!("could not start: {}", e)))?;
+
+    let stdin = child.stdin.take().expect("stdin is piped");
+    let stdout = child.stdout.take().expect("stdout is piped");
+    Ok((child, stdin, stdout))
+}
+
+/// Copy `input` into the command's stdin
+///
+/// The command may stop reading early; a broken pipe is left for the exit
+/// status to report.
+fn copy_to_stdin<R: Read>(input: &mut R, stdin: &mut ChildStdin) -> io::Result<u64> {
+    match io::copy(input, stdin).and_then(|n| stdin.flush().map(|()| n)) {
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(0),
+        result => result,
+    }
+}
+
+/// Fail if the command exited unsuccessfully
+fn check_status(command: &str, status: std::process::ExitStatus) -> Result<()> {
+    if status.success() {
+        Ok(())
+    } else {
+        Err(filter_error(command, &format!("exited with {}", status)))
+    }
+}
+
+fn filter_error(command: &str, detail: &str) -> ResqryptError {
+    ResqryptError::FilterError(format!("`{}` {}", command, detail))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter_reader() {
+        let mut reader = FilterReader::spawn("tr a-z A-Z", &b"shout"[..]).unwrap();
+        let mut output = String::new();
+        reader.read_to_string(&mut output).unwrap();
+        assert_eq!(output, "SHOUT");
+    }
+
+    #[test]
+    fn test_filter_reader_failure() {
+        let mut reader = FilterReader::spawn("cat >/dev/null; exit 3", &b"data"[..]).unwrap();
+        let err = reader.read_to_end(&mut Vec::new()).unwrap_err();
+        assert!(matches!(ResqryptError::from_io(err), ResqryptError::FilterError(_)));
+    }
+
+    #[test]
+    fn test_filter_into_streams() {
+        // More than a pipe buffer in both directions
+        let data = vec![b'x'; 1 << 20];
+        let len = filter_into("cat", data.as_slice(), |mut stdout| {
+            Ok(io::copy(&mut stdout, &mut io::sink())?)
+        })
+        .unwrap();
+        assert_eq!(len, 1 << 20);
+
+        let result = filter_into("false", &b"ignored"[..], |mut stdout| {
+            Ok(io::copy(&mut stdout, &mut io::sink())?)
+        });
+        assert!(matches!(result, Err(ResqryptError::FilterError(_))));
+    }
+}
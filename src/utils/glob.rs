@@ -0,0 +1,189 @@
+//! Glob expansion
+//!
+//! Expands input patterns inside resqrypt rather than relying on the shell,
+//! which does not expand them on Windows. Patterns are matched with
+//! [`globset`]:
+//! - `*` matches any run of characters within a path component
+//! - `?` matches a single character
+//! - `[abc]`, `[a-z]` and `[!abc]` match a character class
+//! - `**` as a whole component matches any number of directories
+//!
+//! As in shells, files and directories whose name starts with a `.` are
+//! only matched when the pattern itself has a component starting with `.`.
+
+use std::path::{Component, Path, PathBuf};
+
+use crate::archive::tar::{DirWalk, WalkEntry};
+use crate::error::{ResqryptError, Result};
+use globset::{GlobBuilder, GlobMatcher};
+
+/// Whether `pattern` contains glob metacharacters
+pub fn has_glob_meta(pattern: &str) -> bool {
+    pattern.contains(['*', '?', '['])
+}
+
+/// A parsed glob pattern
+#[derive(Debug, Clone)]
+pub struct Glob {
+    /// Leading components without metacharacters, where the walk starts
+    base: PathBuf,
+    /// Matcher for paths relative to `base`
+    matcher: GlobMatcher,
+    /// Number of components after `base`, unless one of them is `**`
+    depth: Option<usize>,
+    /// Whether a component after `base` starts with `.`
+    dotted: bool,
+}
+
+impl Glob {
+    /// Parse a pattern
+    pub fn new(pattern: &str) -> Result<Self> {
+        let mut base = PathBuf::new();
+        let mut components = Vec::new();
+
+        for component in Path::new(pattern).components() {
+            let text = component.as_os_str().to_string_lossy();
+            match component {
+                Component::Normal(_) if !components.is_empty() || has_glob_meta(&text) => {
+                    components.push(text.into_owned());
+                }
+                _ if components.is_empty() => base.push(component),
+                _ => {
+                    return Err(ResqryptError::InvalidArgument(format!(
+                        "unsupported component {:?} in glob: {}",
+                        text, pattern
+                    )));
+                }
+            }
+        }
+
+        // A literal pattern matches only itself
+        if components.is_empty() {
+            if let Some(name) = base.file_name() {
+                components.push(globset::escape(&name.to_string_lossy()));
+                base.pop();
+            }
+        }
+        if base.as_os_str().is_empty() {
+            base.push(".");
+        }
+
+        let matcher = GlobBuilder::new(&components.join("/"))
+            .literal_separator(true)
+            .build()
+            .map_err(|e| {
+                ResqryptError::InvalidArgument(format!("invalid glob {}: {}", pattern, e.kind()))
+            })?
+            .compile_matcher();
+        let depth = (!components.iter().any(|c| c == "**")).then_some(components.len());
+        let dotted = components.iter().any(|c| c.starts_with('.'));
+
+        Ok(Self { base, matcher, depth, dotted })
+    }
+
+    /// Directory the pattern is relative to
+    pub fn base(&self) -> &Path {
+        &self.base
+    }
+
+    /// Regular files matching the pattern, in sorted order
+    pub fn expand(&self) -> Result<Vec<PathBuf>> {
+        let mut walk = DirWalk::new(&self.base);
+        if let Some(depth) = self.depth {
+            walk = walk.max_depth(depth);
+        }
+
+        let mut matches = Vec::new();
+        while let Some(entry) = walk.next() {
+            let entry = entry?;
+            if !self.dotted && is_hidden(&entry) {
+                walk.skip_current_dir();
+                continue;
+            }
+            if !entry.file_type().is_file() {
+                continue;
+            }
+
+            let relative = entry.path().strip_prefix(&self.base).unwrap_or(entry.path());
+            if self.matcher.is_match(relative) {
+                matches.push(entry.into_path());
+            }
+        }
+
+        Ok(matches)
+    }
+}
+
+/// Whether a walked entry below the base is named with a leading `.`
+fn is_hidden(entry: &WalkEntry) -> bool {
+    entry.depth() > 0
+        && entry.path().file_name().is_some_and(|name| name.to_string_lossy().starts_with('.'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    fn matches(pattern: &str, name: &str) -> bool {
+        Glob::new(pattern).unwrap().matcher.is_match(name)
+    }
+
+    #[test]
+    fn test_match_component() {
+        assert!(matches("*.pdf", "report.pdf"));
+        assert!(!matches("*.pdf", "report.pdf.bak"));
+        assert!(matches("file?.txt", "file1.txt"));
+        assert!(!matches("file?.txt", "file10.txt"));
+        assert!(matches("[a-c]*", "beta"));
+        assert!(!matches("[!a-c]*", "beta"));
+        assert!(matches("[]x]", "]"));
+        // Many stars must not backtrack exponentially
+        assert!(!matches("*a*a*a*a*a*a*a*b", &"a".repeat(10_000)));
+    }
+
+    #[test]
+    fn test_parse_base() {
+        let glob = Glob::new("docs/**/*.pdf").unwrap();
+        assert_eq!(glob.base(), Path::new("docs"));
+
+        let glob = Glob::new("*.txt").unwrap();
+        assert_eq!(glob.base(), Path::new("."));
+
+        assert!(matches!(Glob::new("docs/[a-z"), Err(ResqryptError::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn test_expand() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        fs::create_dir_all(root.join("docs/a/b")).unwrap();
+        fs::create_dir_all(root.join("docs/.hidden")).unwrap();
+        for file in ["docs/top.pdf", "docs/a/one.pdf", "docs/a/b/two.pdf", "docs/a/notes.txt"] {
+            fs::write(root.join(file), file).unwrap();
+        }
+        fs::write(root.join("docs/.hidden/secret.pdf"), "x").unwrap();
+
+        let pattern = format!("{}/docs/**/*.pdf", root.display());
+        let found = Glob::new(&pattern).unwrap().expand().unwrap();
+        let expected: Vec<PathBuf> = ["docs/a/b/two.pdf", "docs/a/one.pdf", "docs/top.pdf"]
+            .iter()
+            .map(|f| root.join(f))
+            .collect();
+        assert_eq!(found, expected);
+
+        let pattern = format!("{}/docs/*.pdf", root.display());
+        assert_eq!(Glob::new(&pattern).unwrap().expand().unwrap(), vec![root.join("docs/top.pdf")]);
+
+        let pattern = format!("{}/docs/*.doc", root.display());
+        assert!(Glob::new(&pattern).unwrap().expand().unwrap().is_empty());
+
+        // Hidden entries match when the pattern names the dot
+        let pattern = format!("{}/docs/.*/*.pdf", root.display());
+        assert_eq!(
+            Glob::new(&pattern).unwrap().expand().unwrap(),
+            vec![root.join("docs/.hidden/secret.pdf")]
+        );
+    }
+}
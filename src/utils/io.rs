@@ -0,0 +1,718 @@
+//! I/O helpers
+//!
+//! Small adapters used by the streaming code paths.
+//!
+//! The only files resqrypt creates besides its outputs are the temporary
+//! files of [`write_atomic`] and [`write_atomic_all`]. They sit next to the
+//! output, hold exactly the bytes the output will hold (plaintext only when
+//! decrypting) with the same permissions, and are removed if writing fails.
+//! Everything else, including passwords, derived keys and buffered filter
+//! output, stays in memory.
+
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use rand::Rng;
+
+use crate::FILE_EXTENSION;
+use crate::error::{ResqryptError, Result};
+
+/// Reader adapter that counts the bytes read through it
+pub struct CountingReader<R> {
+    inner: R,
+    count: u64,
+}
+
+impl<R: Read> CountingReader<R> {
+    /// Wrap a reader
+    pub fn new(inner: R) -> Self {
+        Self { inner, count: 0 }
+    }
+
+    /// Total number of bytes read so far
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+}
+
+/// Writer adapter that counts the bytes written through it
+pub struct CountingWriter<W> {
+    inner: W,
+    count: u64,
+}
+
+impl<W: Write> CountingWriter<W> {
+    /// Wrap a writer
+    pub fn new(inner: W) -> Self {
+        Self { inner, count: 0 }
+    }
+
+    /// Total number of bytes written so far
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Shared flag asking a long operation to stop
+///
+/// Clones share the flag, so one can be handed to a signal handler or
+/// another thread while the operation holds the other.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    /// Create a token that is not cancelled
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Ask every operation holding a clone of this token to stop
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether [`CancellationToken::cancel`] was called
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Fail with [`ResqryptError::Cancelled`] once cancelled
+    pub fn check(&self) -> Result<()> {
+        if self.is_cancelled() { Err(ResqryptError::Cancelled) } else { Ok(()) }
+    }
+}
+
+/// Reader adapter that fails with [`ResqryptError::Cancelled`] once its
+/// token is cancelled
+///
+/// The token is checked before every read, so a streaming operation stops
+/// within one buffer of the cancellation.
+pub struct CancellableReader<R> {
+    inner: R,
+    token: Option<CancellationToken>,
+}
+
+impl<R: Read> CancellableReader<R> {
+    /// Wrap a reader; without a token it reads through unchanged
+    pub fn new(inner: R, token: Option<CancellationToken>) -> Self {
+        Self { inner, token }
+    }
+}
+
+impl<R: Read> Read for CancellableReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(token) = &self.token {
+            token.check().map_err(io::Error::other)?;
+        }
+        self.inner.read(buf)
+    }
+}
+
+/// Whether `path` is `-`, standing for stdin or stdout
+pub fn is_stdio(path: &Path) -> bool {
+    path == Path::new("-")
+}
+
+/// Whether `path` is an existing FIFO or character device
+///
+/// Such outputs (a named pipe, `/dev/null`, a tape drive) are written in
+/// place: replacing them with a renamed temporary file, as
+/// [`write_atomic`] does, would swap the node for a regular file. The
+/// format is written strictly sequentially, so no seeking is needed.
+#[cfg(unix)]
+pub fn is_stream_output(path: &Path) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+
+    fs::metadata(path).is_ok_and(|metadata| {
+        let file_type = metadata.file_type();
+        file_type.is_fifo() || file_type.is_char_device()
+    })
+}
+
+#[cfg(not(unix))]
+pub fn is_stream_output(_path: &Path) -> bool {
+    false
+}
+
+/// Check that an output path is free to be written
+///
+/// Uses `symlink_metadata` so that a symlink at `path` (dangling or not) is
+/// rejected rather than followed to a location outside the intended one.
+/// FIFOs and character devices are accepted (see [`is_stream_output`]);
+/// any other existing file, append-only or not, is never overwritten.
+pub fn check_output_path(path: &Path) -> Result<()> {
+    if is_stream_output(path) {
+        return Ok(());
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::FileTypeExt;
+
+        if let Ok(metadata) = fs::symlink_metadata(path) {
+            let file_type = metadata.file_type();
+            if file_type.is_block_device() || file_type.is_socket() {
+                return Err(ResqryptError::InvalidArgument(format!(
+                    "output path is a block device or socket: {}",
+                    path.display()
+                )));
+            }
+        }
+    }
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_symlink() => Err(ResqryptError::InvalidArgument(
+            format!("output path is a symlink: {}", path.display()),
+        )),
+        Ok(_) => Err(ResqryptError::AlreadyExists(path.to_path_buf())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Check that input and output do not refer to the same file
+///
+/// Both paths are canonicalized, so `./a`, `a` and a symlink to `a` are all
+/// recognized as the same file. A non-existent output is resolved through
+/// its parent directory.
+pub fn check_distinct_paths(input: &Path, output: &Path) -> Result<()> {
+    let input = fs::canonicalize(input)?;
+    let output = match fs::canonicalize(output) {
+        Ok(path) => path,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let parent = output.parent().filter(|p| !p.as_os_str().is_empty());
+            match (parent.map(fs::canonicalize).transpose(), output.file_name()) {
+                (Ok(parent), Some(name)) => parent.unwrap_or(std::env::current_dir()?).join(name),
+                // Parent missing or no file name: cannot be the input
+                _ => return Ok(()),
+            }
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    if input == output {
+        return Err(ResqryptError::InvalidArgument(
+            "input and output are the same file".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Check that `output_dir` is not inside the `input` directory
+///
+/// Writing into the tree being encrypted would mix outputs with inputs.
+/// A non-existent `output_dir` is resolved through its nearest existing
+/// ancestor.
+pub fn check_output_outside_input(input: &Path, output_dir: &Path) -> Result<()> {
+    if !input.is_dir() {
+        return Ok(());
+    }
+
+    let input = fs::canonicalize(input)?;
+    let output_dir = resolve_path(output_dir)?;
+
+    if output_dir.starts_with(&input) {
+        return Err(ResqryptError::InvalidArgument(format!(
+            "output directory {} is inside the input directory {}",
+            output_dir.display(),
+            input.display()
+        )));
+    }
+
+    Ok(())
+}
+
+/// Canonicalize `path`, resolving the longest existing prefix when the rest
+/// does not exist yet
+pub fn resolve_path(path: &Path) -> Result<PathBuf> {
+    let path = std::env::current_dir()?.join(path);
+    let mut missing = Vec::new();
+    let mut existing = path.as_path();
+
+    loop {
+        match fs::canonicalize(existing) {
+            Ok(resolved) => {
+                return Ok(missing.iter().rev().fold(resolved, |acc, name| acc.join(name)));
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => match existing.parent() {
+                Some(parent) => {
+                    missing.extend(existing.file_name());
+                    existing = parent;
+                }
+                None => return Err(e.into()),
+            },
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Path of the encrypted output for `input` inside `output_dir`
+///
+/// Appends `.resqrypt` to the input's file name.
+pub fn encrypted_output_in(input: &Path, output_dir: &Path) -> Result<PathBuf> {
+    let mut name = input_file_name(input)?;
+    name.push(".");
+    name.push(FILE_EXTENSION);
+    Ok(output_dir.join(name))
+}
+
+/// Path of the decrypted output for `input` inside `output_dir`
+///
+/// Strips a `.resqrypt` extension from the input's file name, or appends
+/// `.decrypted` when there is none.
+pub fn decrypted_output_in(input: &Path, output_dir: &Path) -> Result<PathBuf> {
+    let name = input_file_name(input)?;
+    let path = Path::new(&name);
+
+    let name = match (path.extension(), path.file_stem()) {
+        (Some(ext), Some(stem)) if ext == FILE_EXTENSION => stem.to_os_string(),
+        _ => {
+            let mut name = name.clone();
+            name.push(".decrypted");
+            name
+        }
+    };
+
+    Ok(output_dir.join(name))
+}
+
+/// Describe a likely mistake in the extension of an output path
+///
+/// Encrypted outputs should end in `.resqrypt` and decrypted outputs
+/// should not. Returns `None` when the extension looks right.
+pub fn output_extension_issue(output: &Path, encrypted: bool) -> Option<String> {
+    let has_extension = output.extension().is_some_and(|ext| ext == FILE_EXTENSION);
+
+    match (encrypted, has_extension) {
+        (true, false) => Some(format!(
+            "encrypted output {} does not end in .{}",
+            output.display(),
+            FILE_EXTENSION
+        )),
+        (false, true) => {
+            Some(format!("decrypted output {} still ends in .{}", output.display(), FILE_EXTENSION))
+        }
+        _ => None,
+    }
+}
+
+/// File name of `input`, resolving paths like `.` that have none
+fn input_file_name(input: &Path) -> Result<OsString> {
+    if let Some(name) = input.file_name() {
+        return Ok(name.to_os_string());
+    }
+
+    fs::canonicalize(input)?.file_name().map(|name| name.to_os_string()).ok_or_else(|| {
+        ResqryptError::InvalidArgument(format!("input has no file name: {}", input.display()))
+    })
+}
+
+/// Create an output directory and any missing parents
+///
+/// Fails with `InvalidArgument` naming the offending component when part of
+/// the path already exists as a file, where `fs::create_dir_all` would only
+/// report a bare "File exists" or "Not a directory".
+pub fn create_output_dir(dir: &Path) -> Result<()> {
+    for ancestor in dir.ancestors().filter(|a| !a.as_os_str().is_empty()) {
+        match fs::metadata(ancestor) {
+            Ok(metadata) if metadata.is_dir() => break,
+            Ok(_) => {
+                return Err(ResqryptError::InvalidArgument(format!(
+                    "cannot create output directory; {} is a file",
+                    ancestor.display()
+                )));
+            }
+            Err(_) => continue,
+        }
+    }
+
+    fs::create_dir_all(dir)?;
+    Ok(())
+}
+
+/// Write a file atomically
+///
+/// Data is written to a temporary file next to `path` which is then renamed
+/// into place. The rename replaces the directory entry itself, so an entry
+/// that appears at `path` in the meantime is never written through.
+///
+/// FIFOs and character devices are opened and written directly, without
+/// truncation or a temporary file.
+pub fn write_atomic<F>(path: &Path, write: F) -> Result<()>
+where
+    F: FnOnce(&mut File) -> Result<()>,
+{
+    if is_stream_output(path) {
+        return write(&mut OpenOptions::new().write(true).open(path)?);
+    }
+
+    // Create parent directories if needed
+    if let Some(parent) = path.parent() {
+        create_output_dir(parent)?;
+    }
+
+    let temp_path = temp_path_for(path);
+    let result = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&temp_path)
+        .map_err(ResqryptError::from)
+        .and_then(|mut file| {
+            write(&mut file)?;
+            file.sync_all()?;
+            Ok(())
+        })
+        .and_then(|()| fs::rename(&temp_path, path).map_err(ResqryptError::from));
+
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+
+    result
+}
+
+/// Directory filled under a temporary name before it is moved into place
+///
+/// The directory sits next to its final path, so moving it is a rename.
+/// Unless [`StagingDir::commit`] moved it, it is removed with everything in
+/// it when dropped, including after an error.
+#[derive(Debug)]
+pub struct StagingDir {
+    path: Option<PathBuf>,
+}
+
+impl StagingDir {
+    /// Reserve a staging name for `path`; the directory itself is created
+    /// by whatever fills it
+    pub fn new(path: &Path) -> Self {
+        Self { path: Some(temp_path_for(path)) }
+    }
+
+    /// Where to write the contents
+    pub fn path(&self) -> &Path {
+        self.path.as_deref().expect("path is only taken by commit")
+    }
+
+    /// Move the directory to `path`
+    pub fn commit(mut self, path: &Path) -> Result<()> {
+        fs::rename(self.path(), path)?;
+        self.path = None;
+        Ok(())
+    }
+}
+
+impl Drop for StagingDir {
+    fn drop(&mut self) {
+        if let Some(path) = &self.path {
+            let _ = fs::remove_dir_all(path);
+        }
+    }
+}
+
+/// Write several identical files atomically in one pass
+///
+/// Everything written goes to a temporary file next to each path; once all
+/// are complete they are renamed into place. If any write or rename fails,
+/// all temporary files and any outputs already renamed are removed.
+///
+/// As with [`write_atomic`], FIFOs and character devices are written
+/// directly; whatever reached them cannot be taken back on failure.
+pub fn write_atomic_all<F>(paths: &[PathBuf], write: F) -> Result<()>
+where
+    F: FnOnce(&mut TeeWriter) -> Result<()>,
+{
+    let streams: Vec<bool> = paths.iter().map(|path| is_stream_output(path)).collect();
+    let temp_paths: Vec<PathBuf> = paths.iter().map(|path| temp_path_for(path)).collect();
+    let mut renamed = 0;
+
+    let result = (|| {
+        let mut tee = TeeWriter { files: Vec::with_capacity(paths.len()) };
+        for ((path, temp_path), &stream) in paths.iter().zip(&temp_paths).zip(&streams) {
+            if stream {
+                tee.files.push(OpenOptions::new().write(true).open(path)?);
+                continue;
+            }
+            if let Some(parent) = path.parent() {
+                create_output_dir(parent)?;
+            }
+            tee.files.push(OpenOptions::new().write(true).create_new(true).open(temp_path)?);
+        }
+
+        write(&mut tee)?;
+        for (file, &stream) in tee.files.iter().zip(&streams) {
+            if !stream {
+                file.sync_all()?;
+            }
+        }
+
+        for ((path, temp_path), &stream) in paths.iter().zip(&temp_paths).zip(&streams) {
+            if !stream {
+                fs::rename(temp_path, path)?;
+            }
+            renamed += 1;
+        }
+        Ok(())
+    })();
+
+    if result.is_err() {
+        for (path, &stream) in paths.iter().zip(&streams).take(renamed) {
+            if !stream {
+                let _ = fs::remove_file(path);
+            }
+        }
+        for (temp_path, &stream) in temp_paths.iter().zip(&streams).skip(renamed) {
+            if !stream {
+                let _ = fs::remove_file(temp_path);
+            }
+        }
+    }
+
+    result
+}
+
+/// Writer duplicating everything written to several files
+pub struct TeeWriter {
+    files: Vec<File>,
+}
+
+impl Write for TeeWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.files.iter_mut().try_for_each(|file| file.write_all(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.files.iter_mut().try_for_each(|file| file.flush())
+    }
+}
+
+/// Hidden, randomly named sibling of `path` used as a temporary file
+fn temp_path_for(path: &Path) -> PathBuf {
+    let file_name = path.file_name().map(|n| n.to_string_lossy()).unwrap_or_default();
+    let suffix: u32 = rand::rng().random();
+    path.with_file_name(format!(".{}.{:08x}.tmp", file_name, suffix))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_write_atomic_all() {
+        let temp_dir = TempDir::new().unwrap();
+        let paths = vec![temp_dir.path().join("a.bin"), temp_dir.path().join("copy/b.bin")];
+
+        write_atomic_all(&paths, |out| Ok(out.write_all(b"same bytes")?)).unwrap();
+        for path in &paths {
+            assert_eq!(fs::read(path).unwrap(), b"same bytes");
+        }
+
+        // A failure leaves neither output nor temporary files behind
+        let paths = vec![temp_dir.path().join("c.bin"), temp_dir.path().join("d.bin")];
+        let result = write_atomic_all(&paths, |out| {
+            out.write_all(b"partial")?;
+            Err(ResqryptError::InvalidArgument("stop".to_string()))
+        });
+        assert!(result.is_err());
+        let names: Vec<_> =
+            fs::read_dir(temp_dir.path()).unwrap().map(|e| e.unwrap().file_name()).collect();
+        assert_eq!(names.len(), 2, "unexpected files: {:?}", names);
+    }
+
+    #[test]
+    fn test_counts_bytes_read() {
+        let mut reader = CountingReader::new(&b"twelve bytes"[..]);
+        let mut buf = Vec::new();
+        reader.read_to_end(&mut buf).unwrap();
+
+        assert_eq!(reader.count(), 12);
+    }
+
+    #[test]
+    fn test_cancellable_reader() {
+        let token = CancellationToken::new();
+        let mut reader = CancellableReader::new(&b"some bytes"[..], Some(token.clone()));
+        let mut buf = [0u8; 4];
+        assert_eq!(reader.read(&mut buf).unwrap(), 4);
+
+        token.cancel();
+        let err = reader.read(&mut buf).unwrap_err();
+        assert!(matches!(ResqryptError::from_io(err), ResqryptError::Cancelled));
+    }
+
+    #[test]
+    fn test_output_extension_issue() {
+        assert!(output_extension_issue(Path::new("a.txt.resqrypt"), true).is_none());
+        assert!(output_extension_issue(Path::new("a.txt"), true).is_some());
+        assert!(output_extension_issue(Path::new("a.txt"), false).is_none());
+        assert!(output_extension_issue(Path::new("out/a.resqrypt"), false).is_some());
+    }
+
+    #[test]
+    fn test_check_output_path() {
+        let temp_dir = TempDir::new().unwrap();
+        let free = temp_dir.path().join("free");
+        let taken = temp_dir.path().join("taken");
+        fs::write(&taken, "x").unwrap();
+
+        assert!(check_output_path(&free).is_ok());
+        assert!(matches!(check_output_path(&taken), Err(ResqryptError::AlreadyExists(_))));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_check_output_path_rejects_symlink() {
+        let temp_dir = TempDir::new().unwrap();
+        let target = temp_dir.path().join("elsewhere");
+        let link = temp_dir.path().join("link");
+        std::os::unix::fs::symlink(&target, &link).unwrap();
+
+        // Dangling symlink: `exists()` is false but it must still be refused
+        assert!(!link.exists());
+        let result = check_output_path(&link);
+        assert!(matches!(result, Err(ResqryptError::InvalidArgument(_))));
+        assert!(!target.exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_write_atomic_to_fifo() {
+        let temp_dir = TempDir::new().unwrap();
+        let fifo = temp_dir.path().join("pipe");
+        let status = std::process::Command::new("mkfifo").arg(&fifo).status().unwrap();
+        assert!(status.success());
+        check_output_path(&fifo).unwrap();
+
+        let reader = {
+            let fifo = fifo.clone();
+            std::thread::spawn(move || fs::read(fifo).unwrap())
+        };
+        write_atomic_all(std::slice::from_ref(&fifo), |out| Ok(out.write_all(b"streamed")?))
+            .unwrap();
+
+        // Written in place: the FIFO is still there, with no temporary files
+        assert_eq!(reader.join().unwrap(), b"streamed");
+        assert!(is_stream_output(&fifo));
+        assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_create_output_dir_file_component() {
+        let temp_dir = TempDir::new().unwrap();
+        let file = temp_dir.path().join("file");
+        fs::write(&file, "x").unwrap();
+
+        let result = write_atomic(&file.join("nested/out.bin"), |_| Ok(()));
+        match result {
+            Err(ResqryptError::InvalidArgument(msg)) => {
+                assert!(msg.contains(&file.display().to_string()), "{}", msg);
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        create_output_dir(&temp_dir.path().join("a/b")).unwrap();
+        assert!(temp_dir.path().join("a/b").is_dir());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_write_atomic_replaces_symlink() {
+        let temp_dir = TempDir::new().unwrap();
+        let target = temp_dir.path().join("target");
+        let link = temp_dir.path().join("link");
+        fs::write(&target, "original").unwrap();
+        std::os::unix::fs::symlink(&target, &link).unwrap();
+
+        write_atomic(&link, |file| {
+            use std::io::Write;
+            file.write_all(b"new")?;
+            Ok(())
+        })
+        .unwrap();
+
+        assert_eq!(fs::read_to_string(&target).unwrap(), "original");
+        assert!(!fs::symlink_metadata(&link).unwrap().file_type().is_symlink());
+        assert_eq!(fs::read_to_string(&link).unwrap(), "new");
+    }
+
+    #[test]
+    fn test_check_distinct_paths() {
+        let temp_dir = TempDir::new().unwrap();
+        let input = temp_dir.path().join("data.txt");
+        fs::write(&input, "x").unwrap();
+
+        let same = temp_dir.path().join(".").join("data.txt");
+        let result = check_distinct_paths(&input, &same);
+        assert!(matches!(result, Err(ResqryptError::InvalidArgument(_))));
+
+        let other = temp_dir.path().join("data.txt.resqrypt");
+        assert!(check_distinct_paths(&input, &other).is_ok());
+    }
+
+    #[test]
+    fn test_write_atomic_cleans_up_on_error() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("out");
+
+        let result = write_atomic(&path, |_| Err(ResqryptError::InvalidArgument("boom".into())));
+        assert!(result.is_err());
+        assert!(!path.exists());
+        assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_output_names_in_dir() {
+        let out = Path::new("out");
+
+        let encrypted = encrypted_output_in(Path::new("docs/report.pdf"), out).unwrap();
+        assert_eq!(encrypted, Path::new("out/report.pdf.resqrypt"));
+
+        let decrypted = decrypted_output_in(&encrypted, Path::new("restored")).unwrap();
+        assert_eq!(decrypted, Path::new("restored/report.pdf"));
+
+        let decrypted = decrypted_output_in(Path::new("backup.bin"), out).unwrap();
+        assert_eq!(decrypted, Path::new("out/backup.bin.decrypted"));
+    }
+
+    #[test]
+    fn test_check_output_outside_input() {
+        let temp_dir = TempDir::new().unwrap();
+        let input = temp_dir.path().join("src");
+        fs::create_dir(&input).unwrap();
+
+        let inside = input.join("encrypted").join("nested");
+        let result = check_output_outside_input(&input, &inside);
+        assert!(matches!(result, Err(ResqryptError::InvalidArgument(_))));
+
+        let beside = temp_dir.path().join("src-encrypted");
+        assert!(check_output_outside_input(&input, &beside).is_ok());
+    }
+}
@@ -0,0 +1,128 @@
+//! Manifest of processed files
+//!
+//! A JSON array with one object per source file, recording where it was
+//! written, its size and whether it succeeded:
+//!
+//! ```json
+//! [
+//!   {"source":"docs/a.pdf","output":"docs.resqrypt","size":1024,"status":"ok","error":null}
+//! ]
+//! ```
+//!
+//! Each entry is serialized with `serde_json` as it is added, so nothing is
+//! buffered.
+
+use std::io::{self, Write};
+use std::path::Path;
+
+use serde::ser::{Serialize, SerializeStruct, Serializer};
+
+use crate::error::Result;
+
+/// A processed source file
+#[derive(Debug, Clone)]
+pub struct ManifestEntry<'a> {
+    /// Path the data was read from
+    pub source: &'a Path,
+    /// Path the result was written to
+    pub output: &'a Path,
+    /// Size of the source in bytes (`None` for directories)
+    pub size: Option<u64>,
+    /// Error message if processing failed
+    pub error: Option<&'a str>,
+}
+
+impl Serialize for ManifestEntry<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("ManifestEntry", 5)?;
+        state.serialize_field("source", &self.source.to_string_lossy())?;
+        state.serialize_field("output", &self.output.to_string_lossy())?;
+        state.serialize_field("size", &self.size)?;
+        state.serialize_field("status", if self.error.is_some() { "failed" } else { "ok" })?;
+        state.serialize_field("error", &self.error)?;
+        state.end()
+    }
+}
+
+/// Writer emitting manifest entries one at a time
+pub struct ManifestWriter<W: Write> {
+    out: W,
+    entries: usize,
+}
+
+impl<W: Write> ManifestWriter<W> {
+    /// Start a manifest
+    pub fn new(mut out: W) -> Result<Self> {
+        out.write_all(b"[")?;
+        Ok(Self { out, entries: 0 })
+    }
+
+    /// Append an entry
+    pub fn entry(&mut self, entry: &ManifestEntry<'_>) -> Result<()> {
+        let separator = if self.entries == 0 { "\n  " } else { ",\n  " };
+        self.out.write_all(separator.as_bytes())?;
+        serde_json::to_writer(&mut self.out, entry).map_err(io::Error::from)?;
+        self.entries += 1;
+        Ok(())
+    }
+
+    /// Close the array and return the inner writer
+    pub fn finish(mut self) -> Result<W> {
+        let end = if self.entries == 0 { "]\n" } else { "\n]\n" };
+        self.out.write_all(end.as_bytes())?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manifest_entries() {
+        let mut manifest = ManifestWriter::new(Vec::new()).unwrap();
+        manifest
+            .entry(&ManifestEntry {
+                source: Path::new("a.txt"),
+                output: Path::new("a.txt.resqrypt"),
+                size: Some(12),
+                error: None,
+            })
+            .unwrap();
+        manifest
+            .entry(&ManifestEntry {
+                source: Path::new("dir"),
+                output: Path::new("dir.resqrypt"),
+                size: None,
+                error: Some("disk \"full\""),
+            })
+            .unwrap();
+        let json = String::from_utf8(manifest.finish().unwrap()).unwrap();
+
+        assert_eq!(
+            json,
+            "[\n  {\"source\":\"a.txt\",\"output\":\"a.txt.resqrypt\",\"size\":12,\
+             \"status\":\"ok\",\"error\":null},\n  {\"source\":\"dir\",\"output\":\
+             \"dir.resqrypt\",\"size\":null,\"status\":\"failed\",\"error\":\
+             \"disk \\\"full\\\"\"}\n]\n"
+        );
+    }
+
+    #[test]
+    fn test_manifest_escapes_and_empty() {
+        let mut manifest = ManifestWriter::new(Vec::new()).unwrap();
+        manifest
+            .entry(&ManifestEntry {
+                source: Path::new("a\\b\n\u{1}"),
+                output: Path::new("out"),
+                size: Some(0),
+                error: None,
+            })
+            .unwrap();
+        let json = String::from_utf8(manifest.finish().unwrap()).unwrap();
+        assert!(json.contains("\"source\":\"a\\\\b\\n\\u0001\""), "{}", json);
+
+        assert_eq!(ManifestWriter::new(Vec::new()).unwrap().finish().unwrap(), b"[]\n");
+    }
+}
@@ -2,6 +2,37 @@
 //!
 //! Common utilities for CLI operations.
 
+pub mod digest;
+pub mod file_list;
+pub mod filter;
+pub mod glob;
+pub mod io;
+pub mod manifest;
+pub mod password;
+#[cfg(feature = "cli")]
 pub mod progress;
+pub(crate) mod telemetry;
 
-pub use progress::ProgressReporter;
+pub use digest::{HashAlgo, HashingWriter};
+pub use file_list::{read_file_list, resolve_listed};
+pub use filter::{FilterReader, filter_into};
+pub use glob::{Glob, has_glob_meta};
+pub use io::{
+    CancellableReader, CancellationToken, CountingReader, CountingWriter, StagingDir, TeeWriter,
+    check_distinct_paths, check_output_outside_input, check_output_path, create_output_dir,
+    decrypted_output_in, encrypted_output_in, is_stdio, is_stream_output, output_extension_issue,
+    resolve_path, write_atomic, write_atomic_all,
+};
+pub use manifest::{ManifestEntry, ManifestWriter};
+#[cfg(feature = "cli")]
+pub use password::prompt_for_password;
+#[cfg(unix)]
+pub use password::read_password_fd;
+pub use password::{
+    PASSWORD_ENV, decode_key_base64, estimate_entropy_bits, get_password, load_pepper,
+    password_from_env, read_pepper_file, weak_password_warning,
+};
+#[cfg(feature = "cli")]
+pub use progress::{
+    MultiProgressReporter, ProgressRead, ProgressReporter, format_bytes, format_throughput,
+};
@@ -0,0 +1,369 @@
+//! Password input helpers
+//!
+//! The ways of supplying a password shared by every command, the pepper a
+//! file may require, and a rough strength estimate for passwords.
+//!
+//! Passwords are only ever held in memory: prompts read from the terminal
+//! and descriptors are read directly, never through a temporary file.
+
+use std::env;
+use std::fs;
+#[cfg(unix)]
+use std::fs::File;
+#[cfg(unix)]
+use std::io::Read;
+#[cfg(unix)]
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::path::Path;
+
+use base64ct::{Base64, Encoding};
+#[cfg(feature = "cli")]
+use rpassword::prompt_password;
+use zeroize::Zeroizing;
+
+use crate::crypto::FileHeader;
+use crate::error::{ResqryptError, Result};
+
+/// Environment variable read for the password when no option gives one
+pub const PASSWORD_ENV: &str = "RESQRYPT_PASSWORD";
+
+/// The password in [`PASSWORD_ENV`], if set and not empty
+///
+/// Read only after `--password` and `--password-fd`, so that either one
+/// given on the command line wins over a password left in the
+/// environment.
+pub fn password_from_env() -> Option<String> {
+    env::var(PASSWORD_ENV).ok().filter(|password| !password.is_empty())
+}
+
+/// Get password bytes from a base64 key, a file descriptor, `--password`
+/// or [`PASSWORD_ENV`], in that order, or else from `prompt`
+///
+/// `--password-fd` only exists on Unix, so `password_fd` is `None`
+/// elsewhere. The bytes are wiped from memory when dropped.
+pub fn get_password(
+    key_base64: Option<&str>,
+    password: Option<&str>,
+    password_fd: Option<i32>,
+    prompt: impl FnOnce() -> Result<Zeroizing<Vec<u8>>>,
+) -> Result<Zeroizing<Vec<u8>>> {
+    if let Some(key) = key_base64 {
+        return decode_key_base64(key).map(Zeroizing::new);
+    }
+
+    if let Some(fd) = password_fd {
+        #[cfg(unix)]
+        return read_password_fd(fd).map(|password| Zeroizing::new(password.into_bytes()));
+        #[cfg(not(unix))]
+        return Err(ResqryptError::InvalidArgument(format!(
+            "Cannot read the password from file descriptor {} on this platform",
+            fd
+        )));
+    }
+
+    match password.map(str::to_string).or_else(password_from_env) {
+        Some(p) => Ok(Zeroizing::new(p.into_bytes())),
+        None => prompt(),
+    }
+}
+
+/// Prompt for a password on the terminal, refusing an empty one
+#[cfg(feature = "cli")]
+pub fn prompt_for_password(message: &str) -> Result<Zeroizing<Vec<u8>>> {
+    let password = prompt_password(message)
+        .map_err(|e| ResqryptError::PasswordError(format!("Failed to read password: {}", e)))?;
+
+    if password.is_empty() {
+        return Err(ResqryptError::PasswordError("Password cannot be empty".to_string()));
+    }
+
+    Ok(Zeroizing::new(password.into_bytes()))
+}
+
+/// Decode a base64 binary key into the raw bytes used as the password
+///
+/// The bytes are passed to the KDF unchanged, so keys that are not valid
+/// UTF-8 survive intact. Surrounding whitespace is ignored.
+pub fn decode_key_base64(encoded: &str) -> Result<Vec<u8>> {
+    let key = Base64::decode_vec(encoded.trim())
+        .map_err(|e| ResqryptError::InvalidArgument(format!("Invalid base64 key: {}", e)))?;
+
+    if key.is_empty() {
+        return Err(ResqryptError::PasswordError("Key cannot be empty".to_string()));
+    }
+
+    Ok(key)
+}
+
+/// Read a secret pepper from a file
+///
+/// The bytes are used as-is, trailing newline included, so decryption
+/// must be given the very same file contents.
+pub fn read_pepper_file(path: &Path) -> Result<Vec<u8>> {
+    let pepper = fs::read(path).map_err(|e| {
+        ResqryptError::InvalidArgument(format!(
+            "Failed to read pepper file {}: {}",
+            path.display(),
+            e
+        ))
+    })?;
+
+    if pepper.is_empty() {
+        return Err(ResqryptError::InvalidArgument(format!(
+            "Pepper file {} is empty",
+            path.display()
+        )));
+    }
+
+    Ok(pepper)
+}
+
+/// The pepper a file with `header` requires, read from `pepper_file`
+///
+/// `None` if the file was encrypted without one, whether or not a pepper
+/// file was given.
+pub fn load_pepper(pepper_file: Option<&Path>, header: &FileHeader) -> Result<Option<Vec<u8>>> {
+    if !header.is_peppered() {
+        return Ok(None);
+    }
+    let path = pepper_file.ok_or_else(|| {
+        ResqryptError::InvalidArgument(
+            "this file requires a pepper; pass --pepper-file or set RESQRYPT_PEPPER_FILE"
+                .to_string(),
+        )
+    })?;
+    read_pepper_file(path).map(Some)
+}
+
+/// Read a password from an inherited file descriptor
+///
+/// Reads until EOF and strips one trailing newline, matching the
+/// `--passphrase-fd` convention of tools like gpg. The descriptor is closed
+/// afterwards.
+///
+/// Standard input, output and error (0 to 2) are refused, since closing
+/// them would hand their numbers to the next file opened, such as the
+/// output. So are descriptors that are not open.
+#[cfg(unix)]
+pub fn read_password_fd(fd: RawFd) -> Result<String> {
+    if fd < 0 {
+        return Err(ResqryptError::InvalidArgument(format!("Invalid file descriptor: {}", fd)));
+    }
+    if fd <= 2 {
+        return Err(ResqryptError::InvalidArgument(format!(
+            "--password-fd {} is standard input, output or error; pass the password on \
+             another descriptor, e.g. --password-fd 3 3<password.txt",
+            fd
+        )));
+    }
+    // SAFETY: F_GETFD only queries the descriptor's flags
+    if unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+        return Err(ResqryptError::InvalidArgument(format!(
+            "File descriptor {} is not open: {}",
+            fd,
+            std::io::Error::last_os_error()
+        )));
+    }
+
+    // SAFETY: `fd` is open, and the caller hands us ownership of it on the
+    // command line; it is not used anywhere else in this process.
+    let mut file = unsafe { File::from_raw_fd(fd) };
+
+    let mut password = String::new();
+    file.read_to_string(&mut password).map_err(|e| {
+        ResqryptError::PasswordError(format!("Failed to read password from fd {}: {}", fd, e))
+    })?;
+
+    if password.ends_with('\n') {
+        password.pop();
+        if password.ends_with('\r') {
+            password.pop();
+        }
+    }
+
+    if password.is_empty() {
+        return Err(ResqryptError::PasswordError("Password cannot be empty".to_string()));
+    }
+
+    Ok(password)
+}
+
+/// Estimated entropy below which a password is reported as weak
+pub const WEAK_PASSWORD_BITS: f64 = 60.0;
+
+/// Rough entropy estimate of a password in bits
+///
+/// Multiplies the length by the size of the character pool drawn from
+/// (lowercase, uppercase, digits, ASCII symbols, anything else). Characters
+/// repeating or continuing a run from the previous one (`aaa`, `abc`,
+/// `321`) add only one bit. This is a coarse lower bar for spotting bad
+/// secrets, not a measure of real guessing resistance.
+pub fn estimate_entropy_bits(password: &str) -> f64 {
+    let chars: Vec<char> = password.chars().collect();
+
+    let mut pool = 0u32;
+    if chars.iter().any(char::is_ascii_lowercase) {
+        pool += 26;
+    }
+    if chars.iter().any(char::is_ascii_uppercase) {
+        pool += 26;
+    }
+    if chars.iter().any(char::is_ascii_digit) {
+        pool += 10;
+    }
+    if chars.iter().any(|c| c.is_ascii() && !c.is_ascii_alphanumeric()) {
+        pool += 33;
+    }
+    if chars.iter().any(|c| !c.is_ascii()) {
+        pool += 100;
+    }
+    if pool == 0 {
+        return 0.0;
+    }
+
+    let bits_per_char = f64::from(pool).log2();
+    let mut bits = 0.0;
+    for (i, &c) in chars.iter().enumerate() {
+        let predictable = i > 0 && (u32::from(c)).abs_diff(u32::from(chars[i - 1])) <= 1;
+        bits += if predictable { 1.0 } else { bits_per_char };
+    }
+    bits
+}
+
+/// Warning for a password whose estimated entropy is below
+/// [`WEAK_PASSWORD_BITS`], if any
+pub fn weak_password_warning(password: &str) -> Option<String> {
+    let bits = estimate_entropy_bits(password);
+    (bits < WEAK_PASSWORD_BITS).then(|| {
+        format!(
+            "weak password: about {:.0} bits of estimated entropy (recommended: at least {:.0})",
+            bits, WEAK_PASSWORD_BITS
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(unix)]
+    use std::os::unix::io::IntoRawFd;
+    #[cfg(unix)]
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_estimate_entropy_bits() {
+        assert_eq!(estimate_entropy_bits(""), 0.0);
+        assert!(estimate_entropy_bits("aaaaaaaaaaaaaaaaaaaa") < 25.0);
+        assert!(estimate_entropy_bits("abcdefgh") < estimate_entropy_bits("hbfdgaec") / 2.0);
+        assert!(weak_password_warning("password1").is_some());
+        assert!(weak_password_warning("correct horse battery staple").is_none());
+        assert!(weak_password_warning("tR7#qLm2@vX9!kPz").is_none());
+    }
+
+    #[test]
+    fn test_decode_key_base64_binary() {
+        let key = decode_key_base64(" /wCAgf7/ \n").unwrap();
+        assert_eq!(key, [0xff, 0x00, 0x80, 0x81, 0xfe, 0xff]);
+        assert!(std::str::from_utf8(&key).is_err());
+    }
+
+    #[test]
+    fn test_decode_key_base64_invalid() {
+        for encoded in ["not base64!", "abc", ""] {
+            assert!(decode_key_base64(encoded).is_err(), "{:?}", encoded);
+        }
+        assert!(matches!(decode_key_base64("a$==="), Err(ResqryptError::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn test_get_password_precedence() {
+        let unreachable = || -> Result<Zeroizing<Vec<u8>>> { panic!("prompted") };
+
+        let key = get_password(Some("AAE="), Some("pw"), None, unreachable).unwrap();
+        assert_eq!(*key, [0, 1]);
+        let password = get_password(None, Some("pw"), None, unreachable).unwrap();
+        assert_eq!(*password, b"pw");
+    }
+
+    #[test]
+    fn test_load_pepper() {
+        use crate::crypto::{CipherKind, HeaderFlags, KdfParams};
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("pepper");
+        std::fs::write(&path, "spice").unwrap();
+        let header = |flags| {
+            FileHeader::new(
+                flags,
+                CipherKind::Aes256Gcm,
+                KdfParams::default(),
+                [0; 32],
+                vec![0; 12],
+            )
+        };
+
+        let peppered = header(HeaderFlags::PEPPERED);
+        assert_eq!(load_pepper(Some(&path), &peppered).unwrap().unwrap(), b"spice");
+        let err = load_pepper(None, &peppered).unwrap_err();
+        assert!(err.to_string().contains("requires a pepper"), "{}", err);
+
+        // A pepper the file was not encrypted with is not used
+        assert!(load_pepper(Some(&path), &header(HeaderFlags::empty())).unwrap().is_none());
+    }
+
+    #[cfg(unix)]
+    fn fd_with_contents(dir: &TempDir, contents: &str) -> RawFd {
+        let path = dir.path().join("secret");
+        std::fs::write(&path, contents).unwrap();
+        File::open(&path).unwrap().into_raw_fd()
+    }
+
+    #[test]
+    fn test_read_password_fd_trims_one_newline() {
+        let dir = TempDir::new().unwrap();
+        let fd = fd_with_contents(&dir, "hunter2\n\n");
+
+        assert_eq!(read_password_fd(fd).unwrap(), "hunter2\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_read_password_fd_crlf() {
+        let dir = TempDir::new().unwrap();
+        let fd = fd_with_contents(&dir, "hunter2\r\n");
+
+        assert_eq!(read_password_fd(fd).unwrap(), "hunter2");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_read_password_fd_empty() {
+        let dir = TempDir::new().unwrap();
+        let fd = fd_with_contents(&dir, "\n");
+
+        let result = read_password_fd(fd);
+        assert!(matches!(result, Err(ResqryptError::PasswordError(_))));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_get_password_from_fd() {
+        let dir = TempDir::new().unwrap();
+        let fd = fd_with_contents(&dir, "from fd\n");
+
+        let password = get_password(None, None, Some(fd), || panic!("prompted")).unwrap();
+        assert_eq!(*password, b"from fd");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_read_password_fd_refuses_stdio_and_closed() {
+        for fd in 0..=2 {
+            let err = read_password_fd(fd).unwrap_err();
+            assert!(err.to_string().contains("standard input"), "{}", err);
+        }
+
+        let err = read_password_fd(1_000_000).unwrap_err();
+        assert!(err.to_string().contains("not open"), "{}", err);
+    }
+}
@@ -2,18 +2,50 @@
 //!
 //! Provides progress feedback during encryption/decryption operations.
 
-use indicatif::{ProgressBar, ProgressStyle};
+use std::io::Read;
+use std::time::Duration;
+
+use console::Term;
+use indicatif::{MultiProgress, ProgressBar, ProgressBarIter, ProgressDrawTarget, ProgressStyle};
 
 /// Progress reporter for CLI operations
+///
+/// Reporters are `Send + Sync`; use [`MultiProgressReporter`] to give
+/// concurrent tasks their own bars.
+///
+/// When stderr is not a terminal (or reports zero width), verbose reporters
+/// draw no spinner and instead print each message as a plain line, with the
+/// percentage done once a length is known.
+///
+/// The verbosity is the number of `-v` flags: 1 shows progress and a
+/// summary, 2 and above also enable [`ProgressReporter::debug`] diagnostics
+/// of the matching level.
+#[derive(Clone)]
 pub struct ProgressReporter {
     bar: ProgressBar,
-    verbose: bool,
+    verbosity: u8,
+    /// Print plain lines instead of drawing the bar
+    plain: bool,
+    /// Print regular messages to stderr, keeping stdout for data
+    stderr: bool,
+}
+
+/// Whether stderr can render a progress bar
+fn stderr_is_terminal() -> bool {
+    let term = Term::stderr();
+    term.is_term() && term.size_checked().is_some_and(|(_, width)| width > 0)
 }
 
 impl ProgressReporter {
-    /// Create a new progress reporter
-    pub fn new(verbose: bool) -> Self {
-        let bar = if verbose {
+    /// Create a new progress reporter at the given verbosity (0 is quiet)
+    pub fn new(verbosity: u8) -> Self {
+        Self::for_terminal(verbosity, stderr_is_terminal())
+    }
+
+    /// Create a reporter drawing a spinner only if `terminal` is set
+    fn for_terminal(verbosity: u8, terminal: bool) -> Self {
+        let verbose = verbosity > 0;
+        let bar = if verbose && terminal {
             let pb = ProgressBar::new_spinner();
             pb.set_style(
                 ProgressStyle::default_spinner().template("{spinner:.green} {msg}").unwrap(),
@@ -23,32 +55,262 @@ impl ProgressReporter {
             ProgressBar::hidden()
         };
 
-        Self { bar, verbose }
+        Self { bar, verbosity, plain: verbose && !terminal, stderr: false }
+    }
+
+    /// Send regular messages to stderr, for when stdout carries the output
+    pub fn with_stdout_reserved(self) -> Self {
+        Self { stderr: true, ..self }
+    }
+
+    /// Number of `-v` flags given
+    pub fn verbosity(&self) -> u8 {
+        self.verbosity
     }
 
     /// Set the current operation message
     pub fn set_message(&self, msg: impl Into<String>) {
-        if self.verbose {
+        if self.plain {
+            eprintln!("{}", self.plain_line(&msg.into()));
+        } else if self.verbosity > 0 {
             self.bar.set_message(msg.into());
             self.bar.tick();
         }
     }
 
+    /// `msg` followed by the percentage done, if the length is known
+    fn plain_line(&self, msg: &str) -> String {
+        match self.bar.length() {
+            Some(len) if len > 0 => {
+                let percent = (self.bar.position().min(len) * 100) / len;
+                format!("{} ({}%)", msg, percent)
+            }
+            _ => msg.to_string(),
+        }
+    }
+
+    /// Switch to a byte progress bar with a known total
+    pub fn set_length(&self, len: u64) {
+        if self.plain {
+            self.bar.set_length(len);
+        } else if self.verbosity > 0 {
+            self.bar.set_style(
+                ProgressStyle::default_bar()
+                    .template(
+                        "{spinner:.green} {msg} [{bar:30}] {bytes}/{total_bytes} ({percent}%)",
+                    )
+                    .unwrap(),
+            );
+            self.bar.set_length(len);
+        }
+    }
+
+    /// Wrap a reader so that bytes read through it advance the progress bar
+    ///
+    /// Quiet reporters return the reader untouched, so streaming loops do
+    /// no progress bookkeeping at all.
+    pub fn wrap_read<R: Read>(&self, reader: R) -> ProgressRead<R> {
+        if self.verbosity == 0 {
+            ProgressRead::Untracked(reader)
+        } else {
+            ProgressRead::Tracked(self.bar.wrap_read(reader))
+        }
+    }
+
     /// Mark operation as complete
     pub fn finish(&self, msg: impl Into<String>) {
-        if self.verbose {
+        if self.plain {
+            eprintln!("{}", msg.into());
+        } else if self.verbosity > 0 {
             self.bar.finish_with_message(msg.into());
         }
     }
 
     /// Print a message (always shown, not just in verbose mode)
+    ///
+    /// Visible bars are cleared while printing so the line is not mixed
+    /// into them. Goes to stdout unless stdout is reserved for the output.
+    pub fn println(&self, msg: impl AsRef<str>) {
+        if self.stderr {
+            self.eprintln(msg);
+        } else {
+            self.bar.suspend(|| println!("{}", msg.as_ref()));
+        }
+    }
+
+    /// Print a message to stderr (always shown, not just in verbose mode)
+    pub fn eprintln(&self, msg: impl AsRef<str>) {
+        self.bar.suspend(|| eprintln!("{}", msg.as_ref()));
+    }
+
+    /// Print a diagnostic to stderr if the verbosity is at least `level`
+    ///
+    /// Each line of `msg` is marked so diagnostics stand apart from the
+    /// regular output. Never pass secrets: diagnostics end up in logs.
+    pub fn debug(&self, level: u8, msg: impl AsRef<str>) {
+        if self.verbosity >= level {
+            self.bar.suspend(|| {
+                for line in msg.as_ref().lines() {
+                    eprintln!("🔍 {}", line);
+                }
+            });
+        }
+    }
+}
+
+/// Reader returned by [`ProgressReporter::wrap_read`]
+pub enum ProgressRead<R> {
+    /// Advances a visible or plain-line progress bar
+    Tracked(ProgressBarIter<R>),
+    /// Passed through for quiet reporters
+    Untracked(R),
+}
+
+impl<R: Read> Read for ProgressRead<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Self::Tracked(reader) => reader.read(buf),
+            Self::Untracked(reader) => reader.read(buf),
+        }
+    }
+}
+
+/// Progress reporting for several concurrent tasks
+///
+/// Each task gets its own [`ProgressReporter`], drawn together with the
+/// others by one `MultiProgress` so concurrent updates render cleanly.
+#[derive(Clone)]
+pub struct MultiProgressReporter {
+    multi: MultiProgress,
+    verbosity: u8,
+    terminal: bool,
+}
+
+impl MultiProgressReporter {
+    /// Create a reporter for concurrent tasks at the given verbosity
+    pub fn new(verbosity: u8) -> Self {
+        let terminal = stderr_is_terminal();
+        let multi = MultiProgress::new();
+        if verbosity == 0 || !terminal {
+            multi.set_draw_target(ProgressDrawTarget::hidden());
+        }
+
+        Self { multi, verbosity, terminal }
+    }
+
+    /// Reporter for one task, drawn below the existing ones
+    pub fn task(&self) -> ProgressReporter {
+        let task = ProgressReporter::for_terminal(self.verbosity, self.terminal);
+        ProgressReporter { bar: self.multi.add(task.bar), ..task }
+    }
+
+    /// Print a message above all task bars (always shown)
     pub fn println(&self, msg: impl AsRef<str>) {
-        println!("{}", msg.as_ref());
+        self.multi.suspend(|| println!("{}", msg.as_ref()));
+    }
+}
+
+/// Format a byte count with binary units, e.g. `1.2 GiB` or `362 MiB`
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: &[&str] = &["KiB", "MiB", "GiB", "TiB"];
+
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    if value < 100.0 {
+        format!("{:.1} {}", value, UNITS[unit])
+    } else {
+        format!("{:.0} {}", value, UNITS[unit])
     }
 }
 
+/// Describe how long processing `bytes` took, e.g.
+/// `Processed 1.2 GiB in 3.4s (362 MiB/s)`
+pub fn format_throughput(bytes: u64, elapsed: Duration) -> String {
+    // Avoid dividing by zero for tiny inputs
+    let secs = elapsed.as_secs_f64().max(1e-6);
+    let rate = (bytes as f64 / secs) as u64;
+
+    format!(
+        "Processed {} in {:.1}s ({}/s)",
+        format_bytes(bytes),
+        elapsed.as_secs_f64(),
+        format_bytes(rate)
+    )
+}
+
 impl Default for ProgressReporter {
     fn default() -> Self {
-        Self::new(false)
+        Self::new(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plain_fallback_without_terminal() {
+        let progress = ProgressReporter::for_terminal(1, false);
+        assert!(progress.bar.is_hidden());
+        assert!(progress.plain);
+
+        progress.set_length(200);
+        assert_eq!(progress.plain_line("Processing..."), "Processing... (0%)");
+        let mut reader = progress.wrap_read(&[0u8; 90][..]);
+        std::io::copy(&mut reader, &mut std::io::sink()).unwrap();
+        assert_eq!(progress.plain_line("Processing..."), "Processing... (45%)");
+
+        let quiet = ProgressReporter::for_terminal(0, false);
+        assert!(quiet.bar.is_hidden());
+        assert!(!quiet.plain);
+        assert!(matches!(quiet.wrap_read(&[0u8; 1][..]), ProgressRead::Untracked(_)));
+        assert!(!ProgressReporter::for_terminal(1, true).plain);
+    }
+
+    #[test]
+    fn test_multi_progress_tasks() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<ProgressReporter>();
+        assert_send_sync::<MultiProgressReporter>();
+
+        let multi = MultiProgressReporter::new(0);
+        std::thread::scope(|scope| {
+            for i in 0..4 {
+                let task = multi.task();
+                scope.spawn(move || {
+                    task.set_length(100);
+                    let mut reader = task.wrap_read(&[0u8; 100][..]);
+                    std::io::copy(&mut reader, &mut std::io::sink()).unwrap();
+                    task.finish(format!("task {} done", i));
+                });
+            }
+        });
+    }
+
+    #[test]
+    fn test_format_bytes() {
+        assert_eq!(format_bytes(0), "0 B");
+        assert_eq!(format_bytes(1023), "1023 B");
+        assert_eq!(format_bytes(1536), "1.5 KiB");
+        assert_eq!(format_bytes(362 * 1024 * 1024), "362 MiB");
+        assert_eq!(format_bytes(1288490189), "1.2 GiB");
+    }
+
+    #[test]
+    fn test_format_throughput() {
+        let line = format_throughput(1288490189, Duration::from_millis(3400));
+        assert_eq!(line, "Processed 1.2 GiB in 3.4s (361 MiB/s)");
+
+        let line = format_throughput(10, Duration::ZERO);
+        assert!(line.starts_with("Processed 10 B in 0.0s"));
     }
 }
@@ -0,0 +1,233 @@
+//! Optional `tracing` spans
+//!
+//! With the `tracing` feature, the command `execute` functions,
+//! [`Pipeline::encrypt_stream`](crate::pipeline::Pipeline::encrypt_stream),
+//! [`Pipeline::decrypt_stream`](crate::pipeline::Pipeline::decrypt_stream),
+//! [`derive_key_len`](crate::crypto::derive_key_len) and the archive
+//! builders each open a span. Byte counts and `elapsed_ms` are declared as
+//! empty fields and filled in by a [`SpanStats`] started at the top of the
+//! function, which records the time when dropped, so failed calls are
+//! timed too, or by [`record`] from deeper in the call. resqrypt never
+//! installs a subscriber; the application does.
+//!
+//! Without the feature, [`SpanStats`] is empty and every method compiles to
+//! nothing, so the instrumented code needs no `cfg` of its own.
+
+use std::io::Read;
+#[cfg(feature = "cli")]
+use std::path::Path;
+#[cfg(feature = "tracing")]
+use std::time::Instant;
+
+/// Fills in the byte count and `elapsed_ms` fields of the span current
+/// when it was started
+pub(crate) struct SpanStats {
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
+    #[cfg(feature = "tracing")]
+    started: Instant,
+}
+
+impl SpanStats {
+    /// Start timing the current span
+    pub(crate) fn start() -> Self {
+        Self {
+            #[cfg(feature = "tracing")]
+            span: tracing::Span::current(),
+            #[cfg(feature = "tracing")]
+            started: Instant::now(),
+        }
+    }
+
+    /// Record `value` in the field `field`
+    #[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+    pub(crate) fn record(&self, field: &'static str, value: u64) {
+        #[cfg(feature = "tracing")]
+        self.span.record(field, value);
+    }
+
+    /// Record the length of the file at `path`, if it is one, in `field`
+    #[cfg(feature = "cli")]
+    #[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+    pub(crate) fn record_file_len(&self, field: &'static str, path: &Path) {
+        #[cfg(feature = "tracing")]
+        if let Some(metadata) = std::fs::metadata(path).ok().filter(|metadata| metadata.is_file()) {
+            self.record(field, metadata.len());
+        }
+    }
+
+    /// Count the bytes read from `reader` into `field`, keeping the span
+    /// open and timing it until the reader is dropped
+    #[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+    pub(crate) fn reader<'a>(
+        self,
+        reader: Box<dyn Read + 'a>,
+        field: &'static str,
+    ) -> Box<dyn Read + 'a> {
+        #[cfg(feature = "tracing")]
+        return Box::new(SpanReader { inner: reader, count: 0, field, stats: self });
+        #[cfg(not(feature = "tracing"))]
+        reader
+    }
+}
+
+/// Record `value` in the field `field` of the current span
+#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+pub(crate) fn record(field: &'static str, value: u64) {
+    #[cfg(feature = "tracing")]
+    tracing::Span::current().record(field, value);
+}
+
+#[cfg(feature = "tracing")]
+impl Drop for SpanStats {
+    fn drop(&mut self) {
+        self.span.record("elapsed_ms", self.started.elapsed().as_millis() as u64);
+    }
+}
+
+/// Reader behind [`SpanStats::reader`]
+#[cfg(feature = "tracing")]
+struct SpanReader<'a> {
+    inner: Box<dyn Read + 'a>,
+    count: u64,
+    field: &'static str,
+    stats: SpanStats,
+}
+
+#[cfg(feature = "tracing")]
+impl Read for SpanReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.stats.span.in_scope(|| self.inner.read(buf))?;
+        self.count += n as u64;
+        Ok(n)
+    }
+}
+
+#[cfg(feature = "tracing")]
+impl Drop for SpanReader<'_> {
+    fn drop(&mut self) {
+        self.stats.record(self.field, self.count);
+    }
+}
+
+#[cfg(all(test, feature = "tracing"))]
+mod tests {
+    use super::*;
+    use std::io;
+    use std::sync::{Arc, Mutex};
+
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::{Event, Metadata, Subscriber};
+    use tracing_core::span::Current;
+
+    /// Subscriber keeping the `u64` fields recorded on any span
+    #[derive(Clone, Default)]
+    struct Recorded {
+        fields: Arc<Mutex<Vec<(String, u64)>>>,
+        spans: Arc<Mutex<Vec<&'static Metadata<'static>>>>,
+        entered: Arc<Mutex<Vec<Id>>>,
+    }
+
+    impl Visit for Recorded {
+        fn record_u64(&mut self, field: &Field, value: u64) {
+            self.fields.lock().unwrap().push((field.name().to_string(), value));
+        }
+
+        fn record_debug(&mut self, _: &Field, _: &dyn std::fmt::Debug) {}
+    }
+
+    impl Subscriber for Recorded {
+        fn enabled(&self, _: &Metadata<'_>) -> bool {
+            true
+        }
+        fn new_span(&self, span: &Attributes<'_>) -> Id {
+            span.record(&mut self.clone());
+            let mut spans = self.spans.lock().unwrap();
+            spans.push(span.metadata());
+            Id::from_u64(spans.len() as u64)
+        }
+        fn record(&self, _: &Id, values: &Record<'_>) {
+            values.record(&mut self.clone());
+        }
+        fn record_follows_from(&self, _: &Id, _: &Id) {}
+        fn event(&self, _: &Event<'_>) {}
+        fn enter(&self, span: &Id) {
+            self.entered.lock().unwrap().push(span.clone());
+        }
+        fn exit(&self, _: &Id) {
+            self.entered.lock().unwrap().pop();
+        }
+        fn current_span(&self) -> Current {
+            match self.entered.lock().unwrap().last() {
+                Some(id) => {
+                    let metadata = self.spans.lock().unwrap()[id.into_u64() as usize - 1];
+                    Current::new(id.clone(), metadata)
+                }
+                None => Current::none(),
+            }
+        }
+    }
+
+    impl Recorded {
+        fn field(&self, name: &str) -> Option<u64> {
+            let fields = self.fields.lock().unwrap();
+            fields.iter().rev().find(|(field, _)| field == name).map(|&(_, value)| value)
+        }
+    }
+
+    #[test]
+    fn test_span_stats_recorded() {
+        let recorded = Recorded::default();
+        tracing::subscriber::with_default(recorded.clone(), || {
+            let span = tracing::info_span!(
+                "decrypt",
+                bytes_out = tracing::field::Empty,
+                elapsed_ms = tracing::field::Empty
+            );
+            let _entered = span.enter();
+            let stats = SpanStats::start();
+            let mut reader = stats.reader(Box::new(&b"twelve bytes"[..]), "bytes_out");
+            io::copy(&mut reader, &mut io::sink()).unwrap();
+            assert_eq!(recorded.field("bytes_out"), None);
+            drop(reader);
+        });
+
+        assert_eq!(recorded.field("bytes_out"), Some(12));
+        assert!(recorded.field("elapsed_ms").is_some());
+    }
+
+    #[test]
+    fn test_key_derivation_span() {
+        let recorded = Recorded::default();
+        let params = crate::crypto::KdfParams { memory_cost: 1024, time_cost: 1, parallelism: 1 };
+        tracing::subscriber::with_default(recorded.clone(), || {
+            crate::crypto::derive_key_len(b"pw", &[0u8; 32], &params, 32).unwrap();
+        });
+
+        assert_eq!(recorded.field("memory_kib"), Some(1024));
+        assert!(recorded.field("elapsed_ms").is_some());
+    }
+
+    #[test]
+    fn test_pipeline_spans() {
+        use crate::crypto::{HeaderFlags, KdfParams};
+        use crate::pipeline::Pipeline;
+
+        let recorded = Recorded::default();
+        let pipeline = Pipeline::new()
+            .kdf_params(KdfParams { memory_cost: 1024, time_cost: 1, parallelism: 1 })
+            .chunk_size(1024);
+        let data = vec![7u8; 5000];
+        tracing::subscriber::with_default(recorded.clone(), || {
+            let mut file = Vec::new();
+            pipeline.encrypt_stream(&data[..], HeaderFlags::empty(), b"pw", &mut file).unwrap();
+            assert_eq!(recorded.field("original_len"), Some(5000));
+
+            let (_, mut reader) = pipeline.decrypt_stream(file.as_slice(), b"pw").unwrap();
+            io::copy(&mut reader, &mut io::sink()).unwrap();
+        });
+
+        assert_eq!(recorded.field("bytes_out"), Some(5000));
+    }
+}
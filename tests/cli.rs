@@ -0,0 +1,1183 @@
+//! End-to-end tests driving the built binary
+//!
+//! Each test runs `resqrypt` in a scratch directory with small Argon2id
+//! parameters and an isolated config location.
+
+#![cfg(feature = "cli")]
+
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use assert_cmd::Command;
+use predicates::prelude::*;
+use tempfile::TempDir;
+
+/// `resqrypt` with no inherited password or config
+fn resqrypt(dir: &Path) -> Command {
+    let mut cmd = assert_cmd::cargo::cargo_bin_cmd!("resqrypt");
+    cmd.current_dir(dir)
+        .env_remove("RESQRYPT_PASSWORD")
+        .env_remove("SOURCE_DATE_EPOCH")
+        .env("XDG_CONFIG_HOME", dir.join("config"))
+        .env("HOME", dir);
+    cmd
+}
+
+fn encrypt(dir: &Path, input: &str, output: &str) -> assert_cmd::assert::Assert {
+    resqrypt(dir)
+        .args(["encrypt", "-i", input, "-o", output, "-p", "correct horse"])
+        .args(["--argon2-memory", "8", "--argon2-parallelism", "1"])
+        .assert()
+}
+
+#[test]
+fn test_file_roundtrip() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    let data: Vec<u8> = (0..200_000u32).map(|i| (i * 7 % 251) as u8).collect();
+    fs::write(dir.join("data.bin"), &data).unwrap();
+
+    encrypt(dir, "data.bin", "data.bin.resqrypt")
+        .success()
+        .stdout(predicate::str::contains("Encrypted"));
+
+    resqrypt(dir)
+        .args(["decrypt", "-i", "data.bin.resqrypt", "-o", "restored.bin", "-p", "correct horse"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("memory 8 MiB, below the recommended 19 MiB"));
+
+    assert_eq!(fs::read(dir.join("restored.bin")).unwrap(), data);
+}
+
+#[test]
+fn test_directory_roundtrip() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    fs::create_dir_all(dir.join("project/src")).unwrap();
+    fs::write(dir.join("project/README"), "top").unwrap();
+    fs::write(dir.join("project/src/main.rs"), "fn main() {}").unwrap();
+
+    encrypt(dir, "project", "project.resqrypt").success();
+
+    resqrypt(dir)
+        .args(["decrypt", "-i", "project.resqrypt", "-o", "out", "-p", "correct horse"])
+        .assert()
+        .success();
+
+    assert_eq!(fs::read_to_string(dir.join("out/project/README")).unwrap(), "top");
+    assert_eq!(fs::read_to_string(dir.join("out/project/src/main.rs")).unwrap(), "fn main() {}");
+}
+
+#[test]
+fn test_wrong_password() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    fs::write(dir.join("secret.txt"), "secret").unwrap();
+    encrypt(dir, "secret.txt", "secret.txt.resqrypt").success();
+
+    resqrypt(dir)
+        .args(["decrypt", "-i", "secret.txt.resqrypt", "-o", "out.txt", "-p", "wrong"])
+        .assert()
+        .code(1)
+        .stderr(predicate::str::contains("Password error"));
+    assert!(!dir.join("out.txt").exists());
+
+    resqrypt(dir)
+        .args(["decrypt", "-i", "secret.txt.resqrypt", "--test-password", "-p", "wrong"])
+        .assert()
+        .code(2);
+    resqrypt(dir)
+        .args(["decrypt", "-i", "secret.txt.resqrypt", "--test-password", "-p", "correct horse"])
+        .assert()
+        .success();
+}
+
+#[test]
+fn test_test_password_exit_codes() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    fs::write(dir.join("secret.txt"), "secret").unwrap();
+    fs::write(dir.join("pepper"), "site-wide secret").unwrap();
+    resqrypt(dir)
+        .args(["encrypt", "-i", "secret.txt", "-o", "secret.resqrypt", "-p", "correct horse"])
+        .args(["--pepper-file", "pepper", "--argon2-memory", "8", "--argon2-parallelism", "1"])
+        .assert()
+        .success();
+
+    let test_password = |password: &str| {
+        let mut cmd = resqrypt(dir);
+        cmd.args(["decrypt", "-i", "secret.resqrypt", "--test-password", "-p", password]);
+        cmd
+    };
+    test_password("correct horse").args(["--pepper-file", "pepper"]).assert().code(0);
+    test_password("wrong")
+        .args(["--pepper-file", "pepper"])
+        .assert()
+        .code(2)
+        .stderr(predicate::str::contains("Password is incorrect"));
+
+    // Failures other than a wrong password exit with 1
+    test_password("correct horse")
+        .assert()
+        .code(1)
+        .stderr(predicate::str::contains("this file requires a pepper"));
+
+    #[cfg(unix)]
+    assert_cmd::Command::new("sh")
+        .current_dir(dir)
+        .env_remove("RESQRYPT_PASSWORD")
+        .args([
+            "-c",
+            "exec \"$0\" decrypt -i secret.resqrypt --test-password --password-fd 3 3</dev/null",
+        ])
+        .arg(assert_cmd::cargo::cargo_bin!("resqrypt"))
+        .assert()
+        .code(1)
+        .stderr(predicate::str::contains("Password cannot be empty"));
+}
+
+#[test]
+fn test_existing_output_is_not_overwritten() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    fs::write(dir.join("a.txt"), "new").unwrap();
+    fs::write(dir.join("a.txt.resqrypt"), "keep me").unwrap();
+
+    encrypt(dir, "a.txt", "a.txt.resqrypt")
+        .code(1)
+        .stderr(predicate::str::contains("already exists"));
+    assert_eq!(fs::read_to_string(dir.join("a.txt.resqrypt")).unwrap(), "keep me");
+
+    // Nothing is left behind either
+    let entries = fs::read_dir(dir).unwrap().count();
+    assert_eq!(entries, 2);
+}
+
+#[test]
+fn test_decrypt_to_stdout() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    fs::write(dir.join("note.txt"), "plain words").unwrap();
+    fs::create_dir(dir.join("project")).unwrap();
+    fs::write(dir.join("project/README"), "top").unwrap();
+    encrypt(dir, "note.txt", "note.txt.resqrypt").success();
+    encrypt(dir, "project", "project.resqrypt").success();
+
+    resqrypt(dir)
+        .args(["decrypt", "-i", "note.txt.resqrypt", "-o", "-", "-p", "correct horse"])
+        .assert()
+        .success()
+        .stdout("plain words");
+
+    // An archive needs an explicit opt-in
+    resqrypt(dir)
+        .args(["decrypt", "-i", "project.resqrypt", "-o", "-", "-p", "correct horse"])
+        .assert()
+        .code(1)
+        .stdout("")
+        .stderr(predicate::str::contains("--allow-archive-stdout"));
+
+    resqrypt(dir)
+        .args(["decrypt", "-i", "project.resqrypt", "-o", "-", "-p", "correct horse"])
+        .arg("--allow-archive-stdout")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("project/README"));
+}
+
+#[test]
+fn test_directory_no_compress_roundtrip() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    fs::create_dir_all(dir.join("media/album")).unwrap();
+    let mut state = 7u32;
+    let mut noise = |len: usize| -> Vec<u8> {
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (state >> 16) as u8
+            })
+            .collect()
+    };
+    let (first, second) = (noise(150_000), noise(40_000));
+    fs::write(dir.join("media/a.jpg"), &first).unwrap();
+    fs::write(dir.join("media/album/b.jpg"), &second).unwrap();
+
+    resqrypt(dir)
+        .args(["encrypt", "-i", "media", "-o", "media.resqrypt", "-p", "correct horse"])
+        .args(["--argon2-memory", "8", "--argon2-parallelism", "1", "--no-compress"])
+        .assert()
+        .success();
+    let info = resqrypt::inspect(dir.join("media.resqrypt")).unwrap();
+    assert!(info.is_directory && info.uncompressed);
+
+    resqrypt(dir)
+        .args(["decrypt", "-i", "media.resqrypt", "-o", "out", "-p", "correct horse"])
+        .assert()
+        .success();
+
+    assert_eq!(fs::read(dir.join("out/media/a.jpg")).unwrap(), first);
+    assert_eq!(fs::read(dir.join("out/media/album/b.jpg")).unwrap(), second);
+}
+
+#[test]
+fn test_empty_directory_roundtrip() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    fs::create_dir(dir.join("empty")).unwrap();
+
+    encrypt(dir, "empty", "empty.resqrypt").success();
+    assert!(resqrypt::inspect(dir.join("empty.resqrypt")).unwrap().is_directory);
+
+    resqrypt(dir)
+        .args(["decrypt", "-i", "empty.resqrypt", "-o", "out", "-p", "correct horse"])
+        .assert()
+        .success();
+
+    let restored = dir.join("out/empty");
+    assert!(restored.is_dir());
+    assert_eq!(fs::read_dir(restored).unwrap().count(), 0);
+}
+
+#[test]
+fn test_enforced_policy() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    fs::write(dir.join("data.txt"), "policy bound").unwrap();
+    fs::write(
+        dir.join("policy.toml"),
+        "argon2_memory = 8\nargon2_iterations = 2\nenforce = true\n",
+    )
+    .unwrap();
+    let encrypt = |output: &str, extra: &[&str]| {
+        resqrypt(dir)
+            .args(["encrypt", "-i", "data.txt", "-o", output, "-p", "correct horse"])
+            .args(["--argon2-parallelism", "1", "--kdf-params-from", "policy.toml"])
+            .args(extra)
+            .assert()
+    };
+
+    // Below the minimum: refused before anything is written
+    encrypt("weak.resqrypt", &["--argon2-iterations", "1"])
+        .failure()
+        .stderr(predicate::str::contains("below the minimum of 2"));
+    assert!(!dir.join("weak.resqrypt").exists());
+
+    // Unset parameters come from the policy
+    encrypt("data.resqrypt", &[]).success();
+    let info = resqrypt::inspect(dir.join("data.resqrypt")).unwrap();
+    assert_eq!(info.kdf_params.memory_mib(), 8);
+    assert_eq!(info.kdf_params.time_cost, 2);
+
+    // An update keeps the file's parameters, so they are checked too
+    fs::create_dir(dir.join("tree")).unwrap();
+    fs::write(dir.join("tree/data.txt"), "policy bound").unwrap();
+    let update = |extra: &[&str]| {
+        resqrypt(dir)
+            .args(["encrypt", "-i", "tree", "-o", "tree.resqrypt", "-p", "correct horse"])
+            .args(["--update", "--kdf-params-from", "policy.toml"])
+            .args(extra)
+            .assert()
+    };
+    resqrypt(dir)
+        .args(["encrypt", "-i", "tree", "-o", "tree.resqrypt", "-p", "correct horse"])
+        .args(["--argon2-parallelism", "1", "--kdf-params-from", "policy.toml"])
+        .assert()
+        .success();
+    update(&[]).success();
+    fs::write(
+        dir.join("policy.toml"),
+        "argon2_memory = 8\nargon2_iterations = 3\nenforce = true\n",
+    )
+    .unwrap();
+    update(&[])
+        .failure()
+        .stderr(predicate::str::contains("cannot be updated in place"))
+        .stderr(predicate::str::contains("below the minimum of 3"));
+    update(&["--argon2-iterations", "3"])
+        .failure()
+        .stderr(predicate::str::contains("cannot be used with"));
+}
+
+#[test]
+fn test_decrypt_embedded() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    fs::write(dir.join("secret.txt"), "hidden in plain sight").unwrap();
+    encrypt(dir, "secret.txt", "secret.resqrypt").success();
+
+    // Wrap the encrypted file in a larger container
+    let prefix = vec![0x42u8; 70_001];
+    let mut container = prefix.clone();
+    container.extend(fs::read(dir.join("secret.resqrypt")).unwrap());
+    container.extend_from_slice(b"container trailer");
+    fs::write(dir.join("image.bin"), container).unwrap();
+
+    let decrypt = |output: &str, extra: &[&str]| {
+        resqrypt(dir)
+            .args(["decrypt", "-i", "image.bin", "-o", output, "-p", "correct horse"])
+            .args(extra)
+            .assert()
+    };
+    let skip = prefix.len().to_string();
+    decrypt("skipped.txt", &["--skip-bytes", &skip]).success();
+    decrypt("scanned.txt", &["--scan", "-v"])
+        .success()
+        .stdout(predicate::str::contains("starts at byte 70001"));
+    for output in ["skipped.txt", "scanned.txt"] {
+        assert_eq!(fs::read_to_string(dir.join(output)).unwrap(), "hidden in plain sight");
+    }
+
+    // Without either flag the container is not recognized
+    decrypt("plain.txt", &[]).failure();
+    decrypt("past.txt", &["--skip-bytes", "999999"])
+        .failure()
+        .stderr(predicate::str::contains("past the end"));
+}
+
+#[test]
+fn test_encrypt_progress_has_length() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    fs::write(dir.join("data.txt"), "tracked ".repeat(1000)).unwrap();
+
+    // Without a terminal, progress is printed as plain lines with the
+    // percentage of the input consumed
+    resqrypt(dir)
+        .args(["encrypt", "-i", "data.txt", "-o", "data.resqrypt", "-v", "-p", "correct horse"])
+        .args(["--argon2-memory", "8", "--argon2-parallelism", "1"])
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("Compressing and encrypting... (0%)"));
+}
+
+#[test]
+fn test_pepper_file() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    fs::write(dir.join("secret.txt"), "peppered").unwrap();
+    fs::write(dir.join("pepper"), "site-wide secret").unwrap();
+    resqrypt(dir)
+        .args(["encrypt", "-i", "secret.txt", "-o", "secret.resqrypt", "-p", "correct horse"])
+        .args(["--pepper-file", "pepper", "--argon2-memory", "8", "--argon2-parallelism", "1"])
+        .assert()
+        .success();
+    assert!(resqrypt::inspect(dir.join("secret.resqrypt")).unwrap().peppered);
+
+    let decrypt = |output: &str| {
+        let mut cmd = resqrypt(dir);
+        cmd.args(["decrypt", "-i", "secret.resqrypt", "-o", output, "-p", "correct horse"]);
+        cmd
+    };
+
+    // Told apart from a wrong password
+    decrypt("missing.txt")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("this file requires a pepper"));
+    fs::write(dir.join("other"), "another secret").unwrap();
+    decrypt("wrong.txt").args(["--pepper-file", "other"]).assert().failure();
+    assert!(!dir.join("wrong.txt").exists());
+
+    decrypt("restored.txt").args(["--pepper-file", "pepper"]).assert().success();
+    assert_eq!(fs::read_to_string(dir.join("restored.txt")).unwrap(), "peppered");
+}
+
+#[test]
+fn test_memory_budget() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    fs::write(dir.join("data.txt"), "bounded").unwrap();
+    let encrypt_within = |budget: &str| {
+        resqrypt(dir)
+            .args(["encrypt", "-i", "data.txt", "-o", "data.resqrypt", "-p", "correct horse"])
+            .args(["--argon2-memory", "8", "--argon2-parallelism", "1", "--memory-budget", budget])
+            .assert()
+    };
+
+    // 8 MiB of Argon2id memory alone exceeds 4 MiB
+    encrypt_within("4M")
+        .failure()
+        .stderr(predicate::str::contains("exceeds the budget of 4.0 MiB"));
+    assert!(!dir.join("data.resqrypt").exists());
+    encrypt_within("64M").success();
+
+    resqrypt(dir)
+        .args(["decrypt", "-i", "data.resqrypt", "-o", "out.txt", "-p", "correct horse"])
+        .args(["--memory-budget", "8M"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("raise --memory-budget"));
+}
+
+#[test]
+fn test_hostile_memory_cost() {
+    use resqrypt::crypto::format::{read_header, write_header};
+
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    fs::write(dir.join("data.txt"), "bait").unwrap();
+    encrypt(dir, "data.txt", "data.resqrypt").success();
+
+    // A header asking Argon2id for 4 TiB
+    let file = fs::read(dir.join("data.resqrypt")).unwrap();
+    let mut rest = file.as_slice();
+    let mut header = read_header(&mut rest).unwrap();
+    header.kdf_params.memory_cost = u32::MAX;
+    let mut hostile = Vec::new();
+    write_header(&mut hostile, &header).unwrap();
+    hostile.extend_from_slice(rest);
+    fs::write(dir.join("hostile.resqrypt"), hostile).unwrap();
+
+    // Every command refuses it before deriving a key
+    let password = ["-p", "correct horse"];
+    for args in [
+        &["decrypt", "-i", "hostile.resqrypt", "-o", "out.txt"][..],
+        &["decrypt", "-i", "hostile.resqrypt", "--test-password"],
+        &["verify", "-i", "hostile.resqrypt"],
+        &["recover", "-i", "hostile.resqrypt", "-o", "recovered.bin"],
+        &["migrate", "-i", "hostile.resqrypt", "-o", "migrated.resqrypt"],
+    ] {
+        resqrypt(dir)
+            .args(args)
+            .args(password)
+            .assert()
+            .code(1)
+            .stderr(predicate::str::contains("exceeds the limit of 2048 MiB"));
+    }
+    assert!(!dir.join("out.txt").exists());
+}
+
+#[test]
+fn test_decrypt_discard_output() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    fs::create_dir_all(dir.join("tree/sub")).unwrap();
+    fs::write(dir.join("tree/a.txt"), "alpha").unwrap();
+    fs::write(dir.join("tree/sub/b.txt"), "beta").unwrap();
+    encrypt(dir, "tree", "tree.resqrypt").success();
+
+    resqrypt(dir)
+        .args(["decrypt", "-i", "tree.resqrypt", "--discard-output", "-p", "correct horse"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("nothing (discarded)"))
+        .stdout(predicate::str::contains("Read 4 archive entries"));
+    assert_eq!(fs::read_dir(dir).unwrap().count(), 2);
+}
+
+#[test]
+fn test_decrypt_strip_components() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    fs::create_dir_all(dir.join("tree/sub")).unwrap();
+    fs::write(dir.join("tree/top.txt"), "top").unwrap();
+    fs::write(dir.join("tree/sub/deep.txt"), "deep").unwrap();
+    encrypt(dir, "tree", "tree.resqrypt").success();
+    let decrypt = |output: &str, strip: &str| {
+        resqrypt(dir)
+            .args(["decrypt", "-i", "tree.resqrypt", "-o", output, "-p", "correct horse"])
+            .args(["--strip-components", strip])
+            .assert()
+    };
+
+    // The archive root is dropped without a warning
+    decrypt("one", "1").success().stdout(predicate::str::contains("skipped").not());
+    assert_eq!(fs::read_to_string(dir.join("one/top.txt")).unwrap(), "top");
+    assert_eq!(fs::read_to_string(dir.join("one/sub/deep.txt")).unwrap(), "deep");
+
+    // Files too shallow to strip are skipped with a warning
+    decrypt("two", "2")
+        .success()
+        .stdout(predicate::str::contains("skipped tree/top.txt"))
+        .stdout(predicate::str::contains("skipped tree/sub").not());
+    assert_eq!(fs::read_to_string(dir.join("two/deep.txt")).unwrap(), "deep");
+    assert!(!dir.join("two/top.txt").exists());
+}
+
+#[test]
+fn test_no_archive() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    fs::create_dir_all(dir.join("single")).unwrap();
+    fs::write(dir.join("single/report.txt"), "only one").unwrap();
+    let encrypt_no_archive = |input: &str, output: &str| {
+        resqrypt(dir)
+            .args(["encrypt", "-i", input, "-o", output, "--no-archive", "-p", "correct horse"])
+            .args(["--argon2-memory", "8", "--argon2-parallelism", "1"])
+            .assert()
+    };
+
+    encrypt_no_archive("single", "single.resqrypt").success();
+    let info = resqrypt::inspect(dir.join("single.resqrypt")).unwrap();
+    assert!(!info.is_directory);
+    assert_eq!(info.stored_name.as_deref(), Some("single/report.txt"));
+
+    // The stored name recreates the directory and file, as extraction would
+    resqrypt(dir)
+        .args(["decrypt", "-i", "single.resqrypt", "-o", "restored", "-p", "correct horse"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("restored/single/report.txt"));
+    assert_eq!(fs::read_to_string(dir.join("restored/single/report.txt")).unwrap(), "only one");
+
+    // Streamed to stdout, it is just the file's contents
+    resqrypt(dir)
+        .args(["decrypt", "-i", "single.resqrypt", "-o", "-", "-p", "correct horse"])
+        .assert()
+        .success()
+        .stdout("only one");
+
+    // Anything else is archived as usual
+    fs::write(dir.join("single/second.txt"), "two").unwrap();
+    encrypt_no_archive("single", "both.resqrypt")
+        .success()
+        .stdout(predicate::str::contains("archiving it"));
+    assert!(resqrypt::inspect(dir.join("both.resqrypt")).unwrap().is_directory);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_encrypt_each_manifest() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    fs::create_dir_all(dir.join("docs/sub")).unwrap();
+    fs::write(dir.join("docs/a.txt"), "alpha").unwrap();
+    fs::write(dir.join("docs/sub/b.txt"), "beta").unwrap();
+    let status = std::process::Command::new("mkfifo").arg(dir.join("docs/pipe")).status().unwrap();
+    assert!(status.success());
+    fs::write(dir.join("list"), "a.txt\npipe\nsub/b.txt\n").unwrap();
+
+    // The FIFO fails on its own; the files around it are still encrypted
+    resqrypt(dir)
+        .args(["encrypt", "-i", "docs", "--files-from", "list", "--each", "--output-dir", "vault"])
+        .args(["--manifest", "manifest.json", "-p", "correct horse"])
+        .args(["--argon2-memory", "8", "--argon2-parallelism", "1"])
+        .assert()
+        .code(1)
+        .stdout(predicate::str::contains("cannot encrypt special file"))
+        .stderr(predicate::str::contains("1 of 3 files failed"));
+
+    resqrypt(dir)
+        .args(["decrypt", "-i", "vault/sub/b.txt.resqrypt", "-o", "b.txt", "-p", "correct horse"])
+        .assert()
+        .success();
+    assert_eq!(fs::read_to_string(dir.join("b.txt")).unwrap(), "beta");
+    assert!(dir.join("vault/a.txt.resqrypt").exists());
+    assert!(!dir.join("vault/pipe.resqrypt").exists());
+
+    let manifest: serde_json::Value =
+        serde_json::from_slice(&fs::read(dir.join("manifest.json")).unwrap()).unwrap();
+    let entries = manifest.as_array().unwrap();
+    let statuses: Vec<_> = entries.iter().map(|e| e["status"].as_str().unwrap()).collect();
+    assert_eq!(statuses, ["ok", "failed", "ok"]);
+    assert_eq!(entries[0]["output"], "vault/a.txt.resqrypt");
+    assert_eq!(entries[0]["size"], 5);
+    assert!(entries[1]["error"].as_str().unwrap().contains("special file"));
+    assert_eq!(entries[2]["output"], "vault/sub/b.txt.resqrypt");
+}
+
+#[test]
+fn test_encrypt_update() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    fs::create_dir_all(dir.join("backup")).unwrap();
+    fs::write(dir.join("backup/old.txt"), "old").unwrap();
+    fs::write(dir.join("backup/edited.txt"), "v1").unwrap();
+    encrypt(dir, "backup", "backup.resqrypt").success();
+
+    fs::write(dir.join("backup/edited.txt"), "version 2").unwrap();
+    fs::write(dir.join("backup/new.txt"), "new").unwrap();
+    fs::remove_file(dir.join("backup/old.txt")).unwrap();
+    resqrypt(dir)
+        .args(["encrypt", "-i", "backup", "-o", "backup.resqrypt", "--update"])
+        .args(["-p", "correct horse"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Added 1 entries, updated 1 files"))
+        .stdout(predicate::str::contains("+ backup/new.txt"))
+        .stdout(predicate::str::contains("~ backup/edited.txt"));
+
+    resqrypt(dir)
+        .args(["decrypt", "-i", "backup.resqrypt", "-o", "restored", "-p", "correct horse"])
+        .assert()
+        .success();
+    let read = |name: &str| fs::read_to_string(dir.join("restored/backup").join(name)).unwrap();
+    assert_eq!(read("old.txt"), "old");
+    assert_eq!(read("edited.txt"), "version 2");
+    assert_eq!(read("new.txt"), "new");
+
+    // A wrong password leaves the archive untouched
+    let before = fs::read(dir.join("backup.resqrypt")).unwrap();
+    resqrypt(dir)
+        .args(["encrypt", "-i", "backup", "-o", "backup.resqrypt", "--update", "-p", "wrong"])
+        .assert()
+        .failure();
+    assert_eq!(fs::read(dir.join("backup.resqrypt")).unwrap(), before);
+
+    // Single-file payloads cannot be updated
+    fs::write(dir.join("plain.txt"), "plain").unwrap();
+    encrypt(dir, "plain.txt", "plain.resqrypt").success();
+    resqrypt(dir)
+        .args([
+            "encrypt",
+            "-i",
+            "backup",
+            "-o",
+            "plain.resqrypt",
+            "--update",
+            "-p",
+            "correct horse",
+        ])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("does not hold an encrypted directory"));
+}
+
+#[test]
+fn test_content_defined_chunks() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    fs::create_dir_all(dir.join("backup")).unwrap();
+    let data: Vec<u8> =
+        (0..300_000u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 11) as u8).collect();
+    fs::write(dir.join("backup/data.bin"), &data).unwrap();
+    resqrypt(dir)
+        .args(["encrypt", "-i", "backup", "-o", "backup.resqrypt", "--cdc", "--no-compress"])
+        .args(["-p", "correct horse", "--argon2-memory", "8", "--argon2-parallelism", "1"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("--no-compress").not());
+    assert!(resqrypt::inspect(dir.join("backup.resqrypt")).unwrap().content_defined);
+    let first = fs::read(dir.join("backup.resqrypt")).unwrap();
+
+    // An update keeps the salt, so unchanged chunks keep their bytes
+    fs::write(dir.join("backup/notes.txt"), "added later").unwrap();
+    resqrypt(dir)
+        .args(["encrypt", "-i", "backup", "-o", "backup.resqrypt", "--update"])
+        .args(["-p", "correct horse"])
+        .assert()
+        .success();
+    let second = fs::read(dir.join("backup.resqrypt")).unwrap();
+    let salt = 23..55;
+    assert_eq!(first[salt.clone()], second[salt]);
+    let needle = &first[first.len() / 2..first.len() / 2 + 256];
+    assert!(second.windows(needle.len()).any(|window| window == needle));
+
+    resqrypt(dir)
+        .args(["decrypt", "-i", "backup.resqrypt", "-o", "restored", "-p", "correct horse"])
+        .assert()
+        .success();
+    assert_eq!(fs::read(dir.join("restored/backup/data.bin")).unwrap(), data);
+    assert_eq!(fs::read_to_string(dir.join("restored/backup/notes.txt")).unwrap(), "added later");
+
+    // Compression would spoil deduplication
+    fs::write(dir.join("plain.txt"), "text").unwrap();
+    resqrypt(dir)
+        .args(["encrypt", "-i", "plain.txt", "-o", "plain.resqrypt", "--cdc"])
+        .args(["-p", "correct horse", "--argon2-memory", "8", "--argon2-parallelism", "1"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("without --no-compress"));
+}
+
+#[test]
+fn test_content_defined_splice_extracts_nothing() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    fs::create_dir_all(dir.join("backup")).unwrap();
+    let data: Vec<u8> =
+        (0..300_000u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 11) as u8).collect();
+    fs::write(dir.join("backup/a.txt"), "version 1 ".repeat(100)).unwrap();
+    fs::write(dir.join("backup/data.bin"), &data).unwrap();
+    resqrypt(dir)
+        .args(["encrypt", "-i", "backup", "-o", "v1.resqrypt", "--cdc", "--no-compress"])
+        .args(["-p", "correct horse", "--argon2-memory", "8", "--argon2-parallelism", "1"])
+        .assert()
+        .success();
+    fs::copy(dir.join("v1.resqrypt"), dir.join("v2.resqrypt")).unwrap();
+    fs::write(dir.join("backup/a.txt"), "version 2 ".repeat(100)).unwrap();
+    // Same size, so only a newer mtime tells --update it changed
+    let later = SystemTime::now() + Duration::from_secs(60);
+    let file = fs::File::options().write(true).open(dir.join("backup/a.txt")).unwrap();
+    file.set_modified(later).unwrap();
+    resqrypt(dir)
+        .args(["encrypt", "-i", "backup", "-o", "v2.resqrypt", "--update"])
+        .args(["-p", "correct horse"])
+        .assert()
+        .success();
+
+    // Raw chunks: ciphertext length (top bit marks the final one), nonce,
+    // ciphertext
+    let chunks = |name: &str| {
+        let file = fs::read(dir.join(name)).unwrap();
+        let info = resqrypt::inspect(dir.join(name)).unwrap();
+        let mut pos = (file.len() as u64 - info.ciphertext_len - info.trailing_bytes) as usize;
+        let header = file[..pos].to_vec();
+        let mut chunks = Vec::new();
+        loop {
+            let prefix = u32::from_le_bytes(file[pos..pos + 4].try_into().unwrap());
+            let end = pos + 4 + 12 + (prefix & 0x7fff_ffff) as usize;
+            chunks.push(file[pos..end].to_vec());
+            pos = end;
+            if prefix & 0x8000_0000 != 0 {
+                return (header, chunks, file[pos..].to_vec());
+            }
+        }
+    };
+
+    // The first chunk holds the edited file; splice in the old version's
+    let (_, old, _) = chunks("v1.resqrypt");
+    let (header, mut new, trailer) = chunks("v2.resqrypt");
+    assert_ne!(old[0], new[0]);
+    assert_eq!(old[1..old.len() - 1], new[1..new.len() - 1]);
+    new[0] = old[0].clone();
+    fs::write(dir.join("spliced.resqrypt"), [header, new.concat(), trailer].concat()).unwrap();
+
+    // The final chunk catches it, and nothing of the old version is left
+    resqrypt(dir)
+        .args(["decrypt", "-i", "spliced.resqrypt", "-o", "restored", "-p", "correct horse"])
+        .assert()
+        .failure();
+    assert!(!dir.join("restored").exists());
+    let leftovers = fs::read_dir(dir)
+        .unwrap()
+        .filter(|entry| entry.as_ref().unwrap().file_name().to_string_lossy().starts_with('.'))
+        .count();
+    assert_eq!(leftovers, 0);
+
+    resqrypt(dir)
+        .args(["decrypt", "-i", "v2.resqrypt", "-o", "restored", "-p", "correct horse"])
+        .assert()
+        .success();
+    assert_eq!(
+        fs::read_to_string(dir.join("restored/backup/a.txt")).unwrap(),
+        "version 2 ".repeat(100)
+    );
+    assert_eq!(fs::read(dir.join("restored/backup/data.bin")).unwrap(), data);
+}
+
+#[test]
+fn test_deterministic_seed() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    fs::write(dir.join("fixture.txt"), "reproducible").unwrap();
+    let encrypt_seeded = |output: &str, seed: Option<&str>| {
+        let mut cmd = resqrypt(dir);
+        cmd.env_remove("RESQRYPT_DETERMINISTIC_SEED");
+        if let Some(seed) = seed {
+            cmd.env("RESQRYPT_DETERMINISTIC_SEED", seed);
+        }
+        cmd.args(["encrypt", "-i", "fixture.txt", "-o", output, "-p", "correct horse"])
+            .args(["--argon2-memory", "8", "--argon2-parallelism", "1"])
+            .assert()
+            .success()
+    };
+
+    encrypt_seeded("a.resqrypt", Some("ci")).stderr(predicate::str::contains("INSECURE"));
+    encrypt_seeded("b.resqrypt", Some("ci"));
+    encrypt_seeded("c.resqrypt", None).stderr(predicate::str::contains("INSECURE").not());
+    encrypt_seeded("d.resqrypt", None);
+    let read = |name: &str| fs::read(dir.join(name)).unwrap();
+    assert_eq!(read("a.resqrypt"), read("b.resqrypt"));
+    assert_ne!(read("c.resqrypt"), read("d.resqrypt"));
+
+    resqrypt(dir)
+        .args(["decrypt", "-i", "a.resqrypt", "-o", "out.txt", "-p", "correct horse"])
+        .assert()
+        .success();
+    assert_eq!(fs::read_to_string(dir.join("out.txt")).unwrap(), "reproducible");
+}
+
+#[test]
+fn test_archive_size_limits() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    fs::create_dir_all(dir.join("many")).unwrap();
+    for name in ["a.txt", "b.txt", "c.txt"] {
+        fs::write(dir.join("many").join(name), "0123456789").unwrap();
+    }
+    let encrypt_limited = |output: &str, limit: &[&str]| {
+        resqrypt(dir)
+            .args(["encrypt", "-i", "many", "-o", output, "-p", "correct horse"])
+            .args(["--argon2-memory", "8", "--argon2-parallelism", "1"])
+            .args(limit)
+            .assert()
+    };
+
+    // Without a terminal to ask on, going over a limit needs --yes
+    encrypt_limited("files.resqrypt", &["--max-files", "2"])
+        .failure()
+        .stderr(predicate::str::contains("more than 2 entries"))
+        .stderr(predicate::str::contains("--yes"));
+    encrypt_limited("size.resqrypt", &["--max-size", "16"])
+        .failure()
+        .stderr(predicate::str::contains("more than 16 B"));
+    assert!(!dir.join("files.resqrypt").exists());
+
+    encrypt_limited("files.resqrypt", &["--max-files", "2", "--yes"]).success();
+    encrypt_limited("roomy.resqrypt", &["--max-files", "4", "--max-size", "1K"]).success();
+
+    // The limits can come from the config file too
+    fs::write(dir.join("config.toml"), "max_files = 2\n").unwrap();
+    resqrypt(dir)
+        .args(["--config", "config.toml", "encrypt", "-i", "many", "-o", "config.resqrypt"])
+        .args(["-p", "correct horse", "--argon2-memory", "8", "--argon2-parallelism", "1"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("more than 2 entries"));
+}
+
+#[test]
+fn test_decrypt_to_dir() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    fs::create_dir_all(dir.join("project")).unwrap();
+    fs::write(dir.join("project/notes.txt"), "kept").unwrap();
+    encrypt(dir, "project", "project.resqrypt").success();
+
+    let decrypt = |target: &str| {
+        resqrypt(dir)
+            .args(["decrypt", "-i", "project.resqrypt", "--to-dir", target, "-p", "correct horse"])
+            .assert()
+    };
+
+    // Created as needed, with the archive extracted under the input's
+    // stripped name (the format stores no original name)
+    decrypt("restored/today").success();
+    let restored = dir.join("restored/today/project");
+    let content = fs::read_to_string(restored.join("project/notes.txt")).unwrap();
+    assert_eq!(content, "kept");
+
+    fs::write(dir.join("occupied"), "x").unwrap();
+    decrypt("occupied").failure().stderr(predicate::str::contains("is not a directory"));
+}
+
+#[test]
+fn test_recover_damaged_file() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    let data: Vec<u8> = (0..100_000u32).map(|i| (i % 251 + 1) as u8).collect();
+    fs::write(dir.join("data.bin"), &data).unwrap();
+    resqrypt(dir)
+        .args(["encrypt", "-i", "data.bin", "-o", "data.bin.resqrypt", "--no-compress"])
+        .args(["--chunk-size", "16K", "-p", "correct horse"])
+        .args(["--argon2-memory", "8", "--argon2-parallelism", "1"])
+        .assert()
+        .success();
+
+    // Flip a byte in the middle of the file
+    let mut file = fs::read(dir.join("data.bin.resqrypt")).unwrap();
+    let middle = file.len() / 2;
+    file[middle] ^= 1;
+    fs::write(dir.join("data.bin.resqrypt"), &file).unwrap();
+
+    resqrypt(dir)
+        .args(["decrypt", "-i", "data.bin.resqrypt", "-o", "restored.bin", "-p", "correct horse"])
+        .assert()
+        .failure();
+
+    resqrypt(dir)
+        .args(["recover", "-i", "data.bin.resqrypt", "-o", "salvaged.bin", "-p", "correct horse"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Lost 1 of 7 chunks"))
+        .stdout(predicate::str::contains("bytes 49152-65535 (chunk 3)"));
+
+    // Only the damaged chunk is zeroed
+    let salvaged = fs::read(dir.join("salvaged.bin")).unwrap();
+    assert_eq!(salvaged.len(), data.len());
+    assert!(salvaged[49152..65536].iter().all(|&b| b == 0));
+    assert_eq!(salvaged[..49152], data[..49152]);
+    assert_eq!(salvaged[65536..], data[65536..]);
+
+    // A wrong password opens no chunk and leaves no output behind
+    resqrypt(dir)
+        .args(["recover", "-i", "data.bin.resqrypt", "-o", "wrong.bin", "-p", "wrong horse"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("no chunk could be decrypted"));
+    assert!(!dir.join("wrong.bin").exists());
+
+    // Compressed data is written up to the damage
+    let data: Vec<u8> =
+        (0..2_000_000u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8).collect();
+    fs::write(dir.join("noise.bin"), &data).unwrap();
+    resqrypt(dir)
+        .args(["encrypt", "-i", "noise.bin", "-o", "noise.resqrypt", "--chunk-size", "16K"])
+        .args(["-p", "correct horse", "--argon2-memory", "8", "--argon2-parallelism", "1"])
+        .assert()
+        .success();
+    let mut file = fs::read(dir.join("noise.resqrypt")).unwrap();
+    let middle = file.len() / 2;
+    file[middle] ^= 1;
+    fs::write(dir.join("noise.resqrypt"), &file).unwrap();
+
+    resqrypt(dir)
+        .args(["recover", "-i", "noise.resqrypt", "-o", "noise.out", "-p", "correct horse"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("decompression stopped after"));
+    let salvaged = fs::read(dir.join("noise.out")).unwrap();
+    assert!(!salvaged.is_empty() && salvaged.len() < data.len());
+    assert_eq!(salvaged[..], data[..salvaged.len()]);
+}
+
+#[test]
+fn test_encrypt_tar_input() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path();
+
+    // A tar written by another tool, with a layout of its own
+    let mut builder = tar::Builder::new(Vec::new());
+    let mut header = tar::Header::new_ustar();
+    header.set_size(5);
+    header.set_mode(0o644);
+    header.set_mtime(1_700_000_000);
+    builder.append_data(&mut header, "top/nested/file.txt", &b"hello"[..]).unwrap();
+    fs::write(dir.join("own.tar"), builder.into_inner().unwrap()).unwrap();
+
+    resqrypt(dir)
+        .args(["encrypt", "-i", "own.tar", "-o", "own.resqrypt", "--input-format", "tar"])
+        .args(["-p", "correct horse", "--argon2-memory", "8", "--argon2-parallelism", "1"])
+        .assert()
+        .success();
+    resqrypt(dir)
+        .args(["decrypt", "-i", "own.resqrypt", "-o", "restored", "-p", "correct horse"])
+        .assert()
+        .success();
+    assert_eq!(fs::read(dir.join("restored/top/nested/file.txt")).unwrap(), b"hello");
+
+    // Anything else is refused before encrypting
+    fs::write(dir.join("notes.txt"), "not a tar").unwrap();
+    resqrypt(dir)
+        .args(["encrypt", "-i", "notes.txt", "-o", "notes.resqrypt", "--input-format", "tar"])
+        .args(["-p", "correct horse"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("is not a well-formed tar archive"));
+    assert!(!dir.join("notes.resqrypt").exists());
+}
+
+#[test]
+fn test_verify_range() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    let data: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+    fs::write(dir.join("data.bin"), &data).unwrap();
+    resqrypt(dir)
+        .args(["encrypt", "-i", "data.bin", "-o", "data.bin.resqrypt", "--no-compress"])
+        .args(["--chunk-size", "16K", "-p", "correct horse"])
+        .args(["--argon2-memory", "8", "--argon2-parallelism", "1"])
+        .assert()
+        .success();
+    let verify = |range: Option<&str>| {
+        let mut cmd = resqrypt(dir);
+        cmd.args(["verify", "-i", "data.bin.resqrypt", "-p", "correct horse"]);
+        if let Some(range) = range {
+            cmd.args(["--range", range]);
+        }
+        cmd.assert()
+    };
+
+    verify(None).success().stdout(predicate::str::contains("Authenticated all 7 chunks"));
+
+    // Damage the fifth chunk (payload bytes 64K-80K)
+    let mut file = fs::read(dir.join("data.bin.resqrypt")).unwrap();
+    // The header ends before the data, 7 chunk overheads and the trailer
+    let header_len = file.len() - 100_000 - 7 * 20 - 16;
+    let chunk_start = header_len + 4 * (16384 + 20);
+    file[chunk_start + 100] ^= 1;
+    fs::write(dir.join("data.bin.resqrypt"), &file).unwrap();
+
+    verify(Some("16K-48K")).success().stdout(predicate::str::contains(
+        "Authenticated payload bytes 16384-49152 (4 chunks read)",
+    ));
+    verify(Some("70000-70100")).failure();
+    verify(None).failure();
+
+    // Ranges of compressed payloads would not match the original bytes
+    resqrypt(dir)
+        .args(["encrypt", "-i", "data.bin", "-o", "compressed.resqrypt", "--chunk-size", "16K"])
+        .args(["-p", "correct horse", "--argon2-memory", "8", "--argon2-parallelism", "1"])
+        .assert()
+        .success();
+    resqrypt(dir)
+        .args(["verify", "-i", "compressed.resqrypt", "-p", "correct horse", "--range", "0-1K"])
+        .assert()
+        .code(1)
+        .stderr(predicate::str::contains("is compressed"));
+
+    // Small files are sealed whole by default, with a single tag
+    encrypt(dir, "data.bin", "whole.resqrypt").success();
+    assert_eq!(resqrypt::inspect(dir.join("whole.resqrypt")).unwrap().version, 2);
+    resqrypt(dir)
+        .args(["verify", "-i", "whole.resqrypt", "-p", "correct horse", "--range", "0-1K"])
+        .assert()
+        .code(1)
+        .stderr(predicate::str::contains("sealed as a single message"));
+}
+
+#[test]
+fn test_verify_range_merkle() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    let data: Vec<u8> = (0..200_000u32).map(|i| (i.wrapping_mul(2654435761) >> 13) as u8).collect();
+    fs::write(dir.join("data.bin"), &data).unwrap();
+    let verify = |file: &str, range: &str| {
+        resqrypt(dir).args(["verify", "-i", file, "-p", "correct horse", "--range", range]).assert()
+    };
+
+    // Content-defined chunks need the tree to be checked on their own
+    for (name, merkle) in [("cdc.resqrypt", false), ("tree.resqrypt", true)] {
+        let mut cmd = resqrypt(dir);
+        cmd.args(["encrypt", "-i", "data.bin", "-o", name, "--cdc", "--no-compress"])
+            .args(["--chunk-size", "16K", "-p", "correct horse"])
+            .args(["--argon2-memory", "8", "--argon2-parallelism", "1"]);
+        if merkle {
+            cmd.arg("--merkle");
+        }
+        cmd.assert().success();
+    }
+    verify("cdc.resqrypt", "100K-110K").code(1).stderr(predicate::str::contains("--merkle"));
+    assert!(resqrypt::inspect(dir.join("tree.resqrypt")).unwrap().merkle);
+    verify("tree.resqrypt", "100K-110K")
+        .success()
+        .stdout(predicate::str::contains("each on its Merkle path"));
+    verify("tree.resqrypt", "190K-199999").success();
+
+    // Appended bytes are ignored, a damaged tree is not
+    let mut file = fs::read(dir.join("tree.resqrypt")).unwrap();
+    file.extend_from_slice(&[0u8; 4096]);
+    fs::write(dir.join("tree.resqrypt"), &file).unwrap();
+    verify("tree.resqrypt", "190K-199999").success();
+    let tree_end = file.len() - 4096;
+    file[tree_end - 1] ^= 1;
+    fs::write(dir.join("tree.resqrypt"), &file).unwrap();
+    resqrypt(dir)
+        .args(["verify", "-i", "tree.resqrypt", "-p", "correct horse"])
+        .assert()
+        .code(1)
+        .stderr(predicate::str::contains("Merkle"));
+}
+
+#[test]
+fn test_decrypt_truncated_leaves_no_output() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    let data: Vec<u8> =
+        (0..300_000u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 7) as u8).collect();
+    fs::write(dir.join("data.bin"), &data).unwrap();
+    encrypt(dir, "data.bin", "data.bin.resqrypt").success();
+
+    // The output is written as chunks are decrypted; cutting off the end
+    // must still leave nothing behind
+    let file = fs::read(dir.join("data.bin.resqrypt")).unwrap();
+    fs::write(dir.join("cut.resqrypt"), &file[..file.len() - 1000]).unwrap();
+    resqrypt(dir)
+        .args(["decrypt", "-i", "cut.resqrypt", "-o", "restored.bin", "-p", "correct horse"])
+        .assert()
+        .failure();
+
+    let mut names: Vec<_> = fs::read_dir(dir).unwrap().map(|e| e.unwrap().file_name()).collect();
+    names.sort();
+    assert_eq!(names, ["cut.resqrypt", "data.bin", "data.bin.resqrypt"]);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_filter_cmd_streams_file_output() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    let data = "The quick brown fox jumps over the lazy dog\n".repeat(20_000);
+    fs::write(dir.join("notes.txt"), &data).unwrap();
+
+    // ROT13 is its own inverse
+    let rot13 = "tr a-zA-Z n-za-mN-ZA-M";
+    resqrypt(dir)
+        .args(["encrypt", "-i", "notes.txt", "-o", "notes.resqrypt", "-p", "correct horse"])
+        .args(["--argon2-memory", "8", "--argon2-parallelism", "1", "--filter-cmd", rot13])
+        .assert()
+        .success();
+
+    resqrypt(dir)
+        .args(["decrypt", "-i", "notes.resqrypt", "-o", "restored.txt", "-p", "correct horse"])
+        .args(["--filter-cmd", rot13])
+        .assert()
+        .success();
+    assert_eq!(fs::read_to_string(dir.join("restored.txt")).unwrap(), data);
+
+    // A failing filter leaves no partial output behind
+    resqrypt(dir)
+        .args(["decrypt", "-i", "notes.resqrypt", "-o", "failed.txt", "-p", "correct horse"])
+        .args(["--filter-cmd", "head -c 100; exit 1"])
+        .assert()
+        .failure();
+    assert!(!dir.join("failed.txt").exists());
+}
+
+#[test]
+fn test_hash_algo_prints_output_digest() {
+    use sha2::{Digest, Sha256};
+
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    fs::write(dir.join("data.txt"), "digest me").unwrap();
+
+    let assert = resqrypt(dir)
+        .args(["encrypt", "-i", "data.txt", "-o", "data.resqrypt", "-p", "correct horse"])
+        .args(["--argon2-memory", "8", "--argon2-parallelism", "1", "--hash-algo", "sha256"])
+        .assert()
+        .success();
+
+    // The digest is of the bytes written, without reading them back
+    let digest: String = Sha256::digest(fs::read(dir.join("data.resqrypt")).unwrap())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    let stdout = String::from_utf8_lossy(&assert.get_output().stdout).into_owned();
+    assert!(stdout.contains(&format!("SHA-256: {}", digest)), "{}", stdout);
+}
+
+#[cfg(feature = "age")]
+#[test]
+fn test_age_format() {
+    use std::io::Read;
+
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    let data = "age ".repeat(10_000);
+    fs::write(dir.join("notes.txt"), &data).unwrap();
+    let identity = age::x25519::Identity::generate();
+
+    resqrypt(dir)
+        .args(["encrypt", "-i", "notes.txt", "-o", "notes.txt.age", "--format", "age"])
+        .args(["-r", &identity.to_public().to_string()])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("(age)"));
+
+    // The payload is zstd, for `age -d | zstd -d`
+    let file = fs::read(dir.join("notes.txt.age")).unwrap();
+    let decryptor = age::Decryptor::new(file.as_slice()).unwrap();
+    let mut compressed = Vec::new();
+    let identities = std::iter::once(&identity as &dyn age::Identity);
+    decryptor.decrypt(identities).unwrap().read_to_end(&mut compressed).unwrap();
+    assert_eq!(zstd::decode_all(compressed.as_slice()).unwrap(), data.as_bytes());
+
+    // Options only resqrypt files have are refused
+    resqrypt(dir)
+        .args(["encrypt", "-i", "notes.txt", "-o", "padded.age", "-p", "correct horse"])
+        .args(["--format", "age", "--pad-to", "4K"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("--pad-to cannot be used with --format age"));
+    resqrypt(dir)
+        .args(["encrypt", "-i", "notes.txt", "-o", "notes.txt.rsq"])
+        .args(["-r", &identity.to_public().to_string()])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("--recipient only applies with --format age"));
+}
@@ -0,0 +1,76 @@
+//! Peak memory of the streaming pipeline
+//!
+//! Encrypts and decrypts far more data than the allowed memory growth and
+//! checks the process high-water mark (`VmHWM`) stays well below it.
+
+#![cfg(target_os = "linux")]
+
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read};
+
+use resqrypt::crypto::{HeaderFlags, KdfParams};
+use resqrypt::pipeline::Pipeline;
+use tempfile::TempDir;
+
+/// Plaintext streamed through the pipeline
+const DATA_LEN: u64 = 64 << 20;
+
+/// Allowed growth of the peak resident set size
+const MAX_GROWTH: u64 = 16 << 20;
+
+/// Peak resident set size of this process in bytes
+fn peak_rss() -> u64 {
+    let status = fs::read_to_string("/proc/self/status").unwrap();
+    let line = status.lines().find(|line| line.starts_with("VmHWM:")).unwrap();
+    let kib: u64 = line.split_whitespace().nth(1).unwrap().parse().unwrap();
+    kib * 1024
+}
+
+/// Deterministic, poorly compressible bytes (xorshift64)
+struct NoiseReader {
+    state: u64,
+    remaining: u64,
+}
+
+impl Read for NoiseReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = buf.len().min(self.remaining as usize);
+        for byte in &mut buf[..n] {
+            self.state ^= self.state << 13;
+            self.state ^= self.state >> 7;
+            self.state ^= self.state << 17;
+            *byte = self.state as u8;
+        }
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+}
+
+fn noise() -> NoiseReader {
+    NoiseReader { state: 0x9e37_79b9_7f4a_7c15, remaining: DATA_LEN }
+}
+
+fn pipeline() -> Pipeline {
+    Pipeline::new().kdf_params(KdfParams { memory_cost: 1024, time_cost: 1, parallelism: 1 })
+}
+
+#[test]
+fn test_stream_peak_rss() {
+    let temp_dir = TempDir::new().unwrap();
+    let path = temp_dir.path().join("noise.resqrypt");
+
+    let before = peak_rss();
+    let file = BufWriter::new(File::create(&path).unwrap());
+    let header = pipeline().encrypt_stream(noise(), HeaderFlags::empty(), b"pw", file).unwrap();
+    let growth = peak_rss() - before;
+    assert!(header.is_chunked());
+    assert!(growth < MAX_GROWTH, "encrypting grew peak RSS by {} bytes", growth);
+
+    let before = peak_rss();
+    let file = BufReader::new(File::open(&path).unwrap());
+    let (_, mut plaintext) = pipeline().decrypt_stream(file, b"pw").unwrap();
+    let copied = io::copy(&mut plaintext, &mut io::sink()).unwrap();
+    let growth = peak_rss() - before;
+    assert_eq!(copied, DATA_LEN);
+    assert!(growth < MAX_GROWTH, "decrypting grew peak RSS by {} bytes", growth);
+}